@@ -0,0 +1,105 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Compares the throughput of deadlock-proof mutices with the std mutex
+//! underneath them, uncontended and shared between threads.
+
+use std::{
+    hint::black_box,
+    sync::{Arc, Barrier, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use deadlock_proof_mutex::{DeadlockProofMutex, OuterMutexPermission};
+
+struct Counter;
+
+const THREADS: usize = 4;
+
+fn uncontended(c: &mut Criterion) {
+    let mut group = c.benchmark_group("uncontended");
+    group.bench_function("std", |b| {
+        let mutex = Mutex::new(0u64);
+        b.iter(|| *mutex.lock().unwrap() += 1);
+        black_box(mutex);
+    });
+    group.bench_function("deadlock_proof", |b| {
+        let mutex = DeadlockProofMutex::new(0u64, Counter);
+        let mut permission = Some(OuterMutexPermission::get());
+        b.iter(|| {
+            let mut guard = mutex.lock(permission.take().unwrap()).unwrap();
+            *guard += 1;
+            permission = Some(guard.unlock());
+        });
+        black_box(mutex);
+    });
+    group.finish();
+}
+
+/// Runs `iterations` increments split across [`THREADS`] threads, each
+/// calling `increment` in a loop, and returns the time from all of them
+/// starting to the last finishing.
+fn run_threads<S: Send + Sync + 'static>(
+    shared: S,
+    iterations: u64,
+    increment: fn(&S, u64),
+) -> Duration {
+    let shared = Arc::new(shared);
+    let barrier = Arc::new(Barrier::new(THREADS + 1));
+    let threads: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let shared = shared.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                increment(&shared, iterations / THREADS as u64);
+            })
+        })
+        .collect();
+    barrier.wait();
+    let start = Instant::now();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn contended(c: &mut Criterion) {
+    let mut group = c.benchmark_group("contended");
+    group.bench_function("std", |b| {
+        b.iter_custom(|iterations| {
+            run_threads(Mutex::new(0u64), iterations, |mutex, n| {
+                for _ in 0..n {
+                    *mutex.lock().unwrap() += 1;
+                }
+            })
+        })
+    });
+    group.bench_function("deadlock_proof", |b| {
+        b.iter_custom(|iterations| {
+            run_threads(
+                DeadlockProofMutex::<_, OuterMutexPermission, _>::new(0u64, Counter),
+                iterations,
+                |mutex, n| {
+                    let mut permission = OuterMutexPermission::get();
+                    for _ in 0..n {
+                        let mut guard = mutex.lock(permission).unwrap();
+                        *guard += 1;
+                        permission = guard.unlock();
+                    }
+                },
+            )
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, uncontended, contended);
+criterion_main!(benches);
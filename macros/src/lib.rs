@@ -0,0 +1,292 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Procedural macros for `deadlock-proof-mutex`. Use them through that
+//! crate's `macros` feature rather than depending on this crate directly.
+
+use proc_macro::TokenStream;
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote_spanned,
+    punctuated::Punctuated,
+    spanned::Spanned,
+    visit_mut::{self, VisitMut},
+    Error, ExprMethodCall, FnArg, ItemFn, ItemStruct, Token, Type,
+};
+
+/// Marks the function taking the main thread's mutex permission. Write
+/// `fn main(permission: OuterMutexPermission)`; the macro turns it into a
+/// normal `main` which claims the permission before any of your code runs,
+/// so there's no chance of claiming it anywhere else first.
+#[proc_macro_attribute]
+pub fn main(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as ItemFn);
+    match expand_main(attr.into(), input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand_main(
+    attr: proc_macro2::TokenStream,
+    input: ItemFn,
+) -> syn::Result<proc_macro2::TokenStream> {
+    if !attr.is_empty() {
+        return Err(Error::new(attr.span(), "this attribute takes no arguments"));
+    }
+    let sig = &input.sig;
+    if let Some(asyncness) = sig.asyncness {
+        return Err(Error::new(
+            asyncness.span(),
+            "the main function can't be async; async tasks get their permission from AsyncOuterMutexPermission::scope",
+        ));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(Error::new(
+            sig.generics.span(),
+            "the main function can't be generic",
+        ));
+    }
+    if sig.inputs.len() != 1 || !matches!(sig.inputs[0], FnArg::Typed(_)) {
+        return Err(Error::new(
+            sig.inputs.span(),
+            "the main function must take exactly one argument, the OuterMutexPermission",
+        ));
+    }
+    let attrs = &input.attrs;
+    let vis = &input.vis;
+    let name = &sig.ident;
+    let output = &sig.output;
+    let inputs = &sig.inputs;
+    let body = &input.block;
+    Ok(quote! {
+        #(#attrs)*
+        #vis fn #name() #output {
+            fn __deadlock_proof_main(#inputs) #output #body
+            __deadlock_proof_main(::deadlock_proof_mutex::OuterMutexPermission::get())
+        }
+    })
+}
+
+/// The methods of `DeadlockProofMutex` which claim it using a permission.
+const CLAIMING_METHODS: &[&str] = &[
+    "lock",
+    "lock_borrowing",
+    "lock_for_nested",
+    "lock_when",
+    "try_lock",
+    "try_lock_for",
+    "try_lock_until",
+    "with_lock",
+    "with_lock_nested",
+];
+
+/// Declares the mutices a function claims, by their identifiers, outermost
+/// first: `#[acquires(DbLock, CacheLock)] fn refresh(p: OuterMutexPermission)`
+/// claims a `DbLock` mutex using `p`, then a `CacheLock` mutex nested within
+/// it. The first argument of the function is its permission.
+///
+/// Every claim of a `DeadlockProofMutex` within the body is checked at
+/// compile time against the list: its identifier must be listed, and its
+/// permission type must be the one reached by nesting within each of the
+/// identifiers listed before it. Claims of other types with methods of the
+/// same names, such as pools, can't be checked and are rejected. The list
+/// is also added to the function's documentation.
+#[proc_macro_attribute]
+pub fn acquires(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let identifiers = parse_macro_input!(attr with Punctuated::<Type, Token![,]>::parse_terminated);
+    let input = parse_macro_input!(item as ItemFn);
+    match expand_acquires(identifiers, input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand_acquires(
+    identifiers: Punctuated<Type, Token![,]>,
+    mut input: ItemFn,
+) -> syn::Result<proc_macro2::TokenStream> {
+    if identifiers.is_empty() {
+        return Err(Error::new(
+            input.sig.span(),
+            "list the identifiers of the mutices this function claims, outermost first",
+        ));
+    }
+    let permission = match input.sig.inputs.first() {
+        Some(FnArg::Typed(arg)) => arg.ty.clone(),
+        _ => {
+            return Err(Error::new(
+                input.sig.inputs.span(),
+                "the first argument must be the permission with which the mutices are claimed",
+            ))
+        }
+    };
+    let list = identifiers
+        .iter()
+        .rev()
+        .fold(quote!(()), |tail, identifier| quote!((#identifier, #tail)));
+    let doc = format!(
+        " # Mutices claimed\n\n In this order, each nested within the one before: {}.",
+        identifiers
+            .iter()
+            .map(|identifier| format!("`{}`", quote!(#identifier)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    CheckClaims {
+        list: syn::parse2(list)?,
+        permission: *permission,
+    }
+    .visit_block_mut(&mut input.block);
+    let attrs = &input.attrs;
+    let vis = &input.vis;
+    let sig = &input.sig;
+    let body = &input.block;
+    Ok(quote! {
+        #(#attrs)*
+        #[doc = ""]
+        #[doc = #doc]
+        #vis #sig #body
+    })
+}
+
+/// Routes the receiver of every claiming method call through
+/// `__acquires_check`.
+struct CheckClaims {
+    list: Type,
+    permission: Type,
+}
+
+impl VisitMut for CheckClaims {
+    fn visit_expr_method_call_mut(&mut self, call: &mut ExprMethodCall) {
+        visit_mut::visit_expr_method_call_mut(self, call);
+        if call.args.is_empty() || !CLAIMING_METHODS.iter().any(|m| call.method == m) {
+            return;
+        }
+        let list = &self.list;
+        let permission = &self.permission;
+        let receiver = &call.receiver;
+        *call.receiver = parse_quote_spanned! {receiver.span()=>
+            #receiver.__acquires_check::<#list, #permission, _>()
+        };
+    }
+
+    fn visit_item_mut(&mut self, _item: &mut syn::Item) {
+        // Items nested within the body are separate functions.
+    }
+}
+
+/// Protects the fields of a struct marked `#[lock]`, each with its own
+/// `DeadlockProofMutex`, ordered as the fields are declared. This is the
+/// usual shape of a context object with several locks:
+/// `#[protected] pub struct Context { #[lock] config: Config, #[lock] cache: Cache, name: String }`.
+///
+/// Each marked field becomes a `DeadlockProofMutex` of its original type,
+/// with a newly declared identifier named after the struct and field, here
+/// `ContextConfigLock` and `ContextCacheLock`. The identifiers form a lock
+/// order just as with `declare_lock_order!`, so any of the mutices can be
+/// claimed first using `lock_ordered`, and any later one within it using
+/// `lock_after`. Every mutex is claimed using an `OuterMutexPermission`
+/// unless another permission type is given, as in `#[protected(P)]`.
+///
+/// An accessor method is added for each marked field, with the field's
+/// visibility, so that the mutices can be reached from outside the module
+/// without the fields themselves being public. The identifiers implement
+/// `Default`, so a field can be initialized with `content.into()`.
+#[proc_macro_attribute]
+pub fn protected(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let permission = if attr.is_empty() {
+        syn::parse_quote!(::deadlock_proof_mutex::OuterMutexPermission)
+    } else {
+        parse_macro_input!(attr as Type)
+    };
+    let input = parse_macro_input!(item as ItemStruct);
+    match expand_protected(permission, input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand_protected(
+    permission: Type,
+    mut input: ItemStruct,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &input.ident;
+    let vis = &input.vis;
+    let mut identifiers = Vec::new();
+    let mut declarations = Vec::new();
+    let mut accessors = Vec::new();
+    for field in input.fields.iter_mut() {
+        let before = field.attrs.len();
+        field.attrs.retain(|attr| !attr.path().is_ident("lock"));
+        if field.attrs.len() == before {
+            continue;
+        }
+        let field_name = field.ident.clone().ok_or_else(|| {
+            Error::new(
+                field.span(),
+                "only named fields can be protected, since the identifiers are named after them",
+            )
+        })?;
+        let identifier = format_ident!("{}{}Lock", name, upper_camel_case(&field_name.to_string()));
+        let content = &field.ty;
+        field.ty = syn::parse_quote! {
+            ::deadlock_proof_mutex::DeadlockProofMutex<#content, #permission, #identifier>
+        };
+        let field_vis = &field.vis;
+        let ty = &field.ty;
+        let identifier_doc =
+            format!(" Identifies the mutex protecting the `{field_name}` field of [`{name}`].");
+        let accessor_doc = format!(
+            " The mutex protecting the `{field_name}` field, which is number {} in the lock order.",
+            identifiers.len() + 1
+        );
+        declarations.push(quote! {
+            #[doc = #identifier_doc]
+            #[derive(Default)]
+            #vis struct #identifier;
+        });
+        accessors.push(quote! {
+            #[doc = #accessor_doc]
+            #field_vis fn #field_name(&self) -> &#ty {
+                &self.#field_name
+            }
+        });
+        identifiers.push(identifier);
+    }
+    if identifiers.is_empty() {
+        return Err(Error::new(
+            input.ident.span(),
+            "mark the fields to protect with #[lock]",
+        ));
+    }
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        #input
+        #(#declarations)*
+        ::deadlock_proof_mutex::__lock_order_impls!(#(#identifiers)<*);
+        ::deadlock_proof_mutex::__lock_order_graph!(#([#identifiers])<*);
+        impl #impl_generics #name #ty_generics #where_clause {
+            #(#accessors)*
+        }
+    })
+}
+
+/// Converts a field name such as `read_cache` to `ReadCache`.
+fn upper_camel_case(name: &str) -> String {
+    name.trim_start_matches("r#")
+        .split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_uppercase().chain(chars).collect::<String>())
+                .unwrap_or_default()
+        })
+        .collect()
+}
@@ -0,0 +1,59 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Support for the `acquires` attribute macro. None of this is intended to
+//! be used directly.
+
+use std::marker::PhantomData;
+
+use crate::{DeadlockProofMutex, MutexBackend, MutexPermission, NestedMutexPermission};
+
+/// The position of the first identifier in a list.
+pub struct Here;
+
+/// The position of an identifier later in a list.
+pub struct There<X>(PhantomData<X>);
+
+/// Implemented by a list of identifiers `(A, (B, (C, ())))`, declared
+/// using `#[acquires(A, B, C)]` for a function taking a permission `B`,
+/// when `I` appears in the list at position `X`. The permission needed to
+/// claim that identifier's mutex, having nested within all those before it,
+/// is `Permission`.
+#[diagnostic::on_unimplemented(
+    message = "this function claims `{I}`, which isn't declared in its #[acquires] list in this position",
+    label = "claimed here",
+    note = "#[acquires] lists the identifiers of the mutices claimed, outermost first, each nested within the one before"
+)]
+pub trait AcquiredAt<I, B, X> {
+    /// The permission type of mutices identified by `I`.
+    type Permission;
+}
+
+impl<I, Tail, B> AcquiredAt<I, B, Here> for (I, Tail) {
+    type Permission = B;
+}
+
+impl<I, Head, Tail, B: MutexPermission, X> AcquiredAt<I, B, There<X>> for (Head, Tail)
+where
+    Tail: AcquiredAt<I, NestedMutexPermission<B, Head>, X>,
+{
+    type Permission = Tail::Permission;
+}
+
+impl<T, P: MutexPermission, I, M: MutexBackend<T>> DeadlockProofMutex<T, P, I, M> {
+    /// Checks that this mutex is one that a function taking the permission
+    /// `B` is declared to claim by the list `L`, returning it so that it can
+    /// then be claimed.
+    #[doc(hidden)]
+    pub fn __acquires_check<L, B, X>(&self) -> &Self
+    where
+        L: AcquiredAt<I, B, X, Permission = P>,
+    {
+        self
+    }
+}
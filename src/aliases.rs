@@ -0,0 +1,70 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Short names for the permission and guard types of mutices a few levels
+//! down a chain of [`crate::DeadlockProofMutex::lock_for_nested`] calls in
+//! the default permission domain, for readable function signatures. For
+//! deeper chains, or other starting permissions, use
+//! [`crate::nested_permission`].
+
+use crate::{DeadlockProofMutexGuard, NestedMutexPermission, OuterMutexPermission};
+
+/// The permission to claim mutices nested within the outermost mutex,
+/// identified by `I1`.
+pub type Nested1<I1> = NestedMutexPermission<OuterMutexPermission, I1>;
+
+/// The permission to claim mutices nested within a chain of two mutices,
+/// identified from outermost to innermost by `I1` and `I2`.
+pub type Nested2<I1, I2> = NestedMutexPermission<Nested1<I1>, I2>;
+
+/// The permission to claim mutices nested within a chain of three mutices,
+/// identified from outermost to innermost by `I1`, `I2` and `I3`.
+pub type Nested3<I1, I2, I3> = NestedMutexPermission<Nested2<I1, I2>, I3>;
+
+/// The permission to claim mutices nested within a chain of four mutices,
+/// identified from outermost to innermost by `I1`, `I2`, `I3` and `I4`.
+pub type Nested4<I1, I2, I3, I4> = NestedMutexPermission<Nested3<I1, I2, I3>, I4>;
+
+/// The permission to claim mutices nested within a chain of five mutices,
+/// identified from outermost to innermost by `I1`, `I2`, `I3`, `I4` and `I5`.
+pub type Nested5<I1, I2, I3, I4, I5> = NestedMutexPermission<Nested4<I1, I2, I3, I4>, I5>;
+
+/// The permission to claim mutices nested within a chain of six mutices,
+/// identified from outermost to innermost by `I1`, `I2`, `I3`, `I4`, `I5` and `I6`.
+pub type Nested6<I1, I2, I3, I4, I5, I6> = NestedMutexPermission<Nested5<I1, I2, I3, I4, I5>, I6>;
+
+/// A guard for an outermost mutex identified by `I`.
+pub type OuterGuard<'a, T, I> = DeadlockProofMutexGuard<'a, T, OuterMutexPermission, I>;
+
+/// A guard for a mutex identified by `I`, claimed using a [`Nested1`]
+/// permission.
+pub type Nested1Guard<'a, T, I1, I> = DeadlockProofMutexGuard<'a, T, Nested1<I1>, I>;
+
+/// A guard for a mutex identified by `I`, claimed using a [`Nested2`]
+/// permission.
+pub type Nested2Guard<'a, T, I1, I2, I> = DeadlockProofMutexGuard<'a, T, Nested2<I1, I2>, I>;
+
+/// A guard for a mutex identified by `I`, claimed using a [`Nested3`]
+/// permission.
+pub type Nested3Guard<'a, T, I1, I2, I3, I> =
+    DeadlockProofMutexGuard<'a, T, Nested3<I1, I2, I3>, I>;
+
+/// A guard for a mutex identified by `I`, claimed using a [`Nested4`]
+/// permission.
+pub type Nested4Guard<'a, T, I1, I2, I3, I4, I> =
+    DeadlockProofMutexGuard<'a, T, Nested4<I1, I2, I3, I4>, I>;
+
+/// A guard for a mutex identified by `I`, claimed using a [`Nested5`]
+/// permission.
+pub type Nested5Guard<'a, T, I1, I2, I3, I4, I5, I> =
+    DeadlockProofMutexGuard<'a, T, Nested5<I1, I2, I3, I4, I5>, I>;
+
+/// A guard for a mutex identified by `I`, claimed using a [`Nested6`]
+/// permission.
+pub type Nested6Guard<'a, T, I1, I2, I3, I4, I5, I6, I> =
+    DeadlockProofMutexGuard<'a, T, Nested6<I1, I2, I3, I4, I5, I6>, I>;
@@ -0,0 +1,276 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{Mutex, MutexGuard, PoisonError},
+    task::{Context, Poll, Waker},
+};
+
+use crate::{
+    async_mutex::AsyncMutexBackend,
+    notify::{NotifyState, Wake},
+    AsyncMutexPermission, AsyncNestedMutexPermission, DeadlockProofAsyncMutexGuard,
+    DeadlockProofNestedAsyncMutexGuard,
+};
+#[cfg(feature = "tokio")]
+use crate::{DeadlockProofAsyncRwLockWriteGuard, DeadlockProofNestedAsyncRwLockWriteGuard};
+
+/// A condition variable for use with a [`crate::DeadlockProofAsyncMutex`],
+/// or with the write guards of a `DeadlockProofAsyncRwLock`, just as
+/// [`crate::DeadlockProofCondvar`] is for synchronous locks.
+/// Waiting consumes the guard, releasing the mutex, and hands it back - along
+/// with the permission token it holds - once woken and the mutex reclaimed,
+/// so async producers and consumers needn't drop out of the permission
+/// system to wait. It needs no particular executor.
+///
+/// The condvar is parameterized by the identifier type `I` of the mutex it's
+/// used with, so the type system guarantees that it's only ever used with
+/// that one mutex. As with any condvar, spurious wakeups are possible.
+///
+/// If a task stops waiting because its future is dropped, the permission
+/// within the guard is dropped too, as for
+/// [`crate::DeadlockProofAsyncMutex::lock`], but any notification meant for
+/// it is passed on to another waiter.
+pub struct DeadlockProofAsyncCondvar<I>(Mutex<NotifyState>, PhantomData<fn(I)>);
+
+impl<I> DeadlockProofAsyncCondvar<I> {
+    /// Create a new condvar. The identifier type is usually inferred from
+    /// the first guard passed to [`DeadlockProofAsyncCondvar::wait`].
+    pub fn new() -> Self {
+        Self(Mutex::default(), PhantomData)
+    }
+
+    fn state(&self) -> MutexGuard<'_, NotifyState> {
+        // The state is always consistent, even if a thread panicked.
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits for this condvar to be notified, releasing the mutex while
+    /// waiting and reclaiming it before returning the guard.
+    pub async fn wait<'a, T, P: AsyncMutexPermission, M: AsyncMutexBackend<T>>(
+        &self,
+        guard: DeadlockProofAsyncMutexGuard<'a, T, P, I, M>,
+    ) -> DeadlockProofAsyncMutexGuard<'a, T, P, I, M> {
+        let DeadlockProofAsyncMutexGuard(inner, permission, mutex, held) = guard;
+        // Registered before releasing the mutex, so that a notification
+        // sent as soon as it's released isn't missed.
+        let waiting = self.register();
+        drop(inner);
+        drop(held);
+        waiting.await;
+        mutex.guard(mutex.0.lock().await, permission)
+    }
+
+    /// Equivalent to [`DeadlockProofAsyncCondvar::wait`] for a guard
+    /// obtained from [`crate::DeadlockProofAsyncMutex::lock_for_nested`].
+    /// The permission for nested mutices must be lent back, so that none of
+    /// them can be held: reclaiming this mutex while holding one would claim
+    /// them out of order.
+    pub async fn wait_nested<'a, T, P: AsyncMutexPermission, M: AsyncMutexBackend<T>>(
+        &self,
+        guard: DeadlockProofNestedAsyncMutexGuard<'a, T, P, I, M>,
+        _token: &mut AsyncNestedMutexPermission<P, I>,
+    ) -> DeadlockProofNestedAsyncMutexGuard<'a, T, P, I, M> {
+        let DeadlockProofNestedAsyncMutexGuard(inner, permission, mutex, held) = guard;
+        let waiting = self.register();
+        drop(inner);
+        drop(held);
+        waiting.await;
+        mutex.nested_guard(mutex.0.lock().await, permission)
+    }
+
+    /// Waits for this condvar to be notified until `condition` returns
+    /// false, checking it first, with the mutex held. Returns the guard once
+    /// the condition is false.
+    pub async fn wait_while<'a, T, P: AsyncMutexPermission, M: AsyncMutexBackend<T>>(
+        &self,
+        mut guard: DeadlockProofAsyncMutexGuard<'a, T, P, I, M>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> DeadlockProofAsyncMutexGuard<'a, T, P, I, M> {
+        while condition(&mut guard) {
+            guard = self.wait(guard).await;
+        }
+        guard
+    }
+
+    /// Equivalent to [`DeadlockProofAsyncCondvar::wait_while`] for a guard
+    /// obtained from [`crate::DeadlockProofAsyncMutex::lock_for_nested`],
+    /// which likewise needs the nested permission lent back.
+    pub async fn wait_while_nested<'a, T, P: AsyncMutexPermission, M: AsyncMutexBackend<T>>(
+        &self,
+        mut guard: DeadlockProofNestedAsyncMutexGuard<'a, T, P, I, M>,
+        token: &mut AsyncNestedMutexPermission<P, I>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> DeadlockProofNestedAsyncMutexGuard<'a, T, P, I, M> {
+        while condition(&mut guard) {
+            guard = self.wait_nested(guard, token).await;
+        }
+        guard
+    }
+
+    /// Equivalent to [`DeadlockProofAsyncCondvar::wait`] for a write guard
+    /// of a [`crate::DeadlockProofAsyncRwLock`].
+    #[cfg(feature = "tokio")]
+    pub async fn wait_write<'a, T, P: AsyncMutexPermission>(
+        &self,
+        guard: DeadlockProofAsyncRwLockWriteGuard<'a, T, P, I>,
+    ) -> DeadlockProofAsyncRwLockWriteGuard<'a, T, P, I> {
+        let DeadlockProofAsyncRwLockWriteGuard(inner, permission, lock) = guard;
+        let waiting = self.register();
+        drop(inner);
+        waiting.await;
+        DeadlockProofAsyncRwLockWriteGuard(lock.0.write().await, permission, lock)
+    }
+
+    /// Equivalent to [`DeadlockProofAsyncCondvar::wait_write`] for a guard
+    /// obtained from [`crate::DeadlockProofAsyncRwLock::write_for_nested`].
+    /// The permission for nested locks must be lent back, so that none of
+    /// them can be held: reclaiming this lock while holding one would claim
+    /// them out of order.
+    #[cfg(feature = "tokio")]
+    pub async fn wait_write_nested<'a, T, P: AsyncMutexPermission>(
+        &self,
+        guard: DeadlockProofNestedAsyncRwLockWriteGuard<'a, T, P, I>,
+        _token: &mut AsyncNestedMutexPermission<P, I>,
+    ) -> DeadlockProofNestedAsyncRwLockWriteGuard<'a, T, P, I> {
+        let DeadlockProofNestedAsyncRwLockWriteGuard(inner, permission, lock) = guard;
+        let waiting = self.register();
+        drop(inner);
+        waiting.await;
+        DeadlockProofNestedAsyncRwLockWriteGuard(lock.0.write().await, permission, lock)
+    }
+
+    /// Equivalent to [`DeadlockProofAsyncCondvar::wait_while`] for a write
+    /// guard of a [`crate::DeadlockProofAsyncRwLock`].
+    #[cfg(feature = "tokio")]
+    pub async fn wait_while_write<'a, T, P: AsyncMutexPermission>(
+        &self,
+        mut guard: DeadlockProofAsyncRwLockWriteGuard<'a, T, P, I>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> DeadlockProofAsyncRwLockWriteGuard<'a, T, P, I> {
+        while condition(&mut guard) {
+            guard = self.wait_write(guard).await;
+        }
+        guard
+    }
+
+    /// Equivalent to [`DeadlockProofAsyncCondvar::wait_while_write`] for a
+    /// guard obtained from
+    /// [`crate::DeadlockProofAsyncRwLock::write_for_nested`], which likewise
+    /// needs the nested permission lent back.
+    #[cfg(feature = "tokio")]
+    pub async fn wait_while_write_nested<'a, T, P: AsyncMutexPermission>(
+        &self,
+        mut guard: DeadlockProofNestedAsyncRwLockWriteGuard<'a, T, P, I>,
+        token: &mut AsyncNestedMutexPermission<P, I>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> DeadlockProofNestedAsyncRwLockWriteGuard<'a, T, P, I> {
+        while condition(&mut guard) {
+            guard = self.wait_write_nested(guard, token).await;
+        }
+        guard
+    }
+
+    /// Wakes the task which has been waiting longest, if any.
+    pub fn notify_one(&self) {
+        self.state().wake_one();
+    }
+
+    /// Wakes every task currently waiting.
+    pub fn notify_all(&self) {
+        self.state().wake_all();
+    }
+
+    /// Registers as a waiter, to be woken by the next notification.
+    pub(crate) fn register(&self) -> Waiting<'_, I> {
+        // The real waker is only known once the future is polled.
+        let id = self.state().register(Wake::Task(Waker::noop().clone()));
+        Waiting(self, Some(id))
+    }
+}
+
+impl<I> Default for DeadlockProofAsyncCondvar<I> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Waits for a notification, having registered as a waiter.
+pub(crate) struct Waiting<'a, I>(
+    &'a DeadlockProofAsyncCondvar<I>,
+    /// This future's entry in the list of waiters, until notified.
+    Option<u64>,
+);
+
+impl<I> Future for Waiting<'_, I> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let id = self.1.expect("polled after completion");
+        if self.0.state().poll_notified(id, cx.waker()) {
+            self.1 = None;
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<I> Drop for Waiting<'_, I> {
+    fn drop(&mut self) {
+        if let Some(id) = self.1 {
+            let mut state = self.0.state();
+            // A notification meant for this future mustn't be lost.
+            if state.remove(id) {
+                state.wake_one();
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        task, AsyncOuterMutexPermission, DeadlockProofAsyncCondvar, DeadlockProofAsyncRwLock,
+    };
+
+    struct State;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn wait_while_write_releases_the_lock() {
+        let lock: Arc<DeadlockProofAsyncRwLock<u32, AsyncOuterMutexPermission, State>> =
+            Arc::new(DeadlockProofAsyncRwLock::new(0, State));
+        let condvar = Arc::new(DeadlockProofAsyncCondvar::new());
+        let producer = task::spawn({
+            let lock = Arc::clone(&lock);
+            let condvar = Arc::clone(&condvar);
+            move |mut permission| async move {
+                for _ in 0..3 {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    let mut guard = lock.write(permission).await;
+                    *guard += 1;
+                    permission = guard.unlock();
+                    condvar.notify_all();
+                }
+            }
+        });
+        task::with_permission(|permission| async {
+            let guard = lock.write(permission).await;
+            let guard = condvar.wait_while_write(guard, |n| *n < 3).await;
+            assert_eq!(*guard, 3);
+            guard.unlock();
+        })
+        .await;
+        producer.await.unwrap();
+    }
+}
@@ -0,0 +1,417 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#[cfg(feature = "tokio")]
+use std::time::Duration;
+use std::{
+    future::Future,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+#[cfg(feature = "tokio")]
+use tokio::time::error::Elapsed;
+
+use crate::{
+    async_permission::{
+        check_task, AsyncMutexPermission, AsyncNestedMutexPermission,
+        AsyncSequentialMutexPermission,
+    },
+    DeadlockProofAsyncCondvar,
+};
+
+/// An ordinary async mutex which can be made deadlock-proof by wrapping it
+/// in a [`DeadlockProofAsyncMutex`], just as [`crate::MutexBackend`] does
+/// for synchronous mutices.
+///
+/// With the `tokio` feature, `tokio::sync::Mutex` can be used, and with
+/// the `futures` feature, `futures_util::lock::Mutex` can be. Neither needs
+/// its own runtime, so either works on any executor.
+pub trait AsyncMutexBackend<T> {
+    /// The guard returned when this mutex is claimed.
+    type Guard<'a>: DerefMut<Target = T>
+    where
+        Self: 'a;
+
+    /// Creates a new mutex containing `content`.
+    fn new(content: T) -> Self;
+
+    /// Acquires the mutex, yielding until it is able to do so.
+    fn lock(&self) -> impl Future<Output = Self::Guard<'_>>;
+
+    /// Attempts to acquire the mutex without yielding.
+    fn try_lock(&self) -> Option<Self::Guard<'_>>;
+}
+
+#[cfg(feature = "tokio")]
+impl<T> AsyncMutexBackend<T> for tokio::sync::Mutex<T> {
+    type Guard<'a>
+        = tokio::sync::MutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn new(content: T) -> Self {
+        tokio::sync::Mutex::new(content)
+    }
+
+    fn lock(&self) -> impl Future<Output = tokio::sync::MutexGuard<'_, T>> {
+        tokio::sync::Mutex::lock(self)
+    }
+
+    fn try_lock(&self) -> Option<tokio::sync::MutexGuard<'_, T>> {
+        tokio::sync::Mutex::try_lock(self).ok()
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<T> AsyncMutexBackend<T> for futures_util::lock::Mutex<T> {
+    type Guard<'a>
+        = futures_util::lock::MutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn new(content: T) -> Self {
+        futures_util::lock::Mutex::new(content)
+    }
+
+    fn lock(&self) -> impl Future<Output = futures_util::lock::MutexGuard<'_, T>> {
+        futures_util::lock::Mutex::lock(self)
+    }
+
+    fn try_lock(&self) -> Option<futures_util::lock::MutexGuard<'_, T>> {
+        futures_util::lock::Mutex::try_lock(self)
+    }
+}
+
+/// The backend of a [`DeadlockProofAsyncMutex`] unless another is given:
+/// [`tokio::sync::Mutex`] with the `tokio` feature, otherwise
+/// `futures_util::lock::Mutex`.
+#[cfg(feature = "tokio")]
+pub type DefaultAsyncBackend<T> = tokio::sync::Mutex<T>;
+
+/// The backend of a [`DeadlockProofAsyncMutex`] unless another is given:
+/// `tokio::sync::Mutex` with the `tokio` feature, otherwise
+/// [`futures_util::lock::Mutex`].
+#[cfg(not(feature = "tokio"))]
+pub type DefaultAsyncBackend<T> = futures_util::lock::Mutex<T>;
+
+/// An async mutex which can't deadlock. It works just like
+/// [`crate::DeadlockProofMutex`], except that claiming it yields rather than
+/// blocking the thread, and its permission tokens belong to tasks rather
+/// than threads. See [`crate::AsyncOuterMutexPermission`].
+///
+/// Its guards, like its permission tokens, are `Send` whenever the backend's
+/// guards are, so a task may hold them across an `.await` and still be
+/// spawned onto a work-stealing executor. The lock ordering is proven per
+/// task rather than per thread, so it doesn't matter which thread the task
+/// resumes on.
+///
+/// The backend `M` is any [`AsyncMutexBackend`], by default
+/// [`DefaultAsyncBackend`]. Nothing here depends on a particular executor.
+pub struct DeadlockProofAsyncMutex<
+    T,
+    P: AsyncMutexPermission,
+    I,
+    M: AsyncMutexBackend<T> = DefaultAsyncBackend<T>,
+>(
+    pub(crate) M,
+    PhantomData<fn(P)>,
+    // Only the backend decides whether the mutex is `Send` and `Sync`.
+    PhantomData<fn() -> (T, I)>,
+    /// Notified whenever a guard is released, for
+    /// [`DeadlockProofAsyncMutex::lock_when`].
+    pub(crate) DeadlockProofAsyncCondvar<I>,
+);
+
+impl<T, P: AsyncMutexPermission, I, M: AsyncMutexBackend<T>> DeadlockProofAsyncMutex<T, P, I, M> {
+    /// Create a new async mutex. The `_identifier` is a type unique to this
+    /// mutex, just as for [`crate::DeadlockProofMutex::new`].
+    pub fn new(content: T, _identifier: I) -> Self {
+        Self(
+            M::new(content),
+            PhantomData,
+            PhantomData,
+            DeadlockProofAsyncCondvar::new(),
+        )
+    }
+
+    /// Wraps a guard for the underlying mutex as a deadlock-proof one.
+    pub(crate) fn guard<'a>(
+        &'a self,
+        guard: M::Guard<'a>,
+        permission: P,
+    ) -> DeadlockProofAsyncMutexGuard<'a, T, P, I, M> {
+        DeadlockProofAsyncMutexGuard(guard, permission, self, AsyncHeldLock(&self.3))
+    }
+
+    /// Wraps a guard for the underlying mutex as a deadlock-proof nested
+    /// one.
+    pub(crate) fn nested_guard<'a>(
+        &'a self,
+        guard: M::Guard<'a>,
+        permission: P,
+    ) -> DeadlockProofNestedAsyncMutexGuard<'a, T, P, I, M> {
+        DeadlockProofNestedAsyncMutexGuard(guard, permission, self, AsyncHeldLock(&self.3))
+    }
+
+    /// Acquires this mutex, yielding until it is able to do so.
+    ///
+    /// If the returned future is dropped before the mutex is claimed, for
+    /// instance by a `select!` which chose another branch, the permission
+    /// is dropped with it. An [`crate::AsyncOuterMutexPermission`] then
+    /// returns to the task and can be claimed again, but any other
+    /// permission is lost. Use [`DeadlockProofAsyncMutex::lock_cancel_safe`]
+    /// where that matters.
+    pub async fn lock(&self, permission: P) -> DeadlockProofAsyncMutexGuard<'_, T, P, I, M> {
+        check_task(&permission);
+        self.guard(self.0.lock().await, permission)
+    }
+
+    /// Acquires this mutex once `condition` holds for its contents, yielding
+    /// until then, just as [`crate::DeadlockProofMutex::lock_when`] does.
+    /// The condition is checked whenever another task releases a guard for
+    /// this mutex. If the returned future is dropped first, the permission
+    /// is dropped with it, as for [`DeadlockProofAsyncMutex::lock`].
+    pub async fn lock_when(
+        &self,
+        permission: P,
+        mut condition: impl FnMut(&T) -> bool,
+    ) -> DeadlockProofAsyncMutexGuard<'_, T, P, I, M> {
+        check_task(&permission);
+        let mut guard = self.0.lock().await;
+        while !condition(&guard) {
+            // Registered before releasing the mutex, so that a release by
+            // another task in between isn't missed. Nothing has changed, so
+            // this release needn't wake anyone.
+            let waiting = self.3.register();
+            drop(guard);
+            waiting.await;
+            guard = self.0.lock().await;
+        }
+        self.guard(guard, permission)
+    }
+
+    /// Acquires this mutex, yielding until it is able to do so, taking the
+    /// permission out of `slot` only once the mutex is claimed. If the
+    /// returned future is dropped first, the permission stays in `slot`, so
+    /// this is safe to use within a `select!`. Panics if `slot` is empty.
+    pub async fn lock_cancel_safe(
+        &self,
+        slot: &mut Option<P>,
+    ) -> DeadlockProofAsyncMutexGuard<'_, T, P, I, M> {
+        assert!(slot.is_some(), "No permission to claim the async mutex");
+        check_task(slot.as_ref().unwrap());
+        let guard = self.0.lock().await;
+        self.guard(guard, slot.take().unwrap())
+    }
+
+    /// Acquires this mutex, yielding until it is able to do so or until
+    /// `timeout` has passed, whichever is first. If the time runs out, the
+    /// permission is handed back along with the error, so a request handler
+    /// with a deadline can carry on without it rather than losing it to
+    /// `tokio::time::timeout`. Uses tokio's timer, so must be run within a
+    /// tokio runtime with time enabled.
+    #[cfg(feature = "tokio")]
+    pub async fn lock_timeout(
+        &self,
+        permission: P,
+        timeout: Duration,
+    ) -> Result<DeadlockProofAsyncMutexGuard<'_, T, P, I, M>, (P, Elapsed)> {
+        let mut slot = Some(permission);
+        match tokio::time::timeout(timeout, self.lock_cancel_safe(&mut slot)).await {
+            Ok(guard) => Ok(guard),
+            Err(elapsed) => Err((slot.take().unwrap(), elapsed)),
+        }
+    }
+
+    /// Whether another task currently holds the mutex. This never yields,
+    /// so needs no permission, but the answer may be out of date as soon as
+    /// it's returned: it's a hint for scheduling decisions, not a promise
+    /// that claiming the mutex won't wait.
+    pub fn is_locked(&self) -> bool {
+        self.0.try_lock().is_none()
+    }
+
+    /// Yields until the mutex is likely to be free, without claiming it, for
+    /// deciding where to place work. Someone else may have claimed it again
+    /// by the time the task resumes.
+    ///
+    /// Waiting for the mutex to be released is as dangerous as waiting to
+    /// claim it, so this takes the permission which would claim it, and
+    /// hands it back once the mutex is available. Permissions aren't `Sync`,
+    /// so taking it by value rather than by reference keeps the future
+    /// `Send`.
+    pub async fn lock_available(&self, permission: P) -> P {
+        check_task(&permission);
+        drop(self.0.lock().await);
+        permission
+    }
+
+    /// Acquires this mutex, yielding until it is able to do so. Provides a
+    /// token which can be used to claim a nested async mutex.
+    pub async fn lock_for_nested(
+        &self,
+        permission: P,
+    ) -> (
+        DeadlockProofNestedAsyncMutexGuard<'_, T, P, I, M>,
+        AsyncNestedMutexPermission<P, I>,
+    ) {
+        check_task(&permission);
+        (
+            self.nested_guard(self.0.lock().await, permission),
+            AsyncNestedMutexPermission::new(),
+        )
+    }
+}
+
+/// Stored within each async guard, after the underlying guard, to wake any
+/// tasks waiting in [`DeadlockProofAsyncMutex::lock_when`] once the mutex
+/// has been released.
+pub(crate) struct AsyncHeldLock<'a, I>(&'a DeadlockProofAsyncCondvar<I>);
+
+impl<I> Drop for AsyncHeldLock<'_, I> {
+    fn drop(&mut self) {
+        self.0.notify_all();
+    }
+}
+
+/// A guard for a [`DeadlockProofAsyncMutex`].
+pub struct DeadlockProofAsyncMutexGuard<
+    'a,
+    T,
+    P: AsyncMutexPermission,
+    I,
+    M: AsyncMutexBackend<T> + 'a = DefaultAsyncBackend<T>,
+>(
+    pub(crate) M::Guard<'a>,
+    pub(crate) P,
+    pub(crate) &'a DeadlockProofAsyncMutex<T, P, I, M>,
+    pub(crate) AsyncHeldLock<'a, I>,
+);
+
+impl<'a, T, P: AsyncMutexPermission, I, M: AsyncMutexBackend<T> + 'a>
+    DeadlockProofAsyncMutexGuard<'a, T, P, I, M>
+{
+    /// Unlock the mutex. Returns the permission token such that you can use
+    /// it again to claim a different async mutex.
+    pub fn unlock(self) -> P {
+        self.1
+    }
+
+    /// Unlock the mutex. Returns the permission token such that you can use
+    /// it again to claim a different async mutex. Also, returns an extra
+    /// permission token so that you can claim another async mutex in a
+    /// certain sequence, which the type system will guarantee is the same
+    /// for all tasks.
+    pub fn unlock_for_sequential(self) -> AsyncSequentialMutexPermission<P, I> {
+        AsyncSequentialMutexPermission::new(self.1)
+    }
+}
+
+impl<'a, T, P: AsyncMutexPermission, I, M: AsyncMutexBackend<T> + 'a> Deref
+    for DeadlockProofAsyncMutexGuard<'a, T, P, I, M>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.deref()
+    }
+}
+
+impl<'a, T, P: AsyncMutexPermission, I, M: AsyncMutexBackend<T> + 'a> DerefMut
+    for DeadlockProofAsyncMutexGuard<'a, T, P, I, M>
+{
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.deref_mut()
+    }
+}
+
+/// A guard for a [`DeadlockProofAsyncMutex`] claimed using
+/// [`DeadlockProofAsyncMutex::lock_for_nested`].
+pub struct DeadlockProofNestedAsyncMutexGuard<
+    'a,
+    T,
+    P: AsyncMutexPermission,
+    I,
+    M: AsyncMutexBackend<T> + 'a = DefaultAsyncBackend<T>,
+>(
+    pub(crate) M::Guard<'a>,
+    pub(crate) P,
+    pub(crate) &'a DeadlockProofAsyncMutex<T, P, I, M>,
+    pub(crate) AsyncHeldLock<'a, I>,
+);
+
+impl<'a, T, P: AsyncMutexPermission, I, M: AsyncMutexBackend<T> + 'a>
+    DeadlockProofNestedAsyncMutexGuard<'a, T, P, I, M>
+{
+    /// Unlock the mutex. Returns the permission token such that you can use
+    /// it again to claim a different async mutex.
+    pub fn unlock(self, _token: AsyncNestedMutexPermission<P, I>) -> P {
+        self.1
+    }
+
+    /// Unlock the mutex. Returns the permission token such that you can use
+    /// it again to claim a different async mutex. Also, returns an extra
+    /// permission token so that you can claim another async mutex in a
+    /// certain sequence, which the type system will guarantee is the same
+    /// for all tasks.
+    pub fn unlock_for_sequential(self) -> AsyncSequentialMutexPermission<P, I> {
+        AsyncSequentialMutexPermission::new(self.1)
+    }
+}
+
+impl<'a, T, P: AsyncMutexPermission, I, M: AsyncMutexBackend<T> + 'a> Deref
+    for DeadlockProofNestedAsyncMutexGuard<'a, T, P, I, M>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.deref()
+    }
+}
+
+impl<'a, T, P: AsyncMutexPermission, I, M: AsyncMutexBackend<T> + 'a> DerefMut
+    for DeadlockProofNestedAsyncMutexGuard<'a, T, P, I, M>
+{
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.deref_mut()
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{task, AsyncOuterMutexPermission, DeadlockProofAsyncMutex};
+
+    struct Counter;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lock_when_waits_for_condition() {
+        let mutex: Arc<DeadlockProofAsyncMutex<u32, AsyncOuterMutexPermission, Counter>> =
+            Arc::new(DeadlockProofAsyncMutex::new(0, Counter));
+        let producer = task::spawn({
+            let mutex = Arc::clone(&mutex);
+            move |mut permission| async move {
+                for _ in 0..3 {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    let mut guard = mutex.lock(permission).await;
+                    *guard += 1;
+                    permission = guard.unlock();
+                }
+            }
+        });
+        task::with_permission(|permission| async {
+            let guard = mutex.lock_when(permission, |n| *n == 3).await;
+            assert_eq!(*guard, 3);
+            guard.unlock();
+        })
+        .await;
+        producer.await.unwrap();
+    }
+}
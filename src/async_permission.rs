@@ -0,0 +1,343 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Permission tokens for async mutices. These mirror the synchronous
+//! permission types, except that the outer permission belongs to a task
+//! rather than a thread, and all of them are `Send` so that futures holding
+//! them can move between threads. Since that would also let them be sent to
+//! another task, each remembers the task it belongs to, and claiming an
+//! async lock with a permission from another task panics.
+
+use std::{
+    cell::Cell,
+    future::Future,
+    marker::PhantomData,
+    mem::ManuallyDrop,
+    panic::RefUnwindSafe,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+struct TaskPermissionState {
+    /// Distinguishes this task from every other, for as long as the process
+    /// runs.
+    id: u64,
+    available: Cell<bool>,
+    live_nested: Cell<usize>,
+}
+
+/// The identifier of the next task to be given a permission.
+static NEXT_TASK: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// The state of the task being polled on this thread within
+    /// [`AsyncOuterMutexPermission::scope`], or null if there's none. This is
+    /// a task-local in the same way as tokio's, but works on any executor.
+    static CURRENT_TASK: Cell<*const TaskPermissionState> = const { Cell::new(std::ptr::null()) };
+}
+
+/// Calls `f` with the state of the task currently being polled, or returns
+/// `None` if it isn't running within [`AsyncOuterMutexPermission::scope`].
+fn with_task<R>(f: impl FnOnce(&TaskPermissionState) -> R) -> Option<R> {
+    let state = CURRENT_TASK.try_with(Cell::get).ok()?;
+    // Safety: the pointer is only set while the scope owning the state is
+    // being polled on this thread.
+    unsafe { state.as_ref() }.map(f)
+}
+
+/// The future returned by [`AsyncOuterMutexPermission::scope`].
+struct TaskScope<F> {
+    state: TaskPermissionState,
+    /// Dropped by hand, within the scope, so that permissions held by the
+    /// task are returned to it rather than to whichever task is current.
+    future: ManuallyDrop<F>,
+}
+
+impl<F> TaskScope<F> {
+    /// Makes this the current task until the result is dropped, even if
+    /// the caller panics, unless another task is current, in which case
+    /// this returns `None`: a task running within another, by blocking on
+    /// it say, would let the outer task hold a second permission.
+    fn enter(&self) -> Option<impl Drop> {
+        /// Clears the current task.
+        struct Leave;
+
+        impl Drop for Leave {
+            fn drop(&mut self) {
+                CURRENT_TASK.with(|current| current.set(std::ptr::null()));
+            }
+        }
+
+        CURRENT_TASK.with(|current| {
+            current.get().is_null().then(|| {
+                current.set(&self.state);
+                Leave
+            })
+        })
+    }
+}
+
+impl<F: Future> Future for TaskScope<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // Safety: `future` is structurally pinned and never moved; `state`
+        // isn't, but is only ever borrowed.
+        let this = unsafe { self.get_unchecked_mut() };
+        let _entered = this
+            .enter()
+            .expect("A task with its own mutex permission can't run within another such task");
+        unsafe { Pin::new_unchecked(&mut *this.future) }.poll(cx)
+    }
+}
+
+impl<F> Drop for TaskScope<F> {
+    fn drop(&mut self) {
+        // Entered where possible, so that code run by the future's drop can
+        // find its task. Within another task, say one which dropped this
+        // future without running it, its permissions are dropped anyway,
+        // and only ever return to the task they belong to.
+        let _entered = self.enter();
+        // Safety: the future is dropped in place, and only here.
+        unsafe { ManuallyDrop::drop(&mut self.future) }
+    }
+}
+
+mod sealed {
+    /// Names the task a permission belongs to. Sealed, since a permission
+    /// type from outside this crate could be created at will.
+    pub trait BelongsToTask {
+        /// The identifier of the task, or zero if it had none.
+        fn task(&self) -> u64;
+    }
+}
+
+use sealed::BelongsToTask;
+
+/// Panics unless `permission` belongs to the task being polled, so that a
+/// permission sent to another task can't be used alongside that task's own.
+pub(crate) fn check_task<P: AsyncMutexPermission>(permission: &P) {
+    assert_eq!(
+        with_task(|state| state.id),
+        Some(permission.task()),
+        "Async mutex permission used outside the task it belongs to"
+    );
+}
+
+/// Some type of permission token required to claim an async mutex.
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` is not an async mutex permission token",
+    label = "an async permission token is required here",
+    note = "the first async mutex in each task is claimed with the `AsyncOuterMutexPermission` from `AsyncOuterMutexPermission::get()`"
+)]
+pub trait AsyncMutexPermission: Send + BelongsToTask {}
+
+/// Makes a type `Send` but not `Sync`: permissions travel with their task,
+/// but are never shared. This also stops the permissions being
+/// `RefUnwindSafe`, which they are given back explicitly, since they have no
+/// state for a panic to leave inconsistent.
+type SendNotSync = PhantomData<Cell<()>>;
+
+/// Permission to claim an "outer" async mutex. This is the async equivalent
+/// of [`crate::OuterMutexPermission`], except that there's one per task
+/// rather than one per thread, since tasks may move between threads at
+/// every `.await`.
+///
+/// A task must be run within [`crate::task::with_permission`] or
+/// [`crate::task::spawn`] to be given a token, which they pass straight to
+/// the task. The token must stay within that task; it's `Send` only so that
+/// the task itself can move between threads, and claiming an async lock
+/// with it from any other task panics.
+pub struct AsyncOuterMutexPermission(
+    SendNotSync,
+    /// The task this belongs to.
+    u64,
+);
+
+impl BelongsToTask for AsyncOuterMutexPermission {
+    fn task(&self) -> u64 {
+        self.1
+    }
+}
+
+impl AsyncMutexPermission for AsyncOuterMutexPermission {}
+
+impl RefUnwindSafe for AsyncOuterMutexPermission {}
+
+impl AsyncOuterMutexPermission {
+    /// Runs `future` as a task with its own permission token, which it can
+    /// claim using [`AsyncOuterMutexPermission::get`]. Only reachable through
+    /// [`crate::task`], whose wrappers claim the token before any user code
+    /// runs.
+    pub(crate) fn scope<F: Future>(future: F) -> impl Future<Output = F::Output> {
+        TaskScope {
+            state: TaskPermissionState {
+                id: NEXT_TASK.fetch_add(1, Ordering::Relaxed),
+                available: Cell::new(true),
+                live_nested: Cell::new(0),
+            },
+            future: ManuallyDrop::new(future),
+        }
+    }
+
+    /// Get the current task's mutex claiming permission. Panics if the task
+    /// wasn't started by one of the wrappers in [`crate::task`], or if the
+    /// token has already been claimed. As with the synchronous equivalent,
+    /// dropping the token returns it to the task, though it can't be
+    /// reclaimed while nested permissions derived from it are alive.
+    pub fn get() -> AsyncOuterMutexPermission {
+        with_task(|state| {
+            assert_eq!(
+                state.live_nested.get(),
+                0,
+                "Async mutex permission can't be reclaimed while nested permissions derived from it are alive"
+            );
+            state.available.replace(false).then_some(state.id)
+        })
+        .expect("Async mutex permission requested outside a task started by deadlock_proof_mutex::task")
+        .map(|task| AsyncOuterMutexPermission(PhantomData, task))
+        .expect("Async mutex permission already claimed for this task")
+    }
+}
+
+impl Drop for AsyncOuterMutexPermission {
+    fn drop(&mut self) {
+        // Outside the scope, the task has finished with its token anyway,
+        // and a token sent to another task is lost rather than given to it.
+        let _ = with_task(|state| {
+            if state.id == self.1 {
+                state.available.set(true);
+            }
+        });
+    }
+}
+
+/// Permission to claim some nested async mutex. This can be obtained from
+/// [`crate::DeadlockProofAsyncMutex::lock_for_nested`].
+pub struct AsyncNestedMutexPermission<P: AsyncMutexPermission, I>(
+    SendNotSync,
+    PhantomData<fn(P, I)>,
+    /// The task this belongs to, as for the permission it derives from.
+    u64,
+);
+
+impl<P: AsyncMutexPermission, I> AsyncNestedMutexPermission<P, I> {
+    pub(crate) fn new() -> Self {
+        let task = with_task(|state| {
+            state.live_nested.set(state.live_nested.get() + 1);
+            state.id
+        });
+        Self(PhantomData, PhantomData, task.unwrap_or(0))
+    }
+}
+
+impl<P: AsyncMutexPermission, I> Drop for AsyncNestedMutexPermission<P, I> {
+    fn drop(&mut self) {
+        let _ = with_task(|state| {
+            if state.id == self.2 {
+                state.live_nested.set(state.live_nested.get() - 1);
+            }
+        });
+    }
+}
+
+impl<P: AsyncMutexPermission, I> BelongsToTask for AsyncNestedMutexPermission<P, I> {
+    fn task(&self) -> u64 {
+        self.2
+    }
+}
+
+impl<P: AsyncMutexPermission, I> AsyncMutexPermission for AsyncNestedMutexPermission<P, I> {}
+
+impl<P: AsyncMutexPermission, I> RefUnwindSafe for AsyncNestedMutexPermission<P, I> {}
+
+/// Permission to claim the next async mutex in a sequence. This can be
+/// obtained from [`crate::DeadlockProofAsyncMutexGuard::unlock_for_sequential`].
+pub struct AsyncSequentialMutexPermission<P: AsyncMutexPermission, I>(
+    SendNotSync,
+    P,
+    PhantomData<fn(I)>,
+);
+
+impl<P: AsyncMutexPermission, I> AsyncSequentialMutexPermission<P, I> {
+    pub(crate) fn new(permission: P) -> Self {
+        Self(PhantomData, permission, PhantomData)
+    }
+
+    /// Consumes this sequential permission to return the permission
+    /// token earlier in the sequence.
+    pub fn to_earlier(self) -> P {
+        self.1
+    }
+}
+
+impl<P: AsyncMutexPermission, I> BelongsToTask for AsyncSequentialMutexPermission<P, I> {
+    fn task(&self) -> u64 {
+        self.1.task()
+    }
+}
+
+impl<P: AsyncMutexPermission, I> AsyncMutexPermission for AsyncSequentialMutexPermission<P, I> {}
+
+impl<P: AsyncMutexPermission + RefUnwindSafe, I> RefUnwindSafe
+    for AsyncSequentialMutexPermission<P, I>
+{
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use std::{
+        future::Future,
+        panic::{catch_unwind, AssertUnwindSafe},
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use crate::{task, AsyncOuterMutexPermission, DeadlockProofAsyncMutex};
+
+    struct Shared;
+
+    /// Polls `future` once, which is enough for any future here which
+    /// doesn't wait for another task.
+    fn poll_once<F: Future>(future: F) -> Poll<F::Output> {
+        pin!(future).poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    #[test]
+    fn permission_from_another_task_is_refused() {
+        let mutex: DeadlockProofAsyncMutex<u32, AsyncOuterMutexPermission, Shared> =
+            DeadlockProofAsyncMutex::new(0, Shared);
+        let Poll::Ready(foreign) = poll_once(task::with_permission(|p| async { p })) else {
+            panic!("the task didn't finish");
+        };
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            poll_once(task::with_permission(|permission| async {
+                let guard = mutex.lock(foreign).await;
+                drop(permission);
+                guard.unlock()
+            }))
+        }));
+        assert!(result.is_err());
+        let Poll::Ready(_) = poll_once(task::with_permission(|permission| async {
+            mutex.lock(permission).await.unlock()
+        })) else {
+            panic!("the task didn't finish");
+        };
+    }
+
+    #[test]
+    fn task_within_another_is_refused() {
+        let result = catch_unwind(|| {
+            poll_once(task::with_permission(|_| async {
+                poll_once(task::with_permission(|_| async {}))
+            }))
+        });
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::async_permission::{
+    check_task, AsyncMutexPermission, AsyncNestedMutexPermission, AsyncSequentialMutexPermission,
+};
+
+/// An async reader-writer lock which can't deadlock, backed by
+/// [`tokio::sync::RwLock`]. It takes part in the same orderings as a
+/// [`crate::DeadlockProofAsyncMutex`], using the task's permission tokens,
+/// but any number of tasks may hold read guards at once.
+///
+/// Claiming it for reading still consumes the task's permission, just as
+/// claiming it for writing does, since a task waiting to read can be kept
+/// waiting by a writer just as long as by anything else. Tokio's lock is
+/// fair, so waiting readers can't starve a writer.
+pub struct DeadlockProofAsyncRwLock<T, P: AsyncMutexPermission, I>(
+    pub(crate) RwLock<T>,
+    PhantomData<fn(P)>,
+    // Only tokio's lock decides whether this is `Send` and `Sync`.
+    PhantomData<fn() -> I>,
+);
+
+impl<T, P: AsyncMutexPermission, I> DeadlockProofAsyncRwLock<T, P, I> {
+    /// Create a new async reader-writer lock. The `_identifier` is a type
+    /// unique to this lock, just as for [`crate::DeadlockProofMutex::new`].
+    pub fn new(content: T, _identifier: I) -> Self {
+        Self(RwLock::new(content), PhantomData, PhantomData)
+    }
+
+    /// Acquires shared read access, yielding until it is able to do so.
+    pub async fn read(&self, permission: P) -> DeadlockProofAsyncRwLockReadGuard<'_, T, P, I> {
+        check_task(&permission);
+        DeadlockProofAsyncRwLockReadGuard(self.0.read().await, permission, PhantomData)
+    }
+
+    /// Acquires exclusive write access, yielding until it is able to do so.
+    pub async fn write(&self, permission: P) -> DeadlockProofAsyncRwLockWriteGuard<'_, T, P, I> {
+        check_task(&permission);
+        DeadlockProofAsyncRwLockWriteGuard(self.0.write().await, permission, self)
+    }
+
+    /// Acquires shared read access, yielding until it is able to do so.
+    /// Provides a token which can be used to claim a nested async lock.
+    pub async fn read_for_nested(
+        &self,
+        permission: P,
+    ) -> (
+        DeadlockProofNestedAsyncRwLockReadGuard<'_, T, P, I>,
+        AsyncNestedMutexPermission<P, I>,
+    ) {
+        check_task(&permission);
+        (
+            DeadlockProofNestedAsyncRwLockReadGuard(self.0.read().await, permission, PhantomData),
+            AsyncNestedMutexPermission::new(),
+        )
+    }
+
+    /// Acquires exclusive write access, yielding until it is able to do so.
+    /// Provides a token which can be used to claim a nested async lock.
+    pub async fn write_for_nested(
+        &self,
+        permission: P,
+    ) -> (
+        DeadlockProofNestedAsyncRwLockWriteGuard<'_, T, P, I>,
+        AsyncNestedMutexPermission<P, I>,
+    ) {
+        check_task(&permission);
+        (
+            DeadlockProofNestedAsyncRwLockWriteGuard(self.0.write().await, permission, self),
+            AsyncNestedMutexPermission::new(),
+        )
+    }
+}
+
+/// A read guard for a [`DeadlockProofAsyncRwLock`].
+pub struct DeadlockProofAsyncRwLockReadGuard<'a, T, P: AsyncMutexPermission, I>(
+    RwLockReadGuard<'a, T>,
+    P,
+    PhantomData<fn() -> I>,
+);
+
+impl<T, P: AsyncMutexPermission, I> DeadlockProofAsyncRwLockReadGuard<'_, T, P, I> {
+    /// Unlock the lock. Returns the permission token such that you can use
+    /// it again to claim a different async lock.
+    pub fn unlock(self) -> P {
+        self.1
+    }
+
+    /// Unlock the lock. Returns the permission token, along with an extra
+    /// permission token so that you can claim another async lock in a
+    /// certain sequence, which the type system will guarantee is the same
+    /// for all tasks.
+    pub fn unlock_for_sequential(self) -> AsyncSequentialMutexPermission<P, I> {
+        AsyncSequentialMutexPermission::new(self.1)
+    }
+}
+
+impl<T, P: AsyncMutexPermission, I> Deref for DeadlockProofAsyncRwLockReadGuard<'_, T, P, I> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.deref()
+    }
+}
+
+/// A write guard for a [`DeadlockProofAsyncRwLock`].
+pub struct DeadlockProofAsyncRwLockWriteGuard<'a, T, P: AsyncMutexPermission, I>(
+    pub(crate) RwLockWriteGuard<'a, T>,
+    pub(crate) P,
+    /// So that a condvar can reclaim the lock after waiting.
+    pub(crate) &'a DeadlockProofAsyncRwLock<T, P, I>,
+);
+
+impl<T, P: AsyncMutexPermission, I> DeadlockProofAsyncRwLockWriteGuard<'_, T, P, I> {
+    /// Unlock the lock. Returns the permission token such that you can use
+    /// it again to claim a different async lock.
+    pub fn unlock(self) -> P {
+        self.1
+    }
+
+    /// Unlock the lock. Returns the permission token, along with an extra
+    /// permission token so that you can claim another async lock in a
+    /// certain sequence, which the type system will guarantee is the same
+    /// for all tasks.
+    pub fn unlock_for_sequential(self) -> AsyncSequentialMutexPermission<P, I> {
+        AsyncSequentialMutexPermission::new(self.1)
+    }
+}
+
+impl<T, P: AsyncMutexPermission, I> Deref for DeadlockProofAsyncRwLockWriteGuard<'_, T, P, I> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.deref()
+    }
+}
+
+impl<T, P: AsyncMutexPermission, I> DerefMut for DeadlockProofAsyncRwLockWriteGuard<'_, T, P, I> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.deref_mut()
+    }
+}
+
+/// A read guard for a [`DeadlockProofAsyncRwLock`] claimed using
+/// [`DeadlockProofAsyncRwLock::read_for_nested`].
+pub struct DeadlockProofNestedAsyncRwLockReadGuard<'a, T, P: AsyncMutexPermission, I>(
+    RwLockReadGuard<'a, T>,
+    P,
+    PhantomData<fn() -> I>,
+);
+
+impl<T, P: AsyncMutexPermission, I> DeadlockProofNestedAsyncRwLockReadGuard<'_, T, P, I> {
+    /// Unlock the lock, once the nested permission has been given back.
+    /// Returns the permission token such that you can use it again to claim
+    /// a different async lock.
+    pub fn unlock(self, _token: AsyncNestedMutexPermission<P, I>) -> P {
+        self.1
+    }
+}
+
+impl<T, P: AsyncMutexPermission, I> Deref for DeadlockProofNestedAsyncRwLockReadGuard<'_, T, P, I> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.deref()
+    }
+}
+
+/// A write guard for a [`DeadlockProofAsyncRwLock`] claimed using
+/// [`DeadlockProofAsyncRwLock::write_for_nested`].
+pub struct DeadlockProofNestedAsyncRwLockWriteGuard<'a, T, P: AsyncMutexPermission, I>(
+    pub(crate) RwLockWriteGuard<'a, T>,
+    pub(crate) P,
+    pub(crate) &'a DeadlockProofAsyncRwLock<T, P, I>,
+);
+
+impl<T, P: AsyncMutexPermission, I> DeadlockProofNestedAsyncRwLockWriteGuard<'_, T, P, I> {
+    /// Unlock the lock, once the nested permission has been given back.
+    /// Returns the permission token such that you can use it again to claim
+    /// a different async lock.
+    pub fn unlock(self, _token: AsyncNestedMutexPermission<P, I>) -> P {
+        self.1
+    }
+}
+
+impl<T, P: AsyncMutexPermission, I> Deref
+    for DeadlockProofNestedAsyncRwLockWriteGuard<'_, T, P, I>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.deref()
+    }
+}
+
+impl<T, P: AsyncMutexPermission, I> DerefMut
+    for DeadlockProofNestedAsyncRwLockWriteGuard<'_, T, P, I>
+{
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.deref_mut()
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::marker::PhantomData;
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::async_permission::{check_task, AsyncMutexPermission, AsyncSequentialMutexPermission};
+
+/// An async counting semaphore which can't deadlock, backed by
+/// [`tokio::sync::Semaphore`], for connection pools and rate limiters in
+/// async code. Waiting for a permit is much like waiting for a mutex, so
+/// [`DeadlockProofAsyncSemaphore::acquire`] takes part in the same
+/// orderings as [`crate::DeadlockProofAsyncMutex::lock`], using the task's
+/// permission tokens, just as [`crate::DeadlockProofSemaphore`] does for
+/// threads.
+pub struct DeadlockProofAsyncSemaphore<P: AsyncMutexPermission, I>(
+    Semaphore,
+    PhantomData<fn(P)>,
+    // Only tokio's semaphore decides whether this is `Send` and `Sync`.
+    PhantomData<fn() -> I>,
+);
+
+impl<P: AsyncMutexPermission, I> DeadlockProofAsyncSemaphore<P, I> {
+    /// Create a new async semaphore with the given number of permits. The
+    /// `_identifier` is a type unique to this semaphore, just as for
+    /// [`crate::DeadlockProofMutex::new`].
+    pub fn new(permits: usize, _identifier: I) -> Self {
+        Self(Semaphore::new(permits), PhantomData, PhantomData)
+    }
+
+    /// Acquires a permit, yielding until one is available. Requires a
+    /// permission token to prove that you can't be causing a deadlock.
+    pub async fn acquire(&self, permission: P) -> DeadlockProofAsyncSemaphorePermit<'_, P, I> {
+        check_task(&permission);
+        // The semaphore is never closed.
+        let permit = self.0.acquire().await.unwrap();
+        DeadlockProofAsyncSemaphorePermit(permit, permission, PhantomData)
+    }
+
+    /// Attempts to acquire a permit without yielding. If none is available,
+    /// the permission is returned.
+    pub fn try_acquire(
+        &self,
+        permission: P,
+    ) -> Result<DeadlockProofAsyncSemaphorePermit<'_, P, I>, P> {
+        check_task(&permission);
+        match self.0.try_acquire() {
+            Ok(permit) => Ok(DeadlockProofAsyncSemaphorePermit(
+                permit,
+                permission,
+                PhantomData,
+            )),
+            Err(_) => Err(permission),
+        }
+    }
+
+    /// Adds permits to the semaphore, waking tasks waiting for them.
+    pub fn add_permits(&self, permits: usize) {
+        self.0.add_permits(permits);
+    }
+
+    /// The number of permits currently available.
+    pub fn available_permits(&self) -> usize {
+        self.0.available_permits()
+    }
+}
+
+/// A permit from a [`DeadlockProofAsyncSemaphore`]. It's returned to the
+/// semaphore when dropped.
+pub struct DeadlockProofAsyncSemaphorePermit<'a, P: AsyncMutexPermission, I>(
+    #[allow(dead_code)] // only needed for its drop behaviour
+    SemaphorePermit<'a>,
+    P,
+    PhantomData<fn() -> I>,
+);
+
+impl<P: AsyncMutexPermission, I> DeadlockProofAsyncSemaphorePermit<'_, P, I> {
+    /// Release the permit. Returns the permission token such that you can
+    /// use it again to claim a different async lock.
+    pub fn release(self) -> P {
+        self.1
+    }
+
+    /// Release the permit, returning the permission token along with an
+    /// extra one so that you can claim another async lock in a certain
+    /// sequence, which the type system will guarantee is the same for all
+    /// tasks.
+    pub fn release_for_sequential(self) -> AsyncSequentialMutexPermission<P, I> {
+        AsyncSequentialMutexPermission::new(self.1)
+    }
+}
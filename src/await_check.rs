@@ -0,0 +1,82 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::{
+    held,
+    violation::{report_violation, Violation, ViolationAction, ViolationKind},
+};
+
+/// A future wrapper which detects a [`crate::DeadlockProofMutex`] guard
+/// being alive when the wrapped future yields. Create one with
+/// [`check_guards_across_await`].
+///
+/// Holding a blocking mutex across an `.await` is a deadlock hazard: if
+/// another task on the same thread then tries to claim the same mutex, it
+/// will block the thread forever, and the task which could release the
+/// mutex never gets to run again. Clippy's `await_holding_lock` lint catches
+/// the simple cases, but not guards hidden inside other types or behind
+/// layers of calls. This check catches those too, at runtime.
+pub struct GuardAcrossAwaitCheck<F> {
+    inner: F,
+    action: Option<ViolationAction>,
+}
+
+/// Wraps a future such that it reports a [`Violation`] if, when it yields,
+/// it's holding a deadlock-proof guard which it claimed while being polled.
+/// By default the violation is handled as set by
+/// [`crate::set_violation_action`].
+pub fn check_guards_across_await<F: Future>(future: F) -> GuardAcrossAwaitCheck<F> {
+    GuardAcrossAwaitCheck {
+        inner: future,
+        action: None,
+    }
+}
+
+impl<F> GuardAcrossAwaitCheck<F> {
+    /// Change what happens when a guard is found to be held across an
+    /// `.await`, overriding the global setting.
+    pub fn with_action(self, action: ViolationAction) -> Self {
+        Self {
+            action: Some(action),
+            ..self
+        }
+    }
+}
+
+impl<F: Future> Future for GuardAcrossAwaitCheck<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let action = self.action;
+        // Safety: `inner` is structurally pinned; we never move it out
+        // nor hand out an unpinned reference to it.
+        let inner = unsafe { self.map_unchecked_mut(|this| &mut this.inner) };
+        let checkpoint = held::checkpoint();
+        let result = inner.poll(cx);
+        if result.is_pending() {
+            let held = held::held_since(checkpoint);
+            if !held.is_empty() {
+                report_violation(
+                    Violation {
+                        kind: ViolationKind::GuardHeldAcrossAwait,
+                        identifiers: held,
+                        backtrace: None,
+                    },
+                    action,
+                );
+            }
+        }
+        result
+    }
+}
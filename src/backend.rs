@@ -0,0 +1,230 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use core::ops::DerefMut;
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[cfg(any(feature = "lock_api", feature = "spin"))]
+use crate::poison::TryLockError;
+use crate::poison::{LockResult, TryLockResult};
+
+/// An ordinary mutex which can be made deadlock-proof by wrapping it in a
+/// [`crate::DeadlockProofMutex`]. The deadlock-proofing comes entirely from
+/// the permission tokens, so any mutex will do; this trait just gives access
+/// to the few operations the wrapper needs.
+///
+/// [`Mutex`] is the default. An `Arc<Mutex<T>>` can be used too, so that a
+/// mutex already shared with code not yet converted can be adopted; see
+/// [`crate::DeadlockProofMutex::adopt`]. With the `lock_api` feature, any
+/// `lock_api::Mutex` can be used, whatever raw mutex it's built on. That
+/// includes `parking_lot::Mutex`, enabled by the `parking_lot` feature,
+/// which never becomes poisoned and tends to perform better under
+/// contention. With the `spin` feature, `spin::Mutex` can be used, which
+/// never asks the operating system to block a thread and so suits embedded
+/// targets. With the `loom` feature, `loom::sync::Mutex` can be used within
+/// loom models. With the `realtime` feature, `PriorityInheritMutex` can be
+/// used on Unix, to bound priority inversion, or any backend wrapped in a
+/// `PriorityCeilingMutex`. With the `interprocess` feature,
+/// `InterprocessMutex` can be used on Linux, to share a mutex between
+/// processes. On WebAssembly without threads, `SingleThreadMutex` can be
+/// used, which is just a `RefCell`. [`crate::TicketMutex`] hands the mutex
+/// to waiting threads strictly in turn, so none can be starved. Any of these
+/// can be wrapped in a [`crate::TunedMutex`] to spin before blocking.
+///
+/// # Safety
+///
+/// A guard must give exclusive access to the contents for as long as it
+/// exists, and the contents must stay in the same place even if the guard
+/// is moved, since [`crate::DeadlockProofMutexGuard::map`] relies on this.
+pub unsafe trait MutexBackend<T: ?Sized> {
+    /// The guard returned when this mutex is claimed.
+    type Guard<'a>: DerefMut<Target = T>
+    where
+        Self: 'a;
+
+    /// Acquires the mutex, blocking the current thread until it is able to
+    /// do so.
+    fn lock(&self) -> LockResult<Self::Guard<'_>>;
+
+    /// Attempts to acquire the mutex without blocking.
+    fn try_lock(&self) -> TryLockResult<Self::Guard<'_>>;
+
+    /// Mutably borrows the contents, which needs no locking since the mutex
+    /// itself is mutably borrowed.
+    fn get_mut(&mut self) -> LockResult<&mut T>;
+
+    /// Consumes the mutex, returning its contents.
+    fn into_inner(self) -> LockResult<T>
+    where
+        Self: Sized,
+        T: Sized;
+
+    /// Whether the mutex is poisoned. Mutices which are never poisoned
+    /// needn't implement this.
+    fn is_poisoned(&self) -> bool {
+        false
+    }
+
+    /// Clears the poisoned state, if any. Mutices which are never poisoned
+    /// needn't implement this.
+    fn clear_poison(&self) {}
+}
+
+#[cfg(feature = "std")]
+// Safety: the contents live within the mutex, not the guard.
+unsafe impl<T: ?Sized> MutexBackend<T> for Mutex<T> {
+    type Guard<'a>
+        = MutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        Mutex::lock(self)
+    }
+
+    fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        Mutex::try_lock(self)
+    }
+
+    fn get_mut(&mut self) -> LockResult<&mut T> {
+        Mutex::get_mut(self)
+    }
+
+    fn into_inner(self) -> LockResult<T>
+    where
+        T: Sized,
+    {
+        Mutex::into_inner(self)
+    }
+
+    fn is_poisoned(&self) -> bool {
+        Mutex::is_poisoned(self)
+    }
+
+    fn clear_poison(&self) {
+        Mutex::clear_poison(self)
+    }
+}
+
+#[cfg(feature = "std")]
+// Safety: the contents live within the shared mutex, not the guard, and
+// the guard borrows the `Arc`, so the mutex can't be freed while it exists.
+unsafe impl<T> MutexBackend<T> for Arc<Mutex<T>> {
+    type Guard<'a>
+        = MutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        Mutex::lock(self)
+    }
+
+    fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        Mutex::try_lock(self)
+    }
+
+    /// Panics if the mutex is still shared with other `Arc`s.
+    fn get_mut(&mut self) -> LockResult<&mut T> {
+        Arc::get_mut(self)
+            .expect("adopted mutex is still shared")
+            .get_mut()
+    }
+
+    /// Panics if the mutex is still shared with other `Arc`s.
+    fn into_inner(self) -> LockResult<T> {
+        Arc::into_inner(self)
+            .expect("adopted mutex is still shared")
+            .into_inner()
+    }
+
+    fn is_poisoned(&self) -> bool {
+        Mutex::is_poisoned(self)
+    }
+
+    fn clear_poison(&self) {
+        Mutex::clear_poison(self)
+    }
+}
+
+#[cfg(feature = "lock_api")]
+// Safety: the contents live within the mutex, not the guard, and
+// `RawMutex` implementations promise mutual exclusion.
+unsafe impl<R: lock_api::RawMutex, T> MutexBackend<T> for lock_api::Mutex<R, T> {
+    type Guard<'a>
+        = lock_api::MutexGuard<'a, R, T>
+    where
+        R: 'a,
+        T: 'a;
+
+    fn lock(&self) -> LockResult<lock_api::MutexGuard<'_, R, T>> {
+        Ok(lock_api::Mutex::lock(self))
+    }
+
+    fn try_lock(&self) -> TryLockResult<lock_api::MutexGuard<'_, R, T>> {
+        lock_api::Mutex::try_lock(self).ok_or(TryLockError::WouldBlock)
+    }
+
+    fn get_mut(&mut self) -> LockResult<&mut T> {
+        Ok(lock_api::Mutex::get_mut(self))
+    }
+
+    fn into_inner(self) -> LockResult<T> {
+        Ok(lock_api::Mutex::into_inner(self))
+    }
+}
+
+#[cfg(feature = "spin")]
+// Safety: the contents live within the mutex, not the guard.
+unsafe impl<T> MutexBackend<T> for spin::Mutex<T> {
+    type Guard<'a>
+        = spin::MutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn lock(&self) -> LockResult<spin::MutexGuard<'_, T>> {
+        Ok(spin::Mutex::lock(self))
+    }
+
+    fn try_lock(&self) -> TryLockResult<spin::MutexGuard<'_, T>> {
+        spin::Mutex::try_lock(self).ok_or(TryLockError::WouldBlock)
+    }
+
+    fn get_mut(&mut self) -> LockResult<&mut T> {
+        Ok(spin::Mutex::get_mut(self))
+    }
+
+    fn into_inner(self) -> LockResult<T> {
+        Ok(spin::Mutex::into_inner(self))
+    }
+}
+
+#[cfg(feature = "loom")]
+// Safety: the contents live within the mutex, not the guard.
+unsafe impl<T> MutexBackend<T> for loom::sync::Mutex<T> {
+    type Guard<'a>
+        = loom::sync::MutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn lock(&self) -> LockResult<loom::sync::MutexGuard<'_, T>> {
+        loom::sync::Mutex::lock(self)
+    }
+
+    fn try_lock(&self) -> TryLockResult<loom::sync::MutexGuard<'_, T>> {
+        loom::sync::Mutex::try_lock(self)
+    }
+
+    fn get_mut(&mut self) -> LockResult<&mut T> {
+        loom::sync::Mutex::get_mut(self)
+    }
+
+    fn into_inner(self) -> LockResult<T> {
+        loom::sync::Mutex::into_inner(self)
+    }
+}
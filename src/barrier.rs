@@ -0,0 +1,38 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::sync::{Barrier, BarrierWaitResult};
+
+use crate::{domain, DefaultDomain, OuterMutexPermission};
+
+/// A barrier which is compile-time guaranteed not to deadlock. Otherwise
+/// identical to [`Barrier`].
+///
+/// A thread waiting at a barrier while holding a mutex may keep the other
+/// threads from ever reaching it, so [`DeadlockProofBarrier::wait`] needs
+/// to see this thread's [`OuterMutexPermission`], which proves that no
+/// mutex is held. Mutices from other permission domains can't be ruled out
+/// that way, so holding one is reported at runtime as a
+/// [`crate::Violation`].
+pub struct DeadlockProofBarrier(Barrier);
+
+impl DeadlockProofBarrier {
+    /// Create a new deadlock-proof barrier which blocks until `n` threads
+    /// are waiting at it.
+    pub fn new(n: usize) -> Self {
+        Self(Barrier::new(n))
+    }
+
+    /// Blocks until all threads have waited here, as for [`Barrier::wait`].
+    /// The permission is only borrowed, since the thread holds no mutex
+    /// while waiting and so needs it back afterwards anyway.
+    pub fn wait(&self, _permission: &OuterMutexPermission) -> BarrierWaitResult {
+        domain::check_lock::<DefaultDomain, Self>();
+        self.0.wait()
+    }
+}
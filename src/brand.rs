@@ -0,0 +1,87 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Identifiers made unique by a lifetime rather than by a type, after the
+//! "generativity" technique. Each [`crate::make_mutex`] invocation borrows a
+//! local for an invariant lifetime `'id`, which the compiler must treat as
+//! distinct from every other such lifetime, so no two mutices ever share an
+//! identifier: not even those created by the same invocation on successive
+//! iterations of a loop, unlike [`crate::unique_type`].
+
+use core::marker::PhantomData;
+
+/// Invariant in `'id`, so that the compiler can't shorten or lengthen it to
+/// make two brands equal.
+type Invariant<'id> = PhantomData<fn(&'id ()) -> &'id ()>;
+
+/// A mutex identifier distinguished by the lifetime `'id` alone, created by
+/// [`crate::make_mutex`]. Mutices identified this way can't outlive the
+/// scope in which they're made.
+pub struct BrandedIdentifier<'id>(Invariant<'id>);
+
+impl<'id> BrandedIdentifier<'id> {
+    /// # Safety
+    ///
+    /// Only for use by [`crate::make_mutex`], which guarantees `'id` is
+    /// unique.
+    #[doc(hidden)]
+    pub unsafe fn new(_place: &'id BrandPlace<'id>) -> Self {
+        Self(PhantomData)
+    }
+}
+
+/// The local borrowed by [`crate::make_mutex`] to create a brand.
+#[doc(hidden)]
+pub struct BrandPlace<'id>(Invariant<'id>);
+
+impl BrandPlace<'_> {
+    /// # Safety
+    ///
+    /// Only for use by [`crate::make_mutex`].
+    #[doc(hidden)]
+    pub unsafe fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+/// Keeps the brand's borrow alive until the end of the enclosing scope.
+/// Having a `Drop` implementation is what stops the compiler from ending
+/// `'id` any earlier, and so from letting two brands coincide.
+#[doc(hidden)]
+pub struct LifetimeBrand<'id>(Invariant<'id>);
+
+impl<'id> LifetimeBrand<'id> {
+    /// # Safety
+    ///
+    /// Only for use by [`crate::make_mutex`].
+    #[doc(hidden)]
+    pub unsafe fn new(_place: &'id BrandPlace<'id>) -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl Drop for LifetimeBrand<'_> {
+    #[inline(always)]
+    fn drop(&mut self) {}
+}
+
+/// Declares a local variable `$name` holding a new [`crate::DeadlockProofMutex`]
+/// containing `$value`, whose identifier is a [`BrandedIdentifier`] unique
+/// to this particular mutex. This needs no identifier type at all, but the
+/// mutex can't leave the scope in which it's declared.
+#[macro_export]
+macro_rules! make_mutex {
+    ($name:ident, $value:expr) => {
+        let brand_place = unsafe { $crate::BrandPlace::new() };
+        #[allow(unused)]
+        let lifetime_brand = unsafe { $crate::LifetimeBrand::new(&brand_place) };
+        let $name = $crate::DeadlockProofMutex::new($value, unsafe {
+            $crate::BrandedIdentifier::new(&brand_place)
+        });
+    };
+}
@@ -0,0 +1,89 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::marker::PhantomData;
+
+use crate::{domain, MutexPermission, OuterMutexPermission, PermissionDomain};
+
+/// An order between two lock hierarchies which were developed
+/// independently, each in its own [`PermissionDomain`], such as those of
+/// two libraries. Declare one with [`crate::declare_hierarchy_order`] in
+/// the program which needs to hold mutices from both, then cross from one
+/// hierarchy to the other with a [`BridgePermission`].
+pub trait HierarchyOrder {
+    /// The domain whose mutices are claimed first.
+    type Outer: PermissionDomain;
+    /// The domain whose mutices are claimed while holding those of
+    /// [`HierarchyOrder::Outer`].
+    type Inner: PermissionDomain;
+}
+
+/// Declares a [`HierarchyOrder`] type, which puts the hierarchy of the
+/// first domain outside that of the second:
+/// `declare_hierarchy_order!(pub StorageThenNetwork: storage::Locks => net::Locks)`.
+#[macro_export]
+macro_rules! declare_hierarchy_order {
+    ($vis:vis $name:ident: $outer:ty => $inner:ty) => {
+        $vis struct $name;
+
+        impl $crate::HierarchyOrder for $name {
+            type Outer = $outer;
+            type Inner = $inner;
+        }
+    };
+}
+
+/// Holds a permission from one lock hierarchy while this thread uses
+/// another nested within it, as declared by the [`HierarchyOrder`] `O`.
+///
+/// Each of two libraries can build a hierarchy of mutices rooted in the
+/// [`OuterMutexPermission`] of its own domain, and the type system keeps
+/// each deadlock-free. Between domains, the order is checked at runtime
+/// using their ranks, which the libraries chose without knowing about each
+/// other. [`BridgePermission::new`] takes a permission from anywhere in the
+/// outer hierarchy, such as the one from
+/// [`crate::DeadlockProofMutex::lock_for_nested`] on its innermost mutex,
+/// and hands out the inner domain's [`OuterMutexPermission`], with which
+/// the whole inner hierarchy can be claimed. It also declares the order for
+/// the rest of the program, so the runtime check follows it rather than
+/// the ranks: mutices of the inner domain may be claimed while holding
+/// those of the outer, and never the other way round. Bridging the same two
+/// domains in the opposite order elsewhere is reported as a
+/// [`crate::Violation`].
+///
+/// Give the inner permission back to [`BridgePermission::unbridge`] to get
+/// back the outer one.
+pub struct BridgePermission<O: HierarchyOrder, P: MutexPermission<Domain = O::Outer>>(
+    P,
+    PhantomData<O>,
+);
+
+impl<O: HierarchyOrder, P: MutexPermission<Domain = O::Outer>> BridgePermission<O, P> {
+    /// Crosses from the outer hierarchy, at the point reached by
+    /// `permission`, into the inner one, returning the inner domain's
+    /// permission. Panics if that permission is already claimed in this
+    /// thread, just as [`OuterMutexPermission::get_for_domain`] does, or if
+    /// the two domains are the same.
+    pub fn new(permission: P) -> (Self, OuterMutexPermission<O::Inner>) {
+        assert!(
+            !domain::is_same::<O::Outer, O::Inner>(),
+            "A lock hierarchy can't be bridged to itself"
+        );
+        domain::declare_order::<O::Outer, O::Inner>();
+        (
+            Self(permission, PhantomData),
+            OuterMutexPermission::get_for_domain(),
+        )
+    }
+
+    /// Leaves the inner hierarchy, once its permission has been given back,
+    /// returning the permission from the outer hierarchy.
+    pub fn unbridge(self, _inner: OuterMutexPermission<O::Inner>) -> P {
+        self.0
+    }
+}
@@ -0,0 +1,57 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use core::{
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+use crate::{DeadlockProofMutexGuard, MutexBackend, MutexPermission, NestedMutexPermission};
+
+/// A permission lent to a callback run while a mutex is held, which can
+/// only claim the mutices nested within it, and can't escape the callback.
+/// Obtained from [`DeadlockProofMutexGuard::for_callback`].
+///
+/// It dereferences to the permission `P`, typically a
+/// [`NestedMutexPermission`], so it can claim mutices using
+/// [`crate::DeadlockProofMutex::lock_borrowing`], but the permission itself
+/// can never be moved out. The lifetime `'a` ties it to a mutable borrow of
+/// the guard, so the mutex can't be unlocked while the callback might still
+/// use it.
+pub struct CallbackPermission<'a, P: MutexPermission>(P, PhantomData<&'a ()>);
+
+impl<P: MutexPermission> Deref for CallbackPermission<'_, P> {
+    type Target = P;
+
+    fn deref(&self) -> &P {
+        &self.0
+    }
+}
+
+impl<P: MutexPermission> DerefMut for CallbackPermission<'_, P> {
+    fn deref_mut(&mut self) -> &mut P {
+        &mut self.0
+    }
+}
+
+impl<'a, T, P: MutexPermission, I, M: MutexBackend<T> + 'a>
+    DeadlockProofMutexGuard<'a, T, P, I, M>
+{
+    /// Splits the guard into its contents and a [`CallbackPermission`] for
+    /// claiming the mutices nested within this one, for handing both to a
+    /// user-provided callback while the mutex is held. Both borrow the guard
+    /// mutably, so neither can outlive it.
+    pub fn for_callback(
+        &mut self,
+    ) -> (&mut T, CallbackPermission<'_, NestedMutexPermission<P, I>>) {
+        (
+            &mut self.0,
+            CallbackPermission(NestedMutexPermission::new(), PhantomData),
+        )
+    }
+}
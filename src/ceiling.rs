@@ -0,0 +1,215 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    fmt,
+    marker::PhantomData,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    sync::{LockResult, Mutex, PoisonError, TryLockError, TryLockResult},
+};
+
+use crate::{map_lock_result, realtime::check, DeadlockProofMutex, MutexBackend, MutexPermission};
+
+/// Declares the priority ceiling of the mutex identified by this type: the
+/// realtime priority of the highest-priority thread which ever claims it.
+/// Used by [`DeadlockProofMutex::with_priority_ceiling`].
+pub trait PriorityCeiling {
+    /// The ceiling, as a `SCHED_FIFO` or `SCHED_RR` priority.
+    const CEILING: i32;
+}
+
+/// A backend for [`DeadlockProofMutex`] using the immediate priority ceiling
+/// protocol: a thread claiming it is raised straight away to the mutex's
+/// ceiling priority, and restored to its previous priority when it releases
+/// it. No thread which might claim the mutex can then preempt the holder,
+/// so priority inversion is bounded by the longest critical section, just as
+/// the permission tokens rule out deadlock.
+///
+/// It wraps any other backend. Create one using
+/// [`DeadlockProofMutex::with_priority_ceiling`], with the ceiling declared
+/// by the identifier, or [`PriorityCeilingMutex::new`] along with
+/// [`DeadlockProofMutex::from_backend`]. The priority is only changed for
+/// threads with a realtime scheduling policy, and never lowered.
+#[derive(Debug, Default)]
+pub struct PriorityCeilingMutex<M> {
+    mutex: M,
+    ceiling: i32,
+}
+
+impl<M> PriorityCeilingMutex<M> {
+    /// Wraps `mutex`, raising threads which claim it to `ceiling`.
+    pub const fn new(mutex: M, ceiling: i32) -> Self {
+        Self { mutex, ceiling }
+    }
+
+    /// The priority to which threads claiming the mutex are raised.
+    pub fn ceiling(&self) -> i32 {
+        self.ceiling
+    }
+
+    /// Raises this thread to the ceiling, if it has a realtime policy and is
+    /// below it, returning the priority to restore afterwards.
+    fn raise(&self) -> Option<i32> {
+        let mut policy = 0;
+        // Safety: all-zero is a valid `sched_param`.
+        let mut param: libc::sched_param = unsafe { std::mem::zeroed() };
+        // Safety: the pointers are to valid locals.
+        check(
+            unsafe { libc::pthread_getschedparam(libc::pthread_self(), &mut policy, &mut param) },
+            "pthread_getschedparam",
+        );
+        if (policy != libc::SCHED_FIFO && policy != libc::SCHED_RR)
+            || param.sched_priority >= self.ceiling
+        {
+            return None;
+        }
+        let previous = param.sched_priority;
+        set_priority(self.ceiling);
+        Some(previous)
+    }
+
+    fn guard<'a, T>(
+        guard: M::Guard<'a>,
+        restore: Option<i32>,
+    ) -> PriorityCeilingMutexGuard<'a, T, M>
+    where
+        M: MutexBackend<T> + 'a,
+    {
+        PriorityCeilingMutexGuard {
+            guard: ManuallyDrop::new(guard),
+            restore,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+/// Sets the priority of this thread, keeping its scheduling policy.
+fn set_priority(priority: i32) {
+    let mut policy = 0;
+    // Safety: all-zero is a valid `sched_param`.
+    let mut param: libc::sched_param = unsafe { std::mem::zeroed() };
+    // Safety: the pointers are to valid locals.
+    unsafe {
+        check(
+            libc::pthread_getschedparam(libc::pthread_self(), &mut policy, &mut param),
+            "pthread_getschedparam",
+        );
+        param.sched_priority = priority;
+        check(
+            libc::pthread_setschedparam(libc::pthread_self(), policy, &param),
+            "pthread_setschedparam",
+        );
+    }
+}
+
+/// A guard for a [`PriorityCeilingMutex`]. When dropped, it releases the
+/// mutex and then restores the priority the thread had when claiming it,
+/// so guards should be dropped in the reverse order to that in which they
+/// were obtained. It must be released on the thread which claimed it, so
+/// it isn't `Send`.
+pub struct PriorityCeilingMutexGuard<'a, T, M: MutexBackend<T> + 'a> {
+    guard: ManuallyDrop<M::Guard<'a>>,
+    restore: Option<i32>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, T, M: MutexBackend<T> + 'a> Deref for PriorityCeilingMutexGuard<'a, T, M> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T, M: MutexBackend<T> + 'a> DerefMut for PriorityCeilingMutexGuard<'a, T, M> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T, M: MutexBackend<T> + 'a> Drop for PriorityCeilingMutexGuard<'a, T, M> {
+    fn drop(&mut self) {
+        // Safety: the guard is never used again.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if let Some(priority) = self.restore {
+            set_priority(priority);
+        }
+    }
+}
+
+impl<'a, T: fmt::Debug, M: MutexBackend<T> + 'a> fmt::Debug
+    for PriorityCeilingMutexGuard<'a, T, M>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.deref(), f)
+    }
+}
+
+// Safety: the guards wrap those of the wrapped mutex, which upholds the
+// requirements itself.
+unsafe impl<T, M: MutexBackend<T>> MutexBackend<T> for PriorityCeilingMutex<M> {
+    type Guard<'a>
+        = PriorityCeilingMutexGuard<'a, T, M>
+    where
+        Self: 'a;
+
+    fn lock(&self) -> LockResult<PriorityCeilingMutexGuard<'_, T, M>> {
+        // Raised before waiting, so that nothing of lower priority which
+        // might also claim the mutex can preempt this thread meanwhile.
+        let restore = self.raise();
+        map_lock_result(self.mutex.lock(), |guard| Self::guard(guard, restore))
+    }
+
+    fn try_lock(&self) -> TryLockResult<PriorityCeilingMutexGuard<'_, T, M>> {
+        let restore = self.raise();
+        match self.mutex.try_lock() {
+            Ok(guard) => Ok(Self::guard(guard, restore)),
+            Err(TryLockError::Poisoned(e)) => Err(TryLockError::Poisoned(PoisonError::new(
+                Self::guard(e.into_inner(), restore),
+            ))),
+            Err(TryLockError::WouldBlock) => {
+                if let Some(priority) = restore {
+                    set_priority(priority);
+                }
+                Err(TryLockError::WouldBlock)
+            }
+        }
+    }
+
+    fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.mutex.get_mut()
+    }
+
+    fn into_inner(self) -> LockResult<T> {
+        self.mutex.into_inner()
+    }
+
+    fn is_poisoned(&self) -> bool {
+        self.mutex.is_poisoned()
+    }
+
+    fn clear_poison(&self) {
+        self.mutex.clear_poison()
+    }
+}
+
+impl<T, P: MutexPermission, I: PriorityCeiling>
+    DeadlockProofMutex<T, P, I, PriorityCeilingMutex<Mutex<T>>>
+{
+    /// Create a new deadlock-proof mutex using the immediate priority
+    /// ceiling protocol, with the ceiling declared by implementing
+    /// [`PriorityCeiling`] for the identifier. The `identifier` is a type
+    /// unique to this mutex, just as for [`DeadlockProofMutex::new`].
+    pub fn with_priority_ceiling(content: T, identifier: I) -> Self {
+        Self::from_backend(
+            PriorityCeilingMutex::new(Mutex::new(content), I::CEILING),
+            identifier,
+        )
+    }
+}
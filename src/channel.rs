@@ -0,0 +1,91 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::sync::mpsc::{
+    sync_channel, Receiver, RecvError, SendError, SyncSender, TryRecvError, TrySendError,
+};
+
+use crate::{domain, DefaultDomain, OuterMutexPermission};
+
+/// Creates a bounded channel whose blocking operations can't be performed
+/// while holding a mutex. Otherwise identical to
+/// [`std::sync::mpsc::sync_channel`].
+///
+/// A thread blocked sending to a full channel, or receiving from an empty
+/// one, is waiting for another thread to act, so it mustn't hold a mutex
+/// that thread might need first. The blocking operations therefore need to
+/// see this thread's [`OuterMutexPermission`], which proves that no mutex
+/// is held, and mutices from other permission domains are checked for at
+/// runtime as for [`crate::DeadlockProofBarrier`]. This can't rule out
+/// threads which wait on each other through channels alone.
+pub fn deadlock_proof_sync_channel<T>(
+    bound: usize,
+) -> (DeadlockProofSender<T>, DeadlockProofReceiver<T>) {
+    let (sender, receiver) = sync_channel(bound);
+    (DeadlockProofSender(sender), DeadlockProofReceiver(receiver))
+}
+
+/// Creates a rendezvous channel, with no capacity, whose blocking
+/// operations can't be performed while holding a mutex. Equivalent to
+/// [`deadlock_proof_sync_channel`] with a bound of zero.
+///
+/// Every send blocks until a receiver takes the message, and every receive
+/// until a sender hands one over, so both halves wait on another thread
+/// and both [`DeadlockProofSender::send`] and
+/// [`DeadlockProofReceiver::recv`] need this thread's
+/// [`OuterMutexPermission`]. [`DeadlockProofSender::try_send`] only
+/// succeeds if a receiver is already waiting.
+pub fn deadlock_proof_rendezvous_channel<T>() -> (DeadlockProofSender<T>, DeadlockProofReceiver<T>)
+{
+    deadlock_proof_sync_channel(0)
+}
+
+/// The sending half of a channel from [`deadlock_proof_sync_channel`] or
+/// [`deadlock_proof_rendezvous_channel`].
+pub struct DeadlockProofSender<T>(SyncSender<T>);
+
+impl<T> DeadlockProofSender<T> {
+    /// Sends a message, blocking while the channel is full. Similar to
+    /// [`SyncSender::send`], but requires the permission to prove that no
+    /// mutex is held while blocking.
+    pub fn send(&self, message: T, _permission: &OuterMutexPermission) -> Result<(), SendError<T>> {
+        domain::check_lock::<DefaultDomain, Self>();
+        self.0.send(message)
+    }
+
+    /// Attempts to send a message without blocking, so needs no permission.
+    pub fn try_send(&self, message: T) -> Result<(), TrySendError<T>> {
+        self.0.try_send(message)
+    }
+}
+
+impl<T> Clone for DeadlockProofSender<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+/// The receiving half of a channel from [`deadlock_proof_sync_channel`] or
+/// [`deadlock_proof_rendezvous_channel`].
+pub struct DeadlockProofReceiver<T>(Receiver<T>);
+
+impl<T> DeadlockProofReceiver<T> {
+    /// Receives a message, blocking while the channel is empty. Similar to
+    /// [`Receiver::recv`], but requires the permission to prove that no
+    /// mutex is held while blocking.
+    pub fn recv(&self, _permission: &OuterMutexPermission) -> Result<T, RecvError> {
+        domain::check_lock::<DefaultDomain, Self>();
+        self.0.recv()
+    }
+
+    /// Attempts to receive a message without blocking, so needs no
+    /// permission.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.0.try_recv()
+    }
+}
@@ -0,0 +1,557 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    marker::PhantomData,
+    sync::{Condvar, LockResult, Mutex, PoisonError, WaitTimeoutResult},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    map_lock_result,
+    rwlock::{Writable, WriteLock},
+    DeadlockProofMutexGuard, DeadlockProofNestedMutexGuard, DeadlockProofNestedRwLockWriteGuard,
+    DeadlockProofRwLockWriteGuard, HeldLock, MutexPermission, NestedMutexPermission,
+};
+
+/// A condition variable for use with a [`crate::DeadlockProofMutex`], or
+/// with the write guards of a [`crate::DeadlockProofRwLock`]. Otherwise
+/// identical to [`Condvar`], though at the moment only a subset of APIs are
+/// implemented.
+///
+/// The condvar is parameterized by the identifier type `I` of the mutex or
+/// lock it's used with, so the type system guarantees that it's only ever
+/// used with that one. Waiting consumes the guard and hands it back - along
+/// with the permission token it holds - once woken, so there's no need to
+/// drop out of the permission system in order to wait.
+pub struct DeadlockProofCondvar<I>(
+    Condvar,
+    PhantomData<fn(I)>,
+    /// Counts notifications, for writers of a reader-writer lock, which
+    /// [`Condvar`] can't release while waiting. They wait for it to change.
+    Mutex<u64>,
+    /// Where those writers wait, with the count held.
+    Condvar,
+);
+
+/// A write guard of a [`crate::DeadlockProofRwLock`], which can wait on a
+/// [`DeadlockProofCondvar`].
+trait WriteGuard<'a, T: 'a>: Sized {
+    type Permission;
+
+    fn into_parts(self) -> (WriteLock<'a, T>, Self::Permission, HeldLock);
+
+    fn from_parts(lock: WriteLock<'a, T>, permission: Self::Permission, held: HeldLock) -> Self;
+}
+
+impl<'a, T, P: MutexPermission, I> WriteGuard<'a, T>
+    for DeadlockProofRwLockWriteGuard<'a, T, P, I>
+{
+    type Permission = P;
+
+    fn into_parts(self) -> (WriteLock<'a, T>, P, HeldLock) {
+        (self.0, self.1, self.3)
+    }
+
+    fn from_parts(lock: WriteLock<'a, T>, permission: P, held: HeldLock) -> Self {
+        Self(lock, permission, PhantomData, held)
+    }
+}
+
+impl<'a, T, P: MutexPermission, I> WriteGuard<'a, T>
+    for DeadlockProofNestedRwLockWriteGuard<'a, T, P, I>
+{
+    type Permission = P;
+
+    fn into_parts(self) -> (WriteLock<'a, T>, P, HeldLock) {
+        (self.0, self.1, self.3)
+    }
+
+    fn from_parts(lock: WriteLock<'a, T>, permission: P, held: HeldLock) -> Self {
+        Self(lock, permission, PhantomData, held)
+    }
+}
+
+impl<I> DeadlockProofCondvar<I> {
+    /// Create a new condvar. The identifier type is usually inferred from
+    /// the first guard passed to [`DeadlockProofCondvar::wait`].
+    pub const fn new() -> Self {
+        Self(Condvar::new(), PhantomData, Mutex::new(0), Condvar::new())
+    }
+
+    /// Releases the write lock held by `lock` and blocks until notified, or
+    /// until `deadline`, then claims it again.
+    fn wait_write_lock<'a, T>(
+        &self,
+        lock: WriteLock<'a, T>,
+        deadline: Option<Instant>,
+    ) -> (LockResult<WriteLock<'a, T>>, Option<WaitTimeoutResult>) {
+        let writable: Writable<'a, T> = lock.2;
+        // The count is held while releasing the lock, so that a
+        // notification sent as soon as it's released isn't missed. Nothing
+        // can panic while holding the count, so it's never poisoned.
+        let count = self.2.lock().unwrap_or_else(PoisonError::into_inner);
+        let start = *count;
+        drop(lock);
+        let timeout = match deadline {
+            None => {
+                drop(
+                    self.3
+                        .wait_while(count, |count| *count == start)
+                        .unwrap_or_else(PoisonError::into_inner),
+                );
+                None
+            }
+            Some(deadline) => {
+                let (count, timeout) = self
+                    .3
+                    .wait_timeout_while(
+                        count,
+                        deadline.saturating_duration_since(Instant::now()),
+                        |count| *count == start,
+                    )
+                    .unwrap_or_else(PoisonError::into_inner);
+                drop(count);
+                Some(timeout)
+            }
+        };
+        (writable.lock(), timeout)
+    }
+
+    /// Waits with a write guard until `condition` returns false, or until
+    /// `deadline`, returning the guard and, given a deadline, whether it
+    /// passed.
+    fn wait_write_guard<'a, T: 'a, G: WriteGuard<'a, T>>(
+        &self,
+        guard: G,
+        deadline: Option<Instant>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> LockResult<(G, Option<WaitTimeoutResult>)> {
+        let (mut lock, permission, held) = guard.into_parts();
+        let mut poisoned = false;
+        let mut timeout = None;
+        while condition(&mut lock) {
+            if timeout.is_some_and(|timeout: WaitTimeoutResult| timeout.timed_out()) {
+                break;
+            }
+            let (result, waited) = self.wait_write_lock(lock, deadline);
+            lock = result.unwrap_or_else(|e| {
+                poisoned = true;
+                e.into_inner()
+            });
+            timeout = waited;
+        }
+        let result = (G::from_parts(lock, permission, held), timeout);
+        if poisoned {
+            Err(PoisonError::new(result))
+        } else {
+            Ok(result)
+        }
+    }
+
+    /// Blocks the current thread until this condvar is notified, releasing
+    /// the mutex while waiting and reacquiring it before returning. As with
+    /// [`Condvar::wait`], spurious wakeups are possible. If the mutex was
+    /// poisoned while waiting, the error still contains the guard, and so
+    /// the permission.
+    pub fn wait<'a, T, P: MutexPermission>(
+        &self,
+        guard: DeadlockProofMutexGuard<'a, T, P, I>,
+    ) -> LockResult<DeadlockProofMutexGuard<'a, T, P, I>> {
+        let DeadlockProofMutexGuard(inner, permission, mutex, held) = guard;
+        held.notify_waiters();
+        match self.0.wait(inner) {
+            Ok(inner) => Ok(DeadlockProofMutexGuard(inner, permission, mutex, held)),
+            Err(e) => Err(PoisonError::new(DeadlockProofMutexGuard(
+                e.into_inner(),
+                permission,
+                mutex,
+                held,
+            ))),
+        }
+    }
+
+    /// Equivalent to [`DeadlockProofCondvar::wait`] for a guard obtained
+    /// from [`crate::DeadlockProofMutex::lock_for_nested`]. The permission
+    /// for nested mutices must be lent back, so that none of them can be
+    /// held: reclaiming this mutex while holding one would claim them out of
+    /// order.
+    pub fn wait_nested<'a, T, P: MutexPermission>(
+        &self,
+        guard: DeadlockProofNestedMutexGuard<'a, T, P, I>,
+        _token: &mut NestedMutexPermission<P, I>,
+    ) -> LockResult<DeadlockProofNestedMutexGuard<'a, T, P, I>> {
+        let DeadlockProofNestedMutexGuard(inner, permission, mutex, held) = guard;
+        held.notify_waiters();
+        match self.0.wait(inner) {
+            Ok(inner) => Ok(DeadlockProofNestedMutexGuard(
+                inner, permission, mutex, held,
+            )),
+            Err(e) => Err(PoisonError::new(DeadlockProofNestedMutexGuard(
+                e.into_inner(),
+                permission,
+                mutex,
+                held,
+            ))),
+        }
+    }
+
+    /// Waits on this condvar for a notification, timing out after `dur`,
+    /// releasing the mutex while waiting as [`DeadlockProofCondvar::wait`]
+    /// does. Returns the guard along with whether the wait timed out, as for
+    /// [`Condvar::wait_timeout`].
+    pub fn wait_timeout<'a, T, P: MutexPermission>(
+        &self,
+        guard: DeadlockProofMutexGuard<'a, T, P, I>,
+        dur: Duration,
+    ) -> LockResult<(DeadlockProofMutexGuard<'a, T, P, I>, WaitTimeoutResult)> {
+        let DeadlockProofMutexGuard(inner, permission, mutex, held) = guard;
+        held.notify_waiters();
+        map_lock_result(self.0.wait_timeout(inner, dur), |(inner, timeout)| {
+            (
+                DeadlockProofMutexGuard(inner, permission, mutex, held),
+                timeout,
+            )
+        })
+    }
+
+    /// Blocks the current thread until `condition` returns false, checking
+    /// it each time the condvar is notified, as for [`Condvar::wait_while`].
+    /// `condition` is called with the mutex held, and the mutex is released
+    /// while waiting.
+    pub fn wait_while<'a, T, P: MutexPermission>(
+        &self,
+        guard: DeadlockProofMutexGuard<'a, T, P, I>,
+        condition: impl FnMut(&mut T) -> bool,
+    ) -> LockResult<DeadlockProofMutexGuard<'a, T, P, I>> {
+        let DeadlockProofMutexGuard(inner, permission, mutex, held) = guard;
+        held.notify_waiters();
+        map_lock_result(self.0.wait_while(inner, condition), |inner| {
+            DeadlockProofMutexGuard(inner, permission, mutex, held)
+        })
+    }
+
+    /// Combines [`DeadlockProofCondvar::wait_timeout`] and
+    /// [`DeadlockProofCondvar::wait_while`]: waits until `condition` returns
+    /// false or `dur` has elapsed in total, as for
+    /// [`Condvar::wait_timeout_while`].
+    pub fn wait_timeout_while<'a, T, P: MutexPermission>(
+        &self,
+        guard: DeadlockProofMutexGuard<'a, T, P, I>,
+        dur: Duration,
+        condition: impl FnMut(&mut T) -> bool,
+    ) -> LockResult<(DeadlockProofMutexGuard<'a, T, P, I>, WaitTimeoutResult)> {
+        let DeadlockProofMutexGuard(inner, permission, mutex, held) = guard;
+        held.notify_waiters();
+        map_lock_result(
+            self.0.wait_timeout_while(inner, dur, condition),
+            |(inner, timeout)| {
+                (
+                    DeadlockProofMutexGuard(inner, permission, mutex, held),
+                    timeout,
+                )
+            },
+        )
+    }
+
+    /// Equivalent to [`DeadlockProofCondvar::wait_timeout`] for a guard
+    /// obtained from [`crate::DeadlockProofMutex::lock_for_nested`]. As for
+    /// [`DeadlockProofCondvar::wait_nested`], the permission for nested
+    /// mutices must be lent back.
+    pub fn wait_timeout_nested<'a, T, P: MutexPermission>(
+        &self,
+        guard: DeadlockProofNestedMutexGuard<'a, T, P, I>,
+        _token: &mut NestedMutexPermission<P, I>,
+        dur: Duration,
+    ) -> LockResult<(
+        DeadlockProofNestedMutexGuard<'a, T, P, I>,
+        WaitTimeoutResult,
+    )> {
+        let DeadlockProofNestedMutexGuard(inner, permission, mutex, held) = guard;
+        held.notify_waiters();
+        map_lock_result(self.0.wait_timeout(inner, dur), |(inner, timeout)| {
+            (
+                DeadlockProofNestedMutexGuard(inner, permission, mutex, held),
+                timeout,
+            )
+        })
+    }
+
+    /// Equivalent to [`DeadlockProofCondvar::wait_while`] for a guard
+    /// obtained from [`crate::DeadlockProofMutex::lock_for_nested`]. As for
+    /// [`DeadlockProofCondvar::wait_nested`], the permission for nested
+    /// mutices must be lent back.
+    pub fn wait_while_nested<'a, T, P: MutexPermission>(
+        &self,
+        guard: DeadlockProofNestedMutexGuard<'a, T, P, I>,
+        _token: &mut NestedMutexPermission<P, I>,
+        condition: impl FnMut(&mut T) -> bool,
+    ) -> LockResult<DeadlockProofNestedMutexGuard<'a, T, P, I>> {
+        let DeadlockProofNestedMutexGuard(inner, permission, mutex, held) = guard;
+        held.notify_waiters();
+        map_lock_result(self.0.wait_while(inner, condition), |inner| {
+            DeadlockProofNestedMutexGuard(inner, permission, mutex, held)
+        })
+    }
+
+    /// Equivalent to [`DeadlockProofCondvar::wait_timeout_while`] for a
+    /// guard obtained from [`crate::DeadlockProofMutex::lock_for_nested`]. As for
+    /// [`DeadlockProofCondvar::wait_nested`], the permission for nested
+    /// mutices must be lent back.
+    pub fn wait_timeout_while_nested<'a, T, P: MutexPermission>(
+        &self,
+        guard: DeadlockProofNestedMutexGuard<'a, T, P, I>,
+        _token: &mut NestedMutexPermission<P, I>,
+        dur: Duration,
+        condition: impl FnMut(&mut T) -> bool,
+    ) -> LockResult<(
+        DeadlockProofNestedMutexGuard<'a, T, P, I>,
+        WaitTimeoutResult,
+    )> {
+        let DeadlockProofNestedMutexGuard(inner, permission, mutex, held) = guard;
+        held.notify_waiters();
+        map_lock_result(
+            self.0.wait_timeout_while(inner, dur, condition),
+            |(inner, timeout)| {
+                (
+                    DeadlockProofNestedMutexGuard(inner, permission, mutex, held),
+                    timeout,
+                )
+            },
+        )
+    }
+
+    /// Blocks the current thread until this condvar is notified, releasing
+    /// the reader-writer lock while waiting and reclaiming write access
+    /// before returning, as [`DeadlockProofCondvar::wait`] does for a mutex.
+    // The error holds the guard, just as for every other way of locking.
+    #[allow(clippy::result_large_err)]
+    pub fn wait_write<'a, T, P: MutexPermission>(
+        &self,
+        guard: DeadlockProofRwLockWriteGuard<'a, T, P, I>,
+    ) -> LockResult<DeadlockProofRwLockWriteGuard<'a, T, P, I>> {
+        let mut waited = false;
+        map_lock_result(
+            self.wait_write_guard(guard, None, |_| !std::mem::replace(&mut waited, true)),
+            |(guard, _)| guard,
+        )
+    }
+
+    /// Equivalent to [`DeadlockProofCondvar::wait_write`] for a guard
+    /// obtained from [`crate::DeadlockProofRwLock::write_for_nested`]. As for
+    /// [`DeadlockProofCondvar::wait_nested`], the permission for nested
+    /// mutices must be lent back.
+    // The error holds the guard, just as for every other way of locking.
+    #[allow(clippy::result_large_err)]
+    pub fn wait_write_nested<'a, T, P: MutexPermission>(
+        &self,
+        guard: DeadlockProofNestedRwLockWriteGuard<'a, T, P, I>,
+        _token: &mut NestedMutexPermission<P, I>,
+    ) -> LockResult<DeadlockProofNestedRwLockWriteGuard<'a, T, P, I>> {
+        let mut waited = false;
+        map_lock_result(
+            self.wait_write_guard(guard, None, |_| !std::mem::replace(&mut waited, true)),
+            |(guard, _)| guard,
+        )
+    }
+
+    /// Equivalent to [`DeadlockProofCondvar::wait_timeout`] for a
+    /// reader-writer lock's write guard.
+    // The error holds the guard, just as for every other way of locking.
+    #[allow(clippy::result_large_err)]
+    pub fn wait_timeout_write<'a, T, P: MutexPermission>(
+        &self,
+        guard: DeadlockProofRwLockWriteGuard<'a, T, P, I>,
+        dur: Duration,
+    ) -> LockResult<(
+        DeadlockProofRwLockWriteGuard<'a, T, P, I>,
+        WaitTimeoutResult,
+    )> {
+        let mut waited = false;
+        self.wait_timeout_write_guard(guard, dur, |_| !std::mem::replace(&mut waited, true))
+    }
+
+    /// Equivalent to [`DeadlockProofCondvar::wait_timeout_write`] for a
+    /// guard obtained from [`crate::DeadlockProofRwLock::write_for_nested`]. As for
+    /// [`DeadlockProofCondvar::wait_nested`], the permission for nested
+    /// mutices must be lent back.
+    // The error holds the guard, just as for every other way of locking.
+    #[allow(clippy::result_large_err)]
+    pub fn wait_timeout_write_nested<'a, T, P: MutexPermission>(
+        &self,
+        guard: DeadlockProofNestedRwLockWriteGuard<'a, T, P, I>,
+        _token: &mut NestedMutexPermission<P, I>,
+        dur: Duration,
+    ) -> LockResult<(
+        DeadlockProofNestedRwLockWriteGuard<'a, T, P, I>,
+        WaitTimeoutResult,
+    )> {
+        let mut waited = false;
+        self.wait_timeout_write_guard(guard, dur, |_| !std::mem::replace(&mut waited, true))
+    }
+
+    /// Equivalent to [`DeadlockProofCondvar::wait_while`] for a
+    /// reader-writer lock's write guard.
+    // The error holds the guard, just as for every other way of locking.
+    #[allow(clippy::result_large_err)]
+    pub fn wait_while_write<'a, T, P: MutexPermission>(
+        &self,
+        guard: DeadlockProofRwLockWriteGuard<'a, T, P, I>,
+        condition: impl FnMut(&mut T) -> bool,
+    ) -> LockResult<DeadlockProofRwLockWriteGuard<'a, T, P, I>> {
+        map_lock_result(
+            self.wait_write_guard(guard, None, condition),
+            |(guard, _)| guard,
+        )
+    }
+
+    /// Equivalent to [`DeadlockProofCondvar::wait_while_write`] for a guard
+    /// obtained from [`crate::DeadlockProofRwLock::write_for_nested`]. As for
+    /// [`DeadlockProofCondvar::wait_nested`], the permission for nested
+    /// mutices must be lent back.
+    // The error holds the guard, just as for every other way of locking.
+    #[allow(clippy::result_large_err)]
+    pub fn wait_while_write_nested<'a, T, P: MutexPermission>(
+        &self,
+        guard: DeadlockProofNestedRwLockWriteGuard<'a, T, P, I>,
+        _token: &mut NestedMutexPermission<P, I>,
+        condition: impl FnMut(&mut T) -> bool,
+    ) -> LockResult<DeadlockProofNestedRwLockWriteGuard<'a, T, P, I>> {
+        map_lock_result(
+            self.wait_write_guard(guard, None, condition),
+            |(guard, _)| guard,
+        )
+    }
+
+    /// Equivalent to [`DeadlockProofCondvar::wait_timeout_while`] for a
+    /// reader-writer lock's write guard.
+    // The error holds the guard, just as for every other way of locking.
+    #[allow(clippy::result_large_err)]
+    pub fn wait_timeout_while_write<'a, T, P: MutexPermission>(
+        &self,
+        guard: DeadlockProofRwLockWriteGuard<'a, T, P, I>,
+        dur: Duration,
+        condition: impl FnMut(&mut T) -> bool,
+    ) -> LockResult<(
+        DeadlockProofRwLockWriteGuard<'a, T, P, I>,
+        WaitTimeoutResult,
+    )> {
+        self.wait_timeout_write_guard(guard, dur, condition)
+    }
+
+    /// Equivalent to [`DeadlockProofCondvar::wait_timeout_while_write`] for
+    /// a guard obtained from [`crate::DeadlockProofRwLock::write_for_nested`]. As for
+    /// [`DeadlockProofCondvar::wait_nested`], the permission for nested
+    /// mutices must be lent back.
+    // The error holds the guard, just as for every other way of locking.
+    #[allow(clippy::result_large_err)]
+    pub fn wait_timeout_while_write_nested<'a, T, P: MutexPermission>(
+        &self,
+        guard: DeadlockProofNestedRwLockWriteGuard<'a, T, P, I>,
+        _token: &mut NestedMutexPermission<P, I>,
+        dur: Duration,
+        condition: impl FnMut(&mut T) -> bool,
+    ) -> LockResult<(
+        DeadlockProofNestedRwLockWriteGuard<'a, T, P, I>,
+        WaitTimeoutResult,
+    )> {
+        self.wait_timeout_write_guard(guard, dur, condition)
+    }
+
+    /// [`DeadlockProofCondvar::wait_write_guard`] with a timeout, which
+    /// always yields a [`WaitTimeoutResult`].
+    fn wait_timeout_write_guard<'a, T: 'a, G: WriteGuard<'a, T>>(
+        &self,
+        guard: G,
+        dur: Duration,
+        condition: impl FnMut(&mut T) -> bool,
+    ) -> LockResult<(G, WaitTimeoutResult)> {
+        let result = self.wait_write_guard(guard, Some(Instant::now() + dur), condition);
+        map_lock_result(result, |(guard, timeout)| {
+            // Only absent if the condition was false to begin with, in
+            // which case there was no wait to time out.
+            let timeout = timeout.unwrap_or_else(|| {
+                let count = self.2.lock().unwrap_or_else(PoisonError::into_inner);
+                self.3
+                    .wait_timeout_while(count, Duration::ZERO, |_| false)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .1
+            });
+            (guard, timeout)
+        })
+    }
+
+    /// Wakes up one thread blocked on this condvar.
+    pub fn notify_one(&self) {
+        *self.2.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+        self.3.notify_one();
+        self.0.notify_one()
+    }
+
+    /// Wakes up all threads blocked on this condvar.
+    pub fn notify_all(&self) {
+        *self.2.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+        self.3.notify_all();
+        self.0.notify_all()
+    }
+}
+
+impl<I> Default for DeadlockProofCondvar<I> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use crate::{DeadlockProofCondvar, DeadlockProofRwLock, OuterMutexPermission};
+
+    struct State;
+
+    #[test]
+    fn wait_while_write_releases_the_lock() {
+        let lock: DeadlockProofRwLock<u32, OuterMutexPermission, State> =
+            DeadlockProofRwLock::new(0, State);
+        let condvar = DeadlockProofCondvar::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mut permission = OuterMutexPermission::get();
+                for _ in 0..3 {
+                    thread::sleep(Duration::from_millis(5));
+                    let mut guard = lock.write(permission).unwrap();
+                    *guard += 1;
+                    permission = guard.unlock();
+                    condvar.notify_all();
+                }
+            });
+            let guard = lock.write(OuterMutexPermission::get()).unwrap();
+            let guard = condvar.wait_while_write(guard, |n| *n < 3).unwrap();
+            assert_eq!(*guard, 3);
+            guard.unlock();
+        });
+    }
+
+    #[test]
+    fn wait_timeout_write_times_out() {
+        let lock: DeadlockProofRwLock<u32, OuterMutexPermission, State> =
+            DeadlockProofRwLock::new(0, State);
+        let condvar = DeadlockProofCondvar::new();
+        let guard = lock.write(OuterMutexPermission::get()).unwrap();
+        let (guard, timeout) = condvar
+            .wait_timeout_while_write(guard, Duration::from_millis(10), |n| *n == 0)
+            .unwrap();
+        assert!(timeout.timed_out());
+        let (guard, timeout) = condvar
+            .wait_timeout_while_write(guard, Duration::from_millis(10), |n| *n == 1)
+            .unwrap();
+        assert!(!timeout.timed_out());
+        guard.unlock();
+    }
+}
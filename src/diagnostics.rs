@@ -0,0 +1,129 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A global registry of the deadlock-proof mutices currently held, for
+//! debugging long critical sections.
+
+use std::{
+    cmp::Reverse,
+    fmt,
+    sync::{Mutex, PoisonError},
+    thread::{self, ThreadId},
+    time::{Duration, Instant},
+};
+
+/// One mutex currently held, as reported by [`dump_held_locks`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct HeldLockInfo {
+    /// The name of the thread holding the mutex, or its ID if it has none.
+    pub thread: String,
+    /// The type name of the mutex's identifier.
+    pub identifier: &'static str,
+    /// The name given to the mutex by
+    /// [`crate::DeadlockProofMutex::set_name`], if any.
+    pub name: Option<&'static str>,
+    /// How long the mutex has been held.
+    pub held_for: Duration,
+}
+
+impl fmt::Display for HeldLockInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} holds {}",
+            self.thread,
+            crate::IdentifierName(self.identifier)
+        )?;
+        if let Some(name) = self.name {
+            write!(f, " ({name})")?;
+        }
+        write!(f, " for {:?}", self.held_for)
+    }
+}
+
+struct Hold {
+    key: usize,
+    thread: ThreadId,
+    thread_name: Option<String>,
+    identifier: &'static str,
+    name: Option<&'static str>,
+    since: Instant,
+}
+
+static HOLDS: Mutex<Vec<Hold>> = Mutex::new(Vec::new());
+
+fn holds() -> std::sync::MutexGuard<'static, Vec<Hold>> {
+    HOLDS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Reports which threads currently hold which deadlock-proof mutices, and
+/// for how long, longest first. Only mutices of type
+/// [`crate::DeadlockProofMutex`] are included.
+pub fn dump_held_locks() -> Vec<HeldLockInfo> {
+    let now = Instant::now();
+    let mut held: Vec<HeldLockInfo> = holds()
+        .iter()
+        .map(|hold| HeldLockInfo {
+            thread: hold
+                .thread_name
+                .clone()
+                .unwrap_or_else(|| format!("{:?}", hold.thread)),
+            identifier: hold.identifier,
+            name: hold.name,
+            held_for: now - hold.since,
+        })
+        .collect();
+    held.sort_by_key(|info| Reverse(info.held_for));
+    held
+}
+
+/// The name given to a mutex, stored within it.
+pub(crate) struct MutexName(Mutex<Option<&'static str>>);
+
+impl MutexName {
+    pub(crate) const fn new() -> Self {
+        Self(Mutex::new(None))
+    }
+
+    pub(crate) fn set(&self, name: &'static str) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = Some(name);
+    }
+
+    fn get(&self) -> Option<&'static str> {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Records that the current thread has claimed the mutex with the given
+/// key, identified by `I`.
+pub(crate) fn claimed<I: ?Sized>(key: usize, name: &MutexName) {
+    let thread = thread::current();
+    let hold = Hold {
+        key,
+        thread: thread.id(),
+        thread_name: thread.name().map(str::to_owned),
+        identifier: std::any::type_name::<I>(),
+        name: name.get(),
+        since: Instant::now(),
+    };
+    holds().push(hold);
+}
+
+/// Records that the current thread has released the mutex with the given
+/// key, if it was recorded as claimed.
+pub(crate) fn released(key: usize) {
+    let thread = thread::current().id();
+    let mut holds = holds();
+    if let Some(pos) = holds
+        .iter()
+        .rposition(|hold| hold.key == key && hold.thread == thread)
+    {
+        holds.swap_remove(pos);
+    }
+}
@@ -0,0 +1,313 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Permission domains. Each thread has one [`crate::OuterMutexPermission`]
+//! per domain, so unrelated subsystems needn't share a single token. The
+//! type system keeps each domain deadlock-free by itself; between domains,
+//! this module checks at runtime that a thread only claims mutices from
+//! several domains in increasing order of rank.
+//!
+//! The default domain is handled without any of this bookkeeping, so
+//! programs which don't declare domains don't pay for them.
+//!
+//! An order between two domains declared through a
+//! [`crate::BridgePermission`] takes precedence over their ranks.
+//!
+//! Without the `std` feature there are no thread-local tokens, so only the
+//! domain types themselves are provided, and nothing is checked at runtime.
+
+#[cfg(feature = "std")]
+use std::{
+    any::{type_name, TypeId},
+    cell::{Cell, RefCell},
+    sync::{PoisonError, RwLock},
+};
+
+#[cfg(feature = "std")]
+use crate::violation::{report_violation, Violation, ViolationAction, ViolationKind};
+
+/// A domain of mutices with its own [`crate::OuterMutexPermission`] in each
+/// thread. Declare a domain with [`crate::declare_permission_domain`].
+///
+/// A thread may hold mutices from several domains at once, as long as it
+/// claims them in increasing order of [`PermissionDomain::RANK`]: claiming a
+/// mutex while holding one from another domain of equal or higher rank is
+/// reported as a [`crate::Violation`], since another thread might claim the
+/// same two mutices the other way round. A program can override the ranks
+/// of two domains by bridging them with a [`crate::BridgePermission`].
+pub trait PermissionDomain: 'static {
+    /// The position of this domain in the order in which domains must be
+    /// entered. This must be greater than zero, which is the rank of
+    /// [`DefaultDomain`]; [`crate::declare_permission_domain`] checks this
+    /// at compile time.
+    const RANK: u32;
+
+    /// The most mutices a thread may hold at once within this domain, such
+    /// as three for a rule that code never holds more than three locks.
+    /// Nesting any deeper, so that a thread could end up holding more, is a
+    /// compile error. Unlimited by default.
+    const MAX_DEPTH: usize = usize::MAX;
+}
+
+/// The domain of the ordinary [`crate::OuterMutexPermission`].
+pub struct DefaultDomain;
+
+impl PermissionDomain for DefaultDomain {
+    const RANK: u32 = 0;
+}
+
+/// Declares a [`PermissionDomain`] type with the given rank:
+/// `declare_permission_domain!(pub Logging, 10)`. A maximum nesting depth
+/// may follow: `declare_permission_domain!(pub Logging, 10, max_depth = 3)`.
+/// A rank of zero, which belongs to [`DefaultDomain`], fails to compile.
+#[macro_export]
+macro_rules! declare_permission_domain {
+    ($vis:vis $name:ident, $rank:expr) => {
+        $vis struct $name;
+
+        impl $crate::PermissionDomain for $name {
+            const RANK: u32 = $rank;
+        }
+
+        const _: () = assert!(
+            <$name as $crate::PermissionDomain>::RANK > 0,
+            "Permission domains must have a rank above zero"
+        );
+    };
+    ($vis:vis $name:ident, $rank:expr, max_depth = $max_depth:expr) => {
+        $vis struct $name;
+
+        impl $crate::PermissionDomain for $name {
+            const RANK: u32 = $rank;
+            const MAX_DEPTH: usize = $max_depth;
+        }
+
+        const _: () = assert!(
+            <$name as $crate::PermissionDomain>::RANK > 0,
+            "Permission domains must have a rank above zero"
+        );
+    };
+}
+
+/// This thread's state for one domain other than the default.
+#[cfg(feature = "std")]
+struct DomainState {
+    id: TypeId,
+    name: &'static str,
+    rank: u32,
+    claimed: bool,
+    live_nested: usize,
+    held: usize,
+}
+
+#[cfg(feature = "std")]
+token_thread_local! {
+    static DOMAINS: RefCell<Vec<DomainState>> = RefCell::new(Vec::new());
+    /// The number of mutices held by this thread outside the default domain.
+    static OTHER_DOMAINS_HELD: Cell<usize> = Cell::new(0);
+}
+
+/// Orders between domains declared by bridging them, as pairs of the outer
+/// and inner domain.
+#[cfg(feature = "std")]
+static DECLARED_ORDERS: RwLock<Vec<(TypeId, TypeId)>> = RwLock::new(Vec::new());
+
+/// Records that mutices in domain `Inner` may be claimed while holding
+/// those in `Outer`, whatever their ranks, reporting a violation if the
+/// opposite order has already been declared.
+#[cfg(feature = "std")]
+pub(crate) fn declare_order<Outer: PermissionDomain, Inner: PermissionDomain>() {
+    let pair = (TypeId::of::<Outer>(), TypeId::of::<Inner>());
+    if DECLARED_ORDERS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .contains(&pair)
+    {
+        return;
+    }
+    let mut orders = DECLARED_ORDERS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner);
+    let conflicting = orders.contains(&(pair.1, pair.0));
+    if !conflicting && !orders.contains(&pair) {
+        orders.push(pair);
+    }
+    drop(orders);
+    if conflicting {
+        report_violation(
+            Violation {
+                kind: ViolationKind::ConflictingHierarchyOrder,
+                identifiers: vec![type_name::<Outer>(), type_name::<Inner>()],
+                backtrace: None,
+            },
+            None,
+        );
+    }
+}
+
+/// Whether a mutex in domain `D` may not be claimed while holding one in
+/// the domain `held`, of rank `held_rank`.
+#[cfg(feature = "std")]
+fn out_of_order<D: PermissionDomain>(held: TypeId, held_rank: u32) -> bool {
+    let claiming = TypeId::of::<D>();
+    let orders = DECLARED_ORDERS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner);
+    if orders.contains(&(held, claiming)) {
+        false
+    } else if orders.contains(&(claiming, held)) {
+        true
+    } else {
+        held_rank >= D::RANK
+    }
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn is_default<D: PermissionDomain>() -> bool {
+    is_same::<D, DefaultDomain>()
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn is_same<A: PermissionDomain, B: PermissionDomain>() -> bool {
+    TypeId::of::<A>() == TypeId::of::<B>()
+}
+
+#[cfg(feature = "std")]
+fn with_state<D: PermissionDomain, R>(f: impl FnOnce(&mut DomainState) -> R) -> R {
+    DOMAINS.with(|domains| {
+        let mut domains = domains.borrow_mut();
+        let id = TypeId::of::<D>();
+        let pos = match domains.iter().position(|state| state.id == id) {
+            Some(pos) => pos,
+            None => {
+                domains.push(DomainState {
+                    id,
+                    name: type_name::<D>(),
+                    rank: D::RANK,
+                    claimed: false,
+                    live_nested: 0,
+                    held: 0,
+                });
+                domains.len() - 1
+            }
+        };
+        f(&mut domains[pos])
+    })
+}
+
+/// Claims this thread's token for domain `D`, returning whether it was
+/// available. Not for the default domain.
+#[cfg(feature = "std")]
+pub(crate) fn claim_token<D: PermissionDomain>() -> bool {
+    with_state::<D, _>(|state| !std::mem::replace(&mut state.claimed, true))
+}
+
+/// The number of nested permissions alive within domain `D`. Not for the
+/// default domain.
+#[cfg(feature = "std")]
+pub(crate) fn live_nested<D: PermissionDomain>() -> usize {
+    with_state::<D, _>(|state| state.live_nested)
+}
+
+/// Whether this thread's token for domain `D` is unclaimed. Not for the
+/// default domain.
+#[cfg(feature = "std")]
+pub(crate) fn token_available<D: PermissionDomain>() -> bool {
+    with_state::<D, _>(|state| !state.claimed)
+}
+
+/// Returns this thread's token for domain `D`. Not for the default domain.
+#[cfg(feature = "std")]
+pub(crate) fn return_token<D: PermissionDomain>() {
+    // In thread teardown nobody can claim the token again anyway.
+    let _ = DOMAINS.try_with(|_| with_state::<D, _>(|state| state.claimed = false));
+}
+
+/// Adjusts the count of nested permissions alive within domain `D`. Not for
+/// the default domain.
+#[cfg(feature = "std")]
+pub(crate) fn adjust_live_nested<D: PermissionDomain>(created: bool) {
+    let _ = DOMAINS.try_with(|_| {
+        with_state::<D, _>(|state| {
+            if created {
+                state.live_nested += 1
+            } else {
+                state.live_nested -= 1
+            }
+        })
+    });
+}
+
+/// Checks that this thread may block waiting for a mutex in domain `D`,
+/// identified by `I`, given the mutices it already holds.
+#[cfg(feature = "std")]
+pub(crate) fn check_lock<D: PermissionDomain, I: ?Sized>() {
+    check_lock_reporting::<D, I>(None)
+}
+
+/// Checks as [`check_lock`] does, responding to a violation with `action`
+/// if given, rather than the global setting.
+#[cfg(feature = "std")]
+pub(crate) fn check_lock_reporting<D: PermissionDomain, I: ?Sized>(
+    action: Option<ViolationAction>,
+) {
+    if cfg!(deadlock_proof_mutex_unverified) || OTHER_DOMAINS_HELD.with(|held| held.get()) == 0 {
+        return;
+    }
+    let conflict = DOMAINS.with(|domains| {
+        domains
+            .borrow()
+            .iter()
+            .find(|state| {
+                state.held > 0
+                    && state.id != TypeId::of::<D>()
+                    && out_of_order::<D>(state.id, state.rank)
+            })
+            .map(|state| state.name)
+    });
+    if let Some(held_domain) = conflict {
+        report_violation(
+            Violation {
+                kind: ViolationKind::DomainOrder,
+                identifiers: vec![type_name::<I>(), type_name::<D>(), held_domain],
+                backtrace: None,
+            },
+            action,
+        );
+    }
+}
+
+/// Records that this thread holds a mutex in domain `D` until dropped.
+#[cfg(feature = "std")]
+pub(crate) struct DomainHold(Option<TypeId>);
+
+#[cfg(feature = "std")]
+impl DomainHold {
+    pub(crate) fn new<D: PermissionDomain>() -> Self {
+        if cfg!(deadlock_proof_mutex_unverified) || is_default::<D>() {
+            return Self(None);
+        }
+        with_state::<D, _>(|state| state.held += 1);
+        OTHER_DOMAINS_HELD.with(|held| held.set(held.get() + 1));
+        Self(Some(TypeId::of::<D>()))
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for DomainHold {
+    fn drop(&mut self) {
+        if let Some(id) = self.0 {
+            let _ = DOMAINS.try_with(|domains| {
+                if let Some(state) = domains.borrow_mut().iter_mut().find(|s| s.id == id) {
+                    state.held -= 1;
+                }
+            });
+            let _ = OTHER_DOMAINS_HELD.try_with(|held| held.set(held.get() - 1));
+        }
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Detection of mutices sharing an identifier. Every mutex created with an
+//! identifier value is counted against its identifier type for as long as
+//! it exists, and creating a second while the first still exists is
+//! reported as a [`crate::Violation`].
+//!
+//! Identifiers are told apart by their type ID, ignoring lifetimes, rather
+//! than by name, since every closure from [`crate::unique_type`] within one
+//! function has the same name.
+
+use std::{
+    any::TypeId,
+    collections::BTreeMap,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::violation::{report_violation, Violation, ViolationKind};
+
+/// How many mutices currently exist with each identifier.
+static LIVE: Mutex<BTreeMap<TypeId, usize>> = Mutex::new(BTreeMap::new());
+
+/// Stored within each mutex: the identifier it was counted against, if any.
+/// Mutices created in `const` contexts can't be counted.
+pub(crate) struct Registration(Option<TypeId>);
+
+impl Registration {
+    pub(crate) const fn new() -> Self {
+        Self(None)
+    }
+
+    /// Counts this mutex against the identifier `I`, reporting a violation
+    /// if another mutex with that identifier already exists.
+    pub(crate) fn register<I: ?Sized>(&mut self) {
+        let id = typeid::of::<I>();
+        let mut live = LIVE.lock().unwrap_or_else(PoisonError::into_inner);
+        let count = live.entry(id).or_insert(0);
+        *count += 1;
+        let duplicated = *count > 1;
+        drop(live);
+        self.0 = Some(id);
+        if duplicated {
+            report_violation(
+                Violation {
+                    kind: ViolationKind::DuplicateIdentifier,
+                    identifiers: vec![std::any::type_name::<I>()],
+                    backtrace: None,
+                },
+                None,
+            );
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let Some(id) = self.0 else {
+            return;
+        };
+        let mut live = LIVE.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = live.get_mut(&id) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&id);
+            }
+        }
+    }
+}
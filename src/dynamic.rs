@@ -0,0 +1,164 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::{LockResult, Mutex, MutexGuard, PoisonError, TryLockError, TryLockResult},
+};
+
+use crate::{
+    map_lock_result,
+    runtime_checks::{self, NodeId},
+    violation::ActionOverride,
+    waiters, IdentifierName, ViolationAction,
+};
+
+/// A mutex which needs no permission tokens, and is instead checked for
+/// deadlocks at runtime, as the tracing-mutex crate does. Every time a
+/// thread claims one of these while holding another, that ordering is
+/// recorded in a global graph, and claiming one in a way which would close
+/// a cycle in that graph is reported as a [`crate::Violation`] of kind
+/// [`crate::ViolationKind::LockOrderInversion`] before the thread blocks.
+/// Unless [`crate::set_violation_action`] says otherwise, that's a panic.
+///
+/// This is for code not yet converted to [`crate::DeadlockProofMutex`]:
+/// start with dynamically checked mutices, then move them over to
+/// permissions one at a time, hot paths first. The identifier `I` plays
+/// the same part as for a deadlock-proof mutex, so it carries over, and
+/// names the mutex in violations. The graph is shared with the
+/// `runtime-checks` feature, so with that enabled, orderings between the
+/// two kinds of mutex are checked as well.
+///
+/// Unlike the type system's proof, this only finds orderings which actually
+/// happen, and the graph is never pruned, so every mutex claimed costs a
+/// little memory for the life of the program.
+pub struct DynamicCheckedMutex<T, I>(Mutex<T>, NodeId, PhantomData<I>, ActionOverride);
+
+impl<T, I> DynamicCheckedMutex<T, I> {
+    /// Create a new dynamically checked mutex. The `_identifier` is a type
+    /// unique to this mutex, as for [`crate::DeadlockProofMutex::new`].
+    pub fn new(content: T, identifier: I) -> Self {
+        Self::const_new(content, identifier)
+    }
+
+    /// Create a new dynamically checked mutex in a `const` context, as for
+    /// [`crate::DeadlockProofMutex::const_new`].
+    pub const fn const_new(content: T, identifier: I) -> Self {
+        // An identifier is only a marker, so there's nothing to drop.
+        std::mem::forget(identifier);
+        Self(
+            Mutex::new(content),
+            NodeId::new(),
+            PhantomData,
+            ActionOverride::new(),
+        )
+    }
+
+    /// Acquires this mutex, blocking the current thread until it is able to
+    /// do so, as [`Mutex::lock`] does. First checks that doing so can't
+    /// complete a cycle with the orderings already seen, given the
+    /// dynamically checked mutices this thread holds.
+    pub fn lock(&self) -> LockResult<DynamicCheckedMutexGuard<'_, T>> {
+        runtime_checks::before_lock::<I>(&self.1, self.3.get());
+        map_lock_result(self.0.lock(), |guard| self.guard(guard))
+    }
+
+    /// Attempts to acquire this mutex without blocking, as
+    /// [`Mutex::try_lock`] does. This can't deadlock, so the ordering isn't
+    /// checked, but the mutex is still recorded as held, so that orderings
+    /// with those claimed while it's held are.
+    pub fn try_lock(&self) -> TryLockResult<DynamicCheckedMutexGuard<'_, T>> {
+        match self.0.try_lock() {
+            Ok(guard) => Ok(self.guard(guard)),
+            Err(TryLockError::Poisoned(e)) => Err(TryLockError::Poisoned(PoisonError::new(
+                self.guard(e.into_inner()),
+            ))),
+            Err(TryLockError::WouldBlock) => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Records the mutex as held by this thread, and wraps its guard.
+    fn guard<'a>(&'a self, guard: MutexGuard<'a, T>) -> DynamicCheckedMutexGuard<'a, T> {
+        let key = waiters::key(&self.0);
+        runtime_checks::claimed(key, &self.1);
+        DynamicCheckedMutexGuard(guard, DynamicHold(key))
+    }
+
+    /// Sets what happens when claiming this mutex would invert an ordering
+    /// already seen, overriding [`crate::set_violation_action`].
+    pub fn set_violation_action(&self, action: ViolationAction) {
+        self.3.set(action)
+    }
+
+    /// Determines whether the mutex is poisoned, as [`Mutex::is_poisoned`]
+    /// does.
+    pub fn is_poisoned(&self) -> bool {
+        self.0.is_poisoned()
+    }
+
+    /// Returns a mutable reference to the contents. No locking is needed,
+    /// since the mutable borrow proves nothing else can be using it.
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.0.get_mut()
+    }
+
+    /// Consumes the mutex, returning its contents.
+    pub fn into_inner(self) -> LockResult<T> {
+        self.0.into_inner()
+    }
+}
+
+impl<T: fmt::Debug, I> fmt::Debug for DynamicCheckedMutex<T, I> {
+    /// Shows the contents, unless the mutex is locked, as [`Mutex`] does.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("DynamicCheckedMutex");
+        d.field("identifier", &IdentifierName::of::<I>());
+        match self.0.try_lock() {
+            Ok(guard) => d.field("data", &&*guard).field("poisoned", &false),
+            Err(TryLockError::Poisoned(e)) => {
+                d.field("data", &&**e.get_ref()).field("poisoned", &true)
+            }
+            Err(TryLockError::WouldBlock) => d.field("data", &format_args!("<locked>")),
+        };
+        d.finish_non_exhaustive()
+    }
+}
+
+/// Stored within each [`DynamicCheckedMutexGuard`], after the underlying
+/// guard, and records the mutex as released once that has been dropped.
+struct DynamicHold(usize);
+
+impl Drop for DynamicHold {
+    fn drop(&mut self) {
+        runtime_checks::released(self.0);
+    }
+}
+
+/// A guard for a [`DynamicCheckedMutex`]. The mutex is released when this
+/// is dropped.
+pub struct DynamicCheckedMutexGuard<'a, T>(
+    MutexGuard<'a, T>,
+    #[allow(dead_code)] // only needed for its drop behaviour
+    DynamicHold,
+);
+
+impl<T> Deref for DynamicCheckedMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.deref()
+    }
+}
+
+impl<T> DerefMut for DynamicCheckedMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.deref_mut()
+    }
+}
@@ -0,0 +1,255 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Mutices created at runtime, each ordered by a number handed out when it
+//! was made rather than by a type, so that a thread can hold several of
+//! them so long as it claims them in increasing order.
+
+use std::{
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LockResult, Mutex, MutexGuard,
+    },
+};
+
+use crate::{map_lock_result, HeldLock, Monitor, MutexPermission, PermissionPhantom};
+
+static NEXT_ORDER: AtomicU64 = AtomicU64::new(0);
+
+/// The identifier of a [`DeadlockProofDynamicMutex`]: a place in a single
+/// global order, later for every identifier created, so that mutices can be
+/// made at runtime, such as one per connection, without a type for each.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DynamicIdentifier(u64);
+
+impl DynamicIdentifier {
+    /// Creates an identifier ordered after every one created before it.
+    pub fn new() -> Self {
+        Self(NEXT_ORDER.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// This identifier's place in the global order.
+    pub fn order(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Default for DynamicIdentifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A mutex identified by a [`DynamicIdentifier`] rather than a type. All
+/// the dynamic mutices with the same permission type `P` take their place
+/// in the lock ordering together, just as a single
+/// [`crate::DeadlockProofMutex`] would. A thread may claim one at a time
+/// using [`DeadlockProofDynamicMutex::lock`], or several in strictly
+/// increasing order of their identifiers using
+/// [`DeadlockProofDynamicGuards`], which checks the order at runtime.
+///
+/// This suits mutices whose number isn't known until runtime and which
+/// don't live together in one collection; for those which do,
+/// [`crate::DeadlockProofMutexSlab`] is simpler.
+pub struct DeadlockProofDynamicMutex<T, P: MutexPermission>(
+    Mutex<T>,
+    DynamicIdentifier,
+    PermissionPhantom<P>,
+    Monitor,
+);
+
+impl<T, P: MutexPermission> DeadlockProofDynamicMutex<T, P> {
+    /// Create a new dynamic mutex, ordered by `identifier`.
+    pub fn new(content: T, identifier: DynamicIdentifier) -> Self {
+        Self(Mutex::new(content), identifier, PhantomData, Monitor::new())
+    }
+
+    /// The identifier giving this mutex's place in the order.
+    pub fn identifier(&self) -> &DynamicIdentifier {
+        &self.1
+    }
+
+    /// Claims the underlying mutex for a thread with a permission of type
+    /// `P`.
+    fn lock_raw(&self) -> LockResult<(MutexGuard<'_, T>, HeldLock)> {
+        map_lock_result(
+            self.3.lock::<T, DynamicIdentifier, P::Domain, _>(&self.0),
+            |guard| {
+                (
+                    guard,
+                    self.3.held::<DynamicIdentifier, P::Domain, _>(&self.0),
+                )
+            },
+        )
+    }
+
+    /// Acquires this mutex, blocking the current thread until it is able to
+    /// do so. No other dynamic mutex with the same permission type may be
+    /// claimed while this guard is held. If the mutex is poisoned, the error
+    /// still contains a deadlock-proof guard.
+    pub fn lock(&self, permission: P) -> LockResult<DeadlockProofDynamicMutexGuard<'_, T, P>> {
+        map_lock_result(self.lock_raw(), |(guard, held)| {
+            DeadlockProofDynamicMutexGuard(guard, permission, held)
+        })
+    }
+
+    /// Determines whether the mutex is poisoned, as [`Mutex::is_poisoned`]
+    /// does.
+    pub fn is_poisoned(&self) -> bool {
+        self.0.is_poisoned()
+    }
+
+    /// Returns a mutable reference to the contents. No permission is needed,
+    /// since the mutable borrow proves nothing else can be using it.
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.0.get_mut()
+    }
+
+    /// Consumes the mutex, returning its contents.
+    pub fn into_inner(self) -> LockResult<T> {
+        self.0.into_inner()
+    }
+}
+
+/// A guard for a single [`DeadlockProofDynamicMutex`], obtained from
+/// [`DeadlockProofDynamicMutex::lock`].
+pub struct DeadlockProofDynamicMutexGuard<'a, T, P: MutexPermission>(
+    MutexGuard<'a, T>,
+    P,
+    #[allow(dead_code)] // only needed for its drop behaviour
+    HeldLock,
+);
+
+impl<T, P: MutexPermission> DeadlockProofDynamicMutexGuard<'_, T, P> {
+    /// Unlock the mutex. Returns the mutex permission token such that you
+    /// can use it again to claim a different mutex.
+    pub fn unlock(self) -> P {
+        self.1
+    }
+}
+
+impl<T, P: MutexPermission> Deref for DeadlockProofDynamicMutexGuard<'_, T, P> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.deref()
+    }
+}
+
+impl<T, P: MutexPermission> DerefMut for DeadlockProofDynamicMutexGuard<'_, T, P> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.deref_mut()
+    }
+}
+
+/// Errors from [`DeadlockProofDynamicGuards::lock`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum DynamicLockError {
+    /// The requested mutex isn't ordered after all the mutices already
+    /// held, so claiming it could deadlock. Nothing was claimed.
+    OutOfOrder {
+        /// The order of the mutex which was requested.
+        requested: u64,
+        /// The highest order of the mutices already held.
+        highest_held: u64,
+    },
+    /// The mutex was claimed, but another thread panicked while holding it.
+    /// The mutex is now held and its contents can be accessed anyway.
+    Poisoned,
+}
+
+impl fmt::Display for DynamicLockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfOrder {
+                requested,
+                highest_held,
+            } => write!(
+                f,
+                "dynamic mutex {requested} requested while already holding dynamic mutex {highest_held}"
+            ),
+            Self::Poisoned => write!(f, "poisoned lock: another task failed inside"),
+        }
+    }
+}
+
+impl std::error::Error for DynamicLockError {}
+
+/// A set of [`DeadlockProofDynamicMutex`]es held by this thread, all using
+/// the one permission. Mutices can only be added in strictly increasing
+/// order of their identifiers.
+pub struct DeadlockProofDynamicGuards<'a, T, P: MutexPermission> {
+    permission: P,
+    guards: Vec<(u64, MutexGuard<'a, T>, HeldLock)>,
+}
+
+impl<'a, T, P: MutexPermission> DeadlockProofDynamicGuards<'a, T, P> {
+    /// Starts claiming several dynamic mutices at once, using `permission`.
+    /// Initially no mutices are held; add them using
+    /// [`DeadlockProofDynamicGuards::lock`].
+    pub fn new(permission: P) -> Self {
+        Self {
+            permission,
+            guards: Vec::new(),
+        }
+    }
+
+    /// Acquires `mutex`, blocking until it's able to do so, and returns
+    /// access to its contents. Its identifier must be ordered after that of
+    /// every mutex already held.
+    pub fn lock(
+        &mut self,
+        mutex: &'a DeadlockProofDynamicMutex<T, P>,
+    ) -> Result<&mut T, DynamicLockError> {
+        let requested = mutex.1.order();
+        if let Some(&(highest_held, ..)) = self.guards.last() {
+            if requested <= highest_held {
+                return Err(DynamicLockError::OutOfOrder {
+                    requested,
+                    highest_held,
+                });
+            }
+        }
+        let ((guard, held), poisoned) = match mutex.lock_raw() {
+            Ok(claimed) => (claimed, false),
+            Err(e) => (e.into_inner(), true),
+        };
+        self.guards.push((requested, guard, held));
+        let data = &mut *self.guards.last_mut().unwrap().1;
+        if poisoned {
+            Err(DynamicLockError::Poisoned)
+        } else {
+            Ok(data)
+        }
+    }
+
+    /// Access the contents of a held mutex, or `None` if `mutex` isn't held.
+    pub fn get(&self, mutex: &DeadlockProofDynamicMutex<T, P>) -> Option<&T> {
+        self.guards
+            .binary_search_by_key(&mutex.1.order(), |(order, ..)| *order)
+            .ok()
+            .map(|pos| &*self.guards[pos].1)
+    }
+
+    /// Mutably access the contents of a held mutex, or `None` if `mutex`
+    /// isn't held.
+    pub fn get_mut(&mut self, mutex: &DeadlockProofDynamicMutex<T, P>) -> Option<&mut T> {
+        self.guards
+            .binary_search_by_key(&mutex.1.order(), |(order, ..)| *order)
+            .ok()
+            .map(|pos| &mut *self.guards[pos].1)
+    }
+
+    /// Unlock all the held mutices, returning the mutex permission token.
+    pub fn unlock(self) -> P {
+        self.permission
+    }
+}
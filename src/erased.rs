@@ -0,0 +1,98 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{marker::PhantomData, rc::Rc};
+
+#[cfg(feature = "runtime-checks")]
+use crate::violation::{report_violation, Violation, ViolationKind};
+use crate::{DefaultDomain, MutexPermission};
+
+#[cfg(feature = "runtime-checks")]
+token_thread_local! {
+    /// The levels of the erased permissions alive in this thread, in the
+    /// order they were created.
+    static LIVE_LEVELS: std::cell::RefCell<Vec<u32>> = std::cell::RefCell::new(Vec::new());
+}
+
+/// A permission whose place in the lock ordering is a runtime level rather
+/// than a type, for code compiled separately from the rest of the program,
+/// such as a dynamically loaded plugin, where the permission types of the
+/// host's mutices can't be named. A plugin declares its mutices as
+/// `DeadlockProofMutex<T, ErasedPermission, I>`, and the host hands it an
+/// erased permission at the level it has reached whenever it calls in.
+///
+/// Nothing about erased permissions is checked by the type system. Each
+/// mutex claimed using one must always be claimed at the same level, and
+/// mutices must be nested in increasing order of level. With the
+/// `runtime-checks` feature, creating an erased permission whose level
+/// isn't above that of every other erased permission alive in the thread
+/// is reported as a [`crate::Violation`], and the orders in which the
+/// mutices themselves are claimed are checked as for any other mutex.
+pub struct ErasedPermission(u32, PhantomData<Rc<()>>);
+
+impl ErasedPermission {
+    /// Creates an erased permission at the given level.
+    ///
+    /// # Safety
+    ///
+    /// The permission stands in for one this thread really holds: the
+    /// caller must ensure that the permission it replaces isn't used until
+    /// this one has been dropped, for instance by keeping the host's
+    /// permission borrowed for the duration of a call into a plugin.
+    /// `level` must be above that of every mutex this thread holds which was
+    /// claimed using an erased permission, and every mutex claimed using
+    /// erased permissions must always be claimed at the same level, in every
+    /// thread.
+    pub unsafe fn new(level: u32) -> Self {
+        #[cfg(feature = "runtime-checks")]
+        {
+            let out_of_order = LIVE_LEVELS.with(|levels| {
+                let mut levels = levels.borrow_mut();
+                let out_of_order = levels.iter().any(|&live| live >= level);
+                levels.push(level);
+                out_of_order
+            });
+            if out_of_order {
+                report_violation(
+                    Violation {
+                        kind: ViolationKind::ErasedLevelOrder,
+                        // Only leaked when reporting a bug.
+                        identifiers: vec![Box::leak(format!("level {level}").into_boxed_str())],
+                        backtrace: None,
+                    },
+                    None,
+                );
+            }
+        }
+        Self(level, PhantomData)
+    }
+
+    /// The level of this permission.
+    pub fn level(&self) -> u32 {
+        self.0
+    }
+}
+
+#[cfg(feature = "runtime-checks")]
+impl Drop for ErasedPermission {
+    fn drop(&mut self) {
+        let level = self.0;
+        let _ = LIVE_LEVELS.try_with(|levels| {
+            let mut levels = levels.borrow_mut();
+            if let Some(pos) = levels.iter().rposition(|&live| live == level) {
+                levels.remove(pos);
+            }
+        });
+    }
+}
+
+impl MutexPermission for ErasedPermission {
+    type Domain = DefaultDomain;
+    // The level is only known at runtime.
+    const DEPTH: usize = 0;
+}
@@ -0,0 +1,129 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{fmt, sync::PoisonError};
+
+use crate::{
+    DeadlockProofMutexGuard, DeadlockProofTryLockError, DeadlockProofTryNestedLockError,
+    MutexBackend, MutexPermission, NestedLock, TryLockUntilError,
+};
+
+/// Any failure to claim a deadlock-proof mutex, as a single error type
+/// implementing [`std::error::Error`], so that claiming a mutex composes
+/// with `?` and error handling crates rather than exposing
+/// [`PoisonError`] and the guard within it.
+///
+/// Each of the errors returned when claiming a mutex converts into this,
+/// releasing the mutex if it was claimed anyway, and keeping the permission
+/// so that it can be used again. Permissions are neither `Send` nor `Sync`,
+/// so to hand the error to something requiring those, such as
+/// `anyhow::Error`, first drop the permission using
+/// [`DeadlockProofError::without_permission`].
+pub enum DeadlockProofError<P = ()> {
+    /// Another thread panicked while holding the mutex.
+    Poisoned(P),
+    /// The mutex is held by another thread, and the caller asked not to
+    /// wait.
+    WouldBlock(P),
+    /// The mutex was still held by another thread when the deadline passed.
+    TimedOut(P),
+}
+
+impl<P> DeadlockProofError<P> {
+    /// Returns the permission, such that you can use it again to claim a
+    /// different mutex.
+    pub fn into_permission(self) -> P {
+        match self {
+            Self::Poisoned(permission)
+            | Self::WouldBlock(permission)
+            | Self::TimedOut(permission) => permission,
+        }
+    }
+
+    /// Drops the permission, keeping only what went wrong.
+    pub fn without_permission(self) -> DeadlockProofError {
+        match self {
+            Self::Poisoned(_) => DeadlockProofError::Poisoned(()),
+            Self::WouldBlock(_) => DeadlockProofError::WouldBlock(()),
+            Self::TimedOut(_) => DeadlockProofError::TimedOut(()),
+        }
+    }
+}
+
+impl<P> fmt::Debug for DeadlockProofError<P> {
+    /// Shows only what went wrong, since permissions have nothing to show.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Poisoned(_) => "Poisoned",
+            Self::WouldBlock(_) => "WouldBlock",
+            Self::TimedOut(_) => "TimedOut",
+        })
+    }
+}
+
+impl<P> fmt::Display for DeadlockProofError<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Poisoned(_) => "poisoned lock: another task failed inside",
+            Self::WouldBlock(_) => "try_lock failed because the operation would block",
+            Self::TimedOut(_) => "timed out waiting for the lock",
+        })
+    }
+}
+
+impl<P> std::error::Error for DeadlockProofError<P> {}
+
+impl<'a, T, P: MutexPermission, I, M: MutexBackend<T> + 'a>
+    From<PoisonError<DeadlockProofMutexGuard<'a, T, P, I, M>>> for DeadlockProofError<P>
+{
+    fn from(e: PoisonError<DeadlockProofMutexGuard<'a, T, P, I, M>>) -> Self {
+        Self::Poisoned(e.into_inner().unlock())
+    }
+}
+
+impl<'a, T, P: MutexPermission, I, M: MutexBackend<T> + 'a>
+    From<PoisonError<NestedLock<'a, T, P, I, M>>> for DeadlockProofError<P>
+{
+    fn from(e: PoisonError<NestedLock<'a, T, P, I, M>>) -> Self {
+        let (guard, token) = e.into_inner();
+        Self::Poisoned(guard.unlock(token))
+    }
+}
+
+impl<'a, T, P: MutexPermission, I, M: MutexBackend<T> + 'a>
+    From<DeadlockProofTryLockError<'a, T, P, I, M>> for DeadlockProofError<P>
+{
+    fn from(e: DeadlockProofTryLockError<'a, T, P, I, M>) -> Self {
+        match e {
+            DeadlockProofTryLockError::WouldBlock(permission) => Self::WouldBlock(permission),
+            DeadlockProofTryLockError::Poisoned(e) => e.into(),
+        }
+    }
+}
+
+impl<'a, T, P: MutexPermission, I, M: MutexBackend<T> + 'a>
+    From<DeadlockProofTryNestedLockError<'a, T, P, I, M>> for DeadlockProofError<P>
+{
+    fn from(e: DeadlockProofTryNestedLockError<'a, T, P, I, M>) -> Self {
+        match e {
+            DeadlockProofTryNestedLockError::WouldBlock(permission) => Self::WouldBlock(permission),
+            DeadlockProofTryNestedLockError::Poisoned(e) => e.into(),
+        }
+    }
+}
+
+impl<'a, T, P: MutexPermission, I, M: MutexBackend<T> + 'a> From<TryLockUntilError<'a, T, P, I, M>>
+    for DeadlockProofError<P>
+{
+    fn from(e: TryLockUntilError<'a, T, P, I, M>) -> Self {
+        match e {
+            TryLockUntilError::DeadlineMissed(permission) => Self::TimedOut(permission),
+            TryLockUntilError::Poisoned(e) => e.into(),
+        }
+    }
+}
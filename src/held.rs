@@ -0,0 +1,313 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Bookkeeping done for each guard while it's alive. Unless one of the
+//! features needing it is enabled, this only consists of waking any threads
+//! waiting for the mutex in [`crate::DeadlockProofMutex::lock_when`] once
+//! the guard is released. Without the `std` feature, it's nothing at all.
+
+#[cfg(feature = "await-check")]
+use std::cell::{Cell, RefCell};
+
+use crate::PermissionDomain;
+#[cfg(feature = "std")]
+use crate::{domain::DomainHold, waiters};
+
+#[cfg(feature = "await-check")]
+thread_local! {
+    static NEXT_ID: Cell<u64> = const { Cell::new(0) };
+    static HELD: RefCell<Vec<(u64, &'static str)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The records of several locks claimed together, for guards covering a
+/// group of locks of different types.
+#[doc(hidden)]
+#[cfg(feature = "std")]
+pub struct HeldLocks(
+    #[allow(dead_code)] // only needed for its drop behaviour
+    pub(crate)  Vec<HeldLock>,
+);
+
+/// What [`HeldLock::arm`] records: the identifier's name, where the guard
+/// was created, and the mutex's own [`crate::ViolationAction`], if any.
+#[cfg(all(feature = "leak-check", debug_assertions))]
+type LeakCheck = (
+    &'static str,
+    std::backtrace::Backtrace,
+    Option<crate::ViolationAction>,
+);
+
+/// Stored within each guard, after the underlying guard such that it's
+/// dropped only once the mutex has been released. Records the guard as held
+/// by the current thread when created, and as released when dropped.
+pub(crate) struct HeldLock {
+    /// Identifies the underlying mutex to [`waiters`].
+    #[cfg(feature = "std")]
+    key: usize,
+    /// Records the hold for the checks between permission domains.
+    #[cfg(feature = "std")]
+    _domain: DomainHold,
+    #[cfg(feature = "await-check")]
+    id: u64,
+    /// The identifier's name and when the lock was claimed.
+    #[cfg(feature = "tracing")]
+    traced: (crate::IdentifierName, std::time::Instant),
+    /// The identifier's name, where the guard was created, and how the
+    /// mutex says to report a leak if not as configured globally, until
+    /// it's unlocked, if it must be unlocked rather than dropped.
+    #[cfg(all(feature = "leak-check", debug_assertions))]
+    leak_check: Option<Box<LeakCheck>>,
+    /// Times the hold for the mutex's statistics, if it's a deadlock-proof
+    /// mutex, recording it when dropped.
+    #[cfg(feature = "stats")]
+    hold_timer: Option<crate::stats::HoldTimer>,
+}
+
+impl HeldLock {
+    /// Records that the current thread has claimed `mutex`, identified
+    /// by `I`, using a permission from domain `D`.
+    #[cfg_attr(
+        not(any(feature = "await-check", feature = "tracing")),
+        allow(clippy::extra_unused_type_parameters)
+    )]
+    #[cfg_attr(not(feature = "std"), allow(unused_variables))]
+    pub(crate) fn new<I: ?Sized, D: PermissionDomain, M: ?Sized>(mutex: &M) -> Self {
+        #[cfg(feature = "std")]
+        let key = waiters::key(mutex);
+        #[cfg(feature = "std")]
+        let _domain = DomainHold::new::<D>();
+        #[cfg(feature = "await-check")]
+        let id = {
+            let id = NEXT_ID.with(|next| next.replace(next.get() + 1));
+            HELD.with(|held| held.borrow_mut().push((id, std::any::type_name::<I>())));
+            id
+        };
+        #[cfg(feature = "tracing")]
+        let traced = {
+            let identifier = crate::IdentifierName::of::<I>();
+            tracing::trace!(%identifier, "deadlock-proof lock claimed");
+            (identifier, std::time::Instant::now())
+        };
+        Self {
+            #[cfg(feature = "std")]
+            key,
+            #[cfg(feature = "std")]
+            _domain,
+            #[cfg(feature = "await-check")]
+            id,
+            #[cfg(feature = "tracing")]
+            traced,
+            #[cfg(all(feature = "leak-check", debug_assertions))]
+            leak_check: None,
+            #[cfg(feature = "stats")]
+            hold_timer: None,
+        }
+    }
+
+    /// Requires the guard to be unlocked rather than dropped, reporting a
+    /// [`crate::ViolationKind::GuardDropped`] otherwise. Does nothing
+    /// without the `leak-check` feature in a debug build. Only armed for
+    /// the guards listed there, each of whose unlocking paths disarms it.
+    #[cfg_attr(
+        not(all(feature = "leak-check", debug_assertions)),
+        allow(clippy::extra_unused_type_parameters)
+    )]
+    pub(crate) fn arm<I: ?Sized>(&mut self) {
+        #[cfg(all(feature = "leak-check", debug_assertions))]
+        {
+            self.leak_check = Some(Box::new((
+                std::any::type_name::<I>(),
+                std::backtrace::Backtrace::force_capture(),
+                None,
+            )));
+        }
+    }
+
+    /// Reports a leaked guard with `action`, if given, rather than as
+    /// configured globally. Only meaningful once armed.
+    #[cfg(all(feature = "leak-check", debug_assertions))]
+    pub(crate) fn report_with(&mut self, action: Option<crate::ViolationAction>) {
+        if let Some(check) = &mut self.leak_check {
+            check.2 = action;
+        }
+    }
+
+    /// Times the hold, recording it in the mutex's statistics once released.
+    #[cfg(feature = "stats")]
+    pub(crate) fn time_hold(&mut self, timer: crate::stats::HoldTimer) {
+        self.hold_timer = Some(timer);
+    }
+
+    /// Records that the guard is being unlocked, as it should be.
+    pub(crate) fn disarm(&mut self) {
+        #[cfg(all(feature = "leak-check", debug_assertions))]
+        {
+            self.leak_check = None;
+        }
+    }
+}
+
+impl HeldLock {
+    /// Identifies the underlying mutex, as [`waiters::key`] does.
+    #[cfg(feature = "std")]
+    pub(crate) fn key(&self) -> usize {
+        self.key
+    }
+
+    /// Wakes any threads waiting for the mutex's contents to change. For use
+    /// when the mutex is about to be released temporarily, such as while
+    /// waiting on a condvar. Without the `std` feature nothing can wait.
+    #[cfg(any(feature = "std", feature = "lock_api"))]
+    #[cfg_attr(not(feature = "std"), allow(clippy::unused_self))]
+    pub(crate) fn notify_waiters(&self) {
+        #[cfg(feature = "std")]
+        waiters::notify(self.key);
+    }
+}
+
+impl Drop for HeldLock {
+    fn drop(&mut self) {
+        // Guards may be released in any order, so search for ours. If the
+        // thread-local has already gone we're in thread teardown and there's
+        // nothing left to keep track of.
+        #[cfg(feature = "await-check")]
+        let _ = HELD.try_with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(pos) = held.iter().rposition(|(id, _)| *id == self.id) {
+                held.remove(pos);
+            }
+        });
+        #[cfg(feature = "runtime-checks")]
+        crate::runtime_checks::released(self.key);
+        #[cfg(feature = "diagnostics")]
+        crate::diagnostics::released(self.key);
+        #[cfg(feature = "observer")]
+        crate::observer::released(self.key);
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            identifier = %self.traced.0,
+            held_micros = self.traced.1.elapsed().as_micros() as u64,
+            "deadlock-proof lock released"
+        );
+        #[cfg(feature = "std")]
+        waiters::notify(self.key);
+        // Last, since the violation may panic. A guard dropped while
+        // panicking is the point of dropping guards at all.
+        #[cfg(all(feature = "leak-check", debug_assertions))]
+        if let Some(created) = self.leak_check.take() {
+            let (identifier, backtrace, action) = *created;
+            if !std::thread::panicking() {
+                crate::violation::report_violation(
+                    crate::Violation {
+                        kind: crate::ViolationKind::GuardDropped,
+                        identifiers: vec![identifier],
+                        backtrace: Some(backtrace.into()),
+                    },
+                    action,
+                );
+            }
+        }
+    }
+}
+
+/// A marker for the current point in this thread's sequence of claims, for
+/// use with [`held_since`].
+#[cfg(feature = "await-check")]
+pub(crate) fn checkpoint() -> u64 {
+    NEXT_ID.with(|next| next.get())
+}
+
+/// The identifiers of guards claimed by this thread after the given
+/// [`checkpoint`] and not yet released.
+#[cfg(feature = "await-check")]
+pub(crate) fn held_since(checkpoint: u64) -> Vec<&'static str> {
+    HELD.with(|held| {
+        held.borrow()
+            .iter()
+            .filter(|(id, _)| *id >= checkpoint)
+            .map(|(_, name)| *name)
+            .collect()
+    })
+}
+
+#[cfg(all(test, feature = "leak-check", debug_assertions))]
+mod tests {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use crate::{DeadlockProofMutex, OuterMutexPermission};
+
+    struct Counter;
+    struct Total;
+
+    /// Whether `f` reported a dropped guard, with the default action of
+    /// panicking.
+    fn reports_a_leak(f: impl FnOnce()) -> bool {
+        match catch_unwind(AssertUnwindSafe(f)) {
+            Ok(()) => false,
+            Err(payload) => payload
+                .downcast_ref::<String>()
+                .is_some_and(|message| message.contains("dropped rather than unlocked")),
+        }
+    }
+
+    #[test]
+    fn dropped_guard_is_reported() {
+        let counter: DeadlockProofMutex<u32, OuterMutexPermission, Counter> =
+            DeadlockProofMutex::new(0, Counter);
+        assert!(reports_a_leak(|| drop(
+            counter.lock(OuterMutexPermission::get()).unwrap()
+        )));
+        assert!(reports_a_leak(|| drop(
+            counter.try_lock(OuterMutexPermission::get()).ok().unwrap()
+        )));
+        // The permission went back to the thread along with the guard.
+        assert!(OuterMutexPermission::is_available());
+    }
+
+    #[test]
+    fn dropped_nested_guard_is_reported() {
+        let counter: DeadlockProofMutex<u32, OuterMutexPermission, Counter> =
+            DeadlockProofMutex::new(0, Counter);
+        assert!(reports_a_leak(|| {
+            let (guard, _nested) = counter
+                .lock_for_nested(OuterMutexPermission::get())
+                .unwrap();
+            drop(guard);
+        }));
+    }
+
+    #[test]
+    fn unlocked_and_borrowing_guards_are_not_reported() {
+        let counter: DeadlockProofMutex<u32, OuterMutexPermission, Counter> =
+            DeadlockProofMutex::new(0, Counter);
+        let total: DeadlockProofMutex<u32, OuterMutexPermission, Total> =
+            DeadlockProofMutex::new(0, Total);
+        assert!(!reports_a_leak(|| {
+            let permission = counter.lock(OuterMutexPermission::get()).unwrap().unlock();
+            let (guard, nested) = total.lock_for_nested(permission).unwrap();
+            let mut permission = guard.unlock(nested);
+            // Dropping this loses nothing, since the permission is only lent.
+            drop(counter.lock_borrowing(&mut permission).unwrap());
+        }));
+    }
+
+    #[test]
+    fn guard_dropped_while_panicking_is_not_reported() {
+        let counter: DeadlockProofMutex<u32, OuterMutexPermission, Counter> =
+            DeadlockProofMutex::new(0, Counter);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            let _guard = counter.lock(OuterMutexPermission::get()).unwrap();
+            panic!("job failed");
+        }));
+        assert_eq!(
+            *result.unwrap_err().downcast::<&str>().unwrap(),
+            "job failed"
+        );
+        counter.clear_poison();
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{marker::PhantomData, sync::Mutex};
+
+use crate::{
+    DeadlockProofMutex, MutexBackend, MutexPermission, NestedMutexPermission, OuterMutexPermission,
+};
+
+/// Declares a whole hierarchy of nested mutices in one place:
+///
+/// `let (config, cache) = LockHierarchy::new().level(Config).level(Cache).build();`
+///
+/// Each level is identified by a type, just as a single mutex is, and the
+/// result is a [`LockLevel`] for each, from outermost to innermost. Mutices
+/// created from a level take the permission type of that level, so a
+/// `Cache` mutex can only be claimed using the permission from
+/// [`DeadlockProofMutex::lock_for_nested`] on a `Config` mutex, and the
+/// relationships between the identifiers are written down here rather than
+/// implied by whichever `lock_for_nested` calls happen to exist.
+///
+/// Up to twelve levels can be declared.
+pub struct LockHierarchy<P, L>(PhantomData<fn(P)>, L);
+
+impl LockHierarchy<OuterMutexPermission, ()> {
+    /// Begins a hierarchy whose outermost level is claimed using an
+    /// [`OuterMutexPermission`].
+    pub fn new() -> Self {
+        Self(PhantomData, ())
+    }
+}
+
+impl Default for LockHierarchy<OuterMutexPermission, ()> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: MutexPermission, L> LockHierarchy<P, L> {
+    /// Adds a level identified by `I`, nested within all those added so far.
+    /// Every mutex created at the level shares the identifier, which is
+    /// consumed here just as [`DeadlockProofMutex::new`] consumes one.
+    pub fn level<I>(
+        self,
+        _identifier: I,
+    ) -> LockHierarchy<NestedMutexPermission<P, I>, (L, LockLevel<P, I>)> {
+        LockHierarchy(PhantomData, (self.1, LockLevel(PhantomData)))
+    }
+
+    /// Finishes the hierarchy, returning a tuple of a [`LockLevel`] for
+    /// each level, from outermost to innermost.
+    pub fn build(self) -> L::Handles
+    where
+        L: HierarchyLevels,
+    {
+        self.1.into_handles()
+    }
+}
+
+/// The levels declared so far in a [`LockHierarchy`], which can be turned
+/// into a flat tuple of their [`LockLevel`]s.
+pub trait HierarchyLevels {
+    /// A tuple of the levels, from outermost to innermost.
+    type Handles;
+
+    /// Flattens the levels into their tuple.
+    fn into_handles(self) -> Self::Handles;
+}
+
+/// Nests `A B C` as `((((), A), B), C)`, in either a type or a pattern.
+macro_rules! nest {
+    ([$($acc:tt)*]) => {
+        $($acc)*
+    };
+    ([$($acc:tt)*] $head:ident $($rest:ident)*) => {
+        nest!([($($acc)*, $head)] $($rest)*)
+    };
+}
+
+macro_rules! impl_hierarchy_levels {
+    ($($level:ident)*) => {
+        impl<$($level),*> HierarchyLevels for nest!([()] $($level)*) {
+            type Handles = ($($level,)*);
+
+            #[allow(non_snake_case)]
+            fn into_handles(self) -> Self::Handles {
+                let nest!([()] $($level)*) = self;
+                ($($level,)*)
+            }
+        }
+    };
+}
+
+impl_hierarchy_levels!(A);
+impl_hierarchy_levels!(A B);
+impl_hierarchy_levels!(A B C);
+impl_hierarchy_levels!(A B C D);
+impl_hierarchy_levels!(A B C D E);
+impl_hierarchy_levels!(A B C D E F);
+impl_hierarchy_levels!(A B C D E F G);
+impl_hierarchy_levels!(A B C D E F G H);
+impl_hierarchy_levels!(A B C D E F G H J);
+impl_hierarchy_levels!(A B C D E F G H J K);
+impl_hierarchy_levels!(A B C D E F G H J K L);
+impl_hierarchy_levels!(A B C D E F G H J K L M);
+
+/// One level of a [`LockHierarchy`], identified by `I` and claimed using
+/// the permission type `P`. Use it to create the mutices at that level.
+pub struct LockLevel<P, I>(PhantomData<fn(P, I)>);
+
+impl<P: MutexPermission, I> LockLevel<P, I> {
+    /// Create a new deadlock-proof mutex at this level, containing
+    /// `content`.
+    pub fn mutex<T>(&self, content: T) -> DeadlockProofMutex<T, P, I> {
+        DeadlockProofMutex::unidentified(Mutex::new(content))
+    }
+
+    /// Create a new deadlock-proof mutex at this level using a different
+    /// underlying mutex, as for [`DeadlockProofMutex::from_backend`].
+    pub fn mutex_from_backend<T, M: MutexBackend<T>>(
+        &self,
+        mutex: M,
+    ) -> DeadlockProofMutex<T, P, I, M> {
+        DeadlockProofMutex::unidentified(mutex)
+    }
+}
+
+impl<P, I> Clone for LockLevel<P, I> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<P, I> Copy for LockLevel<P, I> {}
@@ -0,0 +1,80 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Human-readable names for identifier types, for messages and logs, and
+//! the means of making an identifier which only one mutex can have.
+
+use core::fmt;
+
+/// Displays the type name of an identifier without the paths of the
+/// modules it and any of its generic arguments are declared in, so
+/// `my_crate::db::Locks<my_crate::db::Users>` becomes `Locks<Users>`. A
+/// closure, such as one from [`crate::unique_type`], keeps the name of the
+/// function it was declared in, as in `main::{{closure}}`.
+#[derive(Clone, Copy)]
+pub(crate) struct IdentifierName(pub(crate) &'static str);
+
+impl IdentifierName {
+    pub(crate) fn of<I: ?Sized>() -> Self {
+        Self(core::any::type_name::<I>())
+    }
+}
+
+/// The part of `path` worth showing: its last segment, along with the
+/// closest enclosing named segment if that's a closure or similar.
+fn short_path(path: &str) -> &str {
+    let mut end = path.len();
+    for (pos, _) in path.rmatch_indices("::") {
+        if pos == 0 {
+            break;
+        }
+        if !path[pos + 2..end].starts_with("{{") {
+            return &path[pos + 2..];
+        }
+        end = pos;
+    }
+    path
+}
+
+impl fmt::Display for IdentifierName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rest = self.0;
+        while let Some(pos) = rest.find(|c: char| "<>,;()[]&* ".contains(c)) {
+            f.write_str(short_path(&rest[..pos]))?;
+            f.write_str(&rest[pos..pos + 1])?;
+            rest = &rest[pos + 1..];
+        }
+        f.write_str(short_path(rest))
+    }
+}
+
+/// The private part of an identifier declared by
+/// [`crate::declare_static_deadlock_proof_mutex`] or
+/// [`crate::declare_lazy_deadlock_proof_mutex`], which stops anything but
+/// the macro creating a value of it. Every way of creating a mutex consumes
+/// an identifier value, so the macro's mutex is then the only one with that
+/// identifier, and creating another fails to compile.
+#[doc(hidden)]
+pub struct Singleton(());
+
+impl Singleton {
+    /// # Safety
+    ///
+    /// Only for use by the mutex declaration macros, each of which calls it
+    /// once, for the value consumed by the mutex it declares.
+    #[doc(hidden)]
+    pub const unsafe fn new() -> Self {
+        Self(())
+    }
+}
+
+impl fmt::Debug for IdentifierName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
@@ -0,0 +1,405 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    cell::UnsafeCell,
+    ffi::CStr,
+    fmt, io,
+    marker::PhantomData,
+    mem::{size_of, MaybeUninit},
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        LockResult, PoisonError, TryLockError, TryLockResult,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::MutexBackend;
+
+/// What lives in the shared memory object, in every process.
+#[repr(C)]
+struct Shared<T> {
+    /// Set once the creator has initialized the rest.
+    ready: AtomicU32,
+    poisoned: AtomicBool,
+    raw: UnsafeCell<libc::pthread_mutex_t>,
+    data: UnsafeCell<T>,
+}
+
+/// A mutex shared between processes: a robust, process-shared POSIX mutex
+/// and its contents, in a named POSIX shared memory object. Each process
+/// opening the same name gets the same mutex.
+///
+/// Use it as the backend of a [`crate::DeadlockProofMutex`], created with
+/// [`crate::DeadlockProofMutex::from_backend`]. The permission tokens still
+/// belong to threads, so they prove each process can't deadlock by itself;
+/// to rule out deadlocks between processes too, every process must give
+/// the shared mutices the same permission types, and so claim them in the
+/// same order.
+///
+/// If a process dies while holding the mutex, the next to claim it
+/// recovers it, and it's poisoned, just as a [`std::sync::Mutex`] is when a
+/// thread panics while holding it.
+pub struct InterprocessMutex<T> {
+    shared: NonNull<Shared<T>>,
+}
+
+// Safety: the mutex gives exclusive access to the data, as for `Mutex`.
+unsafe impl<T: Send> Send for InterprocessMutex<T> {}
+unsafe impl<T: Send> Sync for InterprocessMutex<T> {}
+
+/// Panics if a pthread function failed.
+fn check(result: libc::c_int, operation: &str) {
+    if result != 0 {
+        panic!(
+            "{operation} failed: {}",
+            io::Error::from_raw_os_error(result)
+        );
+    }
+}
+
+/// How long a process opening an existing mutex waits for the process which
+/// created it to finish setting it up, in case that process died first.
+const CREATOR_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Gives the process creating a mutex a chance to set it up, failing once
+/// `deadline` has passed.
+fn wait_for_creator(deadline: Instant) -> io::Result<()> {
+    if Instant::now() >= deadline {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the process creating the interprocess mutex never finished setting it up",
+        ));
+    }
+    std::thread::sleep(Duration::from_millis(1));
+    Ok(())
+}
+
+impl<T: Copy> InterprocessMutex<T> {
+    /// Opens the interprocess mutex with the given name, such as
+    /// `c"/my-app-state"`, creating it containing `content` if no process
+    /// has yet. The shared memory object lasts until removed using
+    /// [`InterprocessMutex::unlink`], even once no process has it open.
+    ///
+    /// If the mutex exists but the process creating it doesn't finish
+    /// setting it up within a few seconds, perhaps because it died, this
+    /// fails with [`io::ErrorKind::TimedOut`].
+    ///
+    /// # Safety
+    ///
+    /// Every process opening the same name must do so with the same type
+    /// `T`, with the same layout, and the contents must mean the same in
+    /// every process: they mustn't contain pointers or references, for
+    /// example. No other process may claim the mutex while this one
+    /// borrows the contents through [`crate::DeadlockProofMutex::get_mut`],
+    /// which can't claim it.
+    pub unsafe fn open(name: &CStr, content: T) -> io::Result<Self> {
+        let size = size_of::<Shared<T>>();
+        let mut fd = libc::shm_open(
+            name.as_ptr(),
+            libc::O_RDWR | libc::O_CREAT | libc::O_EXCL,
+            0o600,
+        );
+        let created = fd >= 0;
+        if !created {
+            if io::Error::last_os_error().raw_os_error() != Some(libc::EEXIST) {
+                return Err(io::Error::last_os_error());
+            }
+            fd = libc::shm_open(name.as_ptr(), libc::O_RDWR, 0);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        let deadline = Instant::now() + CREATOR_TIMEOUT;
+        let result = Self::map(fd, size, created, deadline);
+        libc::close(fd);
+        let shared = Self { shared: result? };
+        if created {
+            Self::init(shared.shared, content);
+        } else {
+            // The creator may not have finished initializing it yet.
+            while shared.shared().ready.load(Ordering::Acquire) == 0 {
+                wait_for_creator(deadline)?;
+            }
+        }
+        Ok(shared)
+    }
+
+    /// Maps the shared memory object, first giving it its size if this
+    /// process created it, or otherwise waiting until its creator has.
+    unsafe fn map(
+        fd: libc::c_int,
+        size: usize,
+        created: bool,
+        deadline: Instant,
+    ) -> io::Result<NonNull<Shared<T>>> {
+        if created {
+            if libc::ftruncate(fd, size as libc::off_t) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        } else {
+            loop {
+                let mut stat = MaybeUninit::<libc::stat>::uninit();
+                if libc::fstat(fd, stat.as_mut_ptr()) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if stat.assume_init().st_size as usize >= size {
+                    break;
+                }
+                wait_for_creator(deadline)?;
+            }
+        }
+        let address = libc::mmap(
+            std::ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        );
+        if address == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(NonNull::new_unchecked(address.cast()))
+    }
+
+    /// Initializes the newly created shared memory, which is all zeros.
+    unsafe fn init(shared: NonNull<Shared<T>>, content: T) {
+        let shared = shared.as_ptr();
+        let mut attr = MaybeUninit::<libc::pthread_mutexattr_t>::uninit();
+        check(
+            libc::pthread_mutexattr_init(attr.as_mut_ptr()),
+            "pthread_mutexattr_init",
+        );
+        check(
+            libc::pthread_mutexattr_setpshared(attr.as_mut_ptr(), libc::PTHREAD_PROCESS_SHARED),
+            "pthread_mutexattr_setpshared",
+        );
+        check(
+            libc::pthread_mutexattr_setrobust(attr.as_mut_ptr(), libc::PTHREAD_MUTEX_ROBUST),
+            "pthread_mutexattr_setrobust",
+        );
+        check(
+            libc::pthread_mutex_init(UnsafeCell::raw_get(&(*shared).raw), attr.as_ptr()),
+            "pthread_mutex_init",
+        );
+        libc::pthread_mutexattr_destroy(attr.as_mut_ptr());
+        UnsafeCell::raw_get(&(*shared).data).write(content);
+        (*shared).ready.store(1, Ordering::Release);
+    }
+
+    /// Removes the shared memory object with the given name, so that the
+    /// next process to open it creates a new one. Processes which already
+    /// have it open carry on using the old one.
+    pub fn unlink(name: &CStr) -> io::Result<()> {
+        // Safety: the name is a valid C string.
+        if unsafe { libc::shm_unlink(name.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl<T> InterprocessMutex<T> {
+    fn shared(&self) -> &Shared<T> {
+        // Safety: the memory stays mapped until this is dropped.
+        unsafe { self.shared.as_ref() }
+    }
+
+    /// Wraps the mutex, just claimed with the given result, in a guard,
+    /// recovering it if its previous holder died.
+    fn guard(
+        &self,
+        result: libc::c_int,
+        operation: &str,
+    ) -> LockResult<InterprocessMutexGuard<'_, T>> {
+        let shared = self.shared();
+        if result == libc::EOWNERDEAD {
+            // Safety: this thread holds the mutex.
+            check(
+                unsafe { libc::pthread_mutex_consistent(shared.raw.get()) },
+                "pthread_mutex_consistent",
+            );
+            shared.poisoned.store(true, Ordering::Relaxed);
+        } else {
+            check(result, operation);
+        }
+        let guard = InterprocessMutexGuard(self, PhantomData);
+        if shared.poisoned.load(Ordering::Relaxed) {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+}
+
+impl<T> Drop for InterprocessMutex<T> {
+    fn drop(&mut self) {
+        // Other processes may still be using the mutex, so it's only
+        // unmapped, never destroyed.
+        // Safety: the memory was mapped in `open` with this size.
+        unsafe { libc::munmap(self.shared.as_ptr().cast(), size_of::<Shared<T>>()) };
+    }
+}
+
+impl<T> fmt::Debug for InterprocessMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InterprocessMutex").finish_non_exhaustive()
+    }
+}
+
+/// A guard for an [`InterprocessMutex`]. It must be released on the thread
+/// which claimed it, so it isn't `Send`.
+pub struct InterprocessMutexGuard<'a, T>(&'a InterprocessMutex<T>, PhantomData<*const ()>);
+
+impl<T> Deref for InterprocessMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: the mutex is held for as long as the guard exists.
+        unsafe { &*self.0.shared().data.get() }
+    }
+}
+
+impl<T> DerefMut for InterprocessMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: the mutex is held for as long as the guard exists.
+        unsafe { &mut *self.0.shared().data.get() }
+    }
+}
+
+impl<T> Drop for InterprocessMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let shared = self.0.shared();
+        if std::thread::panicking() {
+            shared.poisoned.store(true, Ordering::Relaxed);
+        }
+        // Safety: this thread holds the mutex.
+        unsafe { libc::pthread_mutex_unlock(shared.raw.get()) };
+    }
+}
+
+// Safety: the contents live within the shared memory, not the guard.
+unsafe impl<T: Copy> MutexBackend<T> for InterprocessMutex<T> {
+    type Guard<'a>
+        = InterprocessMutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn lock(&self) -> LockResult<InterprocessMutexGuard<'_, T>> {
+        // Safety: the mutex was initialized by whichever process created it.
+        let result = unsafe { libc::pthread_mutex_lock(self.shared().raw.get()) };
+        self.guard(result, "pthread_mutex_lock")
+    }
+
+    fn try_lock(&self) -> TryLockResult<InterprocessMutexGuard<'_, T>> {
+        // Safety: the mutex was initialized by whichever process created it.
+        match unsafe { libc::pthread_mutex_trylock(self.shared().raw.get()) } {
+            libc::EBUSY => Err(TryLockError::WouldBlock),
+            result => Ok(self.guard(result, "pthread_mutex_trylock")?),
+        }
+    }
+
+    /// Borrows the contents in the shared memory directly, as the mutable
+    /// borrow of the mutex proves no other thread in this process holds it.
+    fn get_mut(&mut self) -> LockResult<&mut T> {
+        let shared = self.shared();
+        // Safety: no thread in this process can be using the contents, and
+        // `open` requires that no other process does meanwhile.
+        let data = unsafe { &mut *shared.data.get() };
+        if shared.poisoned.load(Ordering::Relaxed) {
+            Err(PoisonError::new(data))
+        } else {
+            Ok(data)
+        }
+    }
+
+    /// Returns a copy of the contents, claiming the mutex to take it. The
+    /// contents stay in the shared memory for other processes.
+    fn into_inner(self) -> LockResult<T> {
+        match self.lock() {
+            Ok(guard) => Ok(*guard),
+            Err(e) => Err(PoisonError::new(**e.get_ref())),
+        }
+    }
+
+    fn is_poisoned(&self) -> bool {
+        self.shared().poisoned.load(Ordering::Relaxed)
+    }
+
+    fn clear_poison(&self) {
+        self.shared().poisoned.store(false, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::CString, io, thread};
+
+    use crate::{DeadlockProofMutex, MutexBackend, OuterMutexPermission};
+
+    use super::InterprocessMutex;
+
+    struct Shared;
+
+    /// A shared memory name unique to this test run.
+    fn name(test: &str) -> CString {
+        CString::new(format!("/deadlock-proof-{test}-{}", std::process::id())).unwrap()
+    }
+
+    #[test]
+    fn handles_with_the_same_name_share_the_mutex() {
+        let name = name("share");
+        // Safety: both handles are opened with the same type.
+        let (first, second) = unsafe {
+            (
+                InterprocessMutex::open(&name, 1u64).unwrap(),
+                InterprocessMutex::open(&name, 2u64).unwrap(),
+            )
+        };
+        InterprocessMutex::<u64>::unlink(&name).unwrap();
+        let first: DeadlockProofMutex<u64, OuterMutexPermission, Shared, _> =
+            DeadlockProofMutex::from_backend(first, Shared);
+        let mut second = second;
+        let guard = first.lock(OuterMutexPermission::get()).unwrap();
+        assert_eq!(*guard, 1);
+        assert!(second.try_lock().is_err());
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mut guard = second.lock().unwrap();
+                *guard += 1;
+            });
+            thread::sleep(std::time::Duration::from_millis(10));
+            guard.unlock();
+        });
+        assert_eq!(*second.get_mut().unwrap(), 2);
+        assert_eq!(first.into_inner().unwrap(), 2);
+    }
+
+    #[test]
+    fn gives_up_on_a_creator_which_never_finishes() {
+        let name = name("abandoned");
+        // Safety: the name is a valid C string; the object is left empty,
+        // as if its creator died straight after creating it.
+        unsafe {
+            let fd = libc::shm_open(
+                name.as_ptr(),
+                libc::O_RDWR | libc::O_CREAT | libc::O_EXCL,
+                0o600,
+            );
+            assert!(fd >= 0);
+            libc::close(fd);
+        }
+        // Safety: nothing else opens this name.
+        let result = unsafe { InterprocessMutex::open(&name, 0u64) };
+        InterprocessMutex::<u64>::unlink(&name).unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+}
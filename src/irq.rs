@@ -0,0 +1,141 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Mutices shared between threads and interrupt handlers, claimed only
+//! inside a critical section from the `critical-section` crate.
+//!
+//! A thread holding an ordinary spin lock can be interrupted by a handler
+//! which then spins for the same lock, forever, since the thread can't run
+//! again until the handler returns. Permission tokens can't see this: the
+//! handler isn't another thread, and each context claims only one mutex.
+//! An [`IrqPermission`] exists only inside a critical section, so the
+//! holder of a mutex requiring one can never be interrupted by a handler
+//! wanting the same mutex.
+
+use core::{cell::Cell, marker::PhantomData};
+
+use crate::{DeadlockProofMutex, Mutex, MutexPermission, PermissionDomain};
+
+/// The [`PermissionDomain`] of [`IrqPermission`]. It ranks above every
+/// other domain, since an interrupt handler may run while a thread holds
+/// any mutex at all, whereas a thread in a critical section must not wait
+/// for a mutex which another thread on the same core may hold.
+pub struct IrqDomain;
+
+impl PermissionDomain for IrqDomain {
+    const RANK: u32 = u32::MAX;
+}
+
+/// Whether an [`IrqPermission`] is currently lent out. Only accessed inside
+/// a critical section, so no other context can see it change.
+static LENT: critical_section::Mutex<Cell<bool>> = critical_section::Mutex::new(Cell::new(false));
+
+/// Permission to claim a mutex shared with interrupt handlers, an
+/// [`IrqMutex`]. It can only be borrowed, within
+/// [`IrqPermission::with`], which holds a critical section throughout, so
+/// the mutex is never held while an interrupt handler might run. Claim the
+/// mutex using [`DeadlockProofMutex::lock_borrowing`]; the guard borrows
+/// the permission, so neither can outlive the critical section.
+///
+/// Threads and interrupt handlers alike must use this for every mutex they
+/// share, and an interrupt handler must never claim any other mutex, for
+/// instance with a permission from
+/// [`crate::OuterMutexPermission::new_unchecked`]: the thread it
+/// interrupted might hold it.
+pub struct IrqPermission(PhantomData<*const ()>);
+
+// As for the other permissions, the raw pointer marker already keeps this
+// from being `Send` or `Sync`.
+#[cfg(feature = "nightly")]
+impl !Send for IrqPermission {}
+#[cfg(feature = "nightly")]
+impl !Sync for IrqPermission {}
+
+impl MutexPermission for IrqPermission {
+    type Domain = IrqDomain;
+    const DEPTH: usize = 0;
+}
+
+impl IrqPermission {
+    /// Enters a critical section and lends `f` the permission for the
+    /// duration, returning whatever `f` returns. Only one mutex can be
+    /// claimed with it at a time, since each guard borrows it.
+    ///
+    /// Panics if called within `f`, where a second permission would let the
+    /// context claim a mutex it already holds, and spin forever.
+    pub fn with<R>(f: impl FnOnce(&mut IrqPermission) -> R) -> R {
+        /// Returns the permission once `f` has finished, even if it
+        /// panicked.
+        struct Return<'a>(&'a Cell<bool>);
+
+        impl Drop for Return<'_> {
+            fn drop(&mut self) {
+                self.0.set(false);
+            }
+        }
+
+        critical_section::with(|cs| {
+            let lent = LENT.borrow(cs);
+            assert!(
+                !lent.replace(true),
+                "IrqPermission::with was called while its permission was already lent out"
+            );
+            let _return = Return(lent);
+            f(&mut IrqPermission(PhantomData))
+        })
+    }
+}
+
+/// A deadlock-proof mutex shared with interrupt handlers, identified by
+/// `I`, which can only be claimed with an [`IrqPermission`].
+pub type IrqMutex<T, I, M = Mutex<T>> = DeadlockProofMutex<T, IrqPermission, I, M>;
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::{IrqMutex, IrqPermission};
+
+    #[test]
+    fn threads_and_handlers_share_a_mutex() {
+        struct Counter;
+        let counter: Arc<IrqMutex<u32, Counter>> = Arc::new(IrqMutex::new(0, Counter));
+        // With the host's implementation of critical sections, standing in
+        // for interrupt handlers with threads is as close as a test can get.
+        let handlers: Vec<_> = (0..4)
+            .map(|_| {
+                let counter = Arc::clone(&counter);
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        IrqPermission::with(|permission| {
+                            *counter.lock_borrowing(permission).unwrap() += 1;
+                        });
+                    }
+                })
+            })
+            .collect();
+        for handler in handlers {
+            handler.join().unwrap();
+        }
+        let total = IrqPermission::with(|permission| *counter.lock_borrowing(permission).unwrap());
+        assert_eq!(total, 4000);
+    }
+
+    #[test]
+    #[should_panic(expected = "already lent out")]
+    fn permission_is_not_lent_twice() {
+        IrqPermission::with(|_| IrqPermission::with(|_| ()));
+    }
+
+    #[test]
+    fn permission_is_returned_after_a_panic() {
+        let result = std::panic::catch_unwind(|| IrqPermission::with(|_| panic!("handler failed")));
+        assert!(result.is_err());
+        IrqPermission::with(|_| ());
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{fmt, marker::PhantomData};
+
+use crate::{DefaultDomain, OuterMutexPermission, PermissionDomain};
+
+/// Lends each worker of a long-lived thread pool its mutex permission for
+/// the duration of one job at a time, for pools this crate doesn't know
+/// about. Keep one of these alongside the pool and run each job through
+/// [`PermissionPool::lease`], rather than caching permissions in the
+/// workers.
+///
+/// The permission is claimed from the worker thread as the job starts and
+/// must be back with the thread once the job finishes, which is checked,
+/// so a job that leaks its permission, or a nested permission derived from
+/// it, is caught straight away rather than when some later job on the same
+/// worker fails to claim it. The pool is zero-sized and can be copied
+/// freely. `D` is the permission domain, as for [`OuterMutexPermission`].
+pub struct PermissionPool<D: PermissionDomain = DefaultDomain>(PhantomData<fn() -> D>);
+
+impl PermissionPool {
+    /// Create a new permission pool for the default permission domain.
+    pub const fn new() -> Self {
+        Self::for_domain()
+    }
+}
+
+impl<D: PermissionDomain> PermissionPool<D> {
+    /// Create a new permission pool for the permission domain `D`.
+    pub const fn for_domain() -> Self {
+        Self(PhantomData)
+    }
+
+    /// Runs `job` on the current thread, lending it the thread's mutex
+    /// permission. Panics if the permission is already lent out, for
+    /// example because the pool runs another job on a worker while it waits
+    /// for work it started, or if `job` doesn't give it back.
+    pub fn lease<R>(&self, job: impl FnOnce(OuterMutexPermission<D>) -> R) -> R {
+        self.try_lease(job).expect(
+            "a worker's mutex permission was still lent out when it started \
+             another job; don't wait for other jobs while holding a permission",
+        )
+    }
+
+    /// Runs `job` on the current thread, lending it the thread's mutex
+    /// permission, or returns `None` without running it if the permission
+    /// is already lent out. Panics if `job` doesn't give it back.
+    pub fn try_lease<R>(&self, job: impl FnOnce(OuterMutexPermission<D>) -> R) -> Option<R> {
+        let result = job(OuterMutexPermission::try_get_for_domain()?);
+        assert!(
+            OuterMutexPermission::<D>::is_available_for_domain(),
+            "a job kept its worker's mutex permission, or a nested permission \
+             derived from it, after it finished"
+        );
+        Some(result)
+    }
+}
+
+impl<D: PermissionDomain> Clone for PermissionPool<D> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<D: PermissionDomain> Copy for PermissionPool<D> {}
+
+impl<D: PermissionDomain> Default for PermissionPool<D> {
+    fn default() -> Self {
+        Self::for_domain()
+    }
+}
+
+impl<D: PermissionDomain> fmt::Debug for PermissionPool<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PermissionPool").finish()
+    }
+}
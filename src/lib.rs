@@ -9,14 +9,61 @@
 //! A crate to provide mutices which the Rust type system can prove are
 //! free from the risk of deadlocks. See [`DeadlockProofMutex`] for the main
 //! type you need to use.
+//!
+//! # Turning the runtime checks off
+//!
+//! Building with `RUSTFLAGS="--cfg deadlock_proof_mutex_unverified"` turns
+//! off the bookkeeping behind the thread-local permission tokens: getting
+//! one always succeeds without touching a thread-local, and the checks
+//! between [`PermissionDomain`]s are skipped. Call sites are unchanged and
+//! the lock order is still checked at compile time, but nothing stops a
+//! thread getting several tokens, so deadlock-freedom is no longer
+//! guaranteed. Guards still record their release, which costs an atomic
+//! load unless a thread is waiting in [`DeadlockProofMutex::lock_when`],
+//! and features which observe locking, such as `stats`, still do so.
+//!
+//! This is for measuring the crate's overhead, bisecting a change in
+//! behaviour or staging a rollout, not for production. It's a `--cfg` flag
+//! rather than a feature so that no dependency can turn it on for a whole
+//! build.
+//!
+//! # Without the standard library
+//!
+//! The `std` feature is on by default. Turning it off, and the `spin`
+//! feature on, builds the crate as `no_std` for firmware and other targets
+//! without an operating system. What remains is [`DeadlockProofMutex`]
+//! over a `spin::Mutex`, which becomes the default backend, or any
+//! `lock_api::Mutex` with the `lock_api` feature, along with its guards,
+//! its permissions and ordered locking. There are no thread-local tokens to
+//! hand out, so each context which runs independently takes its one
+//! [`OuterMutexPermission`] from [`OuterMutexPermission::new_unchecked`],
+//! and the checks between [`PermissionDomain`]s aren't made. Everything
+//! which blocks on the operating system, reads a clock or keeps per-thread
+//! records, such as [`DeadlockProofMutex::lock_when`], needs `std`, as
+//! does every feature besides `spin`, `lock_api` and `critical-section`.
+//! The last provides `IrqMutex`, for mutices shared with interrupt
+//! handlers.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(feature = "nightly", feature(negative_impls))]
+
+#[cfg(all(not(feature = "std"), not(feature = "spin")))]
+compile_error!(
+    "without the `std` feature, enable the `spin` feature, whose `spin::Mutex` is then the default backend"
+);
 
 // Next steps in this experiment:
-// * See if there's a way to create anonymous types to make constructing
-//   one of these mutices easier.
-// * Add some negative compile tests.
 // * Convert the examples into tests.
 
-/// A macro to create a unique type.
+/// A macro to create a unique type, for use as the identifier of a mutex.
+/// Each invocation produces a value of a fresh anonymous type (it's an
+/// empty closure, and every closure has its own type), so there's no need
+/// to invent a name for every mutex.
+///
+/// The type is unique to the place where the macro is written, not to each
+/// time it runs: mutices created by the same invocation in a loop, or in a
+/// function called more than once, share an identifier. Use
+/// [`DeadlockProofMutexPool`] for a group of mutices like that.
 #[macro_export]
 macro_rules! unique_type {
     () => {
@@ -24,64 +71,860 @@ macro_rules! unique_type {
     };
 }
 
-use std::{marker::PhantomData, rc::Rc};
+/// Declares `const`-initialized thread-locals for the permission tokens,
+/// which with the `loom` feature must instead be loom's simulated
+/// thread-locals, so that each thread in a model has its own tokens.
+#[cfg(feature = "std")]
+macro_rules! token_thread_local {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr;)*) => {
+        #[cfg(not(feature = "loom"))]
+        std::thread_local! {
+            $($(#[$attr])* $vis static $name: $t = const { $init };)*
+        }
+        #[cfg(feature = "loom")]
+        loom::thread_local! {
+            $($(#[$attr])* $vis static $name: $t = $init;)*
+        }
+    };
+}
 
-use std::{
+use core::{
+    fmt,
+    marker::PhantomData,
     ops::{Deref, DerefMut},
-    sync::{Mutex, MutexGuard, PoisonError},
+    panic::{RefUnwindSafe, UnwindSafe},
+};
+#[cfg(feature = "std")]
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
 };
 
-/// A convenience macro to make it easy to create unique types that
-/// implement [`MutexIdentifier`].
+// Without `std`, this is the default backend instead.
+#[cfg(not(feature = "std"))]
+use spin::Mutex;
+
+#[cfg(not(feature = "std"))]
+pub use poison::{LockResult, PoisonError, TryLockError};
+#[cfg(feature = "std")]
+use poison::{LockResult, PoisonError, TryLockError};
+
+#[cfg(feature = "macros")]
+mod acquires;
+mod aliases;
+#[cfg(any(feature = "tokio", feature = "futures"))]
+mod async_condvar;
+#[cfg(any(feature = "tokio", feature = "futures"))]
+mod async_mutex;
+#[cfg(any(feature = "tokio", feature = "futures"))]
+mod async_permission;
+#[cfg(feature = "tokio")]
+mod async_rwlock;
+#[cfg(feature = "tokio")]
+mod async_semaphore;
+#[cfg(feature = "await-check")]
+mod await_check;
+mod backend;
+#[cfg(feature = "std")]
+mod barrier;
+mod brand;
+#[cfg(feature = "std")]
+mod bridge;
+mod callback;
+#[cfg(all(
+    feature = "realtime",
+    any(target_os = "linux", target_os = "android", target_vendor = "apple")
+))]
+mod ceiling;
+#[cfg(feature = "std")]
+mod channel;
+#[cfg(feature = "std")]
+mod condvar;
+#[cfg(feature = "diagnostics")]
+mod diagnostics;
+mod domain;
+#[cfg(all(feature = "identifier-check", debug_assertions))]
+mod duplicates;
+#[cfg(feature = "std")]
+mod dynamic;
+#[cfg(feature = "std")]
+mod dynamic_order;
+#[cfg(feature = "std")]
+mod erased;
+#[cfg(feature = "std")]
+mod error;
+mod held;
+#[cfg(feature = "std")]
+mod hierarchy;
+mod identifier;
+#[cfg(all(feature = "interprocess", target_os = "linux"))]
+mod interprocess;
+#[cfg(feature = "critical-section")]
+mod irq;
+#[cfg(feature = "std")]
+mod lease;
+#[cfg(feature = "std")]
+mod lock_graph;
+#[cfg(feature = "std")]
+mod lock_token;
+#[cfg(feature = "std")]
+mod lock_trait;
+#[cfg(feature = "std")]
+mod many;
+mod mapped;
+mod monitor;
+#[cfg(feature = "std")]
+mod multi;
+#[cfg(feature = "std")]
+mod no_locks;
+#[cfg(feature = "std")]
+mod notify;
+#[cfg(feature = "observer")]
+mod observer;
+#[cfg(feature = "std")]
+mod once;
+mod order;
+#[cfg(feature = "std")]
+mod owned;
+#[cfg(feature = "rayon")]
+mod parallel;
+#[cfg(feature = "std")]
+mod parker;
+#[cfg(feature = "std")]
+mod permission_cell;
+mod poison;
+#[cfg(feature = "std")]
+mod pool;
+#[cfg(feature = "priority-inversion")]
+pub mod priority_inversion;
+#[cfg(feature = "std")]
+mod queue;
+#[cfg(feature = "std")]
+mod read_handle;
+#[cfg(feature = "read-mostly")]
+mod read_mostly;
+#[cfg(all(feature = "realtime", unix))]
+mod realtime;
+#[cfg(feature = "std")]
+mod reclaim;
+#[cfg(feature = "std")]
+mod reentrant;
+#[cfg(feature = "std")]
+mod runtime_checks;
+#[cfg(feature = "std")]
+mod rwlock;
+#[cfg(feature = "std")]
+mod semaphore;
+#[cfg(feature = "serde")]
+mod serialize;
+#[cfg(feature = "std")]
+mod sharded;
+#[cfg(all(
+    feature = "std",
+    target_family = "wasm",
+    not(target_feature = "atomics")
+))]
+mod single_thread;
+#[cfg(feature = "stats")]
+mod stats;
+#[cfg(any(feature = "tokio", feature = "futures"))]
+pub mod task;
+#[cfg(feature = "std")]
+pub mod thread;
+#[cfg(feature = "std")]
+mod ticket;
+#[cfg(feature = "std")]
+mod transaction;
+#[cfg(feature = "std")]
+mod tuned;
+mod unchecked;
+#[cfg(feature = "std")]
+mod violation;
+#[cfg(feature = "std")]
+mod wait_group;
+#[cfg(feature = "std")]
+mod waiters;
+
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub use acquires::{AcquiredAt, Here, There};
+pub use aliases::{
+    Nested1, Nested1Guard, Nested2, Nested2Guard, Nested3, Nested3Guard, Nested4, Nested4Guard,
+    Nested5, Nested5Guard, Nested6, Nested6Guard, OuterGuard,
+};
+#[cfg(any(feature = "tokio", feature = "futures"))]
+pub use async_condvar::DeadlockProofAsyncCondvar;
+#[cfg(any(feature = "tokio", feature = "futures"))]
+pub use async_mutex::{
+    AsyncMutexBackend, DeadlockProofAsyncMutex, DeadlockProofAsyncMutexGuard,
+    DeadlockProofNestedAsyncMutexGuard, DefaultAsyncBackend,
+};
+#[cfg(any(feature = "tokio", feature = "futures"))]
+pub use async_permission::{
+    AsyncMutexPermission, AsyncNestedMutexPermission, AsyncOuterMutexPermission,
+    AsyncSequentialMutexPermission,
+};
+#[cfg(feature = "tokio")]
+pub use async_rwlock::{
+    DeadlockProofAsyncRwLock, DeadlockProofAsyncRwLockReadGuard,
+    DeadlockProofAsyncRwLockWriteGuard, DeadlockProofNestedAsyncRwLockReadGuard,
+    DeadlockProofNestedAsyncRwLockWriteGuard,
+};
+#[cfg(feature = "tokio")]
+pub use async_semaphore::{DeadlockProofAsyncSemaphore, DeadlockProofAsyncSemaphorePermit};
+#[cfg(feature = "await-check")]
+pub use await_check::{check_guards_across_await, GuardAcrossAwaitCheck};
+pub use backend::MutexBackend;
+#[cfg(feature = "std")]
+pub use barrier::DeadlockProofBarrier;
+pub use brand::BrandedIdentifier;
+#[doc(hidden)]
+pub use brand::{BrandPlace, LifetimeBrand};
+#[cfg(feature = "std")]
+pub use bridge::{BridgePermission, HierarchyOrder};
+pub use callback::CallbackPermission;
+#[cfg(all(
+    feature = "realtime",
+    any(target_os = "linux", target_os = "android", target_vendor = "apple")
+))]
+pub use ceiling::{PriorityCeiling, PriorityCeilingMutex, PriorityCeilingMutexGuard};
+#[cfg(feature = "std")]
+pub use channel::{
+    deadlock_proof_rendezvous_channel, deadlock_proof_sync_channel, DeadlockProofReceiver,
+    DeadlockProofSender,
+};
+#[cfg(feature = "std")]
+pub use condvar::DeadlockProofCondvar;
+#[cfg(feature = "diagnostics")]
+pub use diagnostics::{dump_held_locks, HeldLockInfo};
+pub use domain::{DefaultDomain, PermissionDomain};
+#[cfg(feature = "std")]
+pub use dynamic::{DynamicCheckedMutex, DynamicCheckedMutexGuard};
+#[cfg(feature = "std")]
+pub use dynamic_order::{
+    DeadlockProofDynamicGuards, DeadlockProofDynamicMutex, DeadlockProofDynamicMutexGuard,
+    DynamicIdentifier, DynamicLockError,
+};
+#[cfg(feature = "std")]
+pub use erased::ErasedPermission;
+#[cfg(feature = "std")]
+pub use error::DeadlockProofError;
+use held::HeldLock;
+#[cfg(feature = "std")]
+use held::HeldLocks;
+#[cfg(feature = "std")]
+pub use hierarchy::{HierarchyLevels, LockHierarchy, LockLevel};
+use identifier::IdentifierName;
+#[doc(hidden)]
+pub use identifier::Singleton;
+#[cfg(all(feature = "interprocess", target_os = "linux"))]
+pub use interprocess::{InterprocessMutex, InterprocessMutexGuard};
+#[cfg(feature = "critical-section")]
+pub use irq::{IrqDomain, IrqMutex, IrqPermission};
+#[cfg(feature = "std")]
+pub use lease::PermissionPool;
+#[cfg(feature = "std")]
+pub use lock_graph::{DeclaredLockOrder, LockOrderGraph};
+#[cfg(feature = "std")]
+pub use lock_token::{DeadlockProofLockToken, DeadlockProofLockTokenGuard};
+#[cfg(feature = "std")]
+pub use lock_trait::{DeadlockProofGuard, DeadlockProofLock};
+#[cfg(feature = "std")]
+pub use many::{DeadlockProofManyGuard, ManyLockResult};
+pub use mapped::DeadlockProofMappedMutexGuard;
+use monitor::Monitor;
+#[cfg(feature = "std")]
+pub use multi::{DeadlockProofMultiGuard, LockGroup, MultiLock};
+#[cfg(feature = "std")]
+pub use no_locks::NoLocksHeld;
+#[cfg(any(feature = "tokio", feature = "futures"))]
+pub use notify::DeadlockProofNotified;
+#[cfg(feature = "std")]
+pub use notify::DeadlockProofNotify;
+#[cfg(feature = "observer")]
+pub use observer::{set_lock_observer, LockObserver};
+#[cfg(feature = "std")]
+pub use once::{DeadlockProofLazyLock, DeadlockProofOnceLock, LazyDeadlockProofMutex};
+pub use order::{
+    DeadlockProofOrderedMutexGuard, DeadlockProofPairGuard, LockAfter, LockPair, OrderedLockResult,
+    OrderedMutexPermission, PairLockResult, SwapResult,
+};
+#[cfg(feature = "std")]
+pub use owned::DeadlockProofOwnedMutexGuard;
+#[cfg(feature = "rayon")]
+pub use parallel::PermissionParallelIterator;
+#[cfg(feature = "std")]
+pub use parker::{Parker, Unparker};
+#[cfg(feature = "std")]
+pub use permission_cell::{PermissionCell, PermissionLease};
+pub use poison::IgnorePoison;
+#[cfg(feature = "std")]
+pub use pool::{
+    DeadlockProofMutexPool, DeadlockProofMutexSlab, DeadlockProofPoolGuard,
+    DeadlockProofPoolGuards, DeadlockProofSlabGuards, PoolLockError,
+};
+#[cfg(feature = "std")]
+pub use queue::DeadlockProofBoundedQueue;
+#[cfg(feature = "std")]
+pub use read_handle::{DeadlockProofMutexReadGuard, DeadlockProofMutexReadHandle};
+#[cfg(feature = "read-mostly")]
+pub use read_mostly::DeadlockProofReadMostly;
+#[cfg(all(feature = "realtime", unix))]
+pub use realtime::{PriorityInheritMutex, PriorityInheritMutexGuard};
+#[cfg(feature = "std")]
+pub use reclaim::PermissionReclaim;
+#[cfg(feature = "std")]
+pub use reentrant::{
+    DeadlockProofReentrantLock, DeadlockProofReentrantLockGuard,
+    DeadlockProofReentrantLockRelockGuard,
+};
+#[cfg(feature = "std")]
+pub use rwlock::{
+    DeadlockProofNestedRwLockReadGuard, DeadlockProofNestedRwLockWriteGuard,
+    DeadlockProofReadManyGuard, DeadlockProofRwLock, DeadlockProofRwLockReadGuard,
+    DeadlockProofRwLockTryError, DeadlockProofRwLockUpgradableReadGuard,
+    DeadlockProofRwLockWriteGuard, NestedReadResult, NestedWriteResult, ReadSet, RwLockPolicy,
+    TryReadResult, TryWriteResult,
+};
+#[cfg(feature = "std")]
+pub use semaphore::{DeadlockProofSemaphore, DeadlockProofSemaphorePermit};
+#[cfg(feature = "std")]
+pub use sharded::{DeadlockProofShardedMap, DeadlockProofShardedMapGuards};
+#[cfg(all(
+    feature = "std",
+    target_family = "wasm",
+    not(target_feature = "atomics")
+))]
+pub use single_thread::SingleThreadMutex;
+#[cfg(feature = "stats")]
+pub use stats::MutexStats;
+#[cfg(feature = "std")]
+pub use ticket::{TicketMutex, TicketMutexGuard};
+#[cfg(feature = "std")]
+pub use transaction::{
+    PreparedTransaction, Transaction, TransactionLock, TransactionLocks, TransactionResult,
+};
+#[cfg(feature = "std")]
+pub use tuned::{AcquireStrategy, TunedMutex};
+pub use unchecked::UncheckedMutexGuard;
+#[cfg(feature = "std")]
+pub use violation::{
+    set_violation_action, violation_action, Violation, ViolationAction, ViolationKind,
+};
+#[cfg(feature = "std")]
+pub use wait_group::DeadlockProofWaitGroup;
+
+#[cfg(feature = "macros")]
+pub use deadlock_proof_mutex_macros::{acquires, main, protected};
+
+/// A convenience macro to make it easy to create unique types to identify
+/// mutices: `declare_mutex_identifier!(pub DbLock)`.
+///
+/// Identifiers declared in different crates or modules are always distinct
+/// types, even if they share a name, so they can't be confused in lock
+/// orders. Fields naming identifiers, such as [`Violation::identifiers`],
+/// hold their full path, crate and module included, so the names are
+/// unambiguous too. Messages meant to be read, such as panics, `Debug`
+/// output and tracing events, shorten each name to just the type's own,
+/// generic arguments included, as in `Locks<Users>`.
 #[macro_export]
 macro_rules! declare_mutex_identifier {
-    ($mutex_name:ident) => {
-        struct $mutex_name;
+    ($(#[$attr:meta])* $vis:vis $mutex_name:ident) => {
+        $(#[$attr])*
+        #[allow(dead_code)]
+        $vis struct $mutex_name;
+    };
+}
+
+/// Declares a deadlock-proof mutex in a `static`, along with its identifier
+/// type. This is written just like an ordinary `static`, except that the
+/// initializer is only the contents of the mutex:
+///
+/// `declare_static_deadlock_proof_mutex!(pub static COUNTER: DeadlockProofMutex<u32, OuterMutexPermission, CounterId> = 0;)`
+///
+/// declares both `COUNTER` and the identifier type `CounterId`, which can
+/// then be used to name permissions derived from the mutex. The only value
+/// of `CounterId` is the one the macro hands to `COUNTER`, so creating any
+/// other mutex with that identifier fails to compile. Since `static`s must
+/// be `const`-initialized, this uses [`DeadlockProofMutex::const_new`]; for
+/// contents which can't be created that way, use
+/// [`declare_lazy_deadlock_proof_mutex`].
+#[macro_export]
+macro_rules! declare_static_deadlock_proof_mutex {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: DeadlockProofMutex<$content:ty, $permission:ty, $identifier:ident> = $init:expr;) => {
+        #[allow(dead_code)]
+        $vis struct $identifier($crate::Singleton);
+
+        $(#[$attr])*
+        $vis static $name: $crate::DeadlockProofMutex<$content, $permission, $identifier> =
+            $crate::DeadlockProofMutex::const_new($init, $identifier(unsafe {
+                $crate::Singleton::new()
+            }));
+    };
+}
+
+/// Declares a deadlock-proof mutex in a `static`, along with its identifier
+/// type, whose contents are created on first use. This is written just like
+/// [`declare_static_deadlock_proof_mutex`], except that the initializer
+/// needn't be `const`:
+///
+/// `declare_lazy_deadlock_proof_mutex!(pub static CONFIG: DeadlockProofMutex<Config, OuterMutexPermission, ConfigId> = Config::load();)`
+///
+/// The initializer runs the first time the mutex is used, from whichever
+/// thread uses it first. The static is a [`LazyDeadlockProofMutex`], which
+/// is claimed with [`LazyDeadlockProofMutex::lock`]; since the first use
+/// may wait for the initializer, even reaching the mutex needs a
+/// permission. As for [`declare_static_deadlock_proof_mutex`], no other
+/// mutex can be created with the identifier.
+#[cfg(feature = "std")]
+#[macro_export]
+macro_rules! declare_lazy_deadlock_proof_mutex {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: DeadlockProofMutex<$content:ty, $permission:ty, $identifier:ident> = $init:expr;) => {
+        #[allow(dead_code)]
+        $vis struct $identifier($crate::Singleton);
+
+        $(#[$attr])*
+        $vis static $name: $crate::LazyDeadlockProofMutex<$content, $permission, $identifier> =
+            $crate::LazyDeadlockProofMutex::new(|| $init, $identifier(unsafe {
+                $crate::Singleton::new()
+            }));
+    };
+}
+
+/// Converts a `Box<DeadlockProofMutex<T, P, I>>` to one whose contents are
+/// unsized, such as a trait object which `T` implements, just as a
+/// `Box<Mutex<T>>` can be coerced to a `Box<Mutex<dyn Trait>>`:
+///
+/// `let handler = unsize_deadlock_proof_mutex!(Box::new(DeadlockProofMutex::new(Logger::new(), HandlerId)), dyn Handler + Send);`
+///
+/// The result can be claimed just like any other mutex. To share it, turn it
+/// into an `Arc` using `Arc::from`. Only mutices using the default
+/// [`Mutex`] backend can be converted.
+#[cfg(feature = "std")]
+#[macro_export]
+macro_rules! unsize_deadlock_proof_mutex {
+    ($mutex:expr, $target:ty $(,)?) => {
+        match $mutex {
+            mutex => {
+                // Safety: the closure does nothing but coerce the pointer.
+                unsafe {
+                    $crate::DeadlockProofMutex::__unsize_box(
+                        mutex,
+                        |mutex| -> *const ::std::sync::Mutex<$target> { mutex },
+                    )
+                }
+            }
+        }
+    };
+}
+
+/// Unlocks a whole stack of nested guards in one go, returning the
+/// outermost permission. List the guards from outermost to innermost,
+/// as obtained from [`DeadlockProofMutex::lock_for_nested`]. If the innermost
+/// guard is itself a nested guard, pass its [`NestedMutexPermission`] after
+/// a semicolon:
+///
+/// * `unlock_all!(guard1, guard2, guard3)` where `guard3` came from
+///   [`DeadlockProofMutex::lock`]
+/// * `unlock_all!(guard1, guard2; permission2)` where `guard2` came from
+///   [`DeadlockProofMutex::lock_for_nested`]
+///
+/// Each guard is released before the guard enclosing it, and the type system
+/// checks that every guard is handed exactly the token it requires, so the
+/// guards can't be listed in the wrong order.
+#[macro_export]
+macro_rules! unlock_all {
+    ($guard:expr $(,)?) => {
+        $guard.unlock()
+    };
+    ($guard:expr; $permission:expr $(,)?) => {
+        $guard.unlock($permission)
+    };
+    ($guard:expr, $($rest:tt)+) => {
+        $guard.unlock($crate::unlock_all!($($rest)+))
+    };
+}
+
+/// Claims a whole chain of nested mutices in one go, using
+/// [`DeadlockProofMutex::lock_for_nested`] for each. List the mutices from
+/// outermost to innermost, after the permission for the outermost:
+///
+/// `let (guard1, guard2, guard3, permission3) = lock_in_order!(permission; mutex1, mutex2, mutex3);`
+///
+/// This evaluates to a tuple of all the guards followed by the innermost
+/// [`NestedMutexPermission`], which is just what [`unlock_all`] needs to
+/// unlock them again. The type system still checks that each mutex may be
+/// claimed within the one before it. Panics if any of the mutices is
+/// poisoned; claim them individually to recover from that.
+#[macro_export]
+macro_rules! lock_in_order {
+    ($permission:expr; $($mutex:expr),+ $(,)?) => {
+        $crate::__lock_in_order!($permission; []; $($mutex),+)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __lock_in_order {
+    ($permission:expr; [$($guard:ident)*]; $mutex:expr $(, $rest:expr)*) => {{
+        let (guard, permission) = $mutex
+            .lock_for_nested($permission)
+            .expect("Deadlock-proof mutex poisoned");
+        $crate::__lock_in_order!(permission; [$($guard)* guard]; $($rest),*)
+    }};
+    ($permission:expr; [$($guard:ident)*];) => {
+        ($($guard,)* $permission)
+    };
+}
+
+/// Names the type of a [`NestedMutexPermission`] derived through a chain of
+/// mutices, without writing out each level. List the identifiers of the
+/// mutices from outermost to innermost:
+///
+/// `nested_permission!(A, B, C)` is
+/// `NestedMutexPermission<NestedMutexPermission<NestedMutexPermission<OuterMutexPermission, A>, B>, C>`,
+/// the permission returned by claiming `C` within `B` within `A` using
+/// [`DeadlockProofMutex::lock_for_nested`].
+///
+/// To start from a permission other than [`OuterMutexPermission`], give it
+/// first, followed by a semicolon: `nested_permission!(P; A, B)`. This is
+/// usable anywhere a type is, including struct fields and function
+/// signatures. For chains of up to six mutices from an
+/// [`OuterMutexPermission`], the aliases [`Nested1`] to [`Nested6`] are
+/// shorter still.
+#[macro_export]
+macro_rules! nested_permission {
+    ($permission:ty; $($identifier:ty),* $(,)?) => {
+        $crate::__nested_permission!($permission; $($identifier),*)
+    };
+    ($($identifier:ty),* $(,)?) => {
+        $crate::__nested_permission!($crate::OuterMutexPermission; $($identifier),*)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __nested_permission {
+    ($permission:ty; $identifier:ty $(, $rest:ty)*) => {
+        $crate::__nested_permission!(
+            $crate::NestedMutexPermission<$permission, $identifier>;
+            $($rest),*
+        )
+    };
+    ($permission:ty;) => {
+        $permission
     };
 }
 
 /// Some type of permission token required to claim a mutex.
-pub trait MutexPermission {}
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` is not a mutex permission token",
+    label = "a permission token is required here",
+    note = "the first mutex in each thread is claimed with the `OuterMutexPermission` from `OuterMutexPermission::get()`",
+    note = "later mutices are claimed with the permission returned by `lock_for_nested` or `unlock_for_sequential` on the mutex claimed before them"
+)]
+pub trait MutexPermission {
+    /// The [`PermissionDomain`] of the [`OuterMutexPermission`] from which
+    /// this permission was ultimately derived.
+    type Domain: PermissionDomain;
 
-impl MutexPermission for OuterMutexPermission {}
+    /// The number of mutices the thread holds while it has this permission,
+    /// counting each [`NestedMutexPermission`] in its chain: zero for an
+    /// [`OuterMutexPermission`]. Nesting which would let a thread hold more
+    /// mutices than the domain's [`PermissionDomain::MAX_DEPTH`] fails to
+    /// compile.
+    const DEPTH: usize;
+}
+
+impl<D: PermissionDomain> MutexPermission for OuterMutexPermission<D> {
+    type Domain = D;
+    const DEPTH: usize = 0;
+}
+
+/// A borrowed permission is as good as an owned one, since nothing else can
+/// use it while it's borrowed. See [`DeadlockProofMutex::lock_borrowing`].
+impl<P: MutexPermission> MutexPermission for &mut P {
+    type Domain = P::Domain;
+    const DEPTH: usize = P::DEPTH;
+}
 
 /// Permission to claim an "outer" mutex. That is, a class of mutices where
 /// only one can be claimed at once in each thread, thus preventing deadlock.
 /// An instance of this object can be obtained using [`OuterMutexPermission::get`].
-pub struct OuterMutexPermission(PhantomData<Rc<()>>);
+///
+/// Each thread has one such permission per [`PermissionDomain`], so that
+/// unrelated subsystems can each hold a mutex at the same time. The type
+/// system only rules out deadlocks within a domain; a thread holding mutices
+/// from several domains must claim them in increasing order of the domains'
+/// ranks, which is checked at runtime and reported as a [`Violation`].
+pub struct OuterMutexPermission<D: PermissionDomain = DefaultDomain>(
+    PhantomData<*const ()>,
+    PhantomData<D>,
+);
+
+#[cfg(feature = "std")]
+token_thread_local! {
+/// This thread's [`OuterMutexPermission`] in the default domain, while it
+/// isn't claimed. Only ever accessed through [`OuterMutexPermission`]'s
+/// methods, which keep the token unique.
+static MUTEX_PERMISSION_TOKEN: std::cell::Cell<Option<OuterMutexPermission>>
+= std::cell::Cell::new(Some(OuterMutexPermission(PhantomData, PhantomData)));
 
-thread_local! {
-pub static MUTEX_PERMISSION_TOKEN: std::cell::Cell<Option<OuterMutexPermission>>
-= std::cell::Cell::new(Some(OuterMutexPermission(PhantomData)))
+/// The number of [`NestedMutexPermission`]s in the default domain alive in
+/// this thread.
+static LIVE_NESTED_PERMISSIONS: std::cell::Cell<usize> = std::cell::Cell::new(0);
 }
 
 impl OuterMutexPermission {
-    /// Get the thread-local mutex claiming permission. This can be called exactly once
-    /// per thread, and will panic if it's called more than once in a thread.
-    /// Because it may panic, it's strongly recommended that you claim this in the
-    /// start up of your program (or thread) and store it in some context object.
-    /// This eliminates any chance of runtime panics later.
+    /// Get the thread-local mutex claiming permission. Only one such token
+    /// exists per thread, so this will panic if it's called while the token
+    /// is already claimed. Because it may panic, it's strongly recommended
+    /// that you claim this in the start up of your program (or thread) and
+    /// store it in some context object, for example in a [`PermissionCell`].
+    /// This eliminates any chance of runtime panics later. Threads spawned
+    /// using [`thread::spawn`] are instead handed their token at the start.
     /// The resulting zero-sized type can be used as permission to claim a mutex.
+    ///
+    /// Dropping the token returns it to the thread, so that it can be claimed
+    /// again, for example by the next job run by a thread pool worker, as
+    /// [`PermissionPool`] arranges. It can't be reclaimed while any [`NestedMutexPermission`] derived from it
+    /// is still alive, because such a permission may outlive the guard it
+    /// came from and so doesn't prove that its parent mutex is still held.
+    ///
+    /// With `--cfg deadlock_proof_mutex_unverified`, none of this is
+    /// checked and the token is always available.
+    #[cfg(feature = "std")]
     pub fn get() -> OuterMutexPermission {
-        MUTEX_PERMISSION_TOKEN
-            .with(|thingref| thingref.take())
-            .expect("Mutex permission already claimed for this thread")
+        Self::get_for_domain()
+    }
+
+    /// Get the thread-local mutex claiming permission if it's available, or
+    /// `None` if it's already claimed or can't yet be reclaimed because
+    /// nested permissions derived from it are still alive. Unlike
+    /// [`OuterMutexPermission::get`], this never panics, so it suits library
+    /// code which may be called on threads it doesn't control.
+    #[cfg(feature = "std")]
+    pub fn try_get() -> Option<OuterMutexPermission> {
+        Self::try_get_for_domain()
+    }
+
+    /// Whether [`OuterMutexPermission::get`] would currently succeed in this
+    /// thread, without claiming the permission.
+    #[cfg(feature = "std")]
+    pub fn is_available() -> bool {
+        Self::is_available_for_domain()
+    }
+
+    /// Creates a permission without consulting the thread-local token, for
+    /// execution contexts where thread-locals aren't available or don't
+    /// correspond to the units of concurrency, such as the tasks of an
+    /// embedded executor.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that at most one permission exists for each
+    /// such context at a time, and that permissions created this way and by
+    /// [`OuterMutexPermission::get`] are never mixed within a thread: since
+    /// dropping a permission returns a token to the thread, doing so would
+    /// let [`OuterMutexPermission::get`] hand out a second one.
+    ///
+    /// Without the `std` feature there are no thread-local tokens, so this
+    /// is the only way to get a permission: make one for each thread of
+    /// execution as it starts, such as each task of the executor, and hand
+    /// it down from there.
+    pub unsafe fn new_unchecked() -> OuterMutexPermission {
+        OuterMutexPermission(PhantomData, PhantomData)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<D: PermissionDomain> OuterMutexPermission<D> {
+    /// Get the thread-local mutex claiming permission for the domain `D`.
+    /// This is just like [`OuterMutexPermission::get`], except that each
+    /// domain has its own token.
+    pub fn get_for_domain() -> Self {
+        assert_eq!(
+            Self::live_nested(),
+            0,
+            "Mutex permission can't be reclaimed while nested permissions derived from it are alive"
+        );
+        Self::try_get_for_domain().expect("Mutex permission already claimed for this thread")
+    }
+
+    /// Get the thread-local mutex claiming permission for the domain `D`,
+    /// if it's available. This is just like
+    /// [`OuterMutexPermission::try_get`], except that each domain has its
+    /// own token.
+    pub fn try_get_for_domain() -> Option<Self> {
+        if cfg!(deadlock_proof_mutex_unverified) {
+            return Some(Self(PhantomData, PhantomData));
+        }
+        if Self::live_nested() != 0 {
+            return None;
+        }
+        if domain::is_default::<D>() {
+            // The token is returned when the one we hand out is dropped.
+            core::mem::forget(MUTEX_PERMISSION_TOKEN.with(|thingref| thingref.take())?);
+        } else if !domain::claim_token::<D>() {
+            return None;
+        }
+        Some(Self(PhantomData, PhantomData))
+    }
+
+    /// Whether [`OuterMutexPermission::get_for_domain`] would currently
+    /// succeed in this thread, without claiming the permission.
+    pub fn is_available_for_domain() -> bool {
+        if cfg!(deadlock_proof_mutex_unverified) {
+            return true;
+        }
+        Self::live_nested() == 0
+            && if domain::is_default::<D>() {
+                MUTEX_PERMISSION_TOKEN.with(|thingref| {
+                    let token = thingref.take();
+                    let available = token.is_some();
+                    thingref.set(token);
+                    available
+                })
+            } else {
+                domain::token_available::<D>()
+            }
+    }
+
+    /// Runs `f` with this permission, catching any panic, as
+    /// [`std::panic::catch_unwind`] does. Either way the permission is
+    /// handed back afterwards, so a thread pool worker whose job panicked
+    /// while holding deadlock-proof guards can carry on running jobs.
+    ///
+    /// Unwinding drops the guards `f` held, releasing their mutices and
+    /// returning the token to the thread, from which it's reclaimed here.
+    /// The mutices are poisoned as usual; see
+    /// [`DeadlockProofMutex::clear_poison`]. Panics if the permission, or a
+    /// nested permission derived from it, is still in use once `f` has
+    /// finished, for instance because `f` returned or leaked it, since then
+    /// it can't safely be handed back.
+    pub fn catch_unwind<R>(
+        self,
+        f: impl FnOnce(Self) -> R + std::panic::UnwindSafe,
+    ) -> (std::thread::Result<R>, Self) {
+        // The permission carries no data which a panic could leave broken.
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || f(self)));
+        let permission = Self::try_get_for_domain().expect(
+            "Mutex permission was leaked by a closure passed to OuterMutexPermission::catch_unwind",
+        );
+        (result, permission)
+    }
+
+    /// The number of nested permissions derived from this domain's token
+    /// which are alive in this thread.
+    fn live_nested() -> usize {
+        if cfg!(deadlock_proof_mutex_unverified) {
+            0
+        } else if domain::is_default::<D>() {
+            LIVE_NESTED_PERMISSIONS.with(|live| live.get())
+        } else {
+            domain::live_nested::<D>()
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<D: PermissionDomain> Drop for OuterMutexPermission<D> {
+    fn drop(&mut self) {
+        if cfg!(deadlock_proof_mutex_unverified) {
+            return;
+        }
+        if !domain::is_default::<D>() {
+            domain::return_token::<D>();
+            return;
+        }
+        // If the thread-local has already been destroyed, this thread is
+        // exiting and nobody can claim the token again anyway.
+        let _ = MUTEX_PERMISSION_TOKEN.try_with(|thingref| {
+            // There can't already be a token in the slot, but if there were
+            // we mustn't recursively drop it.
+            if let Some(existing) =
+                thingref.replace(Some(OuterMutexPermission(PhantomData, PhantomData)))
+            {
+                core::mem::forget(existing);
+            }
+        });
     }
 }
 
 /// Permission to claim some nested mutex. This can be obtained from
 /// [`DeadlockProofMutex::lock_for_nested`].
 pub struct NestedMutexPermission<P: MutexPermission, I>(
-    PhantomData<Rc<()>>,
+    PhantomData<*const ()>,
     PhantomData<P>,
     PhantomData<I>,
 );
 
-impl<P: MutexPermission, I> MutexPermission for NestedMutexPermission<P, I> {}
+impl<P: MutexPermission, I> NestedMutexPermission<P, I> {
+    fn new() -> Self {
+        // Evaluated when this is instantiated, so an over-deep chain is a
+        // compile error at the point of nesting. This permission can claim
+        // one more mutex on top of those it holds.
+        const {
+            assert!(
+                Self::DEPTH < <P::Domain as PermissionDomain>::MAX_DEPTH,
+                "mutices are nested more deeply than the domain's MAX_DEPTH allows"
+            )
+        };
+        // Without `std` there are no thread-local tokens to withhold, so
+        // nothing is counted.
+        #[cfg(feature = "std")]
+        if cfg!(deadlock_proof_mutex_unverified) {
+            // Nothing is counted, since the tokens are never withheld.
+        } else if domain::is_default::<P::Domain>() {
+            LIVE_NESTED_PERMISSIONS.with(|live| live.set(live.get() + 1));
+        } else {
+            domain::adjust_live_nested::<P::Domain>(true);
+        }
+        Self(PhantomData, PhantomData, PhantomData)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<P: MutexPermission, I> Drop for NestedMutexPermission<P, I> {
+    fn drop(&mut self) {
+        if cfg!(deadlock_proof_mutex_unverified) {
+            // Nothing was counted.
+        } else if domain::is_default::<P::Domain>() {
+            let _ = LIVE_NESTED_PERMISSIONS.try_with(|live| live.set(live.get() - 1));
+        } else {
+            domain::adjust_live_nested::<P::Domain>(false);
+        }
+    }
+}
+
+impl<P: MutexPermission, I> MutexPermission for NestedMutexPermission<P, I> {
+    type Domain = P::Domain;
+    const DEPTH: usize = P::DEPTH + 1;
+}
 
 /// Permission to claim some nested mutex. This can be obtained from
 /// [`DeadlockProofMutex::lock_for_nested`].
-pub struct SequentialMutexPermission<P: MutexPermission, I>(PhantomData<Rc<()>>, P, PhantomData<I>);
+pub struct SequentialMutexPermission<P: MutexPermission, I>(
+    PhantomData<*const ()>,
+    P,
+    PhantomData<I>,
+);
+
+// The raw pointer markers already keep the permissions from being `Send`
+// or `Sync`; on nightly, say so outright, so that the property doesn't rest
+// on a field someone might tidy away.
+#[cfg(feature = "nightly")]
+impl<D: PermissionDomain> !Send for OuterMutexPermission<D> {}
+#[cfg(feature = "nightly")]
+impl<D: PermissionDomain> !Sync for OuterMutexPermission<D> {}
+#[cfg(feature = "nightly")]
+impl<P: MutexPermission, I> !Send for NestedMutexPermission<P, I> {}
+#[cfg(feature = "nightly")]
+impl<P: MutexPermission, I> !Sync for NestedMutexPermission<P, I> {}
+#[cfg(feature = "nightly")]
+impl<P: MutexPermission, I> !Send for SequentialMutexPermission<P, I> {}
+#[cfg(feature = "nightly")]
+impl<P: MutexPermission, I> !Sync for SequentialMutexPermission<P, I> {}
 
 impl<P: MutexPermission, I> SequentialMutexPermission<P, I> {
     fn new(permission: P) -> Self {
@@ -93,19 +936,121 @@ impl<P: MutexPermission, I> SequentialMutexPermission<P, I> {
     pub fn to_earlier(self) -> P {
         self.1
     }
+
+    /// Consumes this sequential permission, and any earlier in the same
+    /// chain, to return the permission token from before the first mutex in
+    /// the sequence, in one call rather than one
+    /// [`SequentialMutexPermission::to_earlier`] per mutex.
+    pub fn to_outermost(self) -> P::Outermost
+    where
+        P: SequentialChain,
+    {
+        self.1.to_outermost()
+    }
+
+    /// Advances past the mutex identified by `J`, the next in the sequence,
+    /// without claiming it, returning the permission to claim the mutex
+    /// after that. This is just what claiming the mutex and then calling
+    /// [`DeadlockProofMutexGuard::unlock_for_sequential`] would return, and
+    /// since skipping a mutex holds nothing, it can't introduce a cycle. A
+    /// sequence of mutices `A`, `B`, `C` can then claim `A` and `C` alone:
+    /// `guard_a.unlock_for_sequential().skip::<B>()` claims `C`.
+    pub fn skip<J>(self) -> SequentialMutexPermission<Self, J> {
+        SequentialMutexPermission::new(self)
+    }
+}
+
+impl<P: MutexPermission, I> MutexPermission for SequentialMutexPermission<P, I> {
+    type Domain = P::Domain;
+    // The mutex it follows has already been released.
+    const DEPTH: usize = P::DEPTH;
+}
+
+/// A permission which may be a chain of [`SequentialMutexPermission`]s,
+/// however long, so code generic over the depth of the chain can get back
+/// to the permission it started from.
+pub trait SequentialChain: MutexPermission {
+    /// The permission from before the first mutex in the chain: the
+    /// permission itself, unless it's a [`SequentialMutexPermission`].
+    type Outermost: MutexPermission<Domain = Self::Domain>;
+
+    /// Collapses the whole chain, returning the permission it started from.
+    fn to_outermost(self) -> Self::Outermost;
+}
+
+impl<D: PermissionDomain> SequentialChain for OuterMutexPermission<D> {
+    type Outermost = Self;
+
+    fn to_outermost(self) -> Self {
+        self
+    }
+}
+
+impl<P: MutexPermission> SequentialChain for &mut P {
+    type Outermost = Self;
+
+    fn to_outermost(self) -> Self {
+        self
+    }
+}
+
+impl<P: MutexPermission, I> SequentialChain for NestedMutexPermission<P, I> {
+    type Outermost = Self;
+
+    fn to_outermost(self) -> Self {
+        self
+    }
 }
 
-impl<P: MutexPermission, I> MutexPermission for SequentialMutexPermission<P, I> {}
+impl<P: SequentialChain, I> SequentialChain for SequentialMutexPermission<P, I> {
+    type Outermost = P::Outermost;
+
+    fn to_outermost(self) -> P::Outermost {
+        self.1.to_outermost()
+    }
+}
+
+/// Parameterizes a mutex over its permission type `P` without taking on
+/// `P`'s auto traits. The permission tokens mustn't be sent between
+/// threads, but the mutices claimed with them must be, and a function
+/// pointer is `Send` and `Sync` whatever it returns, so this needs no
+/// `unsafe impl`. It's covariant in `P`, as `P` itself would be.
+type PermissionPhantom<P> = PhantomData<fn() -> P>;
+
+/// Parameterizes a mutex over its contents `T`, which may be unsized, and
+/// its identifier `I`. The contents are only reached through the underlying
+/// mutex, so `T` is likewise named only through a function pointer, leaving
+/// it to the underlying mutex to decide whether the whole is `Send` or
+/// `Sync`, as a `Mutex<dyn Trait + Send>` is.
+type ContentsPhantom<T, I> = PhantomData<(fn() -> PhantomData<T>, I)>;
 
-struct PermissionSyncSendWrapper<P: MutexPermission>(P);
+/// A guard from [`DeadlockProofMutex::lock_for_nested`] or
+/// [`DeadlockProofMutex::try_lock_for_nested`], plus the permission required
+/// to claim the next mutex in the nesting.
+pub type NestedLock<'a, T, P, I, M = Mutex<T>> = (
+    DeadlockProofNestedMutexGuard<'a, T, P, I, M>,
+    NestedMutexPermission<P, I>,
+);
+
+/// The result of [`DeadlockProofMutex::lock_for_nested`]: a guard plus the
+/// permission required to claim the next mutex in the nesting.
+pub type NestedLockResult<'a, T, P, I, M = Mutex<T>> = LockResult<NestedLock<'a, T, P, I, M>>;
+
+/// The result of [`DeadlockProofMutex::with_lock`] and
+/// [`DeadlockProofMutex::lock_and_then`]: the closure's result plus the
+/// permission.
+pub type WithLockResult<'a, R, T, P, I, M = Mutex<T>> =
+    Result<(R, P), PoisonError<DeadlockProofMutexGuard<'a, T, P, I, M>>>;
 
-/// Unsafety: these types are only ever used within `PhantomData` and not
-/// exposed beyond this mod, so this is not semantically important.
-/// We need to do this because these permission tokens must not themselves
-/// be sent between threads (we carefully ensure they're not `Send`) but
-/// the mutex needs to be parameterized over this permission type.
-unsafe impl<P: MutexPermission> Send for PermissionSyncSendWrapper<P> {}
-unsafe impl<P: MutexPermission> Sync for PermissionSyncSendWrapper<P> {}
+/// The result of [`DeadlockProofMutex::with_lock_nested`]: the closure's
+/// result plus the permission.
+pub type WithLockNestedResult<'a, R, T, P, I, M = Mutex<T>> = Result<
+    (R, P),
+    PoisonError<(
+        DeadlockProofNestedMutexGuard<'a, T, P, I, M>,
+        NestedMutexPermission<P, I>,
+    )>,
+>;
 
 /// A mutex which is compile-time guaranteed not to deadlock.
 /// Otherwise identical to [`Mutex`], though at the moment only a subset
@@ -128,11 +1073,102 @@ unsafe impl<P: MutexPermission> Sync for PermissionSyncSendWrapper<P> {}
 /// The type system guarantees that all threads claim mutices in the same way
 /// according to the above patterns, as long as each mutex has a unique
 /// type type passed as the second parameter to its constructor.
-pub struct DeadlockProofMutex<T, P: MutexPermission, I>(
-    Mutex<T>,
-    PhantomData<PermissionSyncSendWrapper<P>>,
-    PhantomData<I>,
-);
+///
+/// The final type parameter is the ordinary mutex used underneath, which
+/// is a [`Mutex`] unless the mutex is created using
+/// [`DeadlockProofMutex::from_backend`].
+///
+/// Like [`Mutex`], it's [`UnwindSafe`] and [`RefUnwindSafe`] whatever its
+/// contents, because a panic while it's held poisons it, so long as the
+/// underlying mutex is too. Mutices without poisoning, such as
+/// `parking_lot::Mutex`, aren't, and nor are deadlock-proof mutices using
+/// them. The guards and permission tokens are unwind-safe as well.
+///
+/// With the default backend the contents may be unsized, such as a trait
+/// object, as for `Mutex<dyn Handler>`. Such a mutex can't be created
+/// directly: create a boxed one with sized contents and convert it using
+/// [`unsize_deadlock_proof_mutex`]. Other backends need sized contents; to
+/// protect a trait object with one, protect a box of it instead, as in
+/// `DeadlockProofMutex<Box<dyn Handler>, P, I, M>`.
+///
+/// Unless one of the `diagnostics`, `observer`, `priority-inversion`,
+/// `runtime-checks`, `stats` or `violation-override` features is enabled,
+/// or `identifier-check` in a debug build, which each store extra
+/// information within every mutex, a deadlock-proof mutex is `#[repr(transparent)]` over its
+/// underlying mutex, so it has exactly the same size and layout, and all
+/// the checks cost nothing at runtime.
+#[cfg_attr(
+    not(any(
+        feature = "diagnostics",
+        all(feature = "identifier-check", debug_assertions),
+        feature = "observer",
+        feature = "priority-inversion",
+        feature = "runtime-checks",
+        feature = "stats",
+        feature = "violation-override"
+    )),
+    repr(transparent)
+)]
+// Otherwise its layout still mustn't depend on the permission type, so that
+// `DeadlockProofMutex::lock_borrowing` can give its guards a reference to it.
+#[cfg_attr(
+    any(
+        feature = "diagnostics",
+        all(feature = "identifier-check", debug_assertions),
+        feature = "observer",
+        feature = "priority-inversion",
+        feature = "runtime-checks",
+        feature = "stats",
+        feature = "violation-override"
+    ),
+    repr(C)
+)]
+pub struct DeadlockProofMutex<
+    T: ?Sized,
+    P: MutexPermission,
+    I,
+    M: MutexBackend<T> + ?Sized = Mutex<T>,
+>(Monitor, PermissionPhantom<P>, ContentsPhantom<T, I>, M);
+
+// The contents are only reached through the underlying mutex, so it alone
+// decides, rather than the `PhantomData` above.
+impl<T: ?Sized, P: MutexPermission, I, M: MutexBackend<T> + UnwindSafe + ?Sized> UnwindSafe
+    for DeadlockProofMutex<T, P, I, M>
+{
+}
+
+impl<T: ?Sized, P: MutexPermission, I, M: MutexBackend<T> + RefUnwindSafe + ?Sized> RefUnwindSafe
+    for DeadlockProofMutex<T, P, I, M>
+{
+}
+
+// The layout promised above, checked for a few representative contents.
+#[cfg(not(any(
+    feature = "diagnostics",
+    all(feature = "identifier-check", debug_assertions),
+    feature = "observer",
+    feature = "priority-inversion",
+    feature = "runtime-checks",
+    feature = "stats",
+    feature = "violation-override"
+)))]
+const _: () = {
+    use core::mem::{align_of, size_of};
+    struct Id;
+    assert!(
+        size_of::<DeadlockProofMutex<(), OuterMutexPermission, Id>>() == size_of::<Mutex<()>>()
+    );
+    assert!(
+        size_of::<DeadlockProofMutex<u64, OuterMutexPermission, Id>>() == size_of::<Mutex<u64>>()
+    );
+    assert!(
+        align_of::<DeadlockProofMutex<u64, OuterMutexPermission, Id>>() == align_of::<Mutex<u64>>()
+    );
+    assert!(
+        size_of::<DeadlockProofMutex<[u8; 3], NestedMutexPermission<OuterMutexPermission, Id>, Id>>(
+        ) == size_of::<Mutex<[u8; 3]>>()
+    );
+};
 
 impl<T, P: MutexPermission, I> DeadlockProofMutex<T, P, I> {
     /// Create a new deadlock-proof mutex.
@@ -141,57 +1177,751 @@ impl<T, P: MutexPermission, I> DeadlockProofMutex<T, P, I> {
     /// matter what it is - it's just used by the type system uniquely to
     /// identify this mutex. A good way to create a unique type is with the
     /// [`unique_type`] macro.
-    pub fn new(content: T, _identifier: I) -> Self {
-        Self(Mutex::new(content), PhantomData, PhantomData)
+    ///
+    /// Every way of creating a mutex consumes an identifier value, so an
+    /// identifier whose only value is created in one place belongs to one
+    /// mutex: those declared by [`declare_static_deadlock_proof_mutex`] and
+    /// [`declare_lazy_deadlock_proof_mutex`] are like that, and creating any
+    /// other mutex with one fails to compile. Other identifiers can be
+    /// shared, since the code creating a mutex may run any number of times.
+    /// This doesn't put deadlock-freedom at risk: a permission
+    /// derived from a mutex always has a type strictly containing that
+    /// mutex's own permission type, so no two mutices can ever be claimed
+    /// in both orders whatever their identifiers. What sharing an
+    /// identifier costs is precision: a [`NestedMutexPermission`] from
+    /// either mutex claims the mutices nested within both, and diagnostics
+    /// name both the same. [`make_mutex`] gives every mutex an identifier of
+    /// its own. With the `identifier-check` feature, debug builds report a
+    /// [`Violation`] if a mutex is created here or by
+    /// [`DeadlockProofMutex::from_backend`] while another with the same
+    /// identifier still exists.
+    pub fn new(content: T, identifier: I) -> Self {
+        Self::from_backend(Mutex::new(content), identifier)
+    }
+
+    /// Create a new deadlock-proof mutex in a `const` context, such as the
+    /// initializer of a `static`. The identifier is just as for
+    /// [`DeadlockProofMutex::new`], but isn't checked by the
+    /// `identifier-check` feature. See
+    /// [`declare_static_deadlock_proof_mutex`] for an easy way to do this for
+    /// a static.
+    pub const fn const_new(content: T, identifier: I) -> Self {
+        Self::const_from_backend(Mutex::new(content), identifier)
+    }
+
+    /// Consumes this mutex, returning the plain [`Mutex`] underneath, with
+    /// its contents and poisoning intact, for handing to code which insists
+    /// on a standard library mutex, such as while migrating to deadlock-proof
+    /// mutices bit by bit. Whatever claims it from then on does so without
+    /// a permission, so nothing proves it can't deadlock.
+    #[cfg(feature = "std")]
+    pub fn into_std(self) -> Mutex<T> {
+        self.3
+    }
+
+    /// Borrows the plain [`Mutex`] underneath, for code which needs a
+    /// `&Mutex<T>`, as [`DeadlockProofMutex::into_std`] does. Claiming it
+    /// this way bypasses the permission tokens altogether, and any runtime
+    /// checks, so a thread holding it may deadlock with one claiming it
+    /// through this deadlock-proof mutex: only use it where something else
+    /// ensures that can't happen.
+    #[cfg(feature = "std")]
+    pub fn as_std_unchecked(&self) -> &Mutex<T> {
+        &self.3
+    }
+
+    /// Converts a boxed mutex to one with unsized contents, for
+    /// [`unsize_deadlock_proof_mutex`].
+    ///
+    /// # Safety
+    ///
+    /// `unsize` must return the pointer it's given, unsized by coercion.
+    #[doc(hidden)]
+    #[cfg(feature = "std")]
+    pub unsafe fn __unsize_box<U: ?Sized>(
+        self: Box<Self>,
+        unsize: fn(*const Mutex<T>) -> *const Mutex<U>,
+    ) -> Box<DeadlockProofMutex<U, P, I>> {
+        // The backend is the last field and the mutex is either
+        // `repr(transparent)` or `repr(C)`, so the unsized mutex is laid out
+        // just as the sized one is, and the pointer metadata describing the
+        // contents describes the whole mutex too. The pointer is to the
+        // whole mutex rather than its backend, but it's never read through,
+        // so only the metadata the coercion adds matters.
+        let mutex = unsize(Box::into_raw(self).cast::<Mutex<T>>().cast_const());
+        // Safety: as above, given that `unsize` only coerced the pointer.
+        unsafe { Box::from_raw(mutex.cast_mut() as *mut DeadlockProofMutex<U, P, I>) }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T, P: MutexPermission, I> DeadlockProofMutex<T, P, I, Arc<Mutex<T>>> {
+    /// Create a deadlock-proof mutex sharing an existing mutex, for code
+    /// converted to deadlock-proof mutices while other code, not yet
+    /// converted, keeps its own `Arc` and claims the mutex directly. The
+    /// `_identifier` is a type unique to this mutex, just as for
+    /// [`DeadlockProofMutex::new`].
+    ///
+    /// Claims made directly on the shared mutex need no permission, so as
+    /// with [`DeadlockProofMutex::lock_unchecked`] the proof that the
+    /// program can't deadlock no longer covers this mutex: until the rest of
+    /// the code is converted, it must be sure by other means that it claims
+    /// the mutex in a consistent order with any others. The `runtime-checks`
+    /// feature doesn't see those claims either. Getting the contents back
+    /// using [`DeadlockProofMutex::into_inner`] or
+    /// [`DeadlockProofMutex::get_mut`] panics while the mutex is still
+    /// shared.
+    pub fn adopt(existing: Arc<Mutex<T>>, identifier: I) -> Self {
+        Self::from_backend(existing, identifier)
+    }
+}
+
+impl<T: Default, P: MutexPermission, I: Default> Default for DeadlockProofMutex<T, P, I> {
+    /// Creates a mutex containing the default value, identified by the
+    /// default value of the identifier type.
+    fn default() -> Self {
+        Self::new(T::default(), I::default())
+    }
+}
+
+impl<T, P: MutexPermission, I: Default> From<T> for DeadlockProofMutex<T, P, I> {
+    /// Creates a mutex containing `content`, identified by the default value
+    /// of the identifier type.
+    fn from(content: T) -> Self {
+        Self::new(content, I::default())
+    }
+}
+
+impl<T: fmt::Debug + ?Sized, P: MutexPermission, I, M: MutexBackend<T> + ?Sized> fmt::Debug
+    for DeadlockProofMutex<T, P, I, M>
+{
+    /// Shows the contents, unless the mutex is locked, as [`Mutex`] does.
+    /// This doesn't need a permission, since it never blocks.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("DeadlockProofMutex");
+        d.field("identifier", &IdentifierName::of::<I>());
+        match self.3.try_lock() {
+            Ok(guard) => d.field("data", &&*guard).field("poisoned", &false),
+            Err(TryLockError::Poisoned(e)) => {
+                d.field("data", &&**e.get_ref()).field("poisoned", &true)
+            }
+            Err(TryLockError::WouldBlock) => d.field("data", &format_args!("<locked>")),
+        };
+        d.finish_non_exhaustive()
+    }
+}
+
+impl<T, P: MutexPermission, I, M: MutexBackend<T>> DeadlockProofMutex<T, P, I, M> {
+    /// Create a new deadlock-proof mutex wrapping some other kind of mutex,
+    /// such as a `parking_lot::Mutex` if the `parking_lot` feature is
+    /// enabled, or any `lock_api::Mutex` with the `lock_api` feature. The `_identifier` is a type unique to this mutex, just as
+    /// for [`DeadlockProofMutex::new`].
+    pub fn from_backend(mutex: M, _identifier: I) -> Self {
+        let mut mutex = Self::unidentified(mutex);
+        mutex.0.register::<I>();
+        mutex
+    }
+
+    /// Create a new deadlock-proof mutex wrapping some other kind of mutex,
+    /// as [`DeadlockProofMutex::from_backend`] does, but in a `const`
+    /// context, as for [`DeadlockProofMutex::const_new`].
+    pub const fn const_from_backend(mutex: M, identifier: I) -> Self {
+        // An identifier is only a marker, so there's nothing to drop.
+        core::mem::forget(identifier);
+        Self::unidentified(mutex)
+    }
+
+    /// Create a new deadlock-proof mutex for which the caller has consumed
+    /// an identifier value, or shares one by design, as the mutices at one
+    /// level of a [`LockHierarchy`] do.
+    pub(crate) const fn unidentified(mutex: M) -> Self {
+        Self(Monitor::new(), PhantomData, PhantomData, mutex)
+    }
+}
+
+impl<T: ?Sized, P: MutexPermission, I, M: MutexBackend<T> + ?Sized> DeadlockProofMutex<T, P, I, M> {
+    /// Wraps a guard for the underlying mutex as a deadlock-proof one.
+    fn guard<'a>(
+        &'a self,
+        guard: M::Guard<'a>,
+        permission: P,
+    ) -> DeadlockProofMutexGuard<'a, T, P, I, M> {
+        let mut held = self.0.held::<I, P::Domain, _>(&self.3);
+        self.0.arm::<I>(&mut held);
+        DeadlockProofMutexGuard(guard, permission, self, held)
+    }
+
+    /// Wraps a guard for the underlying mutex as a deadlock-proof nested
+    /// one, along with the permission to claim the mutices nested within.
+    fn nested_guard<'a>(
+        &'a self,
+        guard: M::Guard<'a>,
+        permission: P,
+    ) -> NestedLock<'a, T, P, I, M> {
+        let mut held = self.0.held::<I, P::Domain, _>(&self.3);
+        self.0.arm::<I>(&mut held);
+        (
+            DeadlockProofNestedMutexGuard(guard, permission, self, held),
+            NestedMutexPermission::new(),
+        )
     }
 
     /// Acquires this mutex, blocking the current thread until it
     /// is able to do so. Similar to [`Mutex::lock`], but requires a permission
     /// token to prove that you can't be causing a deadlock.
-    pub fn lock(
+    ///
+    /// If another thread panicked while holding the mutex, the error still
+    /// contains a deadlock-proof guard, so the mutex's contents can be
+    /// recovered and the permission regained by unlocking it.
+    pub fn lock(&self, permission: P) -> LockResult<DeadlockProofMutexGuard<'_, T, P, I, M>> {
+        map_lock_result(self.0.lock::<T, I, P::Domain, M>(&self.3), |guard| {
+            self.guard(guard, permission)
+        })
+    }
+
+    /// Acquires this mutex, blocking the current thread until it is able to
+    /// do so, as [`DeadlockProofMutex::lock`] does, but returns the guard
+    /// whether or not another thread panicked while holding the mutex. The
+    /// mutex stays poisoned; see [`DeadlockProofMutex::clear_poison`]. This
+    /// is the same as using [`IgnorePoison`] on the result.
+    pub fn lock_ignore_poison(&self, permission: P) -> DeadlockProofMutexGuard<'_, T, P, I, M> {
+        self.lock(permission).ignore_poison()
+    }
+
+    /// Acquires this mutex, blocking the current thread until it is able to
+    /// do so. Unlike [`DeadlockProofMutex::lock`], the permission is only
+    /// borrowed for as long as the guard exists, so there's no need to
+    /// unlock the guard to get it back. This suits code which returns early,
+    /// for example using `?`, which would otherwise lose the permission.
+    pub fn lock_borrowing<'p>(
         &self,
-        permission: P,
-    ) -> Result<DeadlockProofMutexGuard<T, P, I>, PoisonError<MutexGuard<T>>> {
-        self.0
-            .lock()
-            .map(|guard| DeadlockProofMutexGuard(guard, permission, PhantomData))
+        permission: &'p mut P,
+    ) -> LockResult<DeadlockProofMutexGuard<'_, T, &'p mut P, I, M>> {
+        // Safety: the permission type only appears within `PhantomData`, and
+        // the mutex is either `repr(transparent)` or `repr(C)`, so the
+        // layout is the same whichever permission the mutex is viewed with.
+        let mutex =
+            unsafe { &*(self as *const Self as *const DeadlockProofMutex<T, &'p mut P, I, M>) };
+        map_lock_result(self.0.lock::<T, I, P::Domain, M>(&self.3), |guard| {
+            DeadlockProofMutexGuard(
+                guard,
+                permission,
+                mutex,
+                self.0.held::<I, P::Domain, _>(&self.3),
+            )
+        })
+    }
+
+    /// Temporarily releases this mutex, which `guard` holds, while `f` runs,
+    /// then reclaims it, as parking_lot's `MutexGuard::unlocked` does. This
+    /// suits a long critical section which occasionally needs to call out,
+    /// without restructuring it. `f` is lent the guard's permission, so it
+    /// can claim whatever the mutex itself could have been claimed alongside,
+    /// but nothing it claims can outlive it.
+    ///
+    /// The mutex is reclaimed even if `f` panics. If another thread
+    /// poisoned it in the meantime, the guard carries on regardless; see
+    /// [`DeadlockProofMutex::is_poisoned`]. Panics if `guard` isn't a guard
+    /// for this mutex.
+    #[cfg(feature = "std")]
+    pub fn unlocked<'a, R>(
+        &'a self,
+        guard: &mut DeadlockProofMutexGuard<'a, T, P, I, M>,
+        f: impl FnOnce(&mut P) -> R,
+    ) -> R {
+        /// Puts a fresh guard in place once the closure has finished, even
+        /// if it panicked.
+        struct Relock<'a, 'g, T: ?Sized, P: MutexPermission, I, M: MutexBackend<T> + ?Sized> {
+            mutex: &'a DeadlockProofMutex<T, P, I, M>,
+            guard: &'g mut DeadlockProofMutexGuard<'a, T, P, I, M>,
+            permission: Option<P>,
+        }
+
+        impl<T: ?Sized, P: MutexPermission, I, M: MutexBackend<T> + ?Sized> Drop
+            for Relock<'_, '_, T, P, I, M>
+        {
+            fn drop(&mut self) {
+                let permission = self.permission.take().unwrap();
+                // The old guard has already been dropped, so there's no
+                // guard for the caller to unwind with if this panics, for
+                // instance because a runtime check failed.
+                let guard = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    self.mutex
+                        .lock(permission)
+                        .unwrap_or_else(PoisonError::into_inner)
+                }))
+                .unwrap_or_else(|_| std::process::abort());
+                // Safety: the old guard was moved out by `unlocked`.
+                unsafe { core::ptr::write(self.guard, guard) };
+            }
+        }
+
+        assert_eq!(
+            guard.3.key(),
+            waiters::key(&self.3),
+            "DeadlockProofMutex::unlocked was given a guard for a different mutex"
+        );
+        // Safety: a new guard is written back by `Relock` before the
+        // borrow of `guard` ends, whether or not `f` panics, and nothing
+        // reads it in the meantime.
+        let DeadlockProofMutexGuard(inner, permission, _, mut held) =
+            unsafe { core::ptr::read(guard) };
+        drop(inner);
+        held.disarm();
+        drop(held);
+        let mut relock = Relock {
+            mutex: self,
+            guard,
+            permission: Some(permission),
+        };
+        f(relock.permission.as_mut().unwrap())
     }
 
     /// Acquires this mutex, blocking the current thread until it
     /// is able to do so. Provides a token which can be used to claim a
-    /// nested mutex.
-    pub fn lock_for_nested(
+    /// nested mutex. Poisoning is reported as for [`DeadlockProofMutex::lock`].
+    /// To hold several nested mutices at once, declare them as siblings
+    /// using [`declare_children`].
+    pub fn lock_for_nested(&self, permission: P) -> NestedLockResult<'_, T, P, I, M> {
+        map_lock_result(self.0.lock::<T, I, P::Domain, M>(&self.3), |guard| {
+            self.nested_guard(guard, permission)
+        })
+    }
+
+    /// Attempts to acquire this mutex without blocking, providing a token
+    /// which can be used to claim a nested mutex, as
+    /// [`DeadlockProofMutex::lock_for_nested`] does. If the mutex is held
+    /// elsewhere, the permission is handed back within the error, as for
+    /// [`DeadlockProofMutex::try_lock`], so a thread holding a parent mutex
+    /// can opportunistically claim a child without blocking while it does.
+    pub fn try_lock_for_nested(&self, permission: P) -> TryNestedLockResult<'_, T, P, I, M> {
+        match self.0.try_lock::<T, I, M>(&self.3) {
+            Ok(guard) => Ok(self.nested_guard(guard, permission)),
+            Err(TryLockError::Poisoned(e)) => Err(DeadlockProofTryNestedLockError::Poisoned(
+                PoisonError::new(self.nested_guard(e.into_inner(), permission)),
+            )),
+            Err(TryLockError::WouldBlock) => {
+                Err(DeadlockProofTryNestedLockError::WouldBlock(permission))
+            }
+        }
+    }
+
+    /// Acquires this mutex, runs `f` on its contents, then unlocks it again,
+    /// returning the result of `f` along with the permission. This saves
+    /// the bookkeeping of unlocking the guard for simple critical sections.
+    /// If the mutex is poisoned, `f` isn't run, and the error contains a
+    /// deadlock-proof guard as for [`DeadlockProofMutex::lock`].
+    pub fn with_lock<R>(
         &self,
         permission: P,
-    ) -> Result<
-        (
-            DeadlockProofNestedMutexGuard<T, P, I>,
-            NestedMutexPermission<P, I>,
-        ),
-        PoisonError<MutexGuard<T>>,
-    > {
-        self.0.lock().map(|guard| {
-            (
-                DeadlockProofNestedMutexGuard(guard, permission, PhantomData),
-                NestedMutexPermission(PhantomData, PhantomData, PhantomData),
-            )
-        })
+        f: impl FnOnce(&mut T) -> R,
+    ) -> WithLockResult<'_, R, T, P, I, M> {
+        let mut guard = self.lock(permission)?;
+        let result = f(&mut guard);
+        Ok((result, guard.unlock()))
+    }
+
+    /// Acquires this mutex, runs `f` on its contents and unlocks it again,
+    /// as [`DeadlockProofMutex::with_lock`] does, for chaining critical
+    /// sections in one expression: since the permission comes back
+    /// alongside the result, it can be handed straight on to the next, as
+    /// in `a.lock_and_then(p, f).ok().and_then(|(x, p)| b.lock_and_then(p, |b| g(b, x)).ok())`.
+    pub fn lock_and_then<R>(
+        &self,
+        permission: P,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> WithLockResult<'_, R, T, P, I, M> {
+        self.with_lock(permission, f)
+    }
+
+    /// Acquires this mutex just long enough to store `value` in it,
+    /// returning the permission. If the mutex is poisoned, `value` is
+    /// dropped rather than stored, and the error contains a deadlock-proof
+    /// guard as for [`DeadlockProofMutex::lock`].
+    pub fn set(
+        &self,
+        permission: P,
+        value: T,
+    ) -> Result<P, PoisonError<DeadlockProofMutexGuard<'_, T, P, I, M>>>
+    where
+        T: Sized,
+    {
+        self.with_lock(permission, |content| *content = value)
+            .map(|((), permission)| permission)
+    }
+
+    /// Acquires this mutex just long enough to store `value` in it,
+    /// returning the previous contents along with the permission. Poisoning
+    /// is reported as for [`DeadlockProofMutex::set`].
+    pub fn replace(&self, permission: P, value: T) -> WithLockResult<'_, T, T, P, I, M>
+    where
+        T: Sized,
+    {
+        self.with_lock(permission, |content| core::mem::replace(content, value))
+    }
+
+    /// Acquires this mutex just long enough to clone its contents, and
+    /// returns the copy along with the permission, for reading a snapshot
+    /// of shared state in one call. Poisoning is reported as for
+    /// [`DeadlockProofMutex::lock`].
+    pub fn get_cloned(&self, permission: P) -> WithLockResult<'_, T, T, P, I, M>
+    where
+        T: Clone,
+    {
+        self.with_lock(permission, |content| content.clone())
+    }
+
+    /// Acquires this mutex just long enough to take its contents, leaving
+    /// the default value in their place, and returns them along with the
+    /// permission. Poisoning is reported as for [`DeadlockProofMutex::lock`].
+    pub fn take(&self, permission: P) -> WithLockResult<'_, T, T, P, I, M>
+    where
+        T: Default,
+    {
+        self.with_lock(permission, core::mem::take)
+    }
+
+    /// Equivalent to [`DeadlockProofMutex::with_lock`], except that `f` is
+    /// also given the permission to claim nested mutices, which it may
+    /// use with [`DeadlockProofMutex::lock_borrowing`].
+    pub fn with_lock_nested<R>(
+        &self,
+        permission: P,
+        f: impl FnOnce(&mut T, &mut NestedMutexPermission<P, I>) -> R,
+    ) -> WithLockNestedResult<'_, R, T, P, I, M> {
+        let (mut guard, mut nested) = self.lock_for_nested(permission)?;
+        let result = f(&mut guard, &mut nested);
+        Ok((result, guard.unlock(nested)))
+    }
+
+    /// Attempts to acquire this mutex without blocking. Similar to
+    /// [`Mutex::try_lock`], except that if the mutex is held elsewhere the
+    /// permission token is handed back within the error, so that the thread
+    /// can fall back to doing something else with it.
+    pub fn try_lock(&self, permission: P) -> TryLockResult<'_, T, P, I, M> {
+        match self.0.try_lock::<T, I, M>(&self.3) {
+            Ok(guard) => Ok(self.guard(guard, permission)),
+            Err(TryLockError::Poisoned(e)) => Err(DeadlockProofTryLockError::Poisoned(
+                PoisonError::new(self.guard(e.into_inner(), permission)),
+            )),
+            Err(TryLockError::WouldBlock) => Err(DeadlockProofTryLockError::WouldBlock(permission)),
+        }
+    }
+
+    /// Acquires this mutex once `condition` holds for its contents, blocking
+    /// the current thread until then. This covers the common need to wait
+    /// until a queue is non-empty, or until some state machine reaches a
+    /// certain state, without needing a condvar. The condition is checked
+    /// whenever another thread releases a guard for this mutex.
+    #[cfg(feature = "std")]
+    pub fn lock_when(
+        &self,
+        permission: P,
+        mut condition: impl FnMut(&T) -> bool,
+    ) -> LockResult<DeadlockProofMutexGuard<'_, T, P, I, M>> {
+        // Only the claim which is handed out counts in the statistics.
+        let start = Instant::now();
+        let mut guard = self.0.lock_unrecorded::<T, I, P::Domain, M>(&self.3);
+        loop {
+            match guard {
+                Ok(g) if !condition(&g) => {
+                    let _registration = waiters::register(waiters::key(&self.3));
+                    drop(g);
+                    self.0.released(&self.3);
+                    std::thread::park();
+                    guard = self.0.lock_unrecorded::<T, I, P::Domain, M>(&self.3);
+                }
+                result => {
+                    self.0.record_acquisition(start);
+                    return map_lock_result(result, |g| self.guard(g, permission));
+                }
+            }
+        }
+    }
+
+    /// Attempts to acquire this mutex by spinning until `deadline`, never
+    /// blocking in the operating system. Intended for realtime threads such
+    /// as audio callbacks, which must degrade gracefully rather than stall:
+    /// if the deadline passes, the permission is handed back within the error
+    /// so the thread can carry on without the lock.
+    ///
+    /// On mainstream platforms neither claiming an uncontended mutex nor
+    /// reading the clock involves a system call, so neither does this.
+    /// Deadline misses are counted in `DeadlockProofMutex::stats` if the
+    /// `stats` feature is enabled.
+    #[cfg(feature = "std")]
+    pub fn try_lock_until(
+        &self,
+        permission: P,
+        deadline: Instant,
+    ) -> TryLockUntilResult<'_, T, P, I, M> {
+        let start = Instant::now();
+        loop {
+            match self.0.try_lock_since::<T, I, M>(&self.3, start) {
+                Ok(guard) => return Ok(self.guard(guard, permission)),
+                Err(TryLockError::Poisoned(e)) => {
+                    return Err(TryLockUntilError::Poisoned(PoisonError::new(
+                        self.guard(e.into_inner(), permission),
+                    )))
+                }
+                Err(TryLockError::WouldBlock) => {
+                    if Instant::now() >= deadline {
+                        self.0.record_deadline_miss();
+                        return Err(TryLockUntilError::DeadlineMissed(permission));
+                    }
+                    core::hint::spin_loop();
+                }
+            }
+        }
+    }
+
+    /// Attempts to acquire this mutex, blocking the current thread for at
+    /// most `timeout`. If the mutex couldn't be claimed in that time, the
+    /// permission is handed back within the error so the thread can carry
+    /// on without the lock. Unlike [`DeadlockProofMutex::try_lock_until`],
+    /// this sleeps rather than spinning while it waits.
+    #[cfg(feature = "std")]
+    pub fn try_lock_for(
+        &self,
+        permission: P,
+        timeout: Duration,
+    ) -> TryLockUntilResult<'_, T, P, I, M> {
+        let start = Instant::now();
+        let deadline = start + timeout;
+        let mut registration = None;
+        loop {
+            match self.0.try_lock_since::<T, I, M>(&self.3, start) {
+                Ok(guard) => return Ok(self.guard(guard, permission)),
+                Err(TryLockError::Poisoned(e)) => {
+                    return Err(TryLockUntilError::Poisoned(PoisonError::new(
+                        self.guard(e.into_inner(), permission),
+                    )))
+                }
+                Err(TryLockError::WouldBlock) => match registration {
+                    // Register to be woken before trying again, so that a
+                    // release in between isn't missed.
+                    None => registration = Some(waiters::register(waiters::key(&self.3))),
+                    Some(_) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            return Err(TryLockUntilError::DeadlineMissed(permission));
+                        }
+                        std::thread::park_timeout(deadline - now);
+                    }
+                },
+            }
+        }
+    }
+
+    /// Returns a mutable reference to the contents. Since this borrows the
+    /// mutex mutably, no locking is needed, and so no permission either.
+    /// Similar to [`Mutex::get_mut`], this reports whether the mutex is
+    /// poisoned.
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.3.get_mut()
+    }
+
+    /// Consumes this mutex, returning its contents. No permission is needed
+    /// since nobody else can be holding the mutex. Similar to
+    /// [`Mutex::into_inner`], this reports whether the mutex is poisoned.
+    pub fn into_inner(self) -> LockResult<T>
+    where
+        T: Sized,
+        M: Sized,
+    {
+        self.3.into_inner()
+    }
+
+    /// Returns a raw pointer to the contents, for handing to foreign code
+    /// which is synchronized by other means, such as a C library which only
+    /// ever invokes its callbacks on one thread. The mutex is claimed
+    /// momentarily to find the contents, so this borrows a permission, as
+    /// for [`DeadlockProofMutex::lock_borrowing`]. The mutex is released
+    /// again before this returns, even if poisoned.
+    ///
+    /// Obtaining the pointer is safe, but using it isn't. The pointer stays
+    /// valid until the mutex is next claimed, moved or dropped, so code
+    /// which also claims the mutex normally must fetch a fresh pointer
+    /// afterwards. The contents may only be accessed through it while
+    /// nothing else is accessing them through another copy of the pointer.
+    /// Neither the mutex nor the type system can check this, so
+    /// accessing the contents this way also voids the proof that the
+    /// program can't deadlock for whatever synchronization the foreign code
+    /// uses instead.
+    pub fn data_ptr(&self, permission: &mut P) -> *mut T {
+        let mut guard = self
+            .lock_borrowing(permission)
+            .unwrap_or_else(PoisonError::into_inner);
+        core::ptr::from_mut(&mut *guard)
+    }
+
+    /// Whether another thread currently holds the mutex. This never blocks,
+    /// so needs no permission, but the answer may be out of date as soon as
+    /// it's returned: it's a hint for scheduling decisions, not a promise
+    /// that claiming the mutex won't wait.
+    pub fn is_locked(&self) -> bool {
+        matches!(self.3.try_lock(), Err(TryLockError::WouldBlock))
+    }
+
+    /// Whether the mutex is poisoned, as for [`Mutex::is_poisoned`]. This
+    /// doesn't claim the mutex, so needs no permission. Backends which are
+    /// never poisoned always return `false`.
+    pub fn is_poisoned(&self) -> bool {
+        self.3.is_poisoned()
+    }
+
+    /// Clears the poisoned state of the mutex, as for
+    /// [`Mutex::clear_poison`], once whatever a panicking thread left behind
+    /// has been put right. This doesn't claim the mutex, so needs no
+    /// permission.
+    pub fn clear_poison(&self) {
+        self.3.clear_poison()
+    }
+
+    /// Gives this mutex a name, to be reported by [`dump_held_locks`]
+    /// alongside its identifier.
+    #[cfg(feature = "diagnostics")]
+    pub fn set_name(&self, name: &'static str) {
+        self.0.name.set(name)
+    }
+
+    /// Sets what happens when a runtime check finds a violation involving
+    /// this mutex, such as claiming it out of order or dropping its guard,
+    /// overriding [`set_violation_action`].
+    #[cfg(feature = "violation-override")]
+    pub fn set_violation_action(&self, action: ViolationAction) {
+        self.0.action.set(action)
+    }
+
+    /// Installs an observer for this mutex, replacing any installed for all
+    /// mutices by [`set_lock_observer`].
+    #[cfg(feature = "observer")]
+    pub fn set_observer(&self, observer: &'static dyn LockObserver) {
+        self.0.observer.set(observer)
+    }
+
+    /// Statistics about the use of this mutex so far: how often it's been
+    /// claimed, and how long threads have waited for it and held it.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> MutexStats {
+        self.0.stats.snapshot()
     }
+
+    /// Reset all the statistics returned by [`DeadlockProofMutex::stats`] to
+    /// zero.
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&self) {
+        self.0.stats.reset()
+    }
+}
+
+/// The result of [`DeadlockProofMutex::try_lock`].
+pub type TryLockResult<'a, T, P, I, M = Mutex<T>> =
+    Result<DeadlockProofMutexGuard<'a, T, P, I, M>, DeadlockProofTryLockError<'a, T, P, I, M>>;
+
+/// Errors from [`DeadlockProofMutex::try_lock`].
+pub enum DeadlockProofTryLockError<
+    'a,
+    T: ?Sized,
+    P: MutexPermission,
+    I,
+    M: MutexBackend<T> + ?Sized + 'a = Mutex<T>,
+> {
+    /// The mutex is held by another thread. The permission is returned so
+    /// it can be used for something else.
+    WouldBlock(P),
+    /// Another thread panicked while holding the mutex. As with
+    /// [`DeadlockProofMutex::lock`], the mutex was claimed anyway and the
+    /// error contains a deadlock-proof guard for it.
+    Poisoned(PoisonError<DeadlockProofMutexGuard<'a, T, P, I, M>>),
+}
+
+/// The result of [`DeadlockProofMutex::try_lock_for_nested`]: a guard plus
+/// the permission required to claim the next mutex in the nesting.
+pub type TryNestedLockResult<'a, T, P, I, M = Mutex<T>> =
+    Result<NestedLock<'a, T, P, I, M>, DeadlockProofTryNestedLockError<'a, T, P, I, M>>;
+
+/// Errors from [`DeadlockProofMutex::try_lock_for_nested`].
+pub enum DeadlockProofTryNestedLockError<
+    'a,
+    T: ?Sized,
+    P: MutexPermission,
+    I,
+    M: MutexBackend<T> + ?Sized + 'a = Mutex<T>,
+> {
+    /// The mutex is held by another thread. The permission is returned so
+    /// it can be used for something else.
+    WouldBlock(P),
+    /// Another thread panicked while holding the mutex. As with
+    /// [`DeadlockProofMutex::lock_for_nested`], the mutex was claimed anyway
+    /// and the error contains a deadlock-proof guard for it, along with the
+    /// nested permission.
+    Poisoned(PoisonError<NestedLock<'a, T, P, I, M>>),
+}
+
+/// The result of [`DeadlockProofMutex::try_lock_until`] and
+/// [`DeadlockProofMutex::try_lock_for`].
+pub type TryLockUntilResult<'a, T, P, I, M = Mutex<T>> =
+    Result<DeadlockProofMutexGuard<'a, T, P, I, M>, TryLockUntilError<'a, T, P, I, M>>;
+
+/// Errors from [`DeadlockProofMutex::try_lock_until`] and
+/// [`DeadlockProofMutex::try_lock_for`].
+pub enum TryLockUntilError<
+    'a,
+    T: ?Sized,
+    P: MutexPermission,
+    I,
+    M: MutexBackend<T> + ?Sized + 'a = Mutex<T>,
+> {
+    /// The mutex was still held by another thread when the deadline passed.
+    /// The permission is returned so it can be used for something else.
+    DeadlineMissed(P),
+    /// Another thread panicked while holding the mutex. As with
+    /// [`DeadlockProofMutex::lock`], the mutex was claimed anyway and the
+    /// error contains a deadlock-proof guard for it.
+    Poisoned(PoisonError<DeadlockProofMutexGuard<'a, T, P, I, M>>),
 }
 
 /// Deadlock-proof equivalent to [`MutexGuard`]. It's strongly recommended that you don't
 /// allow this mutex to drop, but instead explicitly call [`DeadlockProofMutexGuard::unlock`] to obtain
 /// the permission required to reclaim a mutex later.
-pub struct DeadlockProofMutexGuard<'a, T, P: MutexPermission, I>(
-    MutexGuard<'a, T>,
+///
+/// If the guard is dropped anyway, for example while a panic unwinds, the
+/// mutex is released before the permission is dropped. An
+/// [`OuterMutexPermission`] then goes back to its thread, so it can be
+/// claimed again with [`OuterMutexPermission::get`]; other permissions are
+/// lost along with the guard. So a caught panic never leaves a thread
+/// without its permission, and [`OuterMutexPermission::catch_unwind`] hands
+/// it straight back. With the `leak-check` feature, debug builds report a
+/// guard dropped other than while panicking as a [`Violation`], so that a
+/// lost permission shows up where it was lost.
+///
+/// The guard is never `Send`, whatever the backend, because it holds the
+/// permission token, and a thread receiving it would then hold a mutex
+/// outside the ordering its own permission describes. To work on the
+/// contents from a scoped thread, send it `&mut *guard` instead: the
+/// mutex stays claimed by this thread until the scope ends.
+pub struct DeadlockProofMutexGuard<
+    'a,
+    T: ?Sized,
+    P: MutexPermission,
+    I,
+    M: MutexBackend<T> + ?Sized + 'a = Mutex<T>,
+>(
+    M::Guard<'a>,
     P,
-    PhantomData<I>,
+    &'a DeadlockProofMutex<T, P, I, M>,
+    #[allow(dead_code)] // only needed for its drop behaviour
+    HeldLock,
 );
 
-impl<'a, T, P: MutexPermission, I> DeadlockProofMutexGuard<'a, T, P, I> {
+impl<'a, T: ?Sized, P: MutexPermission, I, M: MutexBackend<T> + ?Sized + 'a>
+    DeadlockProofMutexGuard<'a, T, P, I, M>
+{
     /// Unlock the mutex. Returns the mutex permission token such that you
     /// can use it again to claim a different mutex.
-    pub fn unlock(self) -> P {
+    pub fn unlock(mut self) -> P {
+        self.3.disarm();
         self.1
     }
 
@@ -200,12 +1930,101 @@ impl<'a, T, P: MutexPermission, I> DeadlockProofMutexGuard<'a, T, P, I> {
     /// mutex permission token so that you can claim another mutex in
     /// a certain sequence, which the type system will guarantee is the same
     /// for all threads.
-    pub fn unlock_for_sequential(self) -> SequentialMutexPermission<P, I> {
+    pub fn unlock_for_sequential(mut self) -> SequentialMutexPermission<P, I> {
+        self.3.disarm();
         SequentialMutexPermission::new(self.1)
     }
+
+    /// The mutex this guard holds, for instance to check which of several
+    /// mutices it is using [`core::ptr::eq`], or to name it in diagnostics.
+    /// For a guard from [`DeadlockProofMutex::lock_borrowing`], the mutex is
+    /// viewed as one claimed with a borrowed permission.
+    pub fn mutex(&self) -> &'a DeadlockProofMutex<T, P, I, M> {
+        self.2
+    }
+
+    /// Keeps the mutex locked for as long as it's borrowed, which for a
+    /// mutex in a `static` is forever, returning exclusive access to the
+    /// contents for that long, as `parking_lot::MutexGuard::leak` does.
+    ///
+    /// The permission is kept too, since otherwise the thread could use it
+    /// to claim the mutex again and wait forever. Any other thread claiming
+    /// the mutex will likewise wait forever, so only leak mutices which
+    /// nothing else will claim, such as one holding state set up once at
+    /// startup and then owned by a single thread.
+    pub fn leak(self) -> &'a mut T {
+        let Self(mut guard, permission, _, held) = self;
+        // The data lives within the mutex rather than the guard, so this
+        // stays valid, and exclusive, for as long as the mutex stays locked
+        // and borrowed.
+        let data: *mut T = &mut *guard;
+        core::mem::forget(guard);
+        core::mem::forget(permission);
+        core::mem::forget(held);
+        unsafe { &mut *data }
+    }
+}
+
+#[cfg(feature = "lock_api")]
+impl<'a, T, P: MutexPermission, I, R: lock_api::RawMutexFair>
+    DeadlockProofMutexGuard<'a, T, P, I, lock_api::Mutex<R, T>>
+{
+    /// Unlock the mutex using a fair unlock protocol, handing it directly to
+    /// a waiting thread if there is one rather than letting another thread
+    /// steal it, as `lock_api::MutexGuard::unlock_fair` does for raw
+    /// mutices which support it, such as parking_lot's. Returns
+    /// the mutex permission token just as [`DeadlockProofMutexGuard::unlock`]
+    /// does.
+    pub fn unlock_fair(self) -> P {
+        let Self(guard, permission, _, mut held) = self;
+        lock_api::MutexGuard::unlock_fair(guard);
+        held.disarm();
+        drop(held);
+        permission
+    }
+
+    /// Briefly releases the mutex, handing it fairly to any thread waiting
+    /// for it, then reclaims it, as `lock_api::MutexGuard::bump` does.
+    /// This gives long critical sections a point at which to let other
+    /// threads in, without giving up the permission.
+    pub fn bump(&mut self) {
+        // Threads waiting in `lock_when` or `try_lock_for` aren't queued on
+        // the raw mutex, so it's always released, and they're woken only
+        // once it has been, lest they find it still held and miss the
+        // release.
+        let held = &self.3;
+        lock_api::MutexGuard::unlocked_fair(&mut self.0, || held.notify_waiters());
+    }
 }
 
-impl<T, P: MutexPermission, I> Deref for DeadlockProofMutexGuard<'_, T, P, I> {
+#[cfg(feature = "std")]
+impl<'a, T, P: MutexPermission, I> DeadlockProofMutexGuard<'a, T, P, I> {
+    /// Gives scoped access to the underlying [`MutexGuard`], for APIs which
+    /// insist on a real standard library guard. The closure must not swap
+    /// in a guard for a different mutex; this panics if it does.
+    pub fn with_std_guard<R>(&mut self, f: impl FnOnce(&mut MutexGuard<'a, T>) -> R) -> R {
+        let data: *const T = &*self.0;
+        let result = f(&mut self.0);
+        assert_same_mutex(data, &self.0);
+        result
+    }
+
+    /// Hands the underlying [`MutexGuard`] by value to a closure which must
+    /// give it back, for APIs such as [`std::sync::Condvar::wait`] which
+    /// consume the guard and return it later. Panics if the closure returns
+    /// a guard for a different mutex.
+    pub fn map_std_guard(self, f: impl FnOnce(MutexGuard<'a, T>) -> MutexGuard<'a, T>) -> Self {
+        let Self(guard, permission, mutex, held) = self;
+        let data: *const T = &*guard;
+        let guard = f(guard);
+        assert_same_mutex(data, &guard);
+        Self(guard, permission, mutex, held)
+    }
+}
+
+impl<'a, T: ?Sized, P: MutexPermission, I, M: MutexBackend<T> + ?Sized + 'a> Deref
+    for DeadlockProofMutexGuard<'a, T, P, I, M>
+{
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -213,39 +2032,145 @@ impl<T, P: MutexPermission, I> Deref for DeadlockProofMutexGuard<'_, T, P, I> {
     }
 }
 
-impl<T, P: MutexPermission, I> DerefMut for DeadlockProofMutexGuard<'_, T, P, I> {
+impl<'a, T: ?Sized, P: MutexPermission, I, M: MutexBackend<T> + ?Sized + 'a> DerefMut
+    for DeadlockProofMutexGuard<'a, T, P, I, M>
+{
     fn deref_mut(&mut self) -> &mut T {
         self.0.deref_mut()
     }
 }
 
+impl<'a, T: fmt::Debug + ?Sized, P: MutexPermission, I, M: MutexBackend<T> + ?Sized + 'a> fmt::Debug
+    for DeadlockProofMutexGuard<'a, T, P, I, M>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.deref(), f)
+    }
+}
+
+impl<'a, T: fmt::Display + ?Sized, P: MutexPermission, I, M: MutexBackend<T> + ?Sized + 'a>
+    fmt::Display for DeadlockProofMutexGuard<'a, T, P, I, M>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.deref(), f)
+    }
+}
+
 /// Deadlock-proof equivalent to [`MutexGuard`]. It's strongly recommended that you don't
 /// allow this mutex to drop, but instead explicitly call [`DeadlockProofMutexGuard::unlock`] to obtain
 /// the permission required to reclaim a mutex later.
-pub struct DeadlockProofNestedMutexGuard<'a, T, P: MutexPermission, I>(
-    MutexGuard<'a, T>,
+///
+/// If the guard is dropped anyway, for example while a panic unwinds, the
+/// mutex is released before the permission is dropped. An
+/// [`OuterMutexPermission`] then goes back to its thread, so it can be
+/// claimed again with [`OuterMutexPermission::get`]; other permissions are
+/// lost along with the guard.
+pub struct DeadlockProofNestedMutexGuard<
+    'a,
+    T: ?Sized,
+    P: MutexPermission,
+    I,
+    M: MutexBackend<T> + ?Sized + 'a = Mutex<T>,
+>(
+    M::Guard<'a>,
     P,
-    PhantomData<I>,
+    &'a DeadlockProofMutex<T, P, I, M>,
+    #[allow(dead_code)] // only needed for its drop behaviour
+    HeldLock,
 );
 
-impl<'a, T, P: MutexPermission, I> DeadlockProofNestedMutexGuard<'a, T, P, I> {
+impl<'a, T: ?Sized, P: MutexPermission, I, M: MutexBackend<T> + ?Sized + 'a>
+    DeadlockProofNestedMutexGuard<'a, T, P, I, M>
+{
     /// Unlock the mutex. Returns the mutex permission token such that you
     /// can use it again to claim a different mutex.
-    pub fn unlock(self, _token: NestedMutexPermission<P, I>) -> P {
+    pub fn unlock(mut self, _token: NestedMutexPermission<P, I>) -> P {
+        self.3.disarm();
         self.1
     }
 
+    /// The mutex this guard holds, as for [`DeadlockProofMutexGuard::mutex`].
+    pub fn mutex(&self) -> &'a DeadlockProofMutex<T, P, I, M> {
+        self.2
+    }
+
     /// Unlock the mutex. Returns the mutex permission token such that you
     /// can use it again to claim a different mutex. Also, returns an extra
     /// mutex permission token so that you can claim another mutex in
     /// a certain sequence, which the type system will guarantee is the same
     /// for all threads.
-    pub fn unlock_for_sequential(self) -> SequentialMutexPermission<P, I> {
+    pub fn unlock_for_sequential(mut self) -> SequentialMutexPermission<P, I> {
+        self.3.disarm();
         SequentialMutexPermission::new(self.1)
     }
 }
 
-impl<T, P: MutexPermission, I> Deref for DeadlockProofNestedMutexGuard<'_, T, P, I> {
+#[cfg(feature = "lock_api")]
+impl<'a, T, P: MutexPermission, I, R: lock_api::RawMutexFair>
+    DeadlockProofNestedMutexGuard<'a, T, P, I, lock_api::Mutex<R, T>>
+{
+    /// Unlock the mutex fairly, as [`DeadlockProofMutexGuard::unlock_fair`]
+    /// does. As for [`DeadlockProofNestedMutexGuard::unlock`], the
+    /// permission for nested mutices must be handed back.
+    pub fn unlock_fair(self, _token: NestedMutexPermission<P, I>) -> P {
+        let Self(guard, permission, _, mut held) = self;
+        lock_api::MutexGuard::unlock_fair(guard);
+        held.disarm();
+        drop(held);
+        permission
+    }
+
+    /// Briefly releases the mutex, as [`DeadlockProofMutexGuard::bump`]
+    /// does. The permission for nested mutices must be lent back, so that
+    /// none of them can be held: reclaiming this mutex while holding one
+    /// would claim them out of order.
+    pub fn bump(&mut self, _token: &mut NestedMutexPermission<P, I>) {
+        let held = &self.3;
+        lock_api::MutexGuard::unlocked_fair(&mut self.0, || held.notify_waiters());
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, T, P: MutexPermission, I> DeadlockProofNestedMutexGuard<'a, T, P, I> {
+    /// Gives scoped access to the underlying [`MutexGuard`], for APIs which
+    /// insist on a real standard library guard. The closure must not swap
+    /// in a guard for a different mutex; this panics if it does. Since the
+    /// closure may release the mutex, for instance by waiting on a
+    /// [`std::sync::Condvar`], the permission for nested mutices must be
+    /// lent back, as for [`DeadlockProofNestedMutexGuard::bump`].
+    pub fn with_std_guard<R>(
+        &mut self,
+        _token: &mut NestedMutexPermission<P, I>,
+        f: impl FnOnce(&mut MutexGuard<'a, T>) -> R,
+    ) -> R {
+        let data: *const T = &*self.0;
+        let result = f(&mut self.0);
+        assert_same_mutex(data, &self.0);
+        result
+    }
+
+    /// Hands the underlying [`MutexGuard`] by value to a closure which must
+    /// give it back, for APIs such as [`std::sync::Condvar::wait`] which
+    /// consume the guard and return it later. Panics if the closure returns
+    /// a guard for a different mutex. As for
+    /// [`DeadlockProofNestedMutexGuard::with_std_guard`], the permission for
+    /// nested mutices must be lent back.
+    pub fn map_std_guard(
+        self,
+        _token: &mut NestedMutexPermission<P, I>,
+        f: impl FnOnce(MutexGuard<'a, T>) -> MutexGuard<'a, T>,
+    ) -> Self {
+        let Self(guard, permission, mutex, held) = self;
+        let data: *const T = &*guard;
+        let guard = f(guard);
+        assert_same_mutex(data, &guard);
+        Self(guard, permission, mutex, held)
+    }
+}
+
+impl<'a, T: ?Sized, P: MutexPermission, I, M: MutexBackend<T> + ?Sized + 'a> Deref
+    for DeadlockProofNestedMutexGuard<'a, T, P, I, M>
+{
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -253,8 +2178,238 @@ impl<T, P: MutexPermission, I> Deref for DeadlockProofNestedMutexGuard<'_, T, P,
     }
 }
 
-impl<T, P: MutexPermission, I> DerefMut for DeadlockProofNestedMutexGuard<'_, T, P, I> {
+impl<'a, T: ?Sized, P: MutexPermission, I, M: MutexBackend<T> + ?Sized + 'a> DerefMut
+    for DeadlockProofNestedMutexGuard<'a, T, P, I, M>
+{
     fn deref_mut(&mut self) -> &mut T {
         self.0.deref_mut()
     }
 }
+
+impl<'a, T: fmt::Debug + ?Sized, P: MutexPermission, I, M: MutexBackend<T> + ?Sized + 'a> fmt::Debug
+    for DeadlockProofNestedMutexGuard<'a, T, P, I, M>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.deref(), f)
+    }
+}
+
+impl<'a, T: fmt::Display + ?Sized, P: MutexPermission, I, M: MutexBackend<T> + ?Sized + 'a>
+    fmt::Display for DeadlockProofNestedMutexGuard<'a, T, P, I, M>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.deref(), f)
+    }
+}
+
+/// Wraps the guard within the result of claiming an underlying lock, even if
+/// the lock is poisoned, so that the permission is never lost.
+pub(crate) fn map_lock_result<G, H>(
+    result: LockResult<G>,
+    f: impl FnOnce(G) -> H,
+) -> LockResult<H> {
+    match result {
+        Ok(guard) => Ok(f(guard)),
+        Err(e) => Err(PoisonError::new(f(e.into_inner()))),
+    }
+}
+
+/// Checks that a guard handed back from user code is for the same mutex
+/// whose data lived at `data` when the guard was handed out.
+#[cfg(feature = "std")]
+fn assert_same_mutex<T>(data: *const T, guard: &MutexGuard<'_, T>) {
+    assert!(
+        core::ptr::eq(data, &**guard),
+        "A guard for a different mutex was substituted for a deadlock-proof guard"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{DeadlockProofMutex, OuterMutexPermission, TryLockUntilError};
+
+    struct Counter;
+    struct Total;
+    struct Shape;
+
+    trait Area {
+        fn grow(&mut self);
+        fn area(&self) -> u32;
+    }
+
+    struct Square(u32);
+
+    impl Area for Square {
+        fn grow(&mut self) {
+            self.0 += 1;
+        }
+
+        fn area(&self) -> u32 {
+            self.0 * self.0
+        }
+    }
+
+    #[test]
+    fn lock_and_then_chains_critical_sections() {
+        let counter: DeadlockProofMutex<u32, OuterMutexPermission, Counter> =
+            DeadlockProofMutex::new(2, Counter);
+        let total: DeadlockProofMutex<u32, OuterMutexPermission, Total> =
+            DeadlockProofMutex::new(40, Total);
+        let (sum, permission) = counter
+            .lock_and_then(OuterMutexPermission::get(), |n| *n)
+            .ok()
+            .and_then(|(n, p)| total.lock_and_then(p, |t| *t + n).ok())
+            .unwrap();
+        assert_eq!(sum, 42);
+        drop(permission);
+        assert!(OuterMutexPermission::try_get().is_some());
+    }
+
+    #[test]
+    fn catch_unwind_hands_back_the_permission() {
+        let counter: DeadlockProofMutex<u32, OuterMutexPermission, Counter> =
+            DeadlockProofMutex::new(0, Counter);
+        // As a thread pool worker would, carry on after a job panics while
+        // holding a guard.
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mut permission = OuterMutexPermission::get();
+                for _ in 0..2 {
+                    let result;
+                    (result, permission) = permission.catch_unwind(|permission| {
+                        let mut guard = counter.lock(permission).unwrap_or_else(|e| e.into_inner());
+                        *guard += 1;
+                        panic!("job failed");
+                    });
+                    assert!(result.is_err());
+                }
+                let guard = counter.lock(permission).err().unwrap().into_inner();
+                assert_eq!(*guard, 2);
+                guard.unlock();
+            });
+        });
+    }
+
+    #[test]
+    fn lock_when_waits_for_the_condition() {
+        let counter: DeadlockProofMutex<u32, OuterMutexPermission, Counter> =
+            DeadlockProofMutex::new(0, Counter);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                let guard = counter
+                    .lock_when(OuterMutexPermission::get(), |n| *n == 3)
+                    .unwrap();
+                let seen = *guard;
+                guard.unlock();
+                seen
+            });
+            // Give the waiter time to park, so that it's woken for each change.
+            thread::sleep(Duration::from_millis(20));
+            for _ in 0..3 {
+                let mut guard = counter.lock(OuterMutexPermission::get()).unwrap();
+                *guard += 1;
+                guard.unlock();
+            }
+            assert_eq!(waiter.join().unwrap(), 3);
+        });
+    }
+
+    #[test]
+    fn try_lock_for_gives_back_the_permission_on_timeout() {
+        let counter: DeadlockProofMutex<u32, OuterMutexPermission, Counter> =
+            DeadlockProofMutex::new(0, Counter);
+        let guard = counter.lock(OuterMutexPermission::get()).unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let start = Instant::now();
+                let result =
+                    counter.try_lock_for(OuterMutexPermission::get(), Duration::from_millis(20));
+                assert!(start.elapsed() >= Duration::from_millis(20));
+                match result {
+                    Err(TryLockUntilError::DeadlineMissed(permission)) => drop(permission),
+                    _ => panic!("the mutex was claimed while held"),
+                }
+                assert!(OuterMutexPermission::try_get().is_some());
+            });
+        });
+        guard.unlock();
+    }
+
+    #[test]
+    fn try_lock_for_is_woken_by_the_release() {
+        let counter: DeadlockProofMutex<u32, OuterMutexPermission, Counter> =
+            DeadlockProofMutex::new(0, Counter);
+        let mut guard = counter.lock(OuterMutexPermission::get()).unwrap();
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                let start = Instant::now();
+                let Ok(guard) =
+                    counter.try_lock_for(OuterMutexPermission::get(), Duration::from_secs(60))
+                else {
+                    panic!("the mutex wasn't claimed once released");
+                };
+                let seen = *guard;
+                guard.unlock();
+                (seen, start.elapsed())
+            });
+            thread::sleep(Duration::from_millis(20));
+            *guard = 1;
+            guard.unlock();
+            let (seen, waited) = waiter.join().unwrap();
+            assert_eq!(seen, 1);
+            assert!(waited < Duration::from_secs(30));
+        });
+    }
+
+    #[test]
+    fn trait_object_contents_are_shared() {
+        let shape: Arc<DeadlockProofMutex<dyn Area + Send, OuterMutexPermission, Shape>> =
+            Arc::from(unsize_deadlock_proof_mutex!(
+                Box::new(DeadlockProofMutex::new(Square(0), Shape)),
+                dyn Area + Send
+            ));
+        thread::scope(|s| {
+            for _ in 0..4 {
+                let shape = Arc::clone(&shape);
+                s.spawn(move || {
+                    for _ in 0..100 {
+                        let mut guard = shape.lock(OuterMutexPermission::get()).unwrap();
+                        guard.grow();
+                        guard.unlock();
+                    }
+                });
+            }
+        });
+        let guard = shape.lock(OuterMutexPermission::get()).unwrap();
+        assert_eq!(guard.area(), 400 * 400);
+        guard.unlock();
+    }
+
+    #[cfg(feature = "parking_lot")]
+    #[test]
+    fn bump_lets_waiters_in() {
+        let mutex: DeadlockProofMutex<u32, OuterMutexPermission, Counter, parking_lot::Mutex<u32>> =
+            DeadlockProofMutex::from_backend(parking_lot::Mutex::new(0), Counter);
+        let mut guard = mutex.lock(OuterMutexPermission::get()).unwrap();
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                let guard = mutex
+                    .lock_when(OuterMutexPermission::get(), |n| *n == 1)
+                    .unwrap();
+                guard.unlock();
+            });
+            *guard = 1;
+            while !waiter.is_finished() {
+                guard.bump();
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+        guard.unlock();
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Exporting declared lock orders, so that they can be rendered and
+//! reviewed.
+
+use std::fmt::Write;
+
+use crate::IdentifierName;
+
+/// Implemented by [`crate::declare_lock_order`], [`crate::declare_children`]
+/// and [`crate::declare_lock_sets`] for every identifier they declare,
+/// describing the whole lock order the identifier is part of, so that it
+/// can be exported using [`LockOrderGraph`]. Don't implement this by hand.
+pub trait DeclaredLockOrder {
+    /// The type names of the identifiers in the order, as sets, each of
+    /// which comes after all the sets before it. A chain declared with
+    /// [`crate::declare_lock_order`] has one identifier per set.
+    #[doc(hidden)]
+    const LEVELS: &'static [&'static [fn() -> &'static str]];
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __lock_order_graph {
+    ($([$($member:ident),+])<+) => {
+        $crate::__lock_order_graph!(@impls [$([$($member),+])+] $($($member)+)+);
+    };
+    (@impls $levels:tt $($member:ident)+) => {
+        $(
+            impl $crate::DeclaredLockOrder for $member {
+                const LEVELS: &'static [&'static [fn() -> &'static str]] =
+                    $crate::__lock_order_graph!(@levels $levels);
+            }
+        )+
+    };
+    (@levels [$([$($member:ident),+])+]) => {
+        &[$(&[$(::std::any::type_name::<$member>),+]),+]
+    };
+}
+
+/// The identifiers of one or more declared lock orders and the edges
+/// between them, for exporting as [Graphviz](https://graphviz.org) DOT or
+/// JSON so that a team can render and review its lock hierarchy. Add each
+/// order by naming any one of its identifiers, as in
+/// `LockOrderGraph::new().add::<Config>().add::<Ui>().to_dot()`.
+///
+/// Edges only link each identifier to those in the next set of its order;
+/// the rest of the order follows from these transitively.
+#[derive(Clone, Debug, Default)]
+pub struct LockOrderGraph {
+    nodes: Vec<&'static str>,
+    edges: Vec<(&'static str, &'static str)>,
+}
+
+impl LockOrderGraph {
+    /// Create an empty graph.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the whole lock order containing the identifier `I`. Adding an
+    /// order which is already present does nothing.
+    pub fn add<I: DeclaredLockOrder>(&mut self) -> &mut Self {
+        let levels: Vec<Vec<&'static str>> = I::LEVELS
+            .iter()
+            .map(|level| level.iter().map(|name| name()).collect())
+            .collect();
+        if self.nodes.contains(&levels[0][0]) {
+            return self;
+        }
+        for (i, level) in levels.iter().enumerate() {
+            self.nodes.extend(level);
+            if let Some(next) = levels.get(i + 1) {
+                for before in level {
+                    self.edges
+                        .extend(next.iter().map(|after| (*before, *after)));
+                }
+            }
+        }
+        self
+    }
+
+    /// The graph in Graphviz DOT format. Nodes are identified by the full
+    /// type names of the identifiers, and labelled with their short names.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph lock_order {\n");
+        for node in &self.nodes {
+            let _ = writeln!(
+                dot,
+                "    \"{}\" [label=\"{}\"];",
+                escape(node),
+                escape(&IdentifierName(node).to_string())
+            );
+        }
+        for (before, after) in &self.edges {
+            let _ = writeln!(dot, "    \"{}\" -> \"{}\";", escape(before), escape(after));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// The graph as JSON: an object with a `nodes` array, giving each
+    /// identifier's full type name as its `id` and its short `name`, and an
+    /// `edges` array of objects giving the `before` and `after` ids.
+    pub fn to_json(&self) -> String {
+        let nodes: Vec<String> = self
+            .nodes
+            .iter()
+            .map(|node| {
+                format!(
+                    "{{\"id\":\"{}\",\"name\":\"{}\"}}",
+                    escape(node),
+                    escape(&IdentifierName(node).to_string())
+                )
+            })
+            .collect();
+        let edges: Vec<String> = self
+            .edges
+            .iter()
+            .map(|(before, after)| {
+                format!(
+                    "{{\"before\":\"{}\",\"after\":\"{}\"}}",
+                    escape(before),
+                    escape(after)
+                )
+            })
+            .collect();
+        format!(
+            "{{\"nodes\":[{}],\"edges\":[{}]}}",
+            nodes.join(","),
+            edges.join(",")
+        )
+    }
+}
+
+/// Escapes a name for a double-quoted string, which is the same for DOT
+/// and JSON as far as type names go.
+fn escape(name: &str) -> String {
+    name.replace('\\', "\\\\").replace('"', "\\\"")
+}
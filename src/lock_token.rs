@@ -0,0 +1,86 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fmt;
+
+use crate::{
+    DeadlockProofMutex, DeadlockProofMutexGuard, DeadlockProofTryLockError, IdentifierName,
+    MutexPermission, SequentialMutexPermission,
+};
+
+/// A deadlock-proof lock protecting no data, for mutual exclusion over
+/// something outside the program's memory, such as a file, a device or a
+/// hardware register. It takes part in the lock ordering exactly as a
+/// [`DeadlockProofMutex`] does, but reads as what it is rather than as a
+/// `DeadlockProofMutex<(), P, I>` whose contents nobody uses.
+///
+/// With no data to leave inconsistent, it's never poisoned.
+pub struct DeadlockProofLockToken<P: MutexPermission, I>(DeadlockProofMutex<(), P, I>);
+
+impl<P: MutexPermission, I> DeadlockProofLockToken<P, I> {
+    /// Create a new lock token. The `identifier` is a type unique to this
+    /// lock, just as for [`DeadlockProofMutex::new`].
+    pub fn new(identifier: I) -> Self {
+        Self(DeadlockProofMutex::new((), identifier))
+    }
+
+    /// Create a new lock token in a `const` context, for example in the
+    /// initializer of a `static`, as for [`DeadlockProofMutex::const_new`].
+    pub const fn const_new(identifier: I) -> Self {
+        Self(DeadlockProofMutex::const_new((), identifier))
+    }
+
+    /// Acquires the lock, blocking the current thread until it is able to
+    /// do so. Requires a permission token to prove that you can't be
+    /// causing a deadlock.
+    pub fn acquire(&self, permission: P) -> DeadlockProofLockTokenGuard<'_, P, I> {
+        DeadlockProofLockTokenGuard(self.0.lock_ignore_poison(permission))
+    }
+
+    /// Attempts to acquire the lock without blocking. If it's held
+    /// elsewhere, the permission is returned.
+    pub fn try_acquire(&self, permission: P) -> Result<DeadlockProofLockTokenGuard<'_, P, I>, P> {
+        match self.0.try_lock(permission) {
+            Ok(guard) => Ok(DeadlockProofLockTokenGuard(guard)),
+            Err(DeadlockProofTryLockError::Poisoned(e)) => {
+                Ok(DeadlockProofLockTokenGuard(e.into_inner()))
+            }
+            Err(DeadlockProofTryLockError::WouldBlock(permission)) => Err(permission),
+        }
+    }
+}
+
+impl<P: MutexPermission, I> fmt::Debug for DeadlockProofLockToken<P, I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeadlockProofLockToken")
+            .field("identifier", &IdentifierName::of::<I>())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Proof that a [`DeadlockProofLockToken`] is held. As with
+/// [`DeadlockProofMutexGuard`], it's strongly recommended that you
+/// explicitly release this rather than allowing it to drop.
+pub struct DeadlockProofLockTokenGuard<'a, P: MutexPermission, I>(
+    DeadlockProofMutexGuard<'a, (), P, I>,
+);
+
+impl<P: MutexPermission, I> DeadlockProofLockTokenGuard<'_, P, I> {
+    /// Release the lock. Returns the mutex permission token such that you
+    /// can use it again to claim a different lock.
+    pub fn release(self) -> P {
+        self.0.unlock()
+    }
+
+    /// Release the lock, returning the permission token along with an extra
+    /// one so that you can claim another lock in a certain sequence, which
+    /// the type system will guarantee is the same for all threads.
+    pub fn release_for_sequential(self) -> SequentialMutexPermission<P, I> {
+        self.0.unlock_for_sequential()
+    }
+}
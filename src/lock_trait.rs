@@ -0,0 +1,132 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{ops::DerefMut, sync::LockResult};
+
+use crate::{
+    DeadlockProofMutex, DeadlockProofMutexGuard, DeadlockProofNestedMutexGuard,
+    DeadlockProofRwLock, DeadlockProofRwLockWriteGuard, MutexBackend, MutexPermission,
+};
+
+/// Any deadlock-proof lock which can be claimed exclusively using a
+/// permission token, for writing code generic over [`DeadlockProofMutex`],
+/// whatever its backend, and [`DeadlockProofRwLock`], which is claimed for
+/// writing.
+///
+/// The associated types let generic code name the permission the lock needs
+/// and its identifier, for instance to require
+/// `L::Identifier: LockAfter<J>` or to claim a lock with a
+/// `NestedMutexPermission<P, J>`.
+pub trait DeadlockProofLock {
+    /// The type protected by the lock.
+    type Target;
+
+    /// The permission token needed to claim the lock.
+    type Permission: MutexPermission;
+
+    /// The type uniquely identifying the lock.
+    type Identifier;
+
+    /// The guard returned when the lock is claimed.
+    type Guard<'a>: DerefMut<Target = Self::Target>
+    where
+        Self: 'a;
+
+    /// Claims the lock exclusively, blocking the current thread until it is
+    /// able to do so, as [`DeadlockProofMutex::lock`] does.
+    fn lock(&self, permission: Self::Permission) -> LockResult<Self::Guard<'_>>;
+
+    /// Releases the lock, returning the permission token.
+    fn unlock<'a>(guard: Self::Guard<'a>) -> Self::Permission
+    where
+        Self: 'a;
+}
+
+impl<T, P: MutexPermission, I, M: MutexBackend<T>> DeadlockProofLock
+    for DeadlockProofMutex<T, P, I, M>
+{
+    type Target = T;
+    type Permission = P;
+    type Identifier = I;
+    type Guard<'a>
+        = DeadlockProofMutexGuard<'a, T, P, I, M>
+    where
+        Self: 'a;
+
+    fn lock(&self, permission: P) -> LockResult<Self::Guard<'_>> {
+        DeadlockProofMutex::lock(self, permission)
+    }
+
+    fn unlock<'a>(guard: Self::Guard<'a>) -> P
+    where
+        Self: 'a,
+    {
+        guard.unlock()
+    }
+}
+
+impl<T, P: MutexPermission, I> DeadlockProofLock for DeadlockProofRwLock<T, P, I> {
+    type Target = T;
+    type Permission = P;
+    type Identifier = I;
+    type Guard<'a>
+        = DeadlockProofRwLockWriteGuard<'a, T, P, I>
+    where
+        Self: 'a;
+
+    fn lock(&self, permission: P) -> LockResult<Self::Guard<'_>> {
+        self.write(permission)
+    }
+
+    fn unlock<'a>(guard: Self::Guard<'a>) -> P
+    where
+        Self: 'a,
+    {
+        guard.unlock()
+    }
+}
+
+/// A guard for a deadlock-proof mutex, for writing code generic over
+/// [`DeadlockProofMutexGuard`] and [`DeadlockProofNestedMutexGuard`] which
+/// needs to know which mutex is held.
+///
+/// The identifier is an associated type so that generic code holding a
+/// guard can require things of it, such as `G::Identifier: LockAfter<J>`,
+/// or name it in diagnostics using [`std::any::type_name`].
+pub trait DeadlockProofGuard {
+    /// The type uniquely identifying the mutex.
+    type Identifier;
+
+    /// The mutex the guard holds.
+    type Lock: DeadlockProofLock<Identifier = Self::Identifier>;
+
+    /// The mutex the guard holds.
+    fn mutex(&self) -> &Self::Lock;
+}
+
+impl<'a, T, P: MutexPermission, I, M: MutexBackend<T> + 'a> DeadlockProofGuard
+    for DeadlockProofMutexGuard<'a, T, P, I, M>
+{
+    type Identifier = I;
+    type Lock = DeadlockProofMutex<T, P, I, M>;
+
+    fn mutex(&self) -> &Self::Lock {
+        DeadlockProofMutexGuard::mutex(self)
+    }
+}
+
+impl<'a, T, P: MutexPermission, I, M: MutexBackend<T> + 'a> DeadlockProofGuard
+    for DeadlockProofNestedMutexGuard<'a, T, P, I, M>
+{
+    type Identifier = I;
+    type Lock = DeadlockProofMutex<T, P, I, M>;
+
+    fn mutex(&self) -> &Self::Lock {
+        DeadlockProofNestedMutexGuard::mutex(self)
+    }
+}
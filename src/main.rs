@@ -6,7 +6,7 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use deadlock_proof_mutex::{unique_type, DeadlockProofMutex, OuterMutexPermission};
+use deadlock_proof_mutex::{unique_type, unlock_all, DeadlockProofMutex, OuterMutexPermission};
 use std::sync::Arc;
 use std::thread;
 
@@ -39,6 +39,33 @@ fn example_with_exclusive_mutices(
     guard2.unlock()
 }
 
+fn example_with_scoped_threads(
+    my_thread_mutex_permission: OuterMutexPermission,
+) -> OuterMutexPermission {
+    // The same as above, but scoped threads can borrow the mutices, so
+    // there's no need for an Arc. Each scoped thread is handed its own
+    // permission.
+    let mutex1 = DeadlockProofMutex::new(0, unique_type!());
+    let mutex2 = DeadlockProofMutex::new(0, unique_type!());
+
+    deadlock_proof_mutex::thread::scope(|scope| {
+        scope.spawn(|mutex_permission| {
+            let mut guard = mutex1.lock(mutex_permission).unwrap();
+            *guard = 10;
+            let mutex_permission = guard.unlock();
+            let mut guard = mutex2.lock(mutex_permission).unwrap();
+            *guard = 20;
+        });
+    });
+
+    let guard = mutex1.lock(my_thread_mutex_permission).unwrap();
+    assert_eq!(*guard, 10);
+    let my_thread_mutex_permission = guard.unlock();
+    let guard2 = mutex2.lock(my_thread_mutex_permission).unwrap();
+    assert_eq!(*guard2, 20);
+    guard2.unlock()
+}
+
 fn example_with_nested_mutices(
     my_thread_mutex_permission: OuterMutexPermission,
 ) -> OuterMutexPermission {
@@ -81,7 +108,7 @@ fn example_with_nested_mutices(
     assert_eq!(*guard2, 20);
     let guard3 = mutex3.lock(inner_inner_permission).unwrap();
     assert_eq!(*guard3, 30);
-    guard.unlock(guard2.unlock(guard3.unlock()))
+    unlock_all!(guard, guard2, guard3)
 }
 
 fn example_with_sequential_mutices(my_thread_mutex_permission: OuterMutexPermission) {
@@ -111,7 +138,7 @@ fn example_with_sequential_mutices(my_thread_mutex_permission: OuterMutexPermiss
 
         // Explicitly unlock, to show how to get back to the
         // outermost mutex in case we need to claim something else.
-        let _mutex_permission = guard3.unlock().to_earlier().to_earlier();
+        let _mutex_permission = guard3.unlock().to_outermost();
     })
     .join()
     .expect("thread::spawn failed");
@@ -131,6 +158,7 @@ fn example_with_sequential_mutices(my_thread_mutex_permission: OuterMutexPermiss
 fn main() {
     let my_thread_mutex_permission = OuterMutexPermission::get();
     let my_thread_mutex_permission = example_with_exclusive_mutices(my_thread_mutex_permission);
+    let my_thread_mutex_permission = example_with_scoped_threads(my_thread_mutex_permission);
     let my_thread_mutex_permission = example_with_nested_mutices(my_thread_mutex_permission);
     example_with_sequential_mutices(my_thread_mutex_permission);
 }
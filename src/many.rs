@@ -0,0 +1,126 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::{LockResult, Mutex, PoisonError},
+};
+
+use crate::{waiters, DeadlockProofMutex, HeldLock, MutexBackend, MutexPermission};
+
+/// The result of [`DeadlockProofMutex::lock_many`]: a guard for all the
+/// mutices.
+pub type ManyLockResult<'a, T, P, I, M = Mutex<T>> =
+    LockResult<DeadlockProofManyGuard<'a, T, P, I, M>>;
+
+impl<T, P: MutexPermission, I, M: MutexBackend<T>> DeadlockProofMutex<T, P, I, M> {
+    /// Acquires every one of `mutices`, blocking the current thread until it
+    /// is able to do so. They're claimed in ascending order of address, so
+    /// any number of threads can each claim an arbitrary set of mutices of
+    /// this same type without deadlocking against each other. Mutices
+    /// appearing more than once are only claimed once.
+    ///
+    /// All the mutices share the permission type `P`, so the set as a whole
+    /// takes the place in the lock ordering of a single mutex of this type.
+    /// If any mutex is poisoned, the error still contains a guard for all of
+    /// them, as for [`DeadlockProofMutex::lock`].
+    // The error holds the guard, just as for every other way of locking.
+    #[allow(clippy::result_large_err)]
+    pub fn lock_many<'a>(
+        mutices: impl IntoIterator<Item = &'a Self>,
+        permission: P,
+    ) -> ManyLockResult<'a, T, P, I, M>
+    where
+        T: 'a,
+        P: 'a,
+        I: 'a,
+        M: 'a,
+    {
+        let mut mutices: Vec<&'a Self> = mutices.into_iter().collect();
+        mutices.sort_by_key(|mutex| waiters::key(&mutex.3));
+        mutices.dedup_by_key(|mutex| waiters::key(&mutex.3));
+        let mut poisoned = false;
+        let guards = mutices
+            .into_iter()
+            .map(|mutex| {
+                let guard = mutex
+                    .0
+                    .lock::<T, I, P::Domain, M>(&mutex.3)
+                    .unwrap_or_else(|e| {
+                        poisoned = true;
+                        e.into_inner()
+                    });
+                (
+                    waiters::key(&mutex.3),
+                    guard,
+                    mutex.0.held::<I, P::Domain, _>(&mutex.3),
+                )
+            })
+            .collect();
+        let guard = DeadlockProofManyGuard(guards, permission, PhantomData);
+        if poisoned {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+}
+
+/// A guard for a set of mutices claimed together using
+/// [`DeadlockProofMutex::lock_many`]. As with
+/// [`crate::DeadlockProofMutexGuard`], it's strongly recommended that you
+/// explicitly unlock this rather than allowing it to drop.
+pub struct DeadlockProofManyGuard<'a, T, P: MutexPermission, I, M: MutexBackend<T> + 'a = Mutex<T>>(
+    // In ascending order of address, which is also the order in which
+    // they're released.
+    Vec<(usize, M::Guard<'a>, HeldLock)>,
+    P,
+    PhantomData<I>,
+);
+
+impl<'a, T, P: MutexPermission, I, M: MutexBackend<T> + 'a> DeadlockProofManyGuard<'a, T, P, I, M> {
+    /// Unlock all the mutices. Returns the mutex permission token such that
+    /// you can use it again to claim a different mutex.
+    pub fn unlock(self) -> P {
+        self.1
+    }
+
+    /// The number of distinct mutices held.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether no mutices are held at all.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn position(&self, mutex: &DeadlockProofMutex<T, P, I, M>) -> Option<usize> {
+        self.0
+            .binary_search_by_key(&waiters::key(&mutex.3), |(address, ..)| *address)
+            .ok()
+    }
+
+    /// The contents of `mutex`, or `None` if it isn't one of those held.
+    pub fn get(&self, mutex: &DeadlockProofMutex<T, P, I, M>) -> Option<&T> {
+        self.position(mutex).map(|pos| self.0[pos].1.deref())
+    }
+
+    /// The contents of `mutex`, mutably, or `None` if it isn't one of those
+    /// held.
+    pub fn get_mut(&mut self, mutex: &DeadlockProofMutex<T, P, I, M>) -> Option<&mut T> {
+        self.position(mutex).map(|pos| self.0[pos].1.deref_mut())
+    }
+
+    /// The contents of every mutex held, mutably, in ascending order of
+    /// address.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> + use<'_, 'a, T, P, I, M> {
+        self.0.iter_mut().map(|(_, guard, _)| guard.deref_mut())
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use core::{
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
+
+use crate::{
+    DeadlockProofMutexGuard, HeldLock, Mutex, MutexBackend, MutexPermission,
+    SequentialMutexPermission,
+};
+
+/// A guard giving access to just part of the contents of a
+/// [`crate::DeadlockProofMutex`], obtained from
+/// [`DeadlockProofMutexGuard::map`]. The whole mutex remains locked until
+/// this is unlocked, and this carries the permission just like the guard it
+/// came from.
+pub struct DeadlockProofMappedMutexGuard<
+    'a,
+    T,
+    U: ?Sized,
+    P: MutexPermission,
+    I,
+    M: MutexBackend<T> + 'a = Mutex<T>,
+>(
+    #[allow(dead_code)] // only needed to keep the mutex locked
+    M::Guard<'a>,
+    NonNull<U>,
+    P,
+    PhantomData<(I, &'a mut U)>,
+    #[allow(dead_code)] // only needed for its drop behaviour
+    HeldLock,
+);
+
+impl<'a, T, P: MutexPermission, I, M: MutexBackend<T> + 'a>
+    DeadlockProofMutexGuard<'a, T, P, I, M>
+{
+    /// Makes a guard for part of the locked data, such as one field of a
+    /// struct, so that access to just that part can be handed out. The
+    /// mutex stays locked, and the permission stays with the new guard.
+    pub fn map<U: ?Sized>(
+        self,
+        f: impl FnOnce(&mut T) -> &mut U,
+    ) -> DeadlockProofMappedMutexGuard<'a, T, U, P, I, M> {
+        let Self(mut guard, permission, _, held) = self;
+        // The data lives within the mutex rather than the guard, so this
+        // stays valid for as long as the guard is kept.
+        let data = NonNull::from(f(&mut guard));
+        DeadlockProofMappedMutexGuard(guard, data, permission, PhantomData, held)
+    }
+
+    /// Attempts to make a guard for part of the locked data, as for
+    /// [`DeadlockProofMutexGuard::map`]. If `f` returns `None`, the original
+    /// guard is handed back.
+    pub fn try_map<U: ?Sized>(
+        self,
+        f: impl FnOnce(&mut T) -> Option<&mut U>,
+    ) -> Result<DeadlockProofMappedMutexGuard<'a, T, U, P, I, M>, Self> {
+        let Self(mut guard, permission, mutex, held) = self;
+        match f(&mut guard).map(NonNull::from) {
+            Some(data) => Ok(DeadlockProofMappedMutexGuard(
+                guard,
+                data,
+                permission,
+                PhantomData,
+                held,
+            )),
+            None => Err(Self(guard, permission, mutex, held)),
+        }
+    }
+}
+
+impl<'a, T, U: ?Sized, P: MutexPermission, I, M: MutexBackend<T> + 'a>
+    DeadlockProofMappedMutexGuard<'a, T, U, P, I, M>
+{
+    /// Unlock the mutex. Returns the mutex permission token such that you
+    /// can use it again to claim a different mutex.
+    pub fn unlock(mut self) -> P {
+        self.4.disarm();
+        self.2
+    }
+
+    /// Unlock the mutex. Returns the mutex permission token such that you
+    /// can use it again to claim a different mutex. Also, returns an extra
+    /// mutex permission token so that you can claim another mutex in
+    /// a certain sequence, which the type system will guarantee is the same
+    /// for all threads.
+    pub fn unlock_for_sequential(mut self) -> SequentialMutexPermission<P, I> {
+        self.4.disarm();
+        SequentialMutexPermission::new(self.2)
+    }
+
+    /// Narrows this guard further to part of the data it already gives
+    /// access to.
+    pub fn map<V: ?Sized>(
+        self,
+        f: impl FnOnce(&mut U) -> &mut V,
+    ) -> DeadlockProofMappedMutexGuard<'a, T, V, P, I, M> {
+        let Self(guard, mut data, permission, _, held) = self;
+        // Safety: `data` is valid for as long as `guard` is kept.
+        let data = NonNull::from(f(unsafe { data.as_mut() }));
+        DeadlockProofMappedMutexGuard(guard, data, permission, PhantomData, held)
+    }
+
+    /// Attempts to narrow this guard further, as for
+    /// [`DeadlockProofMappedMutexGuard::map`]. If `f` returns `None`, the
+    /// original guard is handed back.
+    pub fn try_map<V: ?Sized>(
+        self,
+        f: impl FnOnce(&mut U) -> Option<&mut V>,
+    ) -> Result<DeadlockProofMappedMutexGuard<'a, T, V, P, I, M>, Self> {
+        let Self(guard, mut data, permission, identifier, held) = self;
+        // Safety: `data` is valid for as long as `guard` is kept.
+        match f(unsafe { data.as_mut() }).map(NonNull::from) {
+            Some(narrowed) => Ok(DeadlockProofMappedMutexGuard(
+                guard,
+                narrowed,
+                permission,
+                PhantomData,
+                held,
+            )),
+            None => Err(Self(guard, data, permission, identifier, held)),
+        }
+    }
+}
+
+impl<'a, T, U: ?Sized, P: MutexPermission, I, M: MutexBackend<T> + 'a> Deref
+    for DeadlockProofMappedMutexGuard<'a, T, U, P, I, M>
+{
+    type Target = U;
+
+    fn deref(&self) -> &U {
+        // Safety: `data` is valid for as long as the guard is kept.
+        unsafe { self.1.as_ref() }
+    }
+}
+
+impl<'a, T, U: ?Sized, P: MutexPermission, I, M: MutexBackend<T> + 'a> DerefMut
+    for DeadlockProofMappedMutexGuard<'a, T, U, P, I, M>
+{
+    fn deref_mut(&mut self) -> &mut U {
+        // Safety: `data` is valid for as long as the guard is kept.
+        unsafe { self.1.as_mut() }
+    }
+}
+
+impl<'a, T, U: ?Sized + fmt::Debug, P: MutexPermission, I, M: MutexBackend<T> + 'a> fmt::Debug
+    for DeadlockProofMappedMutexGuard<'a, T, U, P, I, M>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.deref(), f)
+    }
+}
+
+impl<'a, T, U: ?Sized + fmt::Display, P: MutexPermission, I, M: MutexBackend<T> + 'a> fmt::Display
+    for DeadlockProofMappedMutexGuard<'a, T, U, P, I, M>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.deref(), f)
+    }
+}
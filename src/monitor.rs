@@ -0,0 +1,322 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Per-mutex instrumentation. Unless one of the features needing it is
+//! enabled, [`Monitor`] is an empty type and claiming a mutex through it is
+//! exactly the same as claiming the underlying mutex directly.
+
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+#[cfg(feature = "std")]
+use crate::{domain, ViolationAction};
+use crate::{
+    poison::{LockResult, TryLockError, TryLockResult},
+    HeldLock, MutexBackend, PermissionDomain,
+};
+
+#[cfg(feature = "diagnostics")]
+use crate::diagnostics::{self, MutexName};
+#[cfg(all(feature = "identifier-check", debug_assertions))]
+use crate::duplicates::Registration;
+#[cfg(feature = "observer")]
+use crate::observer::{self, ObserverSlot};
+#[cfg(feature = "priority-inversion")]
+use crate::priority_inversion::HolderRecord;
+#[cfg(feature = "stats")]
+use crate::stats::{HoldTimer, StatsRecord};
+#[cfg(feature = "violation-override")]
+use crate::violation::ActionOverride;
+#[cfg(any(
+    feature = "diagnostics",
+    feature = "observer",
+    feature = "runtime-checks"
+))]
+use crate::waiters;
+#[cfg(feature = "runtime-checks")]
+use crate::{runtime_checks, runtime_checks::NodeId};
+
+/// Stored within each mutex; all claims of the underlying mutex go through
+/// this.
+pub(crate) struct Monitor {
+    #[cfg(feature = "diagnostics")]
+    pub(crate) name: MutexName,
+    #[cfg(all(feature = "identifier-check", debug_assertions))]
+    registration: Registration,
+    #[cfg(feature = "observer")]
+    pub(crate) observer: ObserverSlot,
+    #[cfg(feature = "priority-inversion")]
+    holder: HolderRecord,
+    #[cfg(feature = "stats")]
+    pub(crate) stats: StatsRecord,
+    #[cfg(feature = "runtime-checks")]
+    node: NodeId,
+    #[cfg(feature = "violation-override")]
+    pub(crate) action: ActionOverride,
+}
+
+impl Monitor {
+    pub(crate) const fn new() -> Self {
+        Self {
+            #[cfg(feature = "diagnostics")]
+            name: MutexName::new(),
+            #[cfg(all(feature = "identifier-check", debug_assertions))]
+            registration: Registration::new(),
+            #[cfg(feature = "observer")]
+            observer: ObserverSlot::new(),
+            #[cfg(feature = "priority-inversion")]
+            holder: HolderRecord::new(),
+            #[cfg(feature = "stats")]
+            stats: StatsRecord::new(),
+            #[cfg(feature = "runtime-checks")]
+            node: NodeId::new(),
+            #[cfg(feature = "violation-override")]
+            action: ActionOverride::new(),
+        }
+    }
+
+    /// How to report violations involving this mutex, if not as configured
+    /// globally.
+    #[cfg(feature = "std")]
+    #[cfg_attr(not(feature = "violation-override"), allow(clippy::unused_self))]
+    fn violation_action(&self) -> Option<ViolationAction> {
+        #[cfg(feature = "violation-override")]
+        let action = self.action.get();
+        #[cfg(not(feature = "violation-override"))]
+        let action = None;
+        action
+    }
+
+    /// Records that this mutex was created with an identifier value of type
+    /// `I`, which should be unique to it.
+    #[cfg_attr(
+        not(all(feature = "identifier-check", debug_assertions)),
+        allow(clippy::extra_unused_type_parameters, clippy::unused_self)
+    )]
+    pub(crate) fn register<I: ?Sized>(&mut self) {
+        #[cfg(all(feature = "identifier-check", debug_assertions))]
+        self.registration.register::<I>();
+    }
+
+    /// Claims `mutex`, whose identifier is `I`, using a permission from
+    /// domain `D`.
+    pub(crate) fn lock<
+        'a,
+        T: ?Sized,
+        I: ?Sized,
+        D: PermissionDomain,
+        M: MutexBackend<T> + ?Sized,
+    >(
+        &self,
+        mutex: &'a M,
+    ) -> LockResult<M::Guard<'a>> {
+        #[cfg(feature = "stats")]
+        let start = Instant::now();
+        let result = self.lock_unrecorded::<T, I, D, M>(mutex);
+        #[cfg(feature = "stats")]
+        self.record_acquisition(start);
+        result
+    }
+
+    /// Claims `mutex` as [`Monitor::lock`] does, but without counting it in
+    /// the statistics, for claims which might not be handed out in a guard.
+    /// Call [`Monitor::record_acquisition`] for the one which is.
+    #[cfg_attr(not(feature = "std"), allow(clippy::extra_unused_type_parameters))]
+    pub(crate) fn lock_unrecorded<
+        'a,
+        T: ?Sized,
+        I: ?Sized,
+        D: PermissionDomain,
+        M: MutexBackend<T> + ?Sized,
+    >(
+        &self,
+        mutex: &'a M,
+    ) -> LockResult<M::Guard<'a>> {
+        #[cfg(feature = "std")]
+        domain::check_lock_reporting::<D, I>(self.violation_action());
+        #[cfg(feature = "runtime-checks")]
+        runtime_checks::before_lock::<I>(&self.node, self.violation_action());
+        #[cfg(any(feature = "observer", feature = "tracing"))]
+        let result = self.lock_timed::<T, I, M>(mutex);
+        #[cfg(not(any(feature = "observer", feature = "tracing")))]
+        let result = self.lock_blocking::<T, I, M>(mutex);
+        self.claimed::<I, M>(mutex);
+        result
+    }
+
+    /// Counts a claim of this mutex in the statistics, having started
+    /// waiting for it at `since`.
+    #[cfg_attr(not(feature = "stats"), allow(unused_variables, clippy::unused_self))]
+    #[cfg(feature = "std")]
+    pub(crate) fn record_acquisition(&self, since: Instant) {
+        #[cfg(feature = "stats")]
+        self.stats.record_acquisition(since.elapsed());
+    }
+
+    #[cfg_attr(
+        not(feature = "priority-inversion"),
+        allow(clippy::extra_unused_type_parameters)
+    )]
+    fn lock_blocking<'a, T: ?Sized, I: ?Sized, M: MutexBackend<T> + ?Sized>(
+        &self,
+        mutex: &'a M,
+    ) -> LockResult<M::Guard<'a>> {
+        #[cfg(feature = "priority-inversion")]
+        let result = self.holder.lock::<T, I, M>(mutex);
+        #[cfg(not(feature = "priority-inversion"))]
+        let result = mutex.lock();
+        result
+    }
+
+    /// Claims `mutex` as [`Monitor::lock_blocking`] does, but first tries
+    /// without blocking so that contention can be reported.
+    #[cfg(any(feature = "observer", feature = "tracing"))]
+    fn lock_timed<'a, T: ?Sized, I: ?Sized, M: MutexBackend<T> + ?Sized>(
+        &self,
+        mutex: &'a M,
+    ) -> LockResult<M::Guard<'a>> {
+        let result = match mutex.try_lock() {
+            Ok(guard) => Ok(guard),
+            Err(TryLockError::Poisoned(e)) => Err(e),
+            Err(TryLockError::WouldBlock) => {
+                let start = Instant::now();
+                let result = self.lock_blocking::<T, I, M>(mutex);
+                #[cfg(feature = "tracing")]
+                tracing::debug!(
+                    identifier = %crate::IdentifierName::of::<I>(),
+                    waited_micros = start.elapsed().as_micros() as u64,
+                    "deadlock-proof lock contended"
+                );
+                #[cfg(feature = "observer")]
+                observer::contended::<I>(&self.observer, start.elapsed());
+                return result;
+            }
+        };
+        #[cfg(feature = "priority-inversion")]
+        self.holder.record_holder();
+        result
+    }
+
+    /// Attempts to claim `mutex`, whose identifier is `I`, without blocking.
+    pub(crate) fn try_lock<'a, T: ?Sized, I: ?Sized, M: MutexBackend<T> + ?Sized>(
+        &self,
+        mutex: &'a M,
+    ) -> TryLockResult<M::Guard<'a>> {
+        #[cfg(feature = "std")]
+        let result = self.try_lock_since::<T, I, M>(mutex, Instant::now());
+        #[cfg(not(feature = "std"))]
+        let result = self.try_claim::<T, I, M>(mutex);
+        result
+    }
+
+    /// Attempts to claim `mutex` as [`Monitor::try_lock`] does, as one of
+    /// a series of attempts begun at `since`, which is counted as waiting
+    /// in the statistics.
+    #[cfg(feature = "std")]
+    pub(crate) fn try_lock_since<'a, T: ?Sized, I: ?Sized, M: MutexBackend<T> + ?Sized>(
+        &self,
+        mutex: &'a M,
+        since: Instant,
+    ) -> TryLockResult<M::Guard<'a>> {
+        let result = self.try_claim::<T, I, M>(mutex);
+        if !matches!(result, Err(TryLockError::WouldBlock)) {
+            self.record_acquisition(since);
+        }
+        result
+    }
+
+    /// Attempts to claim `mutex` as [`Monitor::try_lock`] does, without
+    /// counting it in the statistics.
+    fn try_claim<'a, T: ?Sized, I: ?Sized, M: MutexBackend<T> + ?Sized>(
+        &self,
+        mutex: &'a M,
+    ) -> TryLockResult<M::Guard<'a>> {
+        let result = mutex.try_lock();
+        if !matches!(result, Err(TryLockError::WouldBlock)) {
+            #[cfg(feature = "priority-inversion")]
+            self.holder.record_holder();
+            self.claimed::<I, M>(mutex);
+        }
+        result
+    }
+
+    /// Records that `mutex`, which this monitors and whose identifier is
+    /// `I`, is held by the current thread using a permission from domain
+    /// `D`, as [`HeldLock::new`] does, also timing the hold for the
+    /// statistics. The record must belong to a guard which borrows or owns
+    /// the mutex, so that it can't outlive this.
+    #[cfg_attr(not(feature = "stats"), allow(clippy::unused_self))]
+    pub(crate) fn held<I: ?Sized, D: PermissionDomain, M: ?Sized>(&self, mutex: &M) -> HeldLock {
+        #[cfg_attr(not(feature = "stats"), allow(unused_mut))]
+        let mut held = HeldLock::new::<I, D, M>(mutex);
+        // Safety: the record can't outlive this, as required above.
+        #[cfg(feature = "stats")]
+        held.time_hold(unsafe { HoldTimer::start(&self.stats) });
+        held
+    }
+
+    /// Requires the guard holding `held`, whose identifier is `I`, to be
+    /// unlocked rather than dropped, as [`HeldLock::arm`] does, reporting
+    /// a leak as this mutex says to.
+    pub(crate) fn arm<I: ?Sized>(&self, held: &mut HeldLock) {
+        held.arm::<I>();
+        #[cfg(all(feature = "leak-check", debug_assertions))]
+        held.report_with(self.violation_action());
+    }
+
+    /// Records that `mutex`, whose identifier is `I`, has been claimed.
+    #[cfg_attr(
+        not(any(
+            feature = "diagnostics",
+            feature = "observer",
+            feature = "runtime-checks"
+        )),
+        allow(unused_variables)
+    )]
+    #[cfg_attr(
+        not(any(feature = "diagnostics", feature = "observer")),
+        allow(clippy::extra_unused_type_parameters)
+    )]
+    fn claimed<I: ?Sized, M: ?Sized>(&self, mutex: &M) {
+        #[cfg(feature = "runtime-checks")]
+        runtime_checks::claimed(waiters::key(mutex), &self.node);
+        #[cfg(feature = "diagnostics")]
+        diagnostics::claimed::<I>(waiters::key(mutex), &self.name);
+        #[cfg(feature = "observer")]
+        observer::claimed::<I>(waiters::key(mutex), &self.observer);
+    }
+
+    /// Records that a guard for `mutex` claimed through [`Monitor::lock`]
+    /// has been dropped without ever being wrapped in a deadlock-proof
+    /// guard, whose [`crate::HeldLock`] would otherwise do this.
+    #[cfg_attr(
+        not(any(
+            feature = "diagnostics",
+            feature = "observer",
+            feature = "runtime-checks"
+        )),
+        allow(unused_variables)
+    )]
+    #[cfg(feature = "std")]
+    pub(crate) fn released<M: ?Sized>(&self, mutex: &M) {
+        #[cfg(feature = "runtime-checks")]
+        runtime_checks::released(waiters::key(mutex));
+        #[cfg(feature = "diagnostics")]
+        diagnostics::released(waiters::key(mutex));
+        #[cfg(feature = "observer")]
+        observer::released(waiters::key(mutex));
+    }
+
+    /// Records that [`crate::DeadlockProofMutex::try_lock_until`] missed its
+    /// deadline.
+    #[cfg(feature = "std")]
+    pub(crate) fn record_deadline_miss(&self) {
+        #[cfg(feature = "stats")]
+        self.stats.record_deadline_miss();
+    }
+}
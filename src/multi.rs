@@ -0,0 +1,210 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{LockResult, PoisonError},
+};
+
+use crate::{DeadlockProofMutex, HeldLocks, LockAfter, MutexBackend, MutexPermission};
+
+/// A tuple of references to between two and eight mutices, all with the
+/// permission type `P`, whose identifiers are listed in the order declared
+/// with [`crate::declare_lock_order`]. These can be grouped into a
+/// [`MultiLock`].
+pub trait LockGroup<'a, P: MutexPermission> {
+    /// References to the contents of each mutex, in the same order.
+    type Contents<'g>
+    where
+        Self: 'g;
+
+    /// Mutable references to the contents of each mutex, in the same order.
+    type ContentsMut<'g>
+    where
+        Self: 'g;
+
+    #[doc(hidden)]
+    type Guards;
+
+    #[doc(hidden)]
+    fn lock_all(&self) -> (Self::Guards, HeldLocks, bool);
+
+    #[doc(hidden)]
+    fn contents<'g>(guards: &'g Self::Guards) -> Self::Contents<'g>
+    where
+        Self: 'g;
+
+    #[doc(hidden)]
+    fn contents_mut<'g>(guards: &'g mut Self::Guards) -> Self::ContentsMut<'g>
+    where
+        Self: 'g;
+}
+
+/// A group of mutices which are always claimed and released together, as a
+/// transaction over all of them would be: `MultiLock::new((&a, &b, &c))`.
+///
+/// The mutices must share the permission type `P` and be listed in their
+/// declared lock order, which is the order they're claimed in, so a single
+/// permission claims the whole group using [`MultiLock::lock`] without
+/// threading a permission through each mutex in turn. The group as a whole
+/// then takes the place in the lock ordering of its first mutex.
+pub struct MultiLock<L>(L);
+
+impl<L> MultiLock<L> {
+    /// Groups the mutices in the tuple `mutices`.
+    pub fn new<'a, P: MutexPermission>(mutices: L) -> Self
+    where
+        L: LockGroup<'a, P>,
+    {
+        Self(mutices)
+    }
+
+    /// Acquires every mutex in the group, in their lock order, blocking the
+    /// current thread until it is able to do so. If any mutex is poisoned,
+    /// the error still contains a guard for all of them, as for
+    /// [`DeadlockProofMutex::lock`].
+    // The error holds the guard, just as for every other way of locking.
+    #[allow(clippy::result_large_err)]
+    pub fn lock<'a, P: MutexPermission>(
+        &self,
+        permission: P,
+    ) -> LockResult<DeadlockProofMultiGuard<'a, P, L>>
+    where
+        L: LockGroup<'a, P>,
+    {
+        let (guards, held, poisoned) = self.0.lock_all();
+        let guard = DeadlockProofMultiGuard(guards, held, permission);
+        if poisoned {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+}
+
+/// A guard for all the mutices of a [`MultiLock`], obtained from
+/// [`MultiLock::lock`]. As with [`crate::DeadlockProofMutexGuard`], it's
+/// strongly recommended that you explicitly unlock this rather than
+/// allowing it to drop.
+pub struct DeadlockProofMultiGuard<'a, P: MutexPermission, L: LockGroup<'a, P>>(
+    L::Guards,
+    #[allow(dead_code)] // only needed for its drop behaviour
+    HeldLocks,
+    P,
+);
+
+impl<'a, P: MutexPermission, L: LockGroup<'a, P>> DeadlockProofMultiGuard<'a, P, L> {
+    /// Unlock all the mutices. Returns the mutex permission token such that
+    /// you can use it again to claim a different mutex.
+    pub fn unlock(self) -> P {
+        self.2
+    }
+
+    /// The contents of every mutex, in the order they were grouped.
+    pub fn get(&self) -> L::Contents<'_> {
+        L::contents(&self.0)
+    }
+
+    /// The contents of every mutex, mutably, in the order they were
+    /// grouped.
+    pub fn get_mut(&mut self) -> L::ContentsMut<'_> {
+        L::contents_mut(&mut self.0)
+    }
+}
+
+macro_rules! impl_lock_group {
+    (
+        $first_index:tt $first_content:ident $first_identifier:ident $first_backend:ident
+        $(, $index:tt $content:ident $identifier:ident $backend:ident $previous:ident)+
+    ) => {
+        impl<
+                'a,
+                P: MutexPermission,
+                $first_content,
+                $first_identifier,
+                $first_backend: MutexBackend<$first_content> + 'a,
+                $($content, $identifier: LockAfter<$previous>, $backend: MutexBackend<$content> + 'a),+
+            > LockGroup<'a, P>
+            for (
+                &'a DeadlockProofMutex<$first_content, P, $first_identifier, $first_backend>,
+                $(&'a DeadlockProofMutex<$content, P, $identifier, $backend>),+
+            )
+        {
+            type Contents<'g>
+                = (&'g $first_content, $(&'g $content),+)
+            where
+                Self: 'g;
+
+            type ContentsMut<'g>
+                = (&'g mut $first_content, $(&'g mut $content),+)
+            where
+                Self: 'g;
+
+            type Guards = (
+                $first_backend::Guard<'a>,
+                $($backend::Guard<'a>),+
+            );
+
+            fn lock_all(&self) -> (Self::Guards, HeldLocks, bool) {
+                let mut poisoned = false;
+                let guards = (
+                    self.$first_index
+                        .0
+                        .lock::<$first_content, $first_identifier, P::Domain, $first_backend>(
+                            &self.$first_index.3,
+                        )
+                        .unwrap_or_else(|e| {
+                            poisoned = true;
+                            e.into_inner()
+                        }),
+                    $(
+                        self.$index
+                            .0
+                            .lock::<$content, $identifier, P::Domain, $backend>(&self.$index.3)
+                            .unwrap_or_else(|e| {
+                                poisoned = true;
+                                e.into_inner()
+                            }),
+                    )+
+                );
+                let held = HeldLocks(vec![
+                    self.$first_index.0.held::<$first_identifier, P::Domain, _>(&self.$first_index.3),
+                    $(self.$index.0.held::<$identifier, P::Domain, _>(&self.$index.3)),+
+                ]);
+                (guards, held, poisoned)
+            }
+
+            fn contents<'g>(guards: &'g Self::Guards) -> Self::Contents<'g>
+            where
+                Self: 'g,
+            {
+                (guards.$first_index.deref(), $(guards.$index.deref()),+)
+            }
+
+            fn contents_mut<'g>(guards: &'g mut Self::Guards) -> Self::ContentsMut<'g>
+            where
+                Self: 'g,
+            {
+                (guards.$first_index.deref_mut(), $(guards.$index.deref_mut()),+)
+            }
+        }
+    };
+}
+
+impl_lock_group!(0 A IA MA, 1 B IB MB IA);
+impl_lock_group!(0 A IA MA, 1 B IB MB IA, 2 C IC MC IB);
+impl_lock_group!(0 A IA MA, 1 B IB MB IA, 2 C IC MC IB, 3 D ID MD IC);
+impl_lock_group!(0 A IA MA, 1 B IB MB IA, 2 C IC MC IB, 3 D ID MD IC, 4 E IE ME ID);
+impl_lock_group!(0 A IA MA, 1 B IB MB IA, 2 C IC MC IB, 3 D ID MD IC, 4 E IE ME ID, 5 F IF MF IE);
+impl_lock_group!(
+    0 A IA MA, 1 B IB MB IA, 2 C IC MC IB, 3 D ID MD IC, 4 E IE ME ID, 5 F IF MF IE, 6 G IG MG IF
+);
+impl_lock_group!(
+    0 A IA MA, 1 B IB MB IA, 2 C IC MC IB, 3 D ID MD IC, 4 E IE ME ID, 5 F IF MF IE, 6 G IG MG IF,
+    7 H IH MH IG
+);
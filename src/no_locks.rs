@@ -0,0 +1,38 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::marker::PhantomData;
+
+use crate::{domain, DefaultDomain, OuterMutexPermission};
+
+/// Proof that the current thread holds no deadlock-proof mutex, for APIs
+/// which mustn't be called under a lock, such as those performing blocking
+/// I/O or joining threads. Library code can demand one of these in its
+/// signatures to encode that contract, without taking the caller's
+/// [`OuterMutexPermission`] away.
+///
+/// The only way to obtain one is [`OuterMutexPermission::no_locks_held`],
+/// which borrows the permission for as long as the proof exists, so no
+/// mutex can be claimed in the meantime. Once it's gone, the permission is
+/// free to use again. It's zero-sized and `Copy`, so can be passed down
+/// freely, but isn't `Send`, since it only says something about the thread
+/// which created it. As with [`crate::DeadlockProofBarrier`], mutices from
+/// other permission domains can't be ruled out by the type system, so
+/// creating one while holding them is reported at runtime as a
+/// [`crate::Violation`].
+#[derive(Clone, Copy, Debug)]
+pub struct NoLocksHeld<'a>(PhantomData<&'a OuterMutexPermission>);
+
+impl OuterMutexPermission {
+    /// Proves that this thread holds no deadlock-proof mutex, for as long as
+    /// the permission is borrowed. See [`NoLocksHeld`].
+    pub fn no_locks_held(&self) -> NoLocksHeld<'_> {
+        domain::check_lock::<DefaultDomain, NoLocksHeld<'static>>();
+        NoLocksHeld(PhantomData)
+    }
+}
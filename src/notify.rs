@@ -0,0 +1,266 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#[cfg(any(feature = "tokio", feature = "futures"))]
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use std::{
+    sync::{Mutex, MutexGuard, PoisonError},
+    thread::{self, Thread},
+};
+
+#[cfg(any(feature = "tokio", feature = "futures"))]
+use crate::AsyncOuterMutexPermission;
+use crate::{domain, DefaultDomain, OuterMutexPermission};
+
+/// How to wake a waiter.
+pub(crate) enum Wake {
+    Thread(Thread),
+    #[cfg(any(feature = "tokio", feature = "futures"))]
+    Task(Waker),
+}
+
+struct Waiter {
+    id: u64,
+    notified: bool,
+    wake: Wake,
+}
+
+/// The threads and tasks waiting for a [`DeadlockProofNotify`], or for a
+/// [`crate::DeadlockProofAsyncCondvar`].
+#[derive(Default)]
+pub(crate) struct NotifyState {
+    /// Set by [`DeadlockProofNotify::notify_one`] when nobody is waiting.
+    permit: bool,
+    next_id: u64,
+    /// In the order they started waiting.
+    waiters: Vec<Waiter>,
+}
+
+impl NotifyState {
+    pub(crate) fn register(&mut self, wake: Wake) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.waiters.push(Waiter {
+            id,
+            notified: false,
+            wake,
+        });
+        id
+    }
+
+    /// Removes the waiter with the given id, returning whether it had been
+    /// notified.
+    pub(crate) fn remove(&mut self, id: u64) -> bool {
+        match self.waiters.iter().position(|waiter| waiter.id == id) {
+            Some(pos) => self.waiters.remove(pos).notified,
+            None => false,
+        }
+    }
+
+    fn notify_one(&mut self) {
+        if !self.wake_one() {
+            self.permit = true;
+        }
+    }
+
+    /// Wakes the waiter which has been waiting longest, if any, returning
+    /// whether there was one.
+    pub(crate) fn wake_one(&mut self) -> bool {
+        match self.waiters.iter_mut().find(|waiter| !waiter.notified) {
+            Some(waiter) => {
+                waiter.notified = true;
+                waiter.wake.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes every waiter.
+    pub(crate) fn wake_all(&mut self) {
+        for waiter in &mut self.waiters {
+            if !waiter.notified {
+                waiter.notified = true;
+                waiter.wake.wake();
+            }
+        }
+    }
+
+    /// If the waiter with the given id has been notified, removes it and
+    /// returns true; otherwise updates how to wake it.
+    #[cfg(any(feature = "tokio", feature = "futures"))]
+    pub(crate) fn poll_notified(&mut self, id: u64, waker: &Waker) -> bool {
+        let waiter = self
+            .waiters
+            .iter_mut()
+            .find(|waiter| waiter.id == id)
+            .expect("only the waiter removes its entry");
+        if waiter.notified {
+            self.remove(id);
+            return true;
+        }
+        waiter.wake = Wake::Task(waker.clone());
+        false
+    }
+}
+
+impl Wake {
+    fn wake(&self) {
+        match self {
+            Self::Thread(thread) => thread.unpark(),
+            #[cfg(any(feature = "tokio", feature = "futures"))]
+            Self::Task(waker) => waker.wake_by_ref(),
+        }
+    }
+}
+
+/// An event which threads and async tasks can wait for, to be woken by
+/// another thread or task. Waiting for it can't be used to build a deadlock
+/// out of deadlock-proof mutices.
+///
+/// Waiting for a notification is waiting for another thread to act, so it
+/// mustn't happen while holding a mutex that thread might need first.
+/// [`DeadlockProofNotify::wait`] therefore needs to see this thread's
+/// [`OuterMutexPermission`], and `DeadlockProofNotify::notified` the
+/// task's `AsyncOuterMutexPermission`, which prove that no mutex is held.
+/// Mutices from other permission domains are checked for at runtime as for
+/// [`crate::DeadlockProofBarrier`]. Code which really must wait while
+/// holding a mutex, knowing that the notifier never needs it, can declare
+/// so using [`DeadlockProofNotify::wait_unchecked`].
+///
+/// As with tokio's `Notify`, [`DeadlockProofNotify::notify_one`] stores a
+/// single permit if nobody is waiting, which the next waiter consumes
+/// immediately, while [`DeadlockProofNotify::notify_all`] only wakes those
+/// already waiting.
+#[derive(Default)]
+pub struct DeadlockProofNotify(Mutex<NotifyState>);
+
+impl DeadlockProofNotify {
+    /// Create a new deadlock-proof notify with no stored permit.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, NotifyState> {
+        // The state is always consistent, even if a thread panicked.
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Blocks the current thread until notified. The permission is only
+    /// borrowed, since the thread holds no mutex while waiting and so needs
+    /// it back afterwards anyway.
+    pub fn wait(&self, _permission: &OuterMutexPermission) {
+        domain::check_lock::<DefaultDomain, Self>();
+        // Safety: the permission proves that no mutex is held.
+        unsafe { self.wait_unchecked() }
+    }
+
+    /// Blocks the current thread until notified, without a permission.
+    ///
+    /// # Safety
+    ///
+    /// This voids the proof that the program can't deadlock. The caller must
+    /// ensure by other means that whoever will notify this never needs any
+    /// mutex the current thread holds. Doing otherwise can't cause undefined
+    /// behaviour, only deadlocks.
+    pub unsafe fn wait_unchecked(&self) {
+        let mut state = self.state();
+        if std::mem::take(&mut state.permit) {
+            return;
+        }
+        let id = state.register(Wake::Thread(thread::current()));
+        loop {
+            drop(state);
+            thread::park();
+            state = self.state();
+            if state
+                .waiters
+                .iter()
+                .any(|waiter| waiter.id == id && waiter.notified)
+            {
+                state.remove(id);
+                return;
+            }
+        }
+    }
+
+    /// Waits asynchronously until notified. The permission stays borrowed
+    /// until the returned future completes or is dropped, so the task can't
+    /// claim a mutex in the meantime.
+    #[cfg(any(feature = "tokio", feature = "futures"))]
+    pub fn notified<'a>(
+        &'a self,
+        _permission: &'a AsyncOuterMutexPermission,
+    ) -> DeadlockProofNotified<'a> {
+        DeadlockProofNotified(self, None)
+    }
+
+    /// Wakes one waiting thread or task. If none is waiting, stores a permit
+    /// so that the next to wait returns immediately.
+    pub fn notify_one(&self) {
+        self.state().notify_one();
+    }
+
+    /// Wakes every thread and task currently waiting.
+    pub fn notify_all(&self) {
+        self.state().wake_all();
+    }
+}
+
+/// The future returned by [`DeadlockProofNotify::notified`].
+#[cfg(any(feature = "tokio", feature = "futures"))]
+pub struct DeadlockProofNotified<'a>(
+    &'a DeadlockProofNotify,
+    /// This future's entry in the list of waiters, once it has one.
+    Option<u64>,
+);
+
+#[cfg(any(feature = "tokio", feature = "futures"))]
+impl Future for DeadlockProofNotified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.0.state();
+        match self.1 {
+            None => {
+                if std::mem::take(&mut state.permit) {
+                    return Poll::Ready(());
+                }
+                let id = state.register(Wake::Task(cx.waker().clone()));
+                drop(state);
+                self.1 = Some(id);
+                Poll::Pending
+            }
+            Some(id) => {
+                if state.poll_notified(id, cx.waker()) {
+                    drop(state);
+                    self.1 = None;
+                    return Poll::Ready(());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(any(feature = "tokio", feature = "futures"))]
+impl Drop for DeadlockProofNotified<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.1 {
+            let mut state = self.0.state();
+            // A notification meant for this future mustn't be lost.
+            if state.remove(id) {
+                state.notify_one();
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Hooks for feeding lock activity into metrics systems of the user's
+//! choice.
+
+use std::{
+    cell::RefCell,
+    sync::{PoisonError, RwLock},
+    time::{Duration, Instant},
+};
+
+/// Callbacks made as deadlock-proof mutices are used, for recording metrics.
+/// Install one for every mutex with [`set_lock_observer`], or for a single
+/// mutex with [`crate::DeadlockProofMutex::set_observer`].
+///
+/// Each callback is passed the type name of the mutex's identifier. They
+/// run on the thread using the mutex, in some cases while it's held, so
+/// they should be quick and mustn't claim deadlock-proof mutices.
+pub trait LockObserver: Send + Sync {
+    /// The mutex was held elsewhere, so this thread waited `waited` for it.
+    fn on_contended(&self, _identifier: &'static str, _waited: Duration) {}
+
+    /// The mutex has been claimed.
+    fn on_acquired(&self, _identifier: &'static str) {}
+
+    /// The mutex has been released, after being held for `held`.
+    fn on_released(&self, _identifier: &'static str, _held: Duration) {}
+}
+
+type ObserverRef = Option<&'static dyn LockObserver>;
+
+static GLOBAL_OBSERVER: RwLock<ObserverRef> = RwLock::new(None);
+
+/// Installs an observer for all deadlock-proof mutices which don't have
+/// their own.
+pub fn set_lock_observer(observer: &'static dyn LockObserver) {
+    *GLOBAL_OBSERVER
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = Some(observer);
+}
+
+/// An observed mutex held by this thread.
+struct ObservedHold {
+    /// The key used by [`crate::waiters`].
+    key: usize,
+    observer: &'static dyn LockObserver,
+    identifier: &'static str,
+    since: Instant,
+}
+
+thread_local! {
+    static HELD: RefCell<Vec<ObservedHold>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The observer installed for one mutex, stored within it.
+pub(crate) struct ObserverSlot(RwLock<ObserverRef>);
+
+impl ObserverSlot {
+    pub(crate) const fn new() -> Self {
+        Self(RwLock::new(None))
+    }
+
+    pub(crate) fn set(&self, observer: &'static dyn LockObserver) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = Some(observer);
+    }
+
+    /// This mutex's observer, or failing that the global one.
+    fn get(&self) -> ObserverRef {
+        let own = *self.0.read().unwrap_or_else(PoisonError::into_inner);
+        own.or_else(|| {
+            *GLOBAL_OBSERVER
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+        })
+    }
+}
+
+/// Reports that the current thread waited `waited` for a mutex identified
+/// by `I`.
+pub(crate) fn contended<I: ?Sized>(slot: &ObserverSlot, waited: Duration) {
+    if let Some(observer) = slot.get() {
+        observer.on_contended(std::any::type_name::<I>(), waited);
+    }
+}
+
+/// Reports that the current thread has claimed the mutex with the given
+/// key, identified by `I`.
+pub(crate) fn claimed<I: ?Sized>(key: usize, slot: &ObserverSlot) {
+    if let Some(observer) = slot.get() {
+        let identifier = std::any::type_name::<I>();
+        observer.on_acquired(identifier);
+        let hold = ObservedHold {
+            key,
+            observer,
+            identifier,
+            since: Instant::now(),
+        };
+        HELD.with(|held| held.borrow_mut().push(hold));
+    }
+}
+
+/// Reports that the current thread has released the mutex with the given
+/// key, if it was observed when claimed.
+pub(crate) fn released(key: usize) {
+    // Guards may be released in any order, and the thread-local may already
+    // be gone in thread teardown.
+    let hold = HELD
+        .try_with(|held| {
+            let mut held = held.borrow_mut();
+            let pos = held.iter().rposition(|hold| hold.key == key)?;
+            Some(held.remove(pos))
+        })
+        .ok()
+        .flatten();
+    if let Some(hold) = hold {
+        hold.observer
+            .on_released(hold.identifier, hold.since.elapsed());
+    }
+}
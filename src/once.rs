@@ -0,0 +1,196 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    marker::PhantomData,
+    sync::{LockResult, Mutex, OnceLock, PoisonError},
+};
+
+use crate::{
+    domain, DeadlockProofMutex, DeadlockProofMutexGuard, HeldLock, MutexPermission,
+    NestedMutexPermission, PermissionPhantom,
+};
+
+/// A cell which is initialized once and is compile-time guaranteed not to
+/// deadlock. Otherwise similar to [`OnceLock`].
+///
+/// While one thread runs the initializer, others wanting the value wait for
+/// it, just as if the initializer held a mutex. So
+/// [`DeadlockProofOnceLock::get_or_init`] takes part in the lock ordering in
+/// exactly the same way as [`crate::DeadlockProofMutex::lock`], and the
+/// initializer is handed a [`NestedMutexPermission`] with which it may claim
+/// any mutices nested within this cell.
+pub struct DeadlockProofOnceLock<T, P: MutexPermission, I>(
+    OnceLock<T>,
+    PermissionPhantom<P>,
+    PhantomData<I>,
+);
+
+impl<T, P: MutexPermission, I> DeadlockProofOnceLock<T, P, I> {
+    /// Create a new, uninitialized, deadlock-proof cell. The `_identifier`
+    /// is a type unique to this cell, just as for
+    /// [`crate::DeadlockProofMutex::new`].
+    pub fn new(_identifier: I) -> Self {
+        Self(OnceLock::new(), PhantomData, PhantomData)
+    }
+
+    /// Gets the value if the cell has been initialized. This never blocks,
+    /// so needs no permission.
+    pub fn get(&self) -> Option<&T> {
+        self.0.get()
+    }
+
+    /// Gets the value, first initializing it with `f` if nobody has yet.
+    /// This may wait for another thread's initializer, so requires a
+    /// permission token, which is returned along with the value.
+    pub fn get_or_init(
+        &self,
+        permission: P,
+        f: impl FnOnce(&mut NestedMutexPermission<P, I>) -> T,
+    ) -> (&T, P) {
+        if let Some(value) = self.0.get() {
+            return (value, permission);
+        }
+        domain::check_lock::<P::Domain, I>();
+        let value = self.0.get_or_init(|| {
+            let _held = HeldLock::new::<I, P::Domain, _>(&self.0);
+            f(&mut NestedMutexPermission::new())
+        });
+        (value, permission)
+    }
+}
+
+/// A value which is initialized on first use and is compile-time guaranteed
+/// not to deadlock. Otherwise similar to [`std::sync::LazyLock`], except
+/// that it can't be dereferenced directly, since the first use may need to
+/// wait for another thread; use [`DeadlockProofLazyLock::force`] instead.
+pub struct DeadlockProofLazyLock<
+    T,
+    P: MutexPermission,
+    I,
+    F = fn(&mut NestedMutexPermission<P, I>) -> T,
+>(DeadlockProofOnceLock<T, P, I>, Mutex<Option<F>>);
+
+impl<T, P: MutexPermission, I, F: FnOnce(&mut NestedMutexPermission<P, I>) -> T>
+    DeadlockProofLazyLock<T, P, I, F>
+{
+    /// Create a new deadlock-proof lazy value, initialized by `f`. The
+    /// `identifier` is a type unique to this value, just as for
+    /// [`crate::DeadlockProofMutex::new`].
+    pub fn new(f: F, identifier: I) -> Self {
+        Self(DeadlockProofOnceLock::new(identifier), Mutex::new(Some(f)))
+    }
+
+    /// Gets the value if it has been initialized. This never blocks, so
+    /// needs no permission.
+    pub fn get(&self) -> Option<&T> {
+        self.0.get()
+    }
+
+    /// Gets the value, first initializing it if nobody has yet. As with
+    /// [`DeadlockProofOnceLock::get_or_init`], this requires a permission
+    /// token, which is returned along with the value.
+    pub fn force(&self, permission: P) -> (&T, P) {
+        self.0.get_or_init(permission, |nested| {
+            let f = self
+                .1
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take()
+                .expect("DeadlockProofLazyLock initializer panicked previously");
+            f(nested)
+        })
+    }
+}
+
+/// A deadlock-proof mutex whose contents are created by `F` on first use,
+/// for globals whose contents can't be created in a `const` context. See
+/// [`crate::declare_lazy_deadlock_proof_mutex`] for an easy way to declare
+/// one in a `static`.
+///
+/// While one thread runs the initializer, others wanting the mutex wait for
+/// it, and the initializer could claim other mutices with a permission of
+/// its own, so it can't be dereferenced directly. As for
+/// [`DeadlockProofLazyLock::force`], the first use requires a permission
+/// token, and waiting for the initializer takes part in the lock ordering
+/// just like claiming the mutex itself. [`LazyDeadlockProofMutex::lock`]
+/// does both at once.
+pub struct LazyDeadlockProofMutex<T, P: MutexPermission, I, F = fn() -> T>(
+    DeadlockProofOnceLock<DeadlockProofMutex<T, P, I>, P, I>,
+    F,
+);
+
+impl<T, P: MutexPermission, I, F: Fn() -> T> LazyDeadlockProofMutex<T, P, I, F> {
+    /// Create a new lazily initialized deadlock-proof mutex, whose contents
+    /// will be created by `f`. The `identifier` is just as for
+    /// [`DeadlockProofMutex::const_new`].
+    pub const fn new(f: F, identifier: I) -> Self {
+        // An identifier is only a marker, so there's nothing to drop.
+        std::mem::forget(identifier);
+        Self(
+            DeadlockProofOnceLock(OnceLock::new(), PhantomData, PhantomData),
+            f,
+        )
+    }
+
+    /// Gets the mutex if its contents have been created. This never blocks,
+    /// so needs no permission.
+    pub fn get(&self) -> Option<&DeadlockProofMutex<T, P, I>> {
+        self.0.get()
+    }
+
+    /// Gets the mutex, first creating its contents if nobody has yet. As
+    /// with [`DeadlockProofOnceLock::get_or_init`], this requires a
+    /// permission token, which is returned along with the mutex.
+    pub fn force(&self, permission: P) -> (&DeadlockProofMutex<T, P, I>, P) {
+        self.0.get_or_init(permission, |_| {
+            DeadlockProofMutex::unidentified(Mutex::new((self.1)()))
+        })
+    }
+
+    /// Claims the mutex, first creating its contents if nobody has yet.
+    /// Equivalent to [`LazyDeadlockProofMutex::force`] followed by
+    /// [`DeadlockProofMutex::lock`].
+    pub fn lock(&self, permission: P) -> LockResult<DeadlockProofMutexGuard<'_, T, P, I>> {
+        let (mutex, permission) = self.force(permission);
+        mutex.lock(permission)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::OuterMutexPermission;
+
+    crate::declare_lazy_deadlock_proof_mutex!(
+        static SETTINGS: DeadlockProofMutex<u32, OuterMutexPermission, Settings> = {
+            // The caller's permission is taken for the initializer's whole
+            // run, so it can't claim another mutex in the same order.
+            assert!(cfg!(deadlock_proof_mutex_unverified) || !OuterMutexPermission::is_available());
+            INITIALIZED.fetch_add(1, Ordering::SeqCst);
+            7
+        };
+    );
+
+    static INITIALIZED: AtomicUsize = AtomicUsize::new(0);
+
+    #[test]
+    fn lazy_mutex_initializer_runs_without_the_permission() {
+        assert!(SETTINGS.get().is_none());
+        let mut guard = SETTINGS.lock(OuterMutexPermission::get()).unwrap();
+        *guard += 1;
+        let permission = guard.unlock();
+        let (mutex, permission) = SETTINGS.force(permission);
+        let guard = mutex.lock(permission).unwrap();
+        assert_eq!(*guard, 8);
+        guard.unlock();
+        assert_eq!(INITIALIZED.load(Ordering::SeqCst), 1);
+        assert!(SETTINGS.get().is_some());
+    }
+}
@@ -0,0 +1,399 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use core::{
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+use crate::{
+    map_lock_result,
+    poison::{LockResult, PoisonError},
+    DeadlockProofMutex, HeldLock, Mutex, MutexBackend, MutexPermission, NestedMutexPermission,
+};
+
+/// Declares that mutices with the identifier `Self` come after those with
+/// identifier `A` in a lock order, so that they can be claimed using
+/// [`DeadlockProofMutex::lock_after`] while holding one of those.
+///
+/// Don't implement this by hand: use [`crate::declare_lock_order`] or
+/// [`crate::declare_lock_sets`], which also declare the identifier types
+/// and so guarantee there are no cycles in the order.
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` isn't declared to come after `{A}` in a lock order",
+    note = "lock orders are declared using `declare_lock_order!` or `declare_lock_sets!`"
+)]
+pub trait LockAfter<A> {}
+
+/// Implemented for every pair of distinct identifiers within a lock order
+/// declared with [`crate::declare_lock_order`], saying which of the two
+/// comes first. This is what lets [`DeadlockProofMutex::lock_pair`] claim
+/// two mutices in the right order whichever way round they're passed.
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` and `{J}` aren't declared in the same lock order",
+    note = "lock orders are declared using `declare_lock_order!` or `declare_lock_sets!`"
+)]
+pub trait LockPair<J> {
+    /// Whether `Self` comes before `J` in the lock order.
+    const FIRST: bool;
+}
+
+/// Declares a lock order, along with the identifier types within it:
+/// `declare_lock_order!(pub Config < Cache < Io)` declares the types
+/// `Config`, `Cache` and `Io`, and implements [`LockAfter`] for every pair
+/// in which the first is written before the second. Mutices using these
+/// identifiers can then be claimed in any increasing order using
+/// [`DeadlockProofMutex::lock_ordered`] and
+/// [`DeadlockProofMutex::lock_after`].
+///
+/// A real lock order is often a directed acyclic graph rather than a single
+/// chain; list its identifiers in any topological order. Each identifier can
+/// only be part of one order, since the macro declares the type.
+///
+/// The order can be exported for review using [`crate::LockOrderGraph`].
+#[macro_export]
+macro_rules! declare_lock_order {
+    ($vis:vis $first:ident $(< $rest:ident)*) => {
+        #[allow(dead_code)]
+        $vis struct $first;
+        $(
+            #[allow(dead_code)]
+            $vis struct $rest;
+        )*
+        $crate::__lock_order_impls!($first $(< $rest)*);
+        $crate::__lock_order_graph!([$first] $(< [$rest])*);
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __lock_order_impls {
+    ($first:ident $(< $rest:ident)*) => {
+        $(
+            impl $crate::LockAfter<$first> for $rest {}
+            impl $crate::LockPair<$rest> for $first {
+                const FIRST: bool = true;
+            }
+            impl $crate::LockPair<$first> for $rest {
+                const FIRST: bool = false;
+            }
+        )*
+        $crate::__lock_order_impls!($($rest)<*);
+    };
+    () => {};
+}
+
+/// Declares several children of the mutex identified by `Parent`, which may
+/// be held together while the parent is held, in the order listed:
+/// `declare_children!(pub Parent => A, B, C)` declares the types `A`, `B`
+/// and `C` and orders them just as [`crate::declare_lock_order`] would.
+///
+/// Give the children's mutices the permission type
+/// `NestedMutexPermission<P, Parent>`, where `P` is the parent's permission
+/// type. Then, holding the parent, claim the first child wanted using
+/// [`DeadlockProofMutex::lock_ordered`] with the permission from
+/// [`DeadlockProofMutex::lock_for_nested`], and any later siblings using
+/// [`DeadlockProofMutex::lock_after`], without pretending the children are
+/// nested within each other. Any subset of the children can be claimed, as
+/// long as it's in this order.
+#[macro_export]
+macro_rules! declare_children {
+    ($vis:vis $parent:ident => $first:ident $(, $rest:ident)* $(,)?) => {
+        #[doc = concat!("A child of `", stringify!($parent), "`.")]
+        #[allow(dead_code)]
+        $vis struct $first;
+        $(
+            #[doc = concat!("A child of `", stringify!($parent), "`.")]
+            #[allow(dead_code)]
+            $vis struct $rest;
+        )*
+        $crate::__lock_order_impls!($first $(< $rest)*);
+        $crate::__lock_order_graph!([$first] $(< [$rest])*);
+    };
+}
+
+/// Declares a lock order between sets of identifiers, rather than single
+/// identifiers as with [`crate::declare_lock_order`]:
+/// `declare_lock_sets!(pub [Ui, Prefs] < [Db, Cache] < [Log])` declares
+/// the five types and implements [`LockAfter`] for every pair in which the
+/// first is in an earlier set than the second.
+///
+/// This suits applications made of modules which each have their own
+/// mutices: a thread may hold one mutex from each set at once, claiming
+/// them with [`DeadlockProofMutex::lock_ordered`] and
+/// [`DeadlockProofMutex::lock_after`] in the order of the sets, whichever
+/// mutex of each set it picks. Mutices within a set aren't ordered against
+/// each other, so no two of them can be held at once. The sets can't
+/// overlap, since the macro declares the types.
+#[macro_export]
+macro_rules! declare_lock_sets {
+    ($vis:vis $([$($member:ident),+ $(,)?])<+) => {
+        $($(
+            #[allow(dead_code)]
+            $vis struct $member;
+        )+)+
+        $crate::__lock_set_impls!($([$($member),+])<+);
+        $crate::__lock_order_graph!($([$($member),+])<+);
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __lock_set_impls {
+    ([$($first:ident),+] $(< [$($rest:ident),+])*) => {
+        $crate::__lock_set_impls!(@each [$($first),+] [$([$($rest),+])*]);
+        $crate::__lock_set_impls!($([$($rest),+])<*);
+    };
+    () => {};
+    (@each [$first:ident $(, $others:ident)*] $later:tt) => {
+        $crate::__lock_set_impls!(@before $first $later);
+        $crate::__lock_set_impls!(@each [$($others),*] $later);
+    };
+    (@each [] $later:tt) => {};
+    (@before $first:ident [$([$($later:ident),+])*]) => {
+        $($(
+            impl $crate::LockAfter<$first> for $later {}
+            impl $crate::LockPair<$later> for $first {
+                const FIRST: bool = true;
+            }
+            impl $crate::LockPair<$first> for $later {
+                const FIRST: bool = false;
+            }
+        )+)*
+    };
+}
+
+/// Permission to claim mutices whose identifiers come after `I` in a lock
+/// order declared with [`crate::declare_lock_order`], among those sharing
+/// the permission type `P`. This can be obtained from
+/// [`DeadlockProofMutex::lock_ordered`] or [`DeadlockProofMutex::lock_after`].
+pub struct OrderedMutexPermission<P: MutexPermission, I>(NestedMutexPermission<P, I>);
+
+/// The result of [`DeadlockProofMutex::lock_ordered`] and
+/// [`DeadlockProofMutex::lock_after`]: a guard plus the permission required to
+/// claim mutices later in the order.
+pub type OrderedLockResult<'a, T, Q, P, I, M = Mutex<T>> = LockResult<(
+    DeadlockProofOrderedMutexGuard<'a, T, Q, P, I, M>,
+    OrderedMutexPermission<P, I>,
+)>;
+
+/// The result of [`DeadlockProofMutex::lock_pair`]: a guard for both
+/// mutices.
+pub type PairLockResult<'a, T, U, P, I, J, M = Mutex<T>, N = Mutex<U>> =
+    LockResult<DeadlockProofPairGuard<'a, T, U, P, I, J, M, N>>;
+
+/// The result of [`DeadlockProofMutex::swap_contents`]: the permission, or
+/// if either mutex is poisoned, a guard for both.
+pub type SwapResult<'a, T, P, I, J, M = Mutex<T>, N = Mutex<T>> =
+    Result<P, PoisonError<DeadlockProofPairGuard<'a, T, T, P, I, J, M, N>>>;
+
+impl<T, P: MutexPermission, I, M: MutexBackend<T>> DeadlockProofMutex<T, P, I, M> {
+    /// Acquires this mutex, blocking the current thread until it is able to
+    /// do so, as the first of several mutices claimed in a lock order
+    /// declared with [`crate::declare_lock_order`]. Provides a token which
+    /// can be used with [`DeadlockProofMutex::lock_after`] to claim any
+    /// mutex later in the order which has the same permission type `P`.
+    pub fn lock_ordered(&self, permission: P) -> OrderedLockResult<'_, T, P, P, I, M> {
+        map_lock_result(self.0.lock::<T, I, P::Domain, M>(&self.3), |guard| {
+            (
+                DeadlockProofOrderedMutexGuard(
+                    guard,
+                    permission,
+                    PhantomData,
+                    self.0.held::<I, P::Domain, _>(&self.3),
+                ),
+                OrderedMutexPermission(NestedMutexPermission::new()),
+            )
+        })
+    }
+
+    /// Acquires this mutex, blocking the current thread until it is able to
+    /// do so, while holding a mutex which comes before it in a lock order.
+    /// This accepts the permission from any mutex whose identifier `J` is
+    /// declared to come before `I`, rather than only one particular mutex as
+    /// with [`DeadlockProofMutex::lock_for_nested`].
+    pub fn lock_after<J>(
+        &self,
+        permission: OrderedMutexPermission<P, J>,
+    ) -> OrderedLockResult<'_, T, OrderedMutexPermission<P, J>, P, I, M>
+    where
+        I: LockAfter<J>,
+    {
+        map_lock_result(self.0.lock::<T, I, P::Domain, M>(&self.3), |guard| {
+            (
+                DeadlockProofOrderedMutexGuard(
+                    guard,
+                    permission,
+                    PhantomData,
+                    self.0.held::<I, P::Domain, _>(&self.3),
+                ),
+                OrderedMutexPermission(NestedMutexPermission::new()),
+            )
+        })
+    }
+}
+
+impl<T, P: MutexPermission, I, M: MutexBackend<T>> DeadlockProofMutex<T, P, I, M> {
+    /// Acquires both this mutex and `other`, blocking the current thread
+    /// until it is able to do so. The two must be in the same lock order,
+    /// declared with [`crate::declare_lock_order`], which decides the order
+    /// in which they're claimed; they can be passed either way round.
+    ///
+    /// If either mutex is poisoned, the error still contains a guard for
+    /// both, as for [`DeadlockProofMutex::lock`].
+    // The error holds the guard, just as for every other way of locking.
+    #[allow(clippy::result_large_err)]
+    pub fn lock_pair<'a, U, J, N: MutexBackend<U>>(
+        &'a self,
+        other: &'a DeadlockProofMutex<U, P, J, N>,
+        permission: P,
+    ) -> PairLockResult<'a, T, U, P, I, J, M, N>
+    where
+        I: LockPair<J>,
+    {
+        let (this, other_guard) = if I::FIRST {
+            let this = self.0.lock::<T, I, P::Domain, M>(&self.3);
+            (this, other.0.lock::<U, J, P::Domain, N>(&other.3))
+        } else {
+            let other_guard = other.0.lock::<U, J, P::Domain, N>(&other.3);
+            (self.0.lock::<T, I, P::Domain, M>(&self.3), other_guard)
+        };
+        let poisoned = this.is_err() || other_guard.is_err();
+        let guard = DeadlockProofPairGuard(
+            this.unwrap_or_else(PoisonError::into_inner),
+            other_guard.unwrap_or_else(PoisonError::into_inner),
+            permission,
+            PhantomData,
+            self.0.held::<I, P::Domain, _>(&self.3),
+            other.0.held::<J, P::Domain, _>(&other.3),
+        );
+        if poisoned {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+}
+
+impl<T, P: MutexPermission, I, M: MutexBackend<T>> DeadlockProofMutex<T, P, I, M> {
+    /// Acquires both this mutex and `other`, in the order decided by their
+    /// lock order just as for [`DeadlockProofMutex::lock_pair`], swaps their
+    /// contents and releases them again, returning the permission.
+    ///
+    /// If either mutex is poisoned, nothing is swapped, and the error
+    /// contains a guard for both.
+    // The error holds the guard, just as for every other way of locking.
+    #[allow(clippy::result_large_err)]
+    pub fn swap_contents<'a, J, N: MutexBackend<T>>(
+        &'a self,
+        other: &'a DeadlockProofMutex<T, P, J, N>,
+        permission: P,
+    ) -> SwapResult<'a, T, P, I, J, M, N>
+    where
+        I: LockPair<J>,
+    {
+        let mut guard = self.lock_pair(other, permission)?;
+        let (this, other) = guard.get_mut();
+        core::mem::swap(this, other);
+        Ok(guard.unlock())
+    }
+}
+
+/// A guard for two mutices claimed together using
+/// [`DeadlockProofMutex::lock_pair`]. As with
+/// [`crate::DeadlockProofMutexGuard`], it's strongly recommended that you
+/// explicitly unlock this rather than allowing it to drop.
+pub struct DeadlockProofPairGuard<
+    'a,
+    T,
+    U,
+    P: MutexPermission,
+    I,
+    J,
+    M: MutexBackend<T> + 'a = Mutex<T>,
+    N: MutexBackend<U> + 'a = Mutex<U>,
+>(
+    M::Guard<'a>,
+    N::Guard<'a>,
+    P,
+    PhantomData<(I, J)>,
+    #[allow(dead_code)] // only needed for its drop behaviour
+    HeldLock,
+    #[allow(dead_code)] // only needed for its drop behaviour
+    HeldLock,
+);
+
+impl<'a, T, U, P: MutexPermission, I, J, M: MutexBackend<T> + 'a, N: MutexBackend<U> + 'a>
+    DeadlockProofPairGuard<'a, T, U, P, I, J, M, N>
+{
+    /// Unlock both mutices. Returns the mutex permission token such that
+    /// you can use it again to claim a different mutex.
+    pub fn unlock(self) -> P {
+        self.2
+    }
+
+    /// The contents of both mutices, in the order they were passed to
+    /// [`DeadlockProofMutex::lock_pair`].
+    pub fn get(&self) -> (&T, &U) {
+        (self.0.deref(), self.1.deref())
+    }
+
+    /// The contents of both mutices, mutably, in the order they were passed
+    /// to [`DeadlockProofMutex::lock_pair`].
+    pub fn get_mut(&mut self) -> (&mut T, &mut U) {
+        (self.0.deref_mut(), self.1.deref_mut())
+    }
+}
+
+/// A guard for a mutex claimed within a lock order, using
+/// [`DeadlockProofMutex::lock_ordered`] or [`DeadlockProofMutex::lock_after`].
+/// `Q` is the permission it was claimed with, which is returned when it's
+/// unlocked.
+pub struct DeadlockProofOrderedMutexGuard<
+    'a,
+    T,
+    Q,
+    P: MutexPermission,
+    I,
+    M: MutexBackend<T> + 'a = Mutex<T>,
+>(
+    M::Guard<'a>,
+    Q,
+    PhantomData<(P, I)>,
+    #[allow(dead_code)] // only needed for its drop behaviour
+    HeldLock,
+);
+
+impl<'a, T, Q, P: MutexPermission, I, M: MutexBackend<T> + 'a>
+    DeadlockProofOrderedMutexGuard<'a, T, Q, P, I, M>
+{
+    /// Unlock the mutex. Returns the permission it was claimed with. The
+    /// permission for mutices later in the order must be handed back, so
+    /// that none of them can still be held.
+    pub fn unlock(self, _token: OrderedMutexPermission<P, I>) -> Q {
+        self.1
+    }
+}
+
+impl<'a, T, Q, P: MutexPermission, I, M: MutexBackend<T> + 'a> Deref
+    for DeadlockProofOrderedMutexGuard<'a, T, Q, P, I, M>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.deref()
+    }
+}
+
+impl<'a, T, Q, P: MutexPermission, I, M: MutexBackend<T> + 'a> DerefMut
+    for DeadlockProofOrderedMutexGuard<'a, T, Q, P, I, M>
+{
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.deref_mut()
+    }
+}
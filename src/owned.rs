@@ -0,0 +1,131 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::{Arc, LockResult, Mutex},
+};
+
+use crate::{
+    map_lock_result, DeadlockProofMutex, HeldLock, MutexBackend, MutexPermission,
+    SequentialMutexPermission,
+};
+
+impl<T: 'static, P: MutexPermission + 'static, I: 'static, M: MutexBackend<T> + 'static>
+    DeadlockProofMutex<T, P, I, M>
+{
+    /// Acquires this mutex, blocking the current thread until it is able to
+    /// do so, and returns a guard which keeps the mutex alive rather than
+    /// borrowing it. This allows guards to be stored in structs and state
+    /// machines where a borrowed lifetime won't do. Poisoning is reported as
+    /// for [`DeadlockProofMutex::lock`].
+    pub fn lock_owned(
+        self: &Arc<Self>,
+        permission: P,
+    ) -> LockResult<DeadlockProofOwnedMutexGuard<T, P, I, M>> {
+        // Safety: the guard keeps its own reference to the mutex, and
+        // releases the underlying guard before that reference.
+        let mutex: &'static Self = unsafe { &*Arc::as_ptr(self) };
+        map_lock_result(mutex.0.lock::<T, I, P::Domain, M>(&mutex.3), |guard| {
+            DeadlockProofOwnedMutexGuard(
+                guard,
+                permission,
+                PhantomData,
+                mutex.0.held::<I, P::Domain, _>(&mutex.3),
+                Arc::clone(self),
+            )
+        })
+    }
+}
+
+/// A guard for a [`DeadlockProofMutex`] which holds an [`Arc`] to the mutex
+/// rather than borrowing it, obtained from
+/// [`DeadlockProofMutex::lock_owned`].
+pub struct DeadlockProofOwnedMutexGuard<
+    T: 'static,
+    P: MutexPermission + 'static,
+    I: 'static,
+    M: MutexBackend<T> + 'static = Mutex<T>,
+>(
+    // Fields are dropped in order, so the mutex is unlocked before the
+    // reference to it is released.
+    M::Guard<'static>,
+    P,
+    PhantomData<I>,
+    #[allow(dead_code)] // only needed for its drop behaviour
+    HeldLock,
+    Arc<DeadlockProofMutex<T, P, I, M>>,
+);
+
+impl<T: 'static, P: MutexPermission + 'static, I: 'static, M: MutexBackend<T> + 'static>
+    DeadlockProofOwnedMutexGuard<T, P, I, M>
+{
+    /// Unlock the mutex. Returns the mutex permission token such that you
+    /// can use it again to claim a different mutex.
+    pub fn unlock(self) -> P {
+        self.1
+    }
+
+    /// Unlock the mutex. Returns the mutex permission token such that you
+    /// can use it again to claim a different mutex. Also, returns an extra
+    /// mutex permission token so that you can claim another mutex in
+    /// a certain sequence, which the type system will guarantee is the same
+    /// for all threads.
+    pub fn unlock_for_sequential(self) -> SequentialMutexPermission<P, I> {
+        SequentialMutexPermission::new(self.1)
+    }
+
+    /// The mutex which this guard keeps locked.
+    pub fn mutex(&self) -> &Arc<DeadlockProofMutex<T, P, I, M>> {
+        &self.4
+    }
+}
+
+impl<T: 'static, P: MutexPermission + 'static, I: 'static, M: MutexBackend<T> + 'static> Deref
+    for DeadlockProofOwnedMutexGuard<T, P, I, M>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.deref()
+    }
+}
+
+impl<T: 'static, P: MutexPermission + 'static, I: 'static, M: MutexBackend<T> + 'static> DerefMut
+    for DeadlockProofOwnedMutexGuard<T, P, I, M>
+{
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.deref_mut()
+    }
+}
+
+impl<
+        T: 'static + fmt::Debug,
+        P: MutexPermission + 'static,
+        I: 'static,
+        M: MutexBackend<T> + 'static,
+    > fmt::Debug for DeadlockProofOwnedMutexGuard<T, P, I, M>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.deref(), f)
+    }
+}
+
+impl<
+        T: 'static + fmt::Display,
+        P: MutexPermission + 'static,
+        I: 'static,
+        M: MutexBackend<T> + 'static,
+    > fmt::Display for DeadlockProofOwnedMutexGuard<T, P, I, M>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.deref(), f)
+    }
+}
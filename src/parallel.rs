@@ -0,0 +1,53 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use rayon::iter::ParallelIterator;
+
+use crate::OuterMutexPermission;
+
+/// Claims the permission of the rayon worker about to process an item.
+fn claim_for_item() -> OuterMutexPermission {
+    OuterMutexPermission::try_get().expect(
+        "a rayon worker's mutex permission was still claimed when it started \
+         another item; don't block on parallel work while holding a permission",
+    )
+}
+
+/// Extension methods for rayon parallel iterators which pass each item the
+/// mutex permission of the worker thread processing it.
+///
+/// The permission is claimed before each item is processed and returned to
+/// the worker afterwards, so successive items on the same worker each get it
+/// in turn. Rayon may run another item on a worker which is waiting for
+/// parallel work it has started, so waiting for parallel work while holding
+/// the permission, for instance by running a nested parallel iterator
+/// from within one of these closures, panics. Releasing the permission first
+/// is required anyway, because the other item might otherwise claim a mutex
+/// which the waiting one holds.
+pub trait PermissionParallelIterator: ParallelIterator {
+    /// Executes `f` on each item, as [`ParallelIterator::for_each`] does,
+    /// passing it the permission for the thread it runs on.
+    fn for_each_with_permission<F>(self, f: F)
+    where
+        F: Fn(OuterMutexPermission, Self::Item) + Sync + Send,
+    {
+        self.for_each(|item| f(claim_for_item(), item))
+    }
+
+    /// Applies `f` to each item, as [`ParallelIterator::map`] does, passing
+    /// it the permission for the thread it runs on.
+    fn map_with_permission<F, R>(self, f: F) -> impl ParallelIterator<Item = R>
+    where
+        F: Fn(OuterMutexPermission, Self::Item) -> R + Sync + Send,
+        R: Send,
+    {
+        self.map(move |item| f(claim_for_item(), item))
+    }
+}
+
+impl<I: ParallelIterator> PermissionParallelIterator for I {}
@@ -0,0 +1,187 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    fmt,
+    marker::PhantomData,
+    rc::Rc,
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+use crate::{domain, DefaultDomain, OuterMutexPermission};
+
+struct ParkerState {
+    notified: Mutex<bool>,
+    condvar: Condvar,
+}
+
+/// A deadlock-proof thread parker. A thread can block in [`Parker::park`]
+/// until some other thread calls [`Unparker::unpark`].
+///
+/// Parking requires an [`OuterMutexPermission`]. Holding that token proves
+/// that the parking thread hasn't claimed any deadlock-proof mutex with it,
+/// so the thread can't go to sleep while holding a lock which the thread
+/// that's supposed to wake it up might need.
+///
+/// Like [`std::thread::park`], each `Parker` has a single notification
+/// token: an `unpark` which arrives before the `park` is remembered, and
+/// multiple `unpark`s before a `park` only wake it once.
+pub struct Parker {
+    state: Arc<ParkerState>,
+    // A parker belongs to the thread which parks on it.
+    _not_send: PhantomData<Rc<()>>,
+}
+
+/// The waking half of a [`Parker`]. Unlike the parker itself, this can be
+/// cloned and sent to other threads freely, since waking another thread
+/// never blocks.
+#[derive(Clone)]
+pub struct Unparker {
+    state: Arc<ParkerState>,
+}
+
+impl Parker {
+    /// Create a new parker, which is initially not notified.
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(ParkerState {
+                notified: Mutex::new(false),
+                condvar: Condvar::new(),
+            }),
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Obtain an [`Unparker`] which can wake this parker.
+    pub fn unparker(&self) -> Unparker {
+        Unparker {
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    /// Block the current thread until the parker is notified by an
+    /// [`Unparker`]. Returns immediately if a notification arrived since
+    /// the last call. The permission is only borrowed - it serves as
+    /// proof that this thread holds no deadlock-proof mutices.
+    pub fn park(&self, _permission: &OuterMutexPermission) {
+        domain::check_lock::<DefaultDomain, Self>();
+        let mut notified = self.state.lock_state();
+        while !*notified {
+            notified = self
+                .state
+                .condvar
+                .wait(notified)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        *notified = false;
+    }
+
+    /// Block the current thread until the parker is notified or the
+    /// timeout elapses. Returns whether a notification was consumed.
+    pub fn park_timeout(&self, _permission: &OuterMutexPermission, timeout: Duration) -> bool {
+        domain::check_lock::<DefaultDomain, Self>();
+        let notified = self.state.lock_state();
+        let (mut notified, _) = self
+            .state
+            .condvar
+            .wait_timeout_while(notified, timeout, |notified| !*notified)
+            .unwrap_or_else(|e| e.into_inner());
+        std::mem::replace(&mut *notified, false)
+    }
+}
+
+impl Default for Parker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Parker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Parker")
+            .field("notified", &*self.state.lock_state())
+            .finish()
+    }
+}
+
+impl Unparker {
+    /// Wake the associated [`Parker`], or make its next `park` return
+    /// immediately if it isn't currently parked.
+    pub fn unpark(&self) {
+        *self.state.lock_state() = true;
+        self.state.condvar.notify_one();
+    }
+}
+
+impl fmt::Debug for Unparker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Unparker")
+            .field("notified", &*self.state.lock_state())
+            .finish()
+    }
+}
+
+impl ParkerState {
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, bool> {
+        // The protected state is a simple flag which can't be left
+        // inconsistent by a panic, so poisoning is irrelevant.
+        self.notified.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{panic, thread, time::Duration};
+
+    use crate::{declare_permission_domain, DeadlockProofMutex, OuterMutexPermission};
+
+    use super::Parker;
+
+    declare_permission_domain!(Logging, 10);
+
+    struct Log;
+
+    #[test]
+    fn unpark_wakes_parked_thread() {
+        let parker = Parker::new();
+        let unparker = parker.unparker();
+        let waker = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            unparker.unpark();
+        });
+        parker.park(&OuterMutexPermission::get());
+        waker.join().unwrap();
+    }
+
+    #[test]
+    fn unpark_before_park_is_remembered_once() {
+        let parker = Parker::new();
+        let permission = OuterMutexPermission::get();
+        parker.unparker().unpark();
+        parker.unparker().unpark();
+        assert!(parker.park_timeout(&permission, Duration::from_secs(10)));
+        assert!(!parker.park_timeout(&permission, Duration::from_millis(10)));
+    }
+
+    #[test]
+    #[cfg_attr(deadlock_proof_mutex_unverified, ignore = "the check is turned off")]
+    fn parking_while_holding_another_domain_is_reported() {
+        let mutex: DeadlockProofMutex<(), OuterMutexPermission<Logging>, Log> =
+            DeadlockProofMutex::new((), Log);
+        let guard = mutex.lock(OuterMutexPermission::get_for_domain()).unwrap();
+        let parker = Parker::new();
+        parker.unparker().unpark();
+        let permission = OuterMutexPermission::get();
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            parker.park(&permission);
+        }));
+        guard.unlock();
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    panic::UnwindSafe,
+};
+
+/// A place to keep a permission token in a long-lived context struct, as
+/// [`crate::OuterMutexPermission::get`] recommends, which notices if the
+/// token is ever taken out and not put back.
+///
+/// A bare field of type `OuterMutexPermission` can be consumed by any
+/// method with access to it, and if that method forgets to store the token
+/// again, the loss only shows up later as a failure to claim it. Instead,
+/// the token is lent out by [`PermissionCell::lease`], and if the lease ends
+/// without the token back inside it, for example because the code using it
+/// returned early or panicked, the cell becomes poisoned. Leasing from a
+/// poisoned cell panics straight away, pointing at the real problem.
+pub struct PermissionCell<P>(Option<P>);
+
+impl<P> PermissionCell<P> {
+    /// Create a new cell holding `permission`.
+    pub const fn new(permission: P) -> Self {
+        Self(Some(permission))
+    }
+
+    /// Lends out the permission until the returned lease is dropped. Panics
+    /// if the cell is poisoned.
+    pub fn lease(&mut self) -> PermissionLease<'_, P> {
+        self.try_lease()
+            .expect("PermissionCell poisoned: an earlier lease didn't give its permission back")
+    }
+
+    /// Lends out the permission until the returned lease is dropped, or
+    /// returns `None` if the cell is poisoned.
+    pub fn try_lease(&mut self) -> Option<PermissionLease<'_, P>> {
+        let permission = self.0.take()?;
+        Some(PermissionLease {
+            cell: self,
+            permission: Some(permission),
+        })
+    }
+
+    /// Runs `f` with the permission, which it must hand back along with its
+    /// result. Panics if the cell is poisoned. If `f` panics, the cell is
+    /// left poisoned.
+    pub fn with<R>(&mut self, f: impl FnOnce(P) -> (R, P)) -> R {
+        let mut lease = self.lease();
+        let (result, permission) = f(lease.take());
+        lease.put_back(permission);
+        result
+    }
+
+    /// Whether a lease ended without giving the permission back.
+    pub fn is_poisoned(&self) -> bool {
+        self.0.is_none()
+    }
+
+    /// Stores `permission`, clearing the poisoned state, for instance once
+    /// the thread's token has been reclaimed after a panic. Any permission
+    /// already in the cell is dropped.
+    pub fn restore(&mut self, permission: P) {
+        self.0 = Some(permission);
+    }
+
+    /// Consumes the cell, returning the permission, or `None` if the cell is
+    /// poisoned.
+    pub fn into_inner(self) -> Option<P> {
+        self.0
+    }
+}
+
+impl<P> fmt::Debug for PermissionCell<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PermissionCell")
+            .field("poisoned", &self.is_poisoned())
+            .finish()
+    }
+}
+
+/// A permission lent out by [`PermissionCell::lease`]. While it holds the
+/// permission, it dereferences to it, so the permission can be borrowed,
+/// for instance by [`crate::DeadlockProofMutex::lock_borrowing`]. It can
+/// also be taken out to be consumed, as by
+/// [`crate::DeadlockProofMutex::lock`], so long as it's put back before the
+/// lease is dropped; otherwise the cell is poisoned.
+pub struct PermissionLease<'a, P> {
+    cell: &'a mut PermissionCell<P>,
+    permission: Option<P>,
+}
+
+impl<P> PermissionLease<'_, P> {
+    /// Takes the permission out of the lease, to be consumed. Panics if it
+    /// has already been taken and not put back.
+    pub fn take(&mut self) -> P {
+        self.permission
+            .take()
+            .expect("Permission already taken from this lease")
+    }
+
+    /// Puts the permission back into the lease, such as once the guard
+    /// which consumed it has been unlocked.
+    pub fn put_back(&mut self, permission: P) {
+        self.permission = Some(permission);
+    }
+}
+
+impl<P> Deref for PermissionLease<'_, P> {
+    type Target = P;
+
+    /// Panics if the permission has been taken and not put back.
+    fn deref(&self) -> &P {
+        self.permission
+            .as_ref()
+            .expect("Permission taken from this lease")
+    }
+}
+
+impl<P> DerefMut for PermissionLease<'_, P> {
+    /// Panics if the permission has been taken and not put back.
+    fn deref_mut(&mut self) -> &mut P {
+        self.permission
+            .as_mut()
+            .expect("Permission taken from this lease")
+    }
+}
+
+/// A panic while the permission is taken out leaves the cell poisoned, just
+/// as a panic while holding a mutex poisons the mutex, so the lease can
+/// cross [`std::panic::catch_unwind`] even though it borrows the cell
+/// mutably.
+impl<P: UnwindSafe> UnwindSafe for PermissionLease<'_, P> {}
+
+impl<P> Drop for PermissionLease<'_, P> {
+    fn drop(&mut self) {
+        // Leaves the cell poisoned if the permission wasn't put back.
+        self.cell.0 = self.permission.take();
+    }
+}
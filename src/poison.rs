@@ -0,0 +1,116 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Poisoning, which the standard library's locks report with
+//! [`PoisonError`]. Without the `std` feature there's no such type, and no
+//! backend which is ever poisoned, so this provides minimal equivalents of
+//! the standard library's poisoning types for the same signatures to use.
+
+#[cfg(not(feature = "std"))]
+use core::fmt;
+
+#[cfg(feature = "std")]
+pub(crate) use std::sync::{LockResult, PoisonError, TryLockError, TryLockResult};
+
+/// For those who consider poisoning useless: turns the result of claiming
+/// any deadlock-proof lock into the guard itself, whether or not the lock
+/// was poisoned. Since the error always contains the guard too, nothing is
+/// lost, and there's no need to unwrap at every call site.
+///
+/// Mutices using the `lock_api`, `parking_lot` or `spin` backends are never
+/// poisoned, so for them this is exactly equivalent to unwrapping.
+pub trait IgnorePoison<G> {
+    /// The guard, whether or not the lock was poisoned.
+    fn ignore_poison(self) -> G;
+}
+
+impl<G> IgnorePoison<G> for LockResult<G> {
+    fn ignore_poison(self) -> G {
+        self.unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A stand-in for the standard library's `LockResult`, without the `std`
+/// feature.
+#[cfg(not(feature = "std"))]
+pub type LockResult<G> = Result<G, PoisonError<G>>;
+
+/// A stand-in for the standard library's `TryLockResult`, without the `std`
+/// feature.
+#[cfg(not(feature = "std"))]
+pub(crate) type TryLockResult<G> = Result<G, TryLockError<G>>;
+
+/// A stand-in for the standard library's `PoisonError`, without the `std`
+/// feature: the error from claiming a lock which another thread panicked
+/// while holding. It carries the guard anyway.
+#[cfg(not(feature = "std"))]
+pub struct PoisonError<G>(G);
+
+#[cfg(not(feature = "std"))]
+impl<G> PoisonError<G> {
+    /// Wraps the guard for a poisoned lock.
+    pub fn new(guard: G) -> Self {
+        Self(guard)
+    }
+
+    /// The guard, whose lock was poisoned.
+    pub fn into_inner(self) -> G {
+        self.0
+    }
+
+    /// Borrows the guard.
+    pub fn get_ref(&self) -> &G {
+        &self.0
+    }
+
+    /// Mutably borrows the guard.
+    pub fn get_mut(&mut self) -> &mut G {
+        &mut self.0
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<G> fmt::Debug for PoisonError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoisonError").finish_non_exhaustive()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<G> fmt::Display for PoisonError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("poisoned lock: another task failed inside")
+    }
+}
+
+/// A stand-in for the standard library's `TryLockError`, without the `std`
+/// feature.
+#[cfg(not(feature = "std"))]
+pub enum TryLockError<G> {
+    /// The lock was poisoned, but has been claimed anyway.
+    Poisoned(PoisonError<G>),
+    /// The lock is held elsewhere.
+    WouldBlock,
+}
+
+#[cfg(not(feature = "std"))]
+impl<G> From<PoisonError<G>> for TryLockError<G> {
+    fn from(error: PoisonError<G>) -> Self {
+        Self::Poisoned(error)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<G> fmt::Debug for TryLockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Poisoned(error) => error.fmt(f),
+            Self::WouldBlock => f.write_str("WouldBlock"),
+        }
+    }
+}
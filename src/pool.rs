@@ -0,0 +1,320 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::{LockResult, Mutex, MutexGuard},
+};
+
+use crate::{map_lock_result, HeldLock, Monitor, MutexPermission, PermissionPhantom};
+
+struct Bucket<T>(Mutex<T>, Monitor);
+
+impl<T> Bucket<T> {
+    fn new(content: T) -> Self {
+        Self(Mutex::new(content), Monitor::new())
+    }
+
+    fn lock<P: MutexPermission, I>(
+        &self,
+        permission: P,
+    ) -> LockResult<DeadlockProofPoolGuard<'_, T, P, I>> {
+        map_lock_result(self.1.lock::<T, I, P::Domain, _>(&self.0), |guard| {
+            DeadlockProofPoolGuard(
+                guard,
+                permission,
+                PhantomData,
+                self.1.held::<I, P::Domain, _>(&self.0),
+            )
+        })
+    }
+}
+
+/// A fixed-size pool of mutices sharing a single identifier, for example
+/// one lock per bucket of a hash table. Rather than needing a separate
+/// identifier type for each of the `N` mutices, a thread may either claim
+/// one bucket at a time using [`DeadlockProofMutexPool::lock`], or several
+/// buckets in strictly ascending index order using
+/// [`DeadlockProofMutexPool::lock_ascending`]. Since every thread claims
+/// buckets in the same order, they can't deadlock against each other.
+///
+/// The pool as a whole takes its place in the lock ordering just like a
+/// single [`crate::DeadlockProofMutex`] would, using the permission type `P`.
+pub struct DeadlockProofMutexPool<T, P: MutexPermission, I, const N: usize>(
+    [Bucket<T>; N],
+    PermissionPhantom<P>,
+    PhantomData<I>,
+);
+
+impl<T, P: MutexPermission, I, const N: usize> DeadlockProofMutexPool<T, P, I, N> {
+    /// Create a new pool of mutices, one protecting each item of `contents`.
+    /// The `_identifier` is a type unique to this pool, just as for
+    /// [`crate::DeadlockProofMutex::new`].
+    pub fn new(contents: [T; N], _identifier: I) -> Self {
+        Self(contents.map(Bucket::new), PhantomData, PhantomData)
+    }
+
+    /// Acquires the mutex at `index`, blocking the current thread until it
+    /// is able to do so. No other bucket may be claimed while this guard is
+    /// held. Panics if `index` is out of range. If the bucket is poisoned,
+    /// the error still contains a deadlock-proof guard.
+    pub fn lock(
+        &self,
+        index: usize,
+        permission: P,
+    ) -> LockResult<DeadlockProofPoolGuard<'_, T, P, I>> {
+        self.0[index].lock::<P, I>(permission)
+    }
+
+    /// Starts claiming several buckets at once. The returned
+    /// [`DeadlockProofPoolGuards`] initially holds no buckets; add them using
+    /// [`DeadlockProofPoolGuards::lock`] in strictly ascending order.
+    pub fn lock_ascending(&self, permission: P) -> DeadlockProofPoolGuards<'_, T, P, I, N> {
+        DeadlockProofPoolGuards(AscendingGuards::new(&self.0, permission), PhantomData)
+    }
+
+    /// The number of buckets in the pool.
+    pub const fn len(&self) -> usize {
+        N
+    }
+
+    /// Whether the pool has no buckets at all.
+    pub const fn is_empty(&self) -> bool {
+        N == 0
+    }
+}
+
+/// A guard for a single bucket of a [`DeadlockProofMutexPool`], obtained
+/// from [`DeadlockProofMutexPool::lock`], or for a single slot of a
+/// [`DeadlockProofMutexSlab`], from [`DeadlockProofMutexSlab::lock`].
+pub struct DeadlockProofPoolGuard<'a, T, P: MutexPermission, I>(
+    MutexGuard<'a, T>,
+    P,
+    PhantomData<I>,
+    #[allow(dead_code)] // only needed for its drop behaviour
+    HeldLock,
+);
+
+impl<T, P: MutexPermission, I> DeadlockProofPoolGuard<'_, T, P, I> {
+    /// Unlock the bucket. Returns the mutex permission token such that you
+    /// can use it again to claim a different mutex.
+    pub fn unlock(self) -> P {
+        self.1
+    }
+}
+
+impl<T, P: MutexPermission, I> Deref for DeadlockProofPoolGuard<'_, T, P, I> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.deref()
+    }
+}
+
+impl<T, P: MutexPermission, I> DerefMut for DeadlockProofPoolGuard<'_, T, P, I> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.deref_mut()
+    }
+}
+
+/// Errors from [`DeadlockProofPoolGuards::lock`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum PoolLockError {
+    /// The requested bucket doesn't come after all the buckets already held,
+    /// so claiming it could deadlock. Nothing was claimed.
+    OutOfOrder {
+        /// The bucket which was requested.
+        requested: usize,
+        /// The highest bucket already held.
+        highest_held: usize,
+    },
+    /// The bucket was claimed, but another thread panicked while holding
+    /// it. The bucket is now held and its contents can be accessed anyway.
+    Poisoned,
+}
+
+impl fmt::Display for PoolLockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfOrder {
+                requested,
+                highest_held,
+            } => write!(
+                f,
+                "bucket {requested} requested while already holding bucket {highest_held}"
+            ),
+            Self::Poisoned => write!(f, "poisoned lock: another task failed inside"),
+        }
+    }
+}
+
+impl std::error::Error for PoolLockError {}
+
+/// Buckets claimed in strictly ascending index order, shared by
+/// [`DeadlockProofPoolGuards`] and [`DeadlockProofSlabGuards`].
+struct AscendingGuards<'a, T, P: MutexPermission, I> {
+    buckets: &'a [Bucket<T>],
+    permission: P,
+    guards: Vec<(usize, MutexGuard<'a, T>, HeldLock)>,
+    _identifier: PhantomData<I>,
+}
+
+impl<'a, T, P: MutexPermission, I> AscendingGuards<'a, T, P, I> {
+    fn new(buckets: &'a [Bucket<T>], permission: P) -> Self {
+        Self {
+            buckets,
+            permission,
+            guards: Vec::new(),
+            _identifier: PhantomData,
+        }
+    }
+
+    fn lock(&mut self, index: usize) -> Result<&mut T, PoolLockError> {
+        if let Some(&(highest_held, ..)) = self.guards.last() {
+            if index <= highest_held {
+                return Err(PoolLockError::OutOfOrder {
+                    requested: index,
+                    highest_held,
+                });
+            }
+        }
+        let bucket = &self.buckets[index];
+        let (guard, poisoned) = match bucket.1.lock::<T, I, P::Domain, _>(&bucket.0) {
+            Ok(guard) => (guard, false),
+            Err(e) => (e.into_inner(), true),
+        };
+        self.guards
+            .push((index, guard, bucket.1.held::<I, P::Domain, _>(&bucket.0)));
+        let data = &mut *self.guards.last_mut().unwrap().1;
+        if poisoned {
+            Err(PoolLockError::Poisoned)
+        } else {
+            Ok(data)
+        }
+    }
+
+    fn get(&self, index: usize) -> Option<&T> {
+        self.guards
+            .binary_search_by_key(&index, |(i, ..)| *i)
+            .ok()
+            .map(|pos| &*self.guards[pos].1)
+    }
+
+    fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        self.guards
+            .binary_search_by_key(&index, |(i, ..)| *i)
+            .ok()
+            .map(|pos| &mut *self.guards[pos].1)
+    }
+}
+
+macro_rules! ascending_guards_methods {
+    () => {
+        /// Acquires the bucket at `index`, blocking until it's able to do
+        /// so, and returns access to its contents. `index` must be greater
+        /// than that of every bucket already held. Panics if `index` is out
+        /// of range.
+        pub fn lock(&mut self, index: usize) -> Result<&mut T, PoolLockError> {
+            self.0.lock(index)
+        }
+
+        /// Access the contents of a held bucket, or `None` if the bucket at
+        /// `index` isn't held.
+        pub fn get(&self, index: usize) -> Option<&T> {
+            self.0.get(index)
+        }
+
+        /// Mutably access the contents of a held bucket, or `None` if the
+        /// bucket at `index` isn't held.
+        pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+            self.0.get_mut(index)
+        }
+
+        /// Unlock all the held buckets, returning the mutex permission token.
+        pub fn unlock(self) -> P {
+            self.0.permission
+        }
+    };
+}
+
+/// A set of buckets of a [`DeadlockProofMutexPool`] held by this thread,
+/// obtained from [`DeadlockProofMutexPool::lock_ascending`]. Buckets can only
+/// be added in strictly ascending index order.
+pub struct DeadlockProofPoolGuards<'a, T, P: MutexPermission, I, const N: usize>(
+    AscendingGuards<'a, T, P, I>,
+    PhantomData<[(); N]>,
+);
+
+impl<T, P: MutexPermission, I, const N: usize> DeadlockProofPoolGuards<'_, T, P, I, N> {
+    ascending_guards_methods!();
+}
+
+/// A slab of mutices sharing a single identifier, like a
+/// [`DeadlockProofMutexPool`] except that the number of slots is chosen at
+/// runtime, for example one lock per connection. A thread may claim one
+/// slot at a time using [`DeadlockProofMutexSlab::lock`], or several slots
+/// in strictly ascending index order using
+/// [`DeadlockProofMutexSlab::lock_ascending`].
+pub struct DeadlockProofMutexSlab<T, P: MutexPermission, I>(
+    Box<[Bucket<T>]>,
+    PermissionPhantom<P>,
+    PhantomData<I>,
+);
+
+impl<T, P: MutexPermission, I> DeadlockProofMutexSlab<T, P, I> {
+    /// Create a new slab of mutices, one protecting each item of
+    /// `contents`. The `_identifier` is a type unique to this slab, just as
+    /// for [`crate::DeadlockProofMutex::new`].
+    pub fn new(contents: impl IntoIterator<Item = T>, _identifier: I) -> Self {
+        Self(
+            contents.into_iter().map(Bucket::new).collect(),
+            PhantomData,
+            PhantomData,
+        )
+    }
+
+    /// Acquires the mutex at `index`, blocking the current thread until it
+    /// is able to do so. No other slot may be claimed while this guard is
+    /// held. Panics if `index` is out of range. If the slot is poisoned,
+    /// the error still contains a deadlock-proof guard.
+    pub fn lock(
+        &self,
+        index: usize,
+        permission: P,
+    ) -> LockResult<DeadlockProofPoolGuard<'_, T, P, I>> {
+        self.0[index].lock::<P, I>(permission)
+    }
+
+    /// Starts claiming several slots at once. The returned
+    /// [`DeadlockProofSlabGuards`] initially holds no slots; add them using
+    /// [`DeadlockProofSlabGuards::lock`] in strictly ascending order.
+    pub fn lock_ascending(&self, permission: P) -> DeadlockProofSlabGuards<'_, T, P, I> {
+        DeadlockProofSlabGuards(AscendingGuards::new(&self.0, permission))
+    }
+
+    /// The number of slots in the slab.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether the slab has no slots at all.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// A set of slots of a [`DeadlockProofMutexSlab`] held by this thread,
+/// obtained from [`DeadlockProofMutexSlab::lock_ascending`]. Slots can only
+/// be added in strictly ascending index order.
+pub struct DeadlockProofSlabGuards<'a, T, P: MutexPermission, I>(AscendingGuards<'a, T, P, I>);
+
+impl<T, P: MutexPermission, I> DeadlockProofSlabGuards<'_, T, P, I> {
+    ascending_guards_methods!();
+}
@@ -0,0 +1,165 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Detection of priority inversion. The type system can prove that
+//! deadlock-proof mutices never deadlock, but it can't prove that a
+//! high-priority thread is never kept waiting by a low-priority one. This
+//! module reports whenever that happens for longer than a threshold, so that
+//! inversions can be found even on platforms without priority inheritance.
+//! Where there is priority inheritance, the `realtime` feature's
+//! `PriorityInheritMutex` backend prevents lasting inversion instead.
+//!
+//! Thread priorities here are whatever the application says they are: call
+//! [`set_thread_priority`] at the start of each thread. Threads which don't
+//! do so have priority 0.
+
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LockResult, Mutex, MutexGuard, PoisonError, RwLock, TryLockError,
+    },
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+use crate::MutexBackend;
+
+thread_local! {
+    static THREAD_PRIORITY: Cell<i32> = const { Cell::new(0) };
+}
+
+static THRESHOLD_NANOS: AtomicU64 = AtomicU64::new(10_000_000);
+
+type Handler = Box<dyn Fn(&PriorityInversion) + Send + Sync>;
+
+static HANDLER: RwLock<Option<Handler>> = RwLock::new(None);
+
+/// Details of a priority inversion: a thread was kept waiting for a mutex
+/// by a thread of lower priority.
+#[derive(Clone, Debug)]
+pub struct PriorityInversion {
+    /// The type name of the identifier of the mutex concerned.
+    pub identifier: &'static str,
+    /// The name of the thread which had to wait, if it has one.
+    pub waiter_thread: Option<String>,
+    /// The priority of the thread which had to wait.
+    pub waiter_priority: i32,
+    /// The name of the thread which held the mutex, if it has one.
+    pub holder_thread: Option<String>,
+    /// The priority of the thread which held the mutex.
+    pub holder_priority: i32,
+    /// How long the higher-priority thread was blocked.
+    pub waited: Duration,
+}
+
+/// Set the priority of the current thread, as used for detecting priority
+/// inversions. Higher numbers mean higher priority.
+pub fn set_thread_priority(priority: i32) {
+    THREAD_PRIORITY.with(|p| p.set(priority))
+}
+
+/// The priority of the current thread, as set by [`set_thread_priority`].
+pub fn thread_priority() -> i32 {
+    THREAD_PRIORITY.with(|p| p.get())
+}
+
+/// Set how long a higher-priority thread may wait for a lower-priority one
+/// before it's reported as a priority inversion. Defaults to 10ms.
+pub fn set_priority_inversion_threshold(threshold: Duration) {
+    THRESHOLD_NANOS.store(
+        threshold.as_nanos().try_into().unwrap_or(u64::MAX),
+        Ordering::Relaxed,
+    )
+}
+
+/// Install a function to be called whenever a priority inversion is
+/// detected. By default, inversions are printed to stderr.
+pub fn set_priority_inversion_handler(
+    handler: impl Fn(&PriorityInversion) + Send + Sync + 'static,
+) {
+    *HANDLER.write().unwrap_or_else(PoisonError::into_inner) = Some(Box::new(handler));
+}
+
+fn report(inversion: &PriorityInversion) {
+    match &*HANDLER.read().unwrap_or_else(PoisonError::into_inner) {
+        Some(handler) => handler(inversion),
+        None => eprintln!(
+            "Priority inversion on {}: thread {} (priority {}) waited {:?} for thread {} (priority {})",
+            inversion.identifier,
+            inversion.waiter_thread.as_deref().unwrap_or("<unnamed>"),
+            inversion.waiter_priority,
+            inversion.waited,
+            inversion.holder_thread.as_deref().unwrap_or("<unnamed>"),
+            inversion.holder_priority,
+        ),
+    }
+}
+
+#[derive(Clone)]
+struct Holder {
+    thread: Thread,
+    priority: i32,
+}
+
+/// Per-mutex record of which thread most recently claimed it.
+pub(crate) struct HolderRecord(Mutex<Option<Holder>>);
+
+impl HolderRecord {
+    pub(crate) const fn new() -> Self {
+        Self(Mutex::new(None))
+    }
+
+    fn holder(&self) -> MutexGuard<'_, Option<Holder>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Claims `mutex`, reporting if we're kept waiting too long by a
+    /// lower-priority thread.
+    pub(crate) fn lock<'a, T: ?Sized, I: ?Sized, M: MutexBackend<T> + ?Sized>(
+        &self,
+        mutex: &'a M,
+    ) -> LockResult<M::Guard<'a>> {
+        let result = match mutex.try_lock() {
+            Ok(guard) => Ok(guard),
+            Err(TryLockError::Poisoned(e)) => Err(e),
+            Err(TryLockError::WouldBlock) => {
+                let holder = self.holder().clone();
+                let start = Instant::now();
+                let result = mutex.lock();
+                let waited = start.elapsed();
+                let waiter_priority = thread_priority();
+                if let Some(holder) = holder {
+                    if holder.priority < waiter_priority
+                        && waited.as_nanos() >= THRESHOLD_NANOS.load(Ordering::Relaxed).into()
+                    {
+                        report(&PriorityInversion {
+                            identifier: std::any::type_name::<I>(),
+                            waiter_thread: thread::current().name().map(str::to_string),
+                            waiter_priority,
+                            holder_thread: holder.thread.name().map(str::to_string),
+                            holder_priority: holder.priority,
+                            waited,
+                        });
+                    }
+                }
+                result
+            }
+        };
+        self.record_holder();
+        result
+    }
+
+    /// Records that the current thread has just claimed the mutex.
+    pub(crate) fn record_holder(&self) {
+        *self.holder() = Some(Holder {
+            thread: thread::current(),
+            priority: thread_priority(),
+        });
+    }
+}
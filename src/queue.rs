@@ -0,0 +1,128 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    collections::VecDeque,
+    sync::{Condvar, Mutex, MutexGuard, PoisonError},
+};
+
+use crate::{domain, DefaultDomain, OuterMutexPermission};
+
+/// A first-in, first-out queue of bounded capacity, shared between
+/// producer and consumer threads, whose blocking operations can't be
+/// performed while holding a mutex.
+///
+/// A thread blocked pushing onto a full queue, or popping from an empty
+/// one, is waiting for another thread to act, just as with
+/// [`crate::deadlock_proof_sync_channel`]. [`DeadlockProofBoundedQueue::push`]
+/// and [`DeadlockProofBoundedQueue::pop`] therefore need to see this
+/// thread's [`OuterMutexPermission`], which proves that no mutex is held,
+/// and mutices from other permission domains are checked for at runtime as
+/// for [`crate::DeadlockProofBarrier`]. Unlike a channel, any number of
+/// threads may both push and pop through a shared reference.
+///
+/// The queue is a mutex and two condvars. The mutex is only ever held
+/// briefly, with nothing else claimed within it, so it can't be part of a
+/// deadlock itself, and neither can the non-blocking
+/// [`DeadlockProofBoundedQueue::try_push`] and
+/// [`DeadlockProofBoundedQueue::try_pop`], which need no permission.
+pub struct DeadlockProofBoundedQueue<T> {
+    items: Mutex<VecDeque<T>>,
+    capacity: usize,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+impl<T> DeadlockProofBoundedQueue<T> {
+    /// Create a new empty queue which holds at most `capacity` items.
+    /// Panics if `capacity` is zero; use
+    /// [`crate::deadlock_proof_rendezvous_channel`] to hand items directly
+    /// from one thread to another.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "A bounded queue needs room for an item");
+        Self {
+            items: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        }
+    }
+
+    fn items(&self) -> MutexGuard<'_, VecDeque<T>> {
+        // The queue is always consistent, even if a thread panicked.
+        self.items.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Adds an item to the back of the queue, blocking while the queue is
+    /// full. Requires the permission to prove that no mutex is held while
+    /// blocking.
+    pub fn push(&self, item: T, _permission: &OuterMutexPermission) {
+        domain::check_lock::<DefaultDomain, Self>();
+        let mut items = self
+            .not_full
+            .wait_while(self.items(), |items| items.len() == self.capacity)
+            .unwrap_or_else(PoisonError::into_inner);
+        items.push_back(item);
+        drop(items);
+        self.not_empty.notify_one();
+    }
+
+    /// Attempts to add an item to the back of the queue without blocking,
+    /// so needs no permission. If the queue is full, the item is returned.
+    pub fn try_push(&self, item: T) -> Result<(), T> {
+        let mut items = self.items();
+        if items.len() == self.capacity {
+            return Err(item);
+        }
+        items.push_back(item);
+        drop(items);
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Removes the item at the front of the queue, blocking while the queue
+    /// is empty. Requires the permission to prove that no mutex is held
+    /// while blocking.
+    pub fn pop(&self, _permission: &OuterMutexPermission) -> T {
+        domain::check_lock::<DefaultDomain, Self>();
+        let mut items = self
+            .not_empty
+            .wait_while(self.items(), |items| items.is_empty())
+            .unwrap_or_else(PoisonError::into_inner);
+        let item = items.pop_front().unwrap();
+        drop(items);
+        self.not_full.notify_one();
+        item
+    }
+
+    /// Attempts to remove the item at the front of the queue without
+    /// blocking, so needs no permission. Returns `None` if the queue is
+    /// empty.
+    pub fn try_pop(&self) -> Option<T> {
+        let item = self.items().pop_front()?;
+        self.not_full.notify_one();
+        Some(item)
+    }
+
+    /// The number of items in the queue. Other threads may change this at
+    /// any moment.
+    pub fn len(&self) -> usize {
+        self.items().len()
+    }
+
+    /// Whether the queue is empty. Other threads may change this at any
+    /// moment.
+    pub fn is_empty(&self) -> bool {
+        self.items().is_empty()
+    }
+
+    /// The most items the queue can hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
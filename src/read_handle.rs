@@ -0,0 +1,120 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    fmt,
+    ops::Deref,
+    sync::{Arc, LockResult, Mutex},
+};
+
+use crate::{
+    map_lock_result, DeadlockProofMutex, DeadlockProofMutexGuard, IdentifierName, MutexBackend,
+    MutexPermission, SequentialMutexPermission,
+};
+
+impl<T, P: MutexPermission, I, M: MutexBackend<T>> DeadlockProofMutex<T, P, I, M> {
+    /// Makes a [`DeadlockProofMutexReadHandle`] for this mutex, which can
+    /// only ever read its contents.
+    pub fn read_handle(self: &Arc<Self>) -> DeadlockProofMutexReadHandle<T, P, I, M> {
+        DeadlockProofMutexReadHandle(Arc::clone(self))
+    }
+}
+
+/// A handle to a [`DeadlockProofMutex`] which can claim it, but only ever
+/// gives shared access to its contents, obtained from
+/// [`DeadlockProofMutex::read_handle`]. Hand these, rather than the mutex,
+/// to subsystems which should only read, so that the type enforces it. The
+/// handle can be cloned freely.
+///
+/// The mutex is still claimed exclusively, so reading takes part in the
+/// lock ordering exactly as [`DeadlockProofMutex::lock`] does.
+pub struct DeadlockProofMutexReadHandle<T, P: MutexPermission, I, M: MutexBackend<T> = Mutex<T>>(
+    Arc<DeadlockProofMutex<T, P, I, M>>,
+);
+
+impl<T, P: MutexPermission, I, M: MutexBackend<T>> DeadlockProofMutexReadHandle<T, P, I, M> {
+    /// Acquires the mutex, blocking the current thread until it is able to
+    /// do so, as [`DeadlockProofMutex::lock`] does, but returns a guard which
+    /// only gives shared access to the contents.
+    pub fn lock(&self, permission: P) -> LockResult<DeadlockProofMutexReadGuard<'_, T, P, I, M>> {
+        map_lock_result(self.0.lock(permission), DeadlockProofMutexReadGuard)
+    }
+
+    /// Determines whether the mutex is poisoned, as
+    /// [`DeadlockProofMutex::is_poisoned`] does.
+    pub fn is_poisoned(&self) -> bool {
+        self.0.is_poisoned()
+    }
+}
+
+impl<T, P: MutexPermission, I, M: MutexBackend<T>> Clone
+    for DeadlockProofMutexReadHandle<T, P, I, M>
+{
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<T, P: MutexPermission, I, M: MutexBackend<T>> fmt::Debug
+    for DeadlockProofMutexReadHandle<T, P, I, M>
+{
+    /// Doesn't show the contents, since they can't be read without a
+    /// permission.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeadlockProofMutexReadHandle")
+            .field("identifier", &IdentifierName::of::<I>())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A guard from [`DeadlockProofMutexReadHandle::lock`], giving only shared
+/// access to the contents. As with [`DeadlockProofMutexGuard`], it's
+/// strongly recommended that you explicitly unlock this rather than
+/// allowing it to drop.
+pub struct DeadlockProofMutexReadGuard<
+    'a,
+    T,
+    P: MutexPermission,
+    I,
+    M: MutexBackend<T> + 'a = Mutex<T>,
+>(DeadlockProofMutexGuard<'a, T, P, I, M>);
+
+impl<'a, T, P: MutexPermission, I, M: MutexBackend<T> + 'a>
+    DeadlockProofMutexReadGuard<'a, T, P, I, M>
+{
+    /// Unlock the mutex. Returns the mutex permission token such that you
+    /// can use it again to claim a different mutex.
+    pub fn unlock(self) -> P {
+        self.0.unlock()
+    }
+
+    /// Unlock the mutex, returning the permission token along with an extra
+    /// one so that you can claim another mutex in a certain sequence, as
+    /// [`DeadlockProofMutexGuard::unlock_for_sequential`] does.
+    pub fn unlock_for_sequential(self) -> SequentialMutexPermission<P, I> {
+        self.0.unlock_for_sequential()
+    }
+}
+
+impl<'a, T, P: MutexPermission, I, M: MutexBackend<T> + 'a> Deref
+    for DeadlockProofMutexReadGuard<'a, T, P, I, M>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<'a, T: fmt::Debug, P: MutexPermission, I, M: MutexBackend<T> + 'a> fmt::Debug
+    for DeadlockProofMutexReadGuard<'a, T, P, I, M>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.deref(), f)
+    }
+}
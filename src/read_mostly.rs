@@ -0,0 +1,84 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{fmt, sync::Arc};
+
+use arc_swap::ArcSwap;
+
+use crate::{DeadlockProofMutex, IdentifierName, MutexPermission};
+
+/// A value which is read far more often than it's changed, such as
+/// configuration or a routing table. Readers take a snapshot using
+/// [`DeadlockProofReadMostly::load`], which never blocks and so needs no
+/// permission, keeping hot read paths free of locks altogether. Writers
+/// replace the whole value, serialized by a deadlock-proof mutex, so
+/// [`DeadlockProofReadMostly::store`] and
+/// [`DeadlockProofReadMostly::update`] take part in the lock ordering just
+/// as [`crate::DeadlockProofMutex::lock`] does.
+///
+/// A snapshot stays valid, unchanged, for as long as the reader keeps it,
+/// even once a writer has replaced the value.
+pub struct DeadlockProofReadMostly<T, P: MutexPermission, I>(
+    ArcSwap<T>,
+    DeadlockProofMutex<(), P, I>,
+);
+
+impl<T, P: MutexPermission, I> DeadlockProofReadMostly<T, P, I> {
+    /// Create a new read-mostly value. The `identifier` is a type unique to
+    /// this value, just as for [`crate::DeadlockProofMutex::new`].
+    pub fn new(content: T, identifier: I) -> Self {
+        Self(
+            ArcSwap::from_pointee(content),
+            DeadlockProofMutex::new((), identifier),
+        )
+    }
+
+    /// A snapshot of the current value. This never blocks.
+    pub fn load(&self) -> Arc<T> {
+        self.0.load_full()
+    }
+
+    /// Replaces the value, blocking the current thread until no other writer
+    /// is doing so. Returns the permission.
+    pub fn store(&self, permission: P, content: T) -> P {
+        let guard = self.1.lock_ignore_poison(permission);
+        self.0.store(Arc::new(content));
+        guard.unlock()
+    }
+
+    /// Replaces the value with one computed from the current value by `f`,
+    /// blocking the current thread until no other writer is doing so, and
+    /// keeping other writers out until `f` has finished, so that no update
+    /// is lost. Readers carry on seeing the old value meanwhile. Returns the
+    /// permission.
+    ///
+    /// If `f` panics, the value is left as it was.
+    pub fn update(&self, permission: P, f: impl FnOnce(&T) -> T) -> P {
+        let guard = self.1.lock_ignore_poison(permission);
+        let content = f(&self.0.load());
+        self.0.store(Arc::new(content));
+        guard.unlock()
+    }
+
+    /// Consumes this, returning the current value, which readers may still
+    /// hold snapshots of.
+    pub fn into_inner(self) -> Arc<T> {
+        self.0.into_inner()
+    }
+}
+
+impl<T: fmt::Debug, P: MutexPermission, I> fmt::Debug for DeadlockProofReadMostly<T, P, I> {
+    /// Shows the current value. This doesn't need a permission, since it
+    /// never blocks.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeadlockProofReadMostly")
+            .field("identifier", &IdentifierName::of::<I>())
+            .field("data", &*self.0.load())
+            .finish_non_exhaustive()
+    }
+}
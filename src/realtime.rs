@@ -0,0 +1,232 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    cell::UnsafeCell,
+    fmt, io,
+    marker::PhantomData,
+    mem::{ManuallyDrop, MaybeUninit},
+    ops::{Deref, DerefMut},
+    sync::{LockResult, TryLockError, TryLockResult},
+};
+
+use crate::MutexBackend;
+
+/// A mutex built on a POSIX mutex using the `PTHREAD_PRIO_INHERIT`
+/// protocol: while a thread holds it, that thread runs at the priority of
+/// the highest-priority thread waiting for it. This bounds priority
+/// inversion for realtime threads, just as the permission tokens rule out
+/// deadlock.
+///
+/// Use it as the backend of a [`crate::DeadlockProofMutex`], created with
+/// [`crate::DeadlockProofMutex::from_backend`]. Like `parking_lot::Mutex`,
+/// it never becomes poisoned. Priority inheritance only has an effect for
+/// threads with a realtime scheduling policy.
+pub struct PriorityInheritMutex<T> {
+    // Boxed since a POSIX mutex mustn't move once initialized, and only
+    // dropped by `destroy`.
+    raw: ManuallyDrop<Box<UnsafeCell<libc::pthread_mutex_t>>>,
+    data: UnsafeCell<T>,
+}
+
+// Safety: the mutex gives exclusive access to the data, as for `Mutex`.
+unsafe impl<T: Send> Send for PriorityInheritMutex<T> {}
+unsafe impl<T: Send> Sync for PriorityInheritMutex<T> {}
+
+/// Panics if a pthread function failed.
+pub(crate) fn check(result: libc::c_int, operation: &str) {
+    if result != 0 {
+        panic!(
+            "{operation} failed: {}",
+            io::Error::from_raw_os_error(result)
+        );
+    }
+}
+
+impl<T> PriorityInheritMutex<T> {
+    /// Create a new priority-inheriting mutex containing `content`. Panics
+    /// if the platform doesn't support priority inheritance.
+    pub fn new(content: T) -> Self {
+        let raw = Box::new(UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER));
+        // Safety: the attributes are initialized before use and destroyed
+        // afterwards, and the mutex is in its final place.
+        unsafe {
+            let mut attr = MaybeUninit::<libc::pthread_mutexattr_t>::uninit();
+            check(
+                libc::pthread_mutexattr_init(attr.as_mut_ptr()),
+                "pthread_mutexattr_init",
+            );
+            let result =
+                libc::pthread_mutexattr_setprotocol(attr.as_mut_ptr(), libc::PTHREAD_PRIO_INHERIT);
+            if result == 0 {
+                check(
+                    libc::pthread_mutex_init(raw.get(), attr.as_ptr()),
+                    "pthread_mutex_init",
+                );
+            }
+            libc::pthread_mutexattr_destroy(attr.as_mut_ptr());
+            check(result, "pthread_mutexattr_setprotocol");
+        }
+        Self {
+            raw: ManuallyDrop::new(raw),
+            data: UnsafeCell::new(content),
+        }
+    }
+
+    fn guard(&self) -> PriorityInheritMutexGuard<'_, T> {
+        PriorityInheritMutexGuard(self, PhantomData)
+    }
+}
+
+/// Destroys and frees a mutex which no other thread can be using. If a
+/// guard was leaked, with `mem::forget` say, the mutex is still locked, and
+/// destroying a locked mutex is undefined behaviour, so it's leaked too, as
+/// the standard library does.
+fn destroy(raw: Box<UnsafeCell<libc::pthread_mutex_t>>) {
+    // Safety: the mutex was initialized in `new` and hasn't moved, and only
+    // a leaked guard can still hold it.
+    unsafe {
+        if libc::pthread_mutex_trylock(raw.get()) == 0 {
+            libc::pthread_mutex_unlock(raw.get());
+            libc::pthread_mutex_destroy(raw.get());
+        } else {
+            std::mem::forget(raw);
+        }
+    }
+}
+
+impl<T> Drop for PriorityInheritMutex<T> {
+    fn drop(&mut self) {
+        // Safety: `raw` is never used again.
+        destroy(unsafe { ManuallyDrop::take(&mut self.raw) });
+    }
+}
+
+impl<T> fmt::Debug for PriorityInheritMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PriorityInheritMutex")
+            .finish_non_exhaustive()
+    }
+}
+
+/// A guard for a [`PriorityInheritMutex`]. It must be released on the
+/// thread which claimed it, so it isn't `Send`.
+pub struct PriorityInheritMutexGuard<'a, T>(&'a PriorityInheritMutex<T>, PhantomData<*const ()>);
+
+impl<T> Deref for PriorityInheritMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: the mutex is held for as long as the guard exists.
+        unsafe { &*self.0.data.get() }
+    }
+}
+
+impl<T> DerefMut for PriorityInheritMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: the mutex is held for as long as the guard exists.
+        unsafe { &mut *self.0.data.get() }
+    }
+}
+
+impl<T> Drop for PriorityInheritMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Safety: this thread holds the mutex.
+        unsafe { libc::pthread_mutex_unlock(self.0.raw.get()) };
+    }
+}
+
+// Safety: the contents live within the mutex, not the guard.
+unsafe impl<T> MutexBackend<T> for PriorityInheritMutex<T> {
+    type Guard<'a>
+        = PriorityInheritMutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn lock(&self) -> LockResult<PriorityInheritMutexGuard<'_, T>> {
+        // Safety: the mutex was initialized in `new` and hasn't moved.
+        check(
+            unsafe { libc::pthread_mutex_lock(self.raw.get()) },
+            "pthread_mutex_lock",
+        );
+        Ok(self.guard())
+    }
+
+    fn try_lock(&self) -> TryLockResult<PriorityInheritMutexGuard<'_, T>> {
+        // Safety: the mutex was initialized in `new` and hasn't moved.
+        match unsafe { libc::pthread_mutex_trylock(self.raw.get()) } {
+            0 => Ok(self.guard()),
+            libc::EBUSY => Err(TryLockError::WouldBlock),
+            result => {
+                check(result, "pthread_mutex_trylock");
+                unreachable!()
+            }
+        }
+    }
+
+    fn get_mut(&mut self) -> LockResult<&mut T> {
+        Ok(self.data.get_mut())
+    }
+
+    fn into_inner(self) -> LockResult<T> {
+        let mut this = ManuallyDrop::new(self);
+        // Safety: `this` is never used again, and isn't dropped, so the
+        // data is read exactly once; the mutex is destroyed here instead.
+        unsafe {
+            destroy(ManuallyDrop::take(&mut this.raw));
+            Ok(std::ptr::read(this.data.get_mut()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Barrier, thread};
+
+    use crate::{
+        DeadlockProofMutex, DeadlockProofTryLockError, MutexBackend, OuterMutexPermission,
+    };
+
+    use super::PriorityInheritMutex;
+
+    struct Shared;
+
+    #[test]
+    fn excludes_other_threads() {
+        let mutex: DeadlockProofMutex<u32, OuterMutexPermission, Shared, _> =
+            DeadlockProofMutex::from_backend(PriorityInheritMutex::new(0), Shared);
+        let held = Barrier::new(2);
+        thread::scope(|s| {
+            let guard = mutex.lock(OuterMutexPermission::get()).unwrap();
+            s.spawn(|| {
+                let permission = OuterMutexPermission::get();
+                let permission = match mutex.try_lock(permission) {
+                    Err(DeadlockProofTryLockError::WouldBlock(permission)) => permission,
+                    _ => panic!("claimed a mutex held by another thread"),
+                };
+                held.wait();
+                let mut guard = mutex.lock(permission).unwrap();
+                *guard += 1;
+                guard.unlock();
+            });
+            held.wait();
+            guard.unlock();
+        });
+        assert_eq!(mutex.into_inner().unwrap(), 1);
+    }
+
+    #[test]
+    fn dropping_with_a_leaked_guard_leaks_the_mutex() {
+        let mutex = PriorityInheritMutex::new(7);
+        std::mem::forget(mutex.lock().unwrap());
+        drop(mutex);
+        let mutex = PriorityInheritMutex::new(7);
+        std::mem::forget(mutex.lock().unwrap());
+        assert_eq!(mutex.into_inner().unwrap(), 7);
+    }
+}
@@ -0,0 +1,62 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::{MutexPermission, OuterMutexPermission};
+
+/// Holds a permission for code with many ways out, such as early returns
+/// and `?`. The permission is taken out with [`PermissionReclaim::take`]
+/// to claim a mutex, and put back with [`PermissionReclaim::restore`] once
+/// the guard is unlocked.
+///
+/// Whatever path the code takes, the permission ends up back with its
+/// thread at the end of the scope: either it's still held here, or it was
+/// in a guard (or a lock error) which has since been dropped. Either way an
+/// [`OuterMutexPermission`] is returned to its thread when dropped, so it
+/// can be claimed again with [`OuterMutexPermission::get`].
+pub struct PermissionReclaim<P: MutexPermission = OuterMutexPermission>(Option<P>);
+
+impl<P: MutexPermission> PermissionReclaim<P> {
+    /// Starts holding `permission`.
+    pub fn new(permission: P) -> Self {
+        Self(Some(permission))
+    }
+
+    /// Takes out the permission, for example to claim a mutex. Panics if
+    /// it has already been taken and not restored.
+    pub fn take(&mut self) -> P {
+        self.0
+            .take()
+            .expect("Permission already taken from PermissionReclaim")
+    }
+
+    /// Puts back a permission previously taken out, typically as returned
+    /// by unlocking a guard.
+    pub fn restore(&mut self, permission: P) {
+        self.0 = Some(permission);
+    }
+
+    /// Whether the permission is currently held here.
+    pub fn is_held(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Borrows the permission, for use with
+    /// [`crate::DeadlockProofMutex::lock_borrowing`]. Panics if it has been
+    /// taken and not restored.
+    pub fn borrow(&mut self) -> &mut P {
+        self.0
+            .as_mut()
+            .expect("Permission already taken from PermissionReclaim")
+    }
+
+    /// Stops holding the permission, returning it. Panics if it has been
+    /// taken and not restored.
+    pub fn into_inner(mut self) -> P {
+        self.take()
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    marker::PhantomData,
+    ops::Deref,
+    rc::Rc,
+    sync::{Condvar, Mutex, MutexGuard, PoisonError},
+};
+
+use crate::{
+    domain,
+    violation::{report_violation, Violation, ViolationKind},
+    HeldLock, MutexPermission, PermissionPhantom, SequentialMutexPermission,
+};
+
+/// A reentrant lock which is compile-time guaranteed not to deadlock: the
+/// thread holding it may claim it again, without a permission, as often as
+/// it likes. Like [`std::sync::ReentrantLock`] it only gives shared access
+/// to its contents, so use a `RefCell` or similar inside it for mutation.
+///
+/// The first claim in a thread takes part in the lock ordering in exactly
+/// the same way as [`crate::DeadlockProofMutex`], using
+/// [`DeadlockProofReentrantLock::lock`]. While that guard exists, the same
+/// thread can claim the lock again using
+/// [`DeadlockProofReentrantLock::relock`], for example deep in a recursive
+/// call graph which has no permission to hand. Each of those guards must
+/// be released before the first one, since otherwise the lock would still
+/// be held after its permission had been returned; this is checked at
+/// runtime and reported as a [`Violation`].
+pub struct DeadlockProofReentrantLock<T, P: MutexPermission, I>(
+    ReentrantState,
+    T,
+    PermissionPhantom<P>,
+    PhantomData<I>,
+);
+
+/// Safety: the contents are only accessed through guards, which can't be
+/// sent between threads, and only one thread at a time holds any guards.
+unsafe impl<T: Send, P: MutexPermission, I: Sync> Sync for DeadlockProofReentrantLock<T, P, I> {}
+
+/// Which thread holds the lock, and how many times.
+struct ReentrantState(Mutex<Holder>, Condvar);
+
+#[derive(Clone, Copy)]
+struct Holder {
+    /// The address of the holder's [`THREAD_MARKER`], or zero.
+    thread: usize,
+    count: usize,
+}
+
+token_thread_local! {
+    /// Only its address matters: it identifies the current thread.
+    static THREAD_MARKER: u8 = 0;
+}
+
+fn current_thread() -> usize {
+    THREAD_MARKER.with(|marker| marker as *const u8 as usize)
+}
+
+impl ReentrantState {
+    fn holder(&self) -> MutexGuard<'_, Holder> {
+        // The state is always consistent, even if a holder panicked.
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock(&self) {
+        let me = current_thread();
+        let mut holder = self.holder();
+        while holder.thread != 0 && holder.thread != me {
+            holder = self.1.wait(holder).unwrap_or_else(PoisonError::into_inner);
+        }
+        holder.thread = me;
+        holder.count += 1;
+    }
+
+    fn relock(&self) -> bool {
+        let mut holder = self.holder();
+        let held = holder.thread == current_thread();
+        if held {
+            holder.count += 1;
+        }
+        held
+    }
+
+    /// Releases one claim, returning how many remain.
+    fn release(&self) -> usize {
+        let mut holder = self.holder();
+        holder.count -= 1;
+        if holder.count == 0 {
+            holder.thread = 0;
+            self.1.notify_one();
+        }
+        holder.count
+    }
+}
+
+/// Releases one claim of a reentrant lock when dropped. The first claim is
+/// the outer one, which should be the last to be released. Not `Send`,
+/// since the claim belongs to the current thread.
+struct Hold<'a, I>(&'a ReentrantState, bool, PhantomData<(I, Rc<()>)>);
+
+impl<I> Drop for Hold<'_, I> {
+    fn drop(&mut self) {
+        let remaining = self.0.release();
+        if self.1 && remaining != 0 {
+            report_violation(
+                Violation {
+                    kind: ViolationKind::ReentrantLockOutlived,
+                    identifiers: vec![std::any::type_name::<I>()],
+                    backtrace: None,
+                },
+                None,
+            );
+        }
+    }
+}
+
+impl<T, P: MutexPermission, I> DeadlockProofReentrantLock<T, P, I> {
+    /// Create a new deadlock-proof reentrant lock. The `_identifier` is a
+    /// type unique to this lock, just as for
+    /// [`crate::DeadlockProofMutex::new`].
+    pub fn new(content: T, _identifier: I) -> Self {
+        Self(
+            ReentrantState(
+                Mutex::new(Holder {
+                    thread: 0,
+                    count: 0,
+                }),
+                Condvar::new(),
+            ),
+            content,
+            PhantomData,
+            PhantomData,
+        )
+    }
+
+    /// Acquires this lock, blocking the current thread until it is able to
+    /// do so. Requires a permission token to prove that you can't be causing
+    /// a deadlock, even if this thread already holds the lock.
+    pub fn lock(&self, permission: P) -> DeadlockProofReentrantLockGuard<'_, T, P, I> {
+        domain::check_lock::<P::Domain, I>();
+        self.0.lock();
+        DeadlockProofReentrantLockGuard(
+            Hold(&self.0, true, PhantomData),
+            &self.1,
+            permission,
+            HeldLock::new::<I, P::Domain, _>(&self.0),
+        )
+    }
+
+    /// Claims this lock again, without a permission, if the current thread
+    /// already holds it. Returns `None` if it doesn't, since claiming the
+    /// lock would then involve waiting for another thread.
+    pub fn relock(&self) -> Option<DeadlockProofReentrantLockRelockGuard<'_, T, I>> {
+        self.0.relock().then(|| {
+            DeadlockProofReentrantLockRelockGuard(Hold(&self.0, false, PhantomData), &self.1)
+        })
+    }
+}
+
+/// The first guard of a [`DeadlockProofReentrantLock`] in a thread. As with
+/// [`crate::DeadlockProofMutexGuard`], it's strongly recommended that you
+/// explicitly unlock this rather than allowing it to drop.
+pub struct DeadlockProofReentrantLockGuard<'a, T, P: MutexPermission, I>(
+    #[allow(dead_code)] // only needed for its drop behaviour
+    Hold<'a, I>,
+    &'a T,
+    P,
+    #[allow(dead_code)] // only needed for its drop behaviour
+    HeldLock,
+);
+
+impl<T, P: MutexPermission, I> DeadlockProofReentrantLockGuard<'_, T, P, I> {
+    /// Unlock the lock. Returns the mutex permission token such that you can
+    /// use it again to claim a different lock.
+    pub fn unlock(self) -> P {
+        self.2
+    }
+
+    /// Unlock the lock. Returns the mutex permission token such that
+    /// you can use it again to claim a different lock. Also, returns an
+    /// extra mutex permission token so that you can claim another lock
+    /// in a certain sequence, which the type system will guarantee is
+    /// the same for all threads.
+    pub fn unlock_for_sequential(self) -> SequentialMutexPermission<P, I> {
+        SequentialMutexPermission::new(self.2)
+    }
+}
+
+impl<T, P: MutexPermission, I> Deref for DeadlockProofReentrantLockGuard<'_, T, P, I> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.1
+    }
+}
+
+/// A further guard of a [`DeadlockProofReentrantLock`] already held by this
+/// thread, from [`DeadlockProofReentrantLock::relock`]. Dropping it releases
+/// this claim; it must be dropped before the thread's first guard.
+pub struct DeadlockProofReentrantLockRelockGuard<'a, T, I>(
+    #[allow(dead_code)] // only needed for its drop behaviour
+    Hold<'a, I>,
+    &'a T,
+);
+
+impl<T, I> Deref for DeadlockProofReentrantLockRelockGuard<'_, T, I> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.1
+    }
+}
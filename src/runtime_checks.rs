@@ -0,0 +1,145 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Runtime verification of lock ordering, as a backstop for orderings the
+//! type system can't see. Each [`crate::DeadlockProofMutex`] is given an ID
+//! the first time it's claimed, and every time a thread claims one mutex
+//! while holding another, that ordering is recorded in a global graph. If a
+//! thread later claims two mutices the other way round, directly or through
+//! a chain of others, that's reported as a [`crate::Violation`].
+//!
+//! [`crate::DynamicCheckedMutex`] always goes through this; other mutices
+//! only with the `runtime-checks` feature.
+//!
+//! The graph is never pruned, so this is intended for debug builds and tests
+//! rather than production.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+};
+
+use crate::violation::{report_violation, Violation, ViolationAction, ViolationKind};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The orderings seen so far: each mutex maps to those claimed while it was
+/// held.
+static GRAPH: Mutex<BTreeMap<u64, BTreeSet<u64>>> = Mutex::new(BTreeMap::new());
+/// The identifier type name of each mutex in the graph.
+static NAMES: Mutex<BTreeMap<u64, &'static str>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    /// The mutices held by this thread: the key used by [`crate::waiters`]
+    /// and the ID.
+    static HELD: RefCell<Vec<(usize, u64)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The ID of one mutex, allocated when it's first claimed so that mutices
+/// can still be created in `const` contexts.
+pub(crate) struct NodeId(AtomicU64);
+
+impl NodeId {
+    pub(crate) const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    fn get(&self) -> u64 {
+        let id = self.0.load(Ordering::Relaxed);
+        if id != 0 {
+            return id;
+        }
+        let new = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        match self
+            .0
+            .compare_exchange(0, new, Ordering::Relaxed, Ordering::Relaxed)
+        {
+            Ok(_) => new,
+            Err(existing) => existing,
+        }
+    }
+}
+
+/// Whether `to` can be reached from `from` in the graph.
+fn reachable(graph: &BTreeMap<u64, BTreeSet<u64>>, from: u64, to: u64) -> bool {
+    let mut seen = BTreeSet::new();
+    let mut stack = vec![from];
+    while let Some(node) = stack.pop() {
+        if node == to {
+            return true;
+        }
+        if seen.insert(node) {
+            stack.extend(graph.get(&node).into_iter().flatten());
+        }
+    }
+    false
+}
+
+/// Checks that the current thread may wait for the mutex `node`, identified
+/// by `I`, given those it already holds, and records the new orderings.
+/// An inversion is reported by `action` if given, otherwise as configured
+/// globally.
+pub(crate) fn before_lock<I: ?Sized>(node: &NodeId, action: Option<ViolationAction>) {
+    let id = node.get();
+    NAMES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .entry(id)
+        .or_insert_with(std::any::type_name::<I>);
+    let held: Vec<u64> = HELD.with(|held| held.borrow().iter().map(|(_, id)| *id).collect());
+    let inverted = {
+        let mut graph = GRAPH.lock().unwrap_or_else(PoisonError::into_inner);
+        let inverted = held
+            .iter()
+            .copied()
+            .find(|&holding| reachable(&graph, id, holding));
+        if inverted.is_none() {
+            for holding in held {
+                graph.entry(holding).or_default().insert(id);
+            }
+        }
+        inverted
+    };
+    if let Some(holding) = inverted {
+        let names = NAMES.lock().unwrap_or_else(PoisonError::into_inner);
+        let identifiers = vec![names[&id], names[&holding]];
+        drop(names);
+        report_violation(
+            Violation {
+                kind: ViolationKind::LockOrderInversion,
+                identifiers,
+                backtrace: None,
+            },
+            action,
+        );
+    }
+}
+
+/// Records that the current thread has claimed the mutex `node`, whose key
+/// is `key`.
+pub(crate) fn claimed(key: usize, node: &NodeId) {
+    let id = node.get();
+    HELD.with(|held| held.borrow_mut().push((key, id)));
+}
+
+/// Records that the current thread has released the mutex whose key is
+/// `key`, if it was recorded as claimed.
+pub(crate) fn released(key: usize) {
+    // Guards may be released in any order, and the thread-local may already
+    // be gone in thread teardown.
+    let _ = HELD.try_with(|held| {
+        let mut held = held.borrow_mut();
+        if let Some(pos) = held.iter().rposition(|(k, _)| *k == key) {
+            held.remove(pos);
+        }
+    });
+}
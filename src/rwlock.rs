@@ -0,0 +1,942 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::{
+        LockResult, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+        TryLockError,
+    },
+};
+
+use crate::{
+    domain, map_lock_result, HeldLock, HeldLocks, MutexPermission, NestedMutexPermission,
+    PermissionPhantom, SequentialMutexPermission,
+};
+
+/// A reader-writer lock which is compile-time guaranteed not to deadlock.
+/// Otherwise identical to [`RwLock`], though at the moment only a subset of
+/// APIs are implemented.
+///
+/// This takes part in the lock ordering in exactly the same way as
+/// [`crate::DeadlockProofMutex`]: both [`DeadlockProofRwLock::read`] and
+/// [`DeadlockProofRwLock::write`] require a permission token, which is
+/// returned when the guard is unlocked. Even read locks need a permission,
+/// because a reader waiting for a writer can take part in a deadlock just as
+/// surely as a thread waiting for a mutex.
+///
+/// [`DeadlockProofRwLock::upgradable_read`] gives shared read access which
+/// can later be upgraded to write access without any other writer getting
+/// in first, and [`DeadlockProofRwLockWriteGuard::downgrade`] turns write
+/// access into read access without letting one in. [`ReadSet::read_many`]
+/// read-locks several locks with different identifiers at once, for a
+/// read-only traversal across them.
+/// [`DeadlockProofRwLock::with_policy`] stops readers from starving
+/// writers.
+pub struct DeadlockProofRwLock<T, P: MutexPermission, I>(
+    RwLock<T>,
+    PermissionPhantom<P>,
+    PhantomData<I>,
+    /// Held by writers and upgradable readers, so that at most one of them
+    /// exists at once. This is what lets an upgradable reader release its
+    /// read lock and take the write lock without another writer intervening.
+    Mutex<()>,
+    /// With [`RwLockPolicy::Fair`], held by a writer while it waits for the
+    /// lock, and passed through by readers, so that new readers queue
+    /// behind a waiting writer rather than overtaking it.
+    Option<Mutex<()>>,
+);
+
+/// How a [`DeadlockProofRwLock`] chooses between readers and writers
+/// waiting for it, given to [`DeadlockProofRwLock::with_policy`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RwLockPolicy {
+    /// Whatever [`RwLock`] does on this platform. Some platforms let a
+    /// steady stream of readers starve writers.
+    #[default]
+    Platform,
+    /// Readers and writers take turns in roughly the order they arrive: a
+    /// waiting writer holds back readers arriving after it, so it claims
+    /// the lock once the readers already holding it have finished.
+    /// Writers can't be starved, at the cost of some concurrency between
+    /// readers.
+    Fair,
+}
+
+/// The parts of a [`DeadlockProofRwLock`] which a writer claims, for
+/// claiming them again without the lock itself, as a condvar must.
+pub(crate) struct Writable<'a, T>(&'a RwLock<T>, &'a Mutex<()>, Option<&'a Mutex<()>>);
+
+// Not derived, which would need `T: Clone`.
+impl<T> Clone for Writable<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Writable<'_, T> {}
+
+impl<'a, T> Writable<'a, T> {
+    /// Claims the right to write, then the write lock itself. The first is
+    /// never poisoned, since nothing can panic while holding only it.
+    pub(crate) fn lock(self) -> LockResult<WriteLock<'a, T>> {
+        let upgrade = self.1.lock().unwrap_or_else(PoisonError::into_inner);
+        // Waits for any writer which arrived first, as the policy demands.
+        // The turnstile is never poisoned either.
+        let _turnstile = self
+            .2
+            .map(|turnstile| turnstile.lock().unwrap_or_else(PoisonError::into_inner));
+        map_lock_result(self.0.write(), |guard| WriteLock(guard, upgrade, self))
+    }
+}
+
+/// The underlying guards of a write lock, and the parts to claim it again.
+pub(crate) struct WriteLock<'a, T>(
+    RwLockWriteGuard<'a, T>,
+    #[allow(dead_code)] // only needed for its drop behaviour
+    MutexGuard<'a, ()>,
+    pub(crate) Writable<'a, T>,
+);
+
+impl<T> Deref for WriteLock<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.deref()
+    }
+}
+
+impl<T> DerefMut for WriteLock<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.deref_mut()
+    }
+}
+
+/// The underlying guards of an upgradable read lock, and the lock itself for
+/// upgrading it.
+pub(crate) struct UpgradableLock<'a, T>(
+    RwLockReadGuard<'a, T>,
+    MutexGuard<'a, ()>,
+    Writable<'a, T>,
+);
+
+impl<T> Deref for UpgradableLock<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.deref()
+    }
+}
+
+/// The result of [`DeadlockProofRwLock::read_for_nested`]: a guard plus the
+/// permission required to claim the next lock in the nesting.
+pub type NestedReadResult<'a, T, P, I> = LockResult<(
+    DeadlockProofNestedRwLockReadGuard<'a, T, P, I>,
+    NestedMutexPermission<P, I>,
+)>;
+
+/// The result of [`DeadlockProofRwLock::write_for_nested`]: a guard plus the
+/// permission required to claim the next lock in the nesting.
+pub type NestedWriteResult<'a, T, P, I> = LockResult<(
+    DeadlockProofNestedRwLockWriteGuard<'a, T, P, I>,
+    NestedMutexPermission<P, I>,
+)>;
+
+/// The result of [`DeadlockProofRwLock::try_read`].
+pub type TryReadResult<'a, T, P, I> = Result<
+    DeadlockProofRwLockReadGuard<'a, T, P, I>,
+    DeadlockProofRwLockTryError<P, DeadlockProofRwLockReadGuard<'a, T, P, I>>,
+>;
+
+/// The result of [`DeadlockProofRwLock::try_write`].
+pub type TryWriteResult<'a, T, P, I> = Result<
+    DeadlockProofRwLockWriteGuard<'a, T, P, I>,
+    DeadlockProofRwLockTryError<P, DeadlockProofRwLockWriteGuard<'a, T, P, I>>,
+>;
+
+/// Errors from [`DeadlockProofRwLock::try_read`] and
+/// [`DeadlockProofRwLock::try_write`], where `G` is the guard which would
+/// have been returned.
+pub enum DeadlockProofRwLockTryError<P, G> {
+    /// The lock couldn't be claimed without waiting. The permission is
+    /// returned so it can be used for something else.
+    WouldBlock(P),
+    /// Another thread panicked while holding the lock. As with
+    /// [`DeadlockProofRwLock::read`] and [`DeadlockProofRwLock::write`], the
+    /// lock was claimed anyway and the error contains a deadlock-proof guard
+    /// for it.
+    Poisoned(PoisonError<G>),
+}
+
+impl<T, P: MutexPermission, I> DeadlockProofRwLock<T, P, I> {
+    /// Create a new deadlock-proof reader-writer lock. The `_identifier` is
+    /// a type unique to this lock, just as for
+    /// [`crate::DeadlockProofMutex::new`].
+    pub fn new(content: T, identifier: I) -> Self {
+        Self::with_policy(content, identifier, RwLockPolicy::Platform)
+    }
+
+    /// Create a new deadlock-proof reader-writer lock in a `const` context,
+    /// as for [`crate::DeadlockProofMutex::const_new`]. It uses
+    /// [`RwLockPolicy::Platform`].
+    pub const fn const_new(content: T, identifier: I) -> Self {
+        // An identifier is only a marker, so there's nothing to drop.
+        std::mem::forget(identifier);
+        Self(
+            RwLock::new(content),
+            PhantomData,
+            PhantomData,
+            Mutex::new(()),
+            None,
+        )
+    }
+
+    /// Create a new deadlock-proof reader-writer lock which chooses between
+    /// waiting readers and writers according to `policy`.
+    pub fn with_policy(content: T, _identifier: I, policy: RwLockPolicy) -> Self {
+        Self(
+            RwLock::new(content),
+            PhantomData,
+            PhantomData,
+            Mutex::new(()),
+            match policy {
+                RwLockPolicy::Platform => None,
+                RwLockPolicy::Fair => Some(Mutex::new(())),
+            },
+        )
+    }
+
+    /// The parts a writer claims.
+    fn writable(&self) -> Writable<'_, T> {
+        Writable(&self.0, &self.3, self.4.as_ref())
+    }
+
+    /// Claims the read lock, after any writer which arrived first. The
+    /// turnstile is never poisoned, since nothing can panic while holding
+    /// it.
+    fn read_lock(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        if let Some(turnstile) = &self.4 {
+            drop(turnstile.lock().unwrap_or_else(PoisonError::into_inner));
+        }
+        self.0.read()
+    }
+
+    /// Claims the read lock if that needn't wait, which it must if a writer
+    /// arrived first.
+    fn try_read_lock(
+        &self,
+    ) -> Result<RwLockReadGuard<'_, T>, TryLockError<RwLockReadGuard<'_, T>>> {
+        if let Some(turnstile) = &self.4 {
+            if let Err(TryLockError::WouldBlock) = turnstile.try_lock() {
+                return Err(TryLockError::WouldBlock);
+            }
+        }
+        self.0.try_read()
+    }
+
+    /// Claims the right to write, then the write lock itself, if neither
+    /// needs to wait. Holding the first means no other writer can be
+    /// waiting at the turnstile.
+    fn try_write_lock(&self) -> Result<WriteLock<'_, T>, TryLockError<WriteLock<'_, T>>> {
+        let upgrade = match self.3.try_lock() {
+            Ok(upgrade) => upgrade,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => return Err(TryLockError::WouldBlock),
+        };
+        match self.0.try_write() {
+            Ok(guard) => Ok(WriteLock(guard, upgrade, self.writable())),
+            Err(TryLockError::Poisoned(e)) => Err(TryLockError::Poisoned(PoisonError::new(
+                WriteLock(e.into_inner(), upgrade, self.writable()),
+            ))),
+            Err(TryLockError::WouldBlock) => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Claims the right to write, then the write lock itself.
+    fn write_lock(&self) -> LockResult<WriteLock<'_, T>> {
+        self.writable().lock()
+    }
+
+    /// Locks this lock with shared read access, blocking the current thread
+    /// until it is able to do so. Similar to [`RwLock::read`], but requires a
+    /// permission token to prove that you can't be causing a deadlock. If
+    /// the lock is poisoned, the error still contains a deadlock-proof guard.
+    pub fn read(&self, permission: P) -> LockResult<DeadlockProofRwLockReadGuard<'_, T, P, I>> {
+        domain::check_lock::<P::Domain, I>();
+        map_lock_result(self.read_lock(), |guard| {
+            DeadlockProofRwLockReadGuard(
+                guard,
+                permission,
+                PhantomData,
+                HeldLock::new::<I, P::Domain, _>(&self.0),
+            )
+        })
+    }
+
+    /// Locks this lock with exclusive write access, blocking the current
+    /// thread until it is able to do so. Similar to [`RwLock::write`], but
+    /// requires a permission token to prove that you can't be causing a
+    /// deadlock. If the lock is poisoned, the error still contains a
+    /// deadlock-proof guard.
+    // The error holds the guard, just as for every other way of locking.
+    #[allow(clippy::result_large_err)]
+    pub fn write(&self, permission: P) -> LockResult<DeadlockProofRwLockWriteGuard<'_, T, P, I>> {
+        domain::check_lock::<P::Domain, I>();
+        map_lock_result(self.write_lock(), |guard| {
+            DeadlockProofRwLockWriteGuard(
+                guard,
+                permission,
+                PhantomData,
+                HeldLock::new::<I, P::Domain, _>(&self.0),
+            )
+        })
+    }
+
+    /// Attempts to lock this lock with shared read access without blocking.
+    /// Similar to [`RwLock::try_read`], except that if a writer holds the
+    /// lock, or is waiting for it under [`RwLockPolicy::Fair`], the
+    /// permission token is handed back within the error, so that the thread
+    /// can fall back to doing something else with it.
+    pub fn try_read(&self, permission: P) -> TryReadResult<'_, T, P, I> {
+        let guard = |guard, permission| {
+            DeadlockProofRwLockReadGuard(
+                guard,
+                permission,
+                PhantomData,
+                HeldLock::new::<I, P::Domain, _>(&self.0),
+            )
+        };
+        match self.try_read_lock() {
+            Ok(g) => Ok(guard(g, permission)),
+            Err(TryLockError::Poisoned(e)) => Err(DeadlockProofRwLockTryError::Poisoned(
+                PoisonError::new(guard(e.into_inner(), permission)),
+            )),
+            Err(TryLockError::WouldBlock) => {
+                Err(DeadlockProofRwLockTryError::WouldBlock(permission))
+            }
+        }
+    }
+
+    /// Attempts to lock this lock with exclusive write access without
+    /// blocking. Similar to [`RwLock::try_write`], except that if the lock is
+    /// held elsewhere the permission token is handed back within the error,
+    /// as for [`DeadlockProofRwLock::try_read`].
+    // The error holds the guard, just as for every other way of locking.
+    #[allow(clippy::result_large_err)]
+    pub fn try_write(&self, permission: P) -> TryWriteResult<'_, T, P, I> {
+        let guard = |guard, permission| {
+            DeadlockProofRwLockWriteGuard(
+                guard,
+                permission,
+                PhantomData,
+                HeldLock::new::<I, P::Domain, _>(&self.0),
+            )
+        };
+        match self.try_write_lock() {
+            Ok(g) => Ok(guard(g, permission)),
+            Err(TryLockError::Poisoned(e)) => Err(DeadlockProofRwLockTryError::Poisoned(
+                PoisonError::new(guard(e.into_inner(), permission)),
+            )),
+            Err(TryLockError::WouldBlock) => {
+                Err(DeadlockProofRwLockTryError::WouldBlock(permission))
+            }
+        }
+    }
+
+    /// Locks this lock with shared read access which can later be upgraded to
+    /// write access using [`DeadlockProofRwLockUpgradableReadGuard::upgrade`],
+    /// blocking the current thread until it is able to do so. Other readers
+    /// may hold the lock alongside, but no writer and no other upgradable
+    /// reader. Requires a permission token just as [`DeadlockProofRwLock::write`]
+    /// does. If the lock is poisoned, the error still contains a
+    /// deadlock-proof guard.
+    // The error holds the guard, just as for every other way of locking.
+    #[allow(clippy::result_large_err)]
+    pub fn upgradable_read(
+        &self,
+        permission: P,
+    ) -> LockResult<DeadlockProofRwLockUpgradableReadGuard<'_, T, P, I>> {
+        domain::check_lock::<P::Domain, I>();
+        let upgrade = self.3.lock().unwrap_or_else(PoisonError::into_inner);
+        map_lock_result(self.read_lock(), |guard| {
+            DeadlockProofRwLockUpgradableReadGuard(
+                UpgradableLock(guard, upgrade, self.writable()),
+                permission,
+                PhantomData,
+                HeldLock::new::<I, P::Domain, _>(&self.0),
+            )
+        })
+    }
+
+    /// Locks this lock with shared read access, blocking the current thread
+    /// until it is able to do so. Provides a token which can be used to claim
+    /// a nested lock.
+    pub fn read_for_nested(&self, permission: P) -> NestedReadResult<'_, T, P, I> {
+        domain::check_lock::<P::Domain, I>();
+        map_lock_result(self.read_lock(), |guard| {
+            (
+                DeadlockProofNestedRwLockReadGuard(
+                    guard,
+                    permission,
+                    PhantomData,
+                    HeldLock::new::<I, P::Domain, _>(&self.0),
+                ),
+                NestedMutexPermission::new(),
+            )
+        })
+    }
+
+    /// Locks this lock with exclusive write access, blocking the current
+    /// thread until it is able to do so. Provides a token which can be used
+    /// to claim a nested lock.
+    // The error holds the guard, just as for every other way of locking.
+    #[allow(clippy::result_large_err)]
+    pub fn write_for_nested(&self, permission: P) -> NestedWriteResult<'_, T, P, I> {
+        domain::check_lock::<P::Domain, I>();
+        map_lock_result(self.write_lock(), |guard| {
+            (
+                DeadlockProofNestedRwLockWriteGuard(
+                    guard,
+                    permission,
+                    PhantomData,
+                    HeldLock::new::<I, P::Domain, _>(&self.0),
+                ),
+                NestedMutexPermission::new(),
+            )
+        })
+    }
+}
+
+macro_rules! rwlock_guard {
+    (
+        $(#[$attr:meta])*
+        $name:ident, $inner:ident, $($unlock_arg:ident)?
+    ) => {
+        $(#[$attr])*
+        pub struct $name<'a, T, P: MutexPermission, I>(
+            pub(crate) $inner<'a, T>,
+            pub(crate) P,
+            pub(crate) PhantomData<I>,
+            #[allow(dead_code)] // only needed for its drop behaviour
+            pub(crate) HeldLock,
+        );
+
+        impl<T, P: MutexPermission, I> $name<'_, T, P, I> {
+            /// Unlock the lock. Returns the mutex permission token such that
+            /// you can use it again to claim a different lock.
+            pub fn unlock(self, $($unlock_arg: NestedMutexPermission<P, I>)?) -> P {
+                $(drop($unlock_arg);)?
+                self.1
+            }
+
+            /// Unlock the lock. Returns the mutex permission token such that
+            /// you can use it again to claim a different lock. Also, returns an
+            /// extra mutex permission token so that you can claim another lock
+            /// in a certain sequence, which the type system will guarantee is
+            /// the same for all threads.
+            pub fn unlock_for_sequential(self) -> SequentialMutexPermission<P, I> {
+                SequentialMutexPermission::new(self.1)
+            }
+        }
+
+        impl<T, P: MutexPermission, I> Deref for $name<'_, T, P, I> {
+            type Target = T;
+
+            fn deref(&self) -> &T {
+                self.0.deref()
+            }
+        }
+    };
+}
+
+rwlock_guard!(
+    /// Deadlock-proof equivalent to [`RwLockReadGuard`]. As with
+    /// [`crate::DeadlockProofMutexGuard`], it's strongly recommended that you
+    /// explicitly unlock this rather than allowing it to drop.
+    DeadlockProofRwLockReadGuard,
+    RwLockReadGuard,
+);
+
+rwlock_guard!(
+    /// Deadlock-proof equivalent to [`RwLockWriteGuard`]. As with
+    /// [`crate::DeadlockProofMutexGuard`], it's strongly recommended that you
+    /// explicitly unlock this rather than allowing it to drop.
+    DeadlockProofRwLockWriteGuard,
+    WriteLock,
+);
+
+rwlock_guard!(
+    /// Shared read access which can be upgraded to write access, obtained
+    /// from [`DeadlockProofRwLock::upgradable_read`]. As with
+    /// [`crate::DeadlockProofMutexGuard`], it's strongly recommended that you
+    /// explicitly unlock this rather than allowing it to drop.
+    DeadlockProofRwLockUpgradableReadGuard,
+    UpgradableLock,
+);
+
+impl<'a, T, P: MutexPermission, I> DeadlockProofRwLockUpgradableReadGuard<'a, T, P, I> {
+    /// Upgrades to write access, blocking the current thread until other
+    /// readers have released the lock. No writer can claim the lock in the
+    /// meantime, so whatever was read through this guard is still current.
+    /// The lock stays claimed with the same permission throughout.
+    pub fn upgrade(self) -> DeadlockProofRwLockWriteGuard<'a, T, P, I> {
+        let Self(UpgradableLock(read, upgrade, writable), permission, identifier, held) = self;
+        drop(read);
+        // Only a writer panicking could poison the lock, and no writer can
+        // have held it since this guard was created.
+        let write = writable.0.write().unwrap_or_else(PoisonError::into_inner);
+        DeadlockProofRwLockWriteGuard(
+            WriteLock(write, upgrade, writable),
+            permission,
+            identifier,
+            held,
+        )
+    }
+}
+
+rwlock_guard!(
+    /// Deadlock-proof equivalent to [`RwLockReadGuard`], obtained from
+    /// [`DeadlockProofRwLock::read_for_nested`].
+    DeadlockProofNestedRwLockReadGuard,
+    RwLockReadGuard,
+    _token
+);
+
+rwlock_guard!(
+    /// Deadlock-proof equivalent to [`RwLockWriteGuard`], obtained from
+    /// [`DeadlockProofRwLock::write_for_nested`].
+    DeadlockProofNestedRwLockWriteGuard,
+    WriteLock,
+    _token
+);
+
+impl<'a, T, P: MutexPermission, I> DeadlockProofRwLockWriteGuard<'a, T, P, I> {
+    /// Downgrades to shared read access without releasing the lock, so no
+    /// writer can claim it in between and whatever was written through this
+    /// guard is still what other readers see. Other readers may then join.
+    /// The lock stays claimed with the same permission throughout.
+    pub fn downgrade(self) -> DeadlockProofRwLockReadGuard<'a, T, P, I> {
+        let Self(WriteLock(write, upgrade, _), permission, identifier, held) = self;
+        let read = RwLockWriteGuard::downgrade(write);
+        drop(upgrade);
+        DeadlockProofRwLockReadGuard(read, permission, identifier, held)
+    }
+}
+
+impl<'a, T, P: MutexPermission, I> DeadlockProofNestedRwLockWriteGuard<'a, T, P, I> {
+    /// Downgrades to shared read access without releasing the lock, just as
+    /// [`DeadlockProofRwLockWriteGuard::downgrade`] does. The nested
+    /// permission stays valid and is still needed to unlock the read guard.
+    pub fn downgrade(self) -> DeadlockProofNestedRwLockReadGuard<'a, T, P, I> {
+        let Self(WriteLock(write, upgrade, _), permission, identifier, held) = self;
+        let read = RwLockWriteGuard::downgrade(write);
+        drop(upgrade);
+        DeadlockProofNestedRwLockReadGuard(read, permission, identifier, held)
+    }
+}
+
+impl<T, P: MutexPermission, I> DerefMut for DeadlockProofRwLockWriteGuard<'_, T, P, I> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.deref_mut()
+    }
+}
+
+impl<T, P: MutexPermission, I> DerefMut for DeadlockProofNestedRwLockWriteGuard<'_, T, P, I> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.deref_mut()
+    }
+}
+
+/// A tuple of references to reader-writer locks, all with the permission
+/// type `P` but each with its own identifier, which can be read-locked
+/// together using [`ReadSet::read_many`].
+pub trait ReadSet<'a, P: MutexPermission>: Sized {
+    /// References to the contents of each lock, in the same order.
+    type Contents<'g>
+    where
+        Self: 'g;
+
+    #[doc(hidden)]
+    type Guards;
+
+    /// Locks every one of these locks with shared read access, blocking the
+    /// current thread until it is able to do so. A read-only traversal can
+    /// then look at all of them at once, whatever their identifiers.
+    ///
+    /// Simply holding one read guard while waiting for the next could
+    /// deadlock against another thread reading them in the opposite order,
+    /// since each may be waiting behind a writer queued for the other's
+    /// lock. Instead, this only ever blocks while holding none of the
+    /// locks: if any of the rest would block, it releases them all, waits
+    /// for that one, and tries again. So the set as a whole takes the place
+    /// in the lock ordering of a single lock with the permission type `P`.
+    /// If any lock is poisoned, the error still contains a guard for all of
+    /// them.
+    fn read_many(self, permission: P) -> LockResult<DeadlockProofReadManyGuard<'a, P, Self>> {
+        let (guards, held, poisoned) = self.read_all();
+        let guard = DeadlockProofReadManyGuard(guards, held, permission);
+        if poisoned {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    #[doc(hidden)]
+    fn read_all(self) -> (Self::Guards, HeldLocks, bool);
+
+    #[doc(hidden)]
+    fn contents<'g>(guards: &'g Self::Guards) -> Self::Contents<'g>
+    where
+        Self: 'g;
+}
+
+/// A guard for a set of reader-writer locks read-locked together using
+/// [`ReadSet::read_many`]. As with [`crate::DeadlockProofMutexGuard`], it's
+/// strongly recommended that you explicitly unlock this rather than
+/// allowing it to drop.
+pub struct DeadlockProofReadManyGuard<'a, P: MutexPermission, S: ReadSet<'a, P>>(
+    S::Guards,
+    #[allow(dead_code)] // only needed for its drop behaviour
+    HeldLocks,
+    P,
+);
+
+impl<'a, P: MutexPermission, S: ReadSet<'a, P>> DeadlockProofReadManyGuard<'a, P, S> {
+    /// The contents of each lock, in the order they were given to
+    /// [`ReadSet::read_many`].
+    pub fn contents(&self) -> S::Contents<'_> {
+        S::contents(&self.0)
+    }
+
+    /// Unlock all the locks. Returns the mutex permission token such that
+    /// you can use it again to claim a different lock.
+    pub fn unlock(self) -> P {
+        self.2
+    }
+}
+
+macro_rules! impl_read_set {
+    ($($index:tt $content:ident $identifier:ident),*) => {
+        impl<'a, P: MutexPermission, $($content, $identifier),*> ReadSet<'a, P>
+            for ($(&'a DeadlockProofRwLock<$content, P, $identifier>,)*)
+        {
+            type Contents<'g>
+                = ($(&'g $content,)*)
+            where
+                Self: 'g;
+
+            type Guards = ($(RwLockReadGuard<'a, $content>,)*);
+
+            fn read_all(self) -> (Self::Guards, HeldLocks, bool) {
+                $(domain::check_lock::<P::Domain, $identifier>();)*
+                let mut poisoned = false;
+                let mut block = 0;
+                'retry: loop {
+                    let mut guards = ($(None::<RwLockReadGuard<'a, $content>>,)*);
+                    $(
+                        if block == $index {
+                            guards.$index = Some(self.$index.read_lock().unwrap_or_else(|e| {
+                                poisoned = true;
+                                e.into_inner()
+                            }));
+                        }
+                    )*
+                    $(
+                        if guards.$index.is_none() {
+                            guards.$index = Some(match self.$index.try_read_lock() {
+                                Ok(guard) => guard,
+                                Err(TryLockError::Poisoned(e)) => {
+                                    poisoned = true;
+                                    e.into_inner()
+                                }
+                                Err(TryLockError::WouldBlock) => {
+                                    block = $index;
+                                    continue 'retry;
+                                }
+                            });
+                        }
+                    )*
+                    let held = HeldLocks(vec![
+                        $(HeldLock::new::<$identifier, P::Domain, _>(&self.$index.0)),*
+                    ]);
+                    return (($(guards.$index.unwrap(),)*), held, poisoned);
+                }
+            }
+
+            fn contents<'g>(guards: &'g Self::Guards) -> Self::Contents<'g>
+            where
+                Self: 'g,
+            {
+                ($(guards.$index.deref(),)*)
+            }
+        }
+    };
+}
+
+impl_read_set!(0 A IA);
+impl_read_set!(0 A IA, 1 B IB);
+impl_read_set!(0 A IA, 1 B IB, 2 C IC);
+impl_read_set!(0 A IA, 1 B IB, 2 C IC, 3 D ID);
+impl_read_set!(0 A IA, 1 B IB, 2 C IC, 3 D ID, 4 E IE);
+impl_read_set!(0 A IA, 1 B IB, 2 C IC, 3 D ID, 4 E IE, 5 F IF);
+impl_read_set!(0 A IA, 1 B IB, 2 C IC, 3 D ID, 4 E IE, 5 F IF, 6 G IG);
+impl_read_set!(0 A IA, 1 B IB, 2 C IC, 3 D ID, 4 E IE, 5 F IF, 6 G IG, 7 H IH);
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{mpsc, TryLockError},
+        thread,
+        time::Duration,
+    };
+
+    use crate::OuterMutexPermission;
+
+    use super::{DeadlockProofRwLock, DeadlockProofRwLockTryError, ReadSet, RwLockPolicy};
+
+    struct Left;
+    struct Right;
+
+    #[test]
+    fn read_many_shares_locks_with_other_readers() {
+        let left: DeadlockProofRwLock<u32, OuterMutexPermission, Left> =
+            DeadlockProofRwLock::new(1, Left);
+        let right: DeadlockProofRwLock<u32, OuterMutexPermission, Right> =
+            DeadlockProofRwLock::new(2, Right);
+        let (held, wait_for_held) = mpsc::channel();
+        let (done, wait_for_done) = mpsc::channel::<()>();
+        thread::scope(|s| {
+            let reader = &left;
+            s.spawn(move || {
+                let guard = reader.read(OuterMutexPermission::get()).unwrap();
+                held.send(()).unwrap();
+                let _ = wait_for_done.recv();
+                guard.unlock();
+            });
+            wait_for_held.recv().unwrap();
+            let guard = (&left, &right)
+                .read_many(OuterMutexPermission::get())
+                .unwrap();
+            assert_eq!(guard.contents(), (&1, &2));
+            guard.unlock();
+            drop(done);
+        });
+    }
+
+    #[test]
+    fn read_many_waits_for_a_writer() {
+        let left: DeadlockProofRwLock<u32, OuterMutexPermission, Left> =
+            DeadlockProofRwLock::new(1, Left);
+        let right: DeadlockProofRwLock<u32, OuterMutexPermission, Right> =
+            DeadlockProofRwLock::new(2, Right);
+        let (held, wait_for_held) = mpsc::channel();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mut guard = right.write(OuterMutexPermission::get()).unwrap();
+                held.send(()).unwrap();
+                thread::sleep(Duration::from_millis(20));
+                *guard = 3;
+                guard.unlock();
+            });
+            wait_for_held.recv().unwrap();
+            let guard = (&left, &right)
+                .read_many(OuterMutexPermission::get())
+                .unwrap();
+            assert_eq!(guard.contents(), (&1, &3));
+            guard.unlock();
+        });
+    }
+
+    #[test]
+    fn fair_policy_holds_new_readers_behind_a_waiting_writer() {
+        let lock: DeadlockProofRwLock<u32, OuterMutexPermission, Left> =
+            DeadlockProofRwLock::with_policy(0, Left, RwLockPolicy::Fair);
+        let (held, wait_for_held) = mpsc::channel();
+        let (done, wait_for_done) = mpsc::channel::<()>();
+        thread::scope(|s| {
+            let first = &lock;
+            s.spawn(move || {
+                let guard = first.read(OuterMutexPermission::get()).unwrap();
+                held.send(()).unwrap();
+                let _ = wait_for_done.recv();
+                guard.unlock();
+            });
+            wait_for_held.recv().unwrap();
+            s.spawn(|| {
+                let mut guard = lock.write(OuterMutexPermission::get()).unwrap();
+                *guard = 1;
+                guard.unlock();
+            });
+            // Wait for the writer to reach the turnstile.
+            while !matches!(
+                lock.4.as_ref().unwrap().try_lock(),
+                Err(TryLockError::WouldBlock)
+            ) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            match lock.try_read(OuterMutexPermission::get()) {
+                Err(DeadlockProofRwLockTryError::WouldBlock(permission)) => drop(permission),
+                _ => panic!("a new reader overtook the waiting writer"),
+            }
+            let later = s.spawn(|| {
+                let guard = lock.read(OuterMutexPermission::get()).unwrap();
+                let seen = *guard;
+                guard.unlock();
+                seen
+            });
+            thread::sleep(Duration::from_millis(20));
+            drop(done);
+            assert_eq!(later.join().unwrap(), 1);
+        });
+    }
+
+    #[test]
+    fn fair_policy_holds_read_many_behind_a_waiting_writer() {
+        let left: DeadlockProofRwLock<u32, OuterMutexPermission, Left> =
+            DeadlockProofRwLock::new(1, Left);
+        let right: DeadlockProofRwLock<u32, OuterMutexPermission, Right> =
+            DeadlockProofRwLock::with_policy(2, Right, RwLockPolicy::Fair);
+        // Stands in for a writer waiting its turn. The fair lock isn't the
+        // first of the set, so it's only tried rather than waited for, which
+        // must still respect the turnstile.
+        let turnstile = right.4.as_ref().unwrap().lock().unwrap();
+        thread::scope(|s| {
+            let later = s.spawn(|| {
+                let guard = (&left, &right)
+                    .read_many(OuterMutexPermission::get())
+                    .unwrap();
+                assert_eq!(guard.contents(), (&1, &2));
+                guard.unlock();
+            });
+            thread::sleep(Duration::from_millis(20));
+            assert!(
+                !later.is_finished(),
+                "read_many overtook the waiting writer"
+            );
+            drop(turnstile);
+            later.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn readers_share_the_lock() {
+        let lock: DeadlockProofRwLock<u32, OuterMutexPermission, Left> =
+            DeadlockProofRwLock::new(1, Left);
+        let (held, wait_for_held) = mpsc::channel();
+        let (done, wait_for_done) = mpsc::channel::<()>();
+        thread::scope(|s| {
+            let reader = &lock;
+            s.spawn(move || {
+                let guard = reader.read(OuterMutexPermission::get()).unwrap();
+                held.send(()).unwrap();
+                let _ = wait_for_done.recv();
+                guard.unlock();
+            });
+            wait_for_held.recv().unwrap();
+            let guard = lock.read(OuterMutexPermission::get()).unwrap();
+            assert_eq!(*guard, 1);
+            match lock.try_write(guard.unlock()) {
+                Err(DeadlockProofRwLockTryError::WouldBlock(permission)) => drop(permission),
+                _ => panic!("a writer claimed the lock alongside a reader"),
+            }
+            drop(done);
+        });
+    }
+
+    #[test]
+    fn writer_excludes_readers() {
+        let lock: DeadlockProofRwLock<u32, OuterMutexPermission, Left> =
+            DeadlockProofRwLock::new(1, Left);
+        let (held, wait_for_held) = mpsc::channel();
+        let (done, wait_for_done) = mpsc::channel::<()>();
+        thread::scope(|s| {
+            let writer = &lock;
+            s.spawn(move || {
+                let mut guard = writer.write(OuterMutexPermission::get()).unwrap();
+                *guard = 2;
+                held.send(()).unwrap();
+                let _ = wait_for_done.recv();
+                guard.unlock();
+            });
+            wait_for_held.recv().unwrap();
+            let permission = match lock.try_read(OuterMutexPermission::get()) {
+                Err(DeadlockProofRwLockTryError::WouldBlock(permission)) => permission,
+                _ => panic!("a reader claimed the lock alongside a writer"),
+            };
+            drop(done);
+            let guard = lock.read(permission).unwrap();
+            assert_eq!(*guard, 2);
+            guard.unlock();
+        });
+    }
+
+    #[test]
+    fn upgrade_waits_for_other_readers() {
+        let lock: DeadlockProofRwLock<u32, OuterMutexPermission, Left> =
+            DeadlockProofRwLock::new(1, Left);
+        let (held, wait_for_held) = mpsc::channel();
+        let (done, wait_for_done) = mpsc::channel::<()>();
+        thread::scope(|s| {
+            let upgrader = &lock;
+            let upgraded = s.spawn(move || {
+                let guard = upgrader
+                    .upgradable_read(OuterMutexPermission::get())
+                    .unwrap();
+                held.send(*guard).unwrap();
+                let _ = wait_for_done.recv();
+                let mut guard = guard.upgrade();
+                *guard += 1;
+                guard.unlock();
+            });
+            // Readers may join an upgradable reader, but another writer
+            // may not.
+            assert_eq!(wait_for_held.recv().unwrap(), 1);
+            let guard = lock.read(OuterMutexPermission::get()).unwrap();
+            let permission = match lock.try_write(guard.unlock()) {
+                Err(DeadlockProofRwLockTryError::WouldBlock(permission)) => permission,
+                _ => panic!("a writer claimed the lock alongside an upgradable reader"),
+            };
+            let guard = lock.read(permission).unwrap();
+            drop(done);
+            thread::sleep(Duration::from_millis(20));
+            // The upgrade can't complete while this reader remains.
+            assert_eq!(*guard, 1);
+            let permission = guard.unlock();
+            upgraded.join().unwrap();
+            let guard = lock.read(permission).unwrap();
+            assert_eq!(*guard, 2);
+            guard.unlock();
+        });
+    }
+
+    #[test]
+    fn downgrade_admits_readers_but_not_writers() {
+        let lock: DeadlockProofRwLock<u32, OuterMutexPermission, Left> =
+            DeadlockProofRwLock::new(1, Left);
+        let mut guard = lock.write(OuterMutexPermission::get()).unwrap();
+        *guard = 2;
+        let guard = guard.downgrade();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let reader = match lock.try_read(OuterMutexPermission::get()) {
+                    Ok(reader) => reader,
+                    _ => panic!("a reader was kept out by a downgraded guard"),
+                };
+                assert_eq!(*reader, 2);
+                match lock.try_write(reader.unlock()) {
+                    Err(DeadlockProofRwLockTryError::WouldBlock(permission)) => drop(permission),
+                    _ => panic!("a writer claimed the lock alongside a downgraded guard"),
+                }
+            });
+        });
+        assert_eq!(*guard, 2);
+        guard.unlock();
+    }
+}
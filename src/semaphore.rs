@@ -0,0 +1,127 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    marker::PhantomData,
+    sync::{Condvar, Mutex, MutexGuard, PoisonError},
+};
+
+use crate::{domain, HeldLock, MutexPermission, PermissionPhantom, SequentialMutexPermission};
+
+/// A counting semaphore which is compile-time guaranteed not to deadlock.
+/// Waiting for a permit is much like waiting for a mutex, so
+/// [`DeadlockProofSemaphore::acquire`] takes part in the lock ordering in
+/// exactly the same way as [`crate::DeadlockProofMutex::lock`]: it requires
+/// a permission token, which is returned when the permit is released.
+pub struct DeadlockProofSemaphore<P: MutexPermission, I>(
+    Mutex<usize>,
+    Condvar,
+    PermissionPhantom<P>,
+    PhantomData<I>,
+);
+
+impl<P: MutexPermission, I> DeadlockProofSemaphore<P, I> {
+    /// Create a new deadlock-proof semaphore with the given number of
+    /// permits. The `_identifier` is a type unique to this semaphore, just
+    /// as for [`crate::DeadlockProofMutex::new`].
+    pub fn new(permits: usize, _identifier: I) -> Self {
+        Self(
+            Mutex::new(permits),
+            Condvar::new(),
+            PhantomData,
+            PhantomData,
+        )
+    }
+
+    fn available(&self) -> MutexGuard<'_, usize> {
+        // The count is always consistent, even if a thread panicked.
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Acquires a permit, blocking the current thread until one is
+    /// available. Requires a permission token to prove that you can't be
+    /// causing a deadlock.
+    pub fn acquire(&self, permission: P) -> DeadlockProofSemaphorePermit<'_, P, I> {
+        domain::check_lock::<P::Domain, I>();
+        let mut available = self.available();
+        while *available == 0 {
+            available = self
+                .1
+                .wait(available)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        *available -= 1;
+        self.permit(permission)
+    }
+
+    /// Attempts to acquire a permit without blocking. If none is available,
+    /// the permission is returned.
+    pub fn try_acquire(&self, permission: P) -> Result<DeadlockProofSemaphorePermit<'_, P, I>, P> {
+        let mut available = self.available();
+        if *available == 0 {
+            return Err(permission);
+        }
+        *available -= 1;
+        Ok(self.permit(permission))
+    }
+
+    /// Adds permits to the semaphore, waking threads waiting for them.
+    pub fn add_permits(&self, permits: usize) {
+        *self.available() += permits;
+        self.1.notify_all();
+    }
+
+    /// The number of permits currently available.
+    pub fn available_permits(&self) -> usize {
+        *self.available()
+    }
+
+    fn permit(&self, permission: P) -> DeadlockProofSemaphorePermit<'_, P, I> {
+        DeadlockProofSemaphorePermit(
+            PermitHold(self),
+            permission,
+            HeldLock::new::<I, P::Domain, _>(&self.0),
+        )
+    }
+}
+
+/// Returns a permit to its semaphore when dropped.
+struct PermitHold<'a, P: MutexPermission, I>(&'a DeadlockProofSemaphore<P, I>);
+
+impl<P: MutexPermission, I> Drop for PermitHold<'_, P, I> {
+    fn drop(&mut self) {
+        *self.0.available() += 1;
+        self.0 .1.notify_one();
+    }
+}
+
+/// A permit from a [`DeadlockProofSemaphore`]. As with
+/// [`crate::DeadlockProofMutexGuard`], it's strongly recommended that you
+/// explicitly release this rather than allowing it to drop.
+pub struct DeadlockProofSemaphorePermit<'a, P: MutexPermission, I>(
+    #[allow(dead_code)] // only needed for its drop behaviour
+    PermitHold<'a, P, I>,
+    P,
+    #[allow(dead_code)] // only needed for its drop behaviour
+    HeldLock,
+);
+
+impl<P: MutexPermission, I> DeadlockProofSemaphorePermit<'_, P, I> {
+    /// Release the permit. Returns the mutex permission token such that you
+    /// can use it again to claim a different lock.
+    pub fn release(self) -> P {
+        self.1
+    }
+
+    /// Release the permit, returning the permission token along with an
+    /// extra one so that you can claim another lock in a certain sequence,
+    /// which the type system will guarantee is the same for all threads.
+    pub fn release_for_sequential(self) -> SequentialMutexPermission<P, I> {
+        SequentialMutexPermission::new(self.1)
+    }
+}
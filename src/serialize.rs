@@ -0,0 +1,54 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::sync::TryLockError;
+
+use serde::{ser, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{DeadlockProofMutex, DeadlockProofMutexGuard, MutexBackend, MutexPermission};
+
+/// Serializes the contents without a permission. Serializing can't wait for
+/// the mutex, since whatever is serializing may hold others, so this fails
+/// if the mutex is locked, just as it does if the mutex is poisoned. To
+/// serialize contents which may be contended, claim the mutex and serialize
+/// the guard instead.
+impl<T: Serialize, P: MutexPermission, I, M: MutexBackend<T>> Serialize
+    for DeadlockProofMutex<T, P, I, M>
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.3.try_lock() {
+            Ok(guard) => (*guard).serialize(serializer),
+            Err(TryLockError::Poisoned(_)) => Err(ser::Error::custom(
+                "deadlock-proof mutex poisoned while serializing",
+            )),
+            Err(TryLockError::WouldBlock) => Err(ser::Error::custom(
+                "deadlock-proof mutex locked while serializing",
+            )),
+        }
+    }
+}
+
+/// Creates a mutex containing the deserialized contents, identified by the
+/// default value of the identifier type, as for
+/// `DeadlockProofMutex::from`.
+impl<'de, T: Deserialize<'de>, P: MutexPermission, I: Default> Deserialize<'de>
+    for DeadlockProofMutex<T, P, I>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Self::from)
+    }
+}
+
+/// Serializes the contents of the claimed mutex.
+impl<'a, T: Serialize, P: MutexPermission, I, M: MutexBackend<T> + 'a> Serialize
+    for DeadlockProofMutexGuard<'a, T, P, I, M>
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(serializer)
+    }
+}
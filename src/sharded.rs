@@ -0,0 +1,180 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    borrow::Borrow,
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hash},
+    sync::{LockResult, PoisonError},
+};
+
+use crate::{
+    DeadlockProofMutexPool, DeadlockProofPoolGuard, DeadlockProofPoolGuards, MutexPermission,
+    PoolLockError,
+};
+
+/// A hash map split into `N` shards, each protected by its own bucket of a
+/// [`DeadlockProofMutexPool`], so that threads working on keys in different
+/// shards don't contend.
+///
+/// As with the pool, the map as a whole takes its place in the lock ordering
+/// like a single [`crate::DeadlockProofMutex`] using the permission type
+/// `P`. A thread either holds the one shard containing a key, using
+/// [`DeadlockProofShardedMap::lock_shard`], or the shards for several keys
+/// at once, using [`DeadlockProofShardedMap::lock_keys`], which claims them
+/// in ascending order so that threads can't deadlock against each other.
+pub struct DeadlockProofShardedMap<K, V, P: MutexPermission, I, const N: usize> {
+    shards: DeadlockProofMutexPool<HashMap<K, V>, P, I, N>,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq, V, P: MutexPermission, I, const N: usize>
+    DeadlockProofShardedMap<K, V, P, I, N>
+{
+    /// Create a new, empty sharded map. The `identifier` is a type unique
+    /// to this map, just as for [`crate::DeadlockProofMutex::new`]. Panics
+    /// if `N` is zero.
+    pub fn new(identifier: I) -> Self {
+        assert!(N > 0, "a sharded map needs at least one shard");
+        Self {
+            shards: DeadlockProofMutexPool::new(
+                std::array::from_fn(|_| HashMap::new()),
+                identifier,
+            ),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// The index of the shard in which `key` belongs.
+    pub fn shard_index<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        (self.hasher.hash_one(key) % N as u64) as usize
+    }
+
+    /// Acquires the shard containing `key`, blocking the current thread
+    /// until it is able to do so. The guard gives access to the whole shard
+    /// as a [`HashMap`], in which `key` may or may not be present. No other
+    /// shard may be claimed while it's held. If the shard is poisoned, the
+    /// error still contains a deadlock-proof guard.
+    pub fn lock_shard<Q>(
+        &self,
+        key: &Q,
+        permission: P,
+    ) -> LockResult<DeadlockProofPoolGuard<'_, HashMap<K, V>, P, I>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shards.lock(self.shard_index(key), permission)
+    }
+
+    /// Acquires the shards containing all of `keys`, in ascending order,
+    /// blocking the current thread until it is able to do so. The keys may
+    /// be given in any order and may share shards. If any shard is poisoned,
+    /// the error still contains a guard for all of them.
+    pub fn lock_keys<Q>(
+        &self,
+        keys: &[&Q],
+        permission: P,
+    ) -> LockResult<DeadlockProofShardedMapGuards<'_, K, V, P, I, N>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut indices: Vec<usize> = keys.iter().map(|key| self.shard_index(*key)).collect();
+        indices.sort_unstable();
+        indices.dedup();
+        let mut guards = self.shards.lock_ascending(permission);
+        let mut poisoned = false;
+        for index in indices {
+            match guards.lock(index) {
+                Ok(_) => {}
+                Err(PoolLockError::Poisoned) => poisoned = true,
+                Err(PoolLockError::OutOfOrder { .. }) => unreachable!("indices are sorted"),
+            }
+        }
+        let guards = DeadlockProofShardedMapGuards { map: self, guards };
+        if poisoned {
+            Err(PoisonError::new(guards))
+        } else {
+            Ok(guards)
+        }
+    }
+}
+
+/// The shards of a [`DeadlockProofShardedMap`] containing a set of keys,
+/// obtained from [`DeadlockProofShardedMap::lock_keys`]. Operations on keys
+/// whose shards aren't held panic.
+pub struct DeadlockProofShardedMapGuards<'a, K, V, P: MutexPermission, I, const N: usize> {
+    map: &'a DeadlockProofShardedMap<K, V, P, I, N>,
+    guards: DeadlockProofPoolGuards<'a, HashMap<K, V>, P, I, N>,
+}
+
+impl<K: Hash + Eq, V, P: MutexPermission, I, const N: usize>
+    DeadlockProofShardedMapGuards<'_, K, V, P, I, N>
+{
+    fn shard<Q>(&self, key: &Q) -> &HashMap<K, V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.guards
+            .get(self.map.shard_index(key))
+            .expect("the shard for this key isn't held")
+    }
+
+    fn shard_mut<Q>(&mut self, key: &Q) -> &mut HashMap<K, V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.guards
+            .get_mut(self.map.shard_index(key))
+            .expect("the shard for this key isn't held")
+    }
+
+    /// The value for `key`, as [`HashMap::get`].
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(key).get(key)
+    }
+
+    /// The value for `key`, mutably, as [`HashMap::get_mut`].
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard_mut(key).get_mut(key)
+    }
+
+    /// Inserts a value for `key`, as [`HashMap::insert`].
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.shard_mut(&key).insert(key, value)
+    }
+
+    /// Removes the value for `key`, as [`HashMap::remove`].
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard_mut(key).remove(key)
+    }
+
+    /// Unlock all the held shards, returning the mutex permission token.
+    pub fn unlock(self) -> P {
+        self.guards.unlock()
+    }
+}
@@ -0,0 +1,66 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    cell::{RefCell, RefMut},
+    sync::{LockResult, TryLockError, TryLockResult},
+};
+
+use crate::MutexBackend;
+
+/// A mutex for WebAssembly without threads, which is no more than a
+/// [`RefCell`]: with only one thread there's nobody to wait for, so
+/// claiming it needs no atomics. It's available only on `wasm32` targets
+/// built without the `atomics` target feature.
+///
+/// Use it as the backend of a [`crate::DeadlockProofMutex`], created with
+/// [`crate::DeadlockProofMutex::from_backend`]. The permission tokens work
+/// exactly as they do with any other backend, so code shared with native
+/// targets needn't change beyond the choice of backend. Since the tokens
+/// already rule out claiming a mutex which the current thread holds, and
+/// there are no other threads, claiming it never fails. It never becomes
+/// poisoned.
+#[derive(Debug, Default)]
+pub struct SingleThreadMutex<T>(RefCell<T>);
+
+// Safety: the target has no threads, so nothing can share the mutex
+// between threads.
+unsafe impl<T> Sync for SingleThreadMutex<T> {}
+
+impl<T> SingleThreadMutex<T> {
+    /// Create a new single-threaded mutex containing `content`.
+    pub const fn new(content: T) -> Self {
+        Self(RefCell::new(content))
+    }
+}
+
+// Safety: the contents live within the mutex, not the guard.
+unsafe impl<T> MutexBackend<T> for SingleThreadMutex<T> {
+    type Guard<'a>
+        = RefMut<'a, T>
+    where
+        T: 'a;
+
+    fn lock(&self) -> LockResult<RefMut<'_, T>> {
+        Ok(self.0.borrow_mut())
+    }
+
+    fn try_lock(&self) -> TryLockResult<RefMut<'_, T>> {
+        self.0
+            .try_borrow_mut()
+            .map_err(|_| TryLockError::WouldBlock)
+    }
+
+    fn get_mut(&mut self) -> LockResult<&mut T> {
+        Ok(self.0.get_mut())
+    }
+
+    fn into_inner(self) -> LockResult<T> {
+        Ok(self.0.into_inner())
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+/// Statistics about the use of a single mutex, as returned by
+/// [`crate::DeadlockProofMutex::stats`]. They're kept by the mutex itself,
+/// so need no tracing subscriber or observer to collect them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MutexStats {
+    /// How many times the mutex has been claimed.
+    pub acquisitions: u64,
+    /// The total time threads have spent waiting to claim the mutex.
+    pub total_wait: Duration,
+    /// The longest any thread has waited to claim the mutex.
+    pub max_wait: Duration,
+    /// The total time the mutex has been held, from claiming it to
+    /// releasing the guard.
+    pub total_hold: Duration,
+    /// The longest the mutex has been held at once.
+    pub max_hold: Duration,
+    /// How many times [`crate::DeadlockProofMutex::try_lock_until`] gave up
+    /// because its deadline passed.
+    pub deadline_misses: u64,
+}
+
+/// The live counters behind [`MutexStats`], stored within each mutex. The
+/// times are in nanoseconds.
+pub(crate) struct StatsRecord {
+    acquisitions: AtomicU64,
+    total_wait: AtomicU64,
+    max_wait: AtomicU64,
+    total_hold: AtomicU64,
+    max_hold: AtomicU64,
+    deadline_misses: AtomicU64,
+}
+
+/// Converts a duration to nanoseconds, saturating after some 584 years.
+fn nanos(duration: Duration) -> u64 {
+    duration.as_nanos().try_into().unwrap_or(u64::MAX)
+}
+
+/// The current time, in nanoseconds since some fixed point.
+fn now() -> u64 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    nanos(EPOCH.get_or_init(Instant::now).elapsed())
+}
+
+impl StatsRecord {
+    pub(crate) const fn new() -> Self {
+        Self {
+            acquisitions: AtomicU64::new(0),
+            total_wait: AtomicU64::new(0),
+            max_wait: AtomicU64::new(0),
+            total_hold: AtomicU64::new(0),
+            max_hold: AtomicU64::new(0),
+            deadline_misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Records that the mutex was claimed after waiting for `wait`.
+    pub(crate) fn record_acquisition(&self, wait: Duration) {
+        let wait = nanos(wait);
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        self.total_wait.fetch_add(wait, Ordering::Relaxed);
+        self.max_wait.fetch_max(wait, Ordering::Relaxed);
+    }
+
+    fn record_hold(&self, claimed_at: u64) {
+        let hold = now().saturating_sub(claimed_at);
+        self.total_hold.fetch_add(hold, Ordering::Relaxed);
+        self.max_hold.fetch_max(hold, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_deadline_miss(&self) {
+        self.deadline_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> MutexStats {
+        let duration = |nanos: &AtomicU64| Duration::from_nanos(nanos.load(Ordering::Relaxed));
+        MutexStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            total_wait: duration(&self.total_wait),
+            max_wait: duration(&self.max_wait),
+            total_hold: duration(&self.total_hold),
+            max_hold: duration(&self.max_hold),
+            deadline_misses: self.deadline_misses.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        for counter in [
+            &self.acquisitions,
+            &self.total_wait,
+            &self.max_wait,
+            &self.total_hold,
+            &self.max_hold,
+            &self.deadline_misses,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Stored within a [`crate::HeldLock`], timing how long the mutex is held
+/// and recording it in the mutex's [`StatsRecord`] once released. It keeps
+/// the time the mutex was claimed itself, since the underlying mutex is
+/// released before the timer is dropped, and the next holder may already
+/// have claimed it by then.
+pub(crate) struct HoldTimer(NonNull<StatsRecord>, u64);
+
+// Safety: the record is only used through a shared reference, and is made
+// of atomics.
+unsafe impl Send for HoldTimer {}
+unsafe impl Sync for HoldTimer {}
+
+impl HoldTimer {
+    /// Times the hold of the mutex containing `record`, which has just been
+    /// claimed.
+    ///
+    /// # Safety
+    ///
+    /// The timer mustn't outlive `record`.
+    pub(crate) unsafe fn start(record: &StatsRecord) -> Self {
+        Self(NonNull::from(record), now())
+    }
+}
+
+impl Drop for HoldTimer {
+    fn drop(&mut self) {
+        // Safety: as required by `start`.
+        unsafe { self.0.as_ref() }.record_hold(self.1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Barrier, thread, time::Duration};
+
+    use crate::{DeadlockProofMutex, OuterMutexPermission};
+
+    struct Counter;
+
+    #[test]
+    fn lock_when_counts_only_the_claim_handed_out() {
+        let mutex: DeadlockProofMutex<u32, OuterMutexPermission, Counter> =
+            DeadlockProofMutex::new(0, Counter);
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mut permission = OuterMutexPermission::get();
+                for _ in 0..3 {
+                    thread::sleep(Duration::from_millis(10));
+                    permission = mutex.with_lock(permission, |n| *n += 1).unwrap().1;
+                }
+            });
+            let guard = mutex
+                .lock_when(OuterMutexPermission::get(), |n| *n == 3)
+                .unwrap();
+            guard.unlock();
+        });
+        let stats = mutex.stats();
+        assert_eq!(stats.acquisitions, 4);
+        assert!(stats.max_wait >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn try_lock_for_records_its_wait() {
+        let mutex: DeadlockProofMutex<u32, OuterMutexPermission, Counter> =
+            DeadlockProofMutex::new(0, Counter);
+        let held = Barrier::new(2);
+        thread::scope(|s| {
+            s.spawn(|| {
+                let guard = mutex.lock(OuterMutexPermission::get()).unwrap();
+                held.wait();
+                thread::sleep(Duration::from_millis(50));
+                guard.unlock();
+            });
+            held.wait();
+            let guard = mutex
+                .try_lock_for(OuterMutexPermission::get(), Duration::from_secs(10))
+                .ok()
+                .unwrap();
+            guard.unlock();
+        });
+        let stats = mutex.stats();
+        assert_eq!(stats.acquisitions, 2);
+        assert!(stats.max_wait >= Duration::from_millis(40));
+        assert!(stats.max_hold >= Duration::from_millis(40));
+    }
+}
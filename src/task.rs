@@ -0,0 +1,49 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Running async tasks which start out with their mutex permission. This is
+//! the async equivalent of [`crate::thread`]: each task has exactly one
+//! [`AsyncOuterMutexPermission`], and a task which hasn't started yet can't
+//! have claimed it, so these wrappers claim it before any user code runs and
+//! pass it to the closure.
+//!
+//! Thread-local permissions are no use to async code, since a work-stealing
+//! executor may move a task to another thread at any `.await`, and run
+//! other tasks on the original thread meanwhile. The permission therefore
+//! belongs to the task, and travels with it.
+//!
+//! [`with_permission`] works with any executor: wrap the future passed to
+//! its `spawn` function. With the `tokio` feature, `spawn` does this for
+//! `tokio::spawn`.
+
+use std::future::Future;
+
+use crate::AsyncOuterMutexPermission;
+
+/// Runs the future returned by `f` as a task with its own mutex permission,
+/// which is passed to `f`. The result is typically handed straight to an
+/// executor's `spawn` function.
+pub fn with_permission<F, Fut>(f: F) -> impl Future<Output = Fut::Output>
+where
+    F: FnOnce(AsyncOuterMutexPermission) -> Fut,
+    Fut: Future,
+{
+    AsyncOuterMutexPermission::scope(async move { f(AsyncOuterMutexPermission::get()).await })
+}
+
+/// Spawns a new task, as [`tokio::spawn`] does, passing the new task's mutex
+/// permission to `f`.
+#[cfg(feature = "tokio")]
+pub fn spawn<F, Fut>(f: F) -> tokio::task::JoinHandle<Fut::Output>
+where
+    F: FnOnce(AsyncOuterMutexPermission) -> Fut + Send + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: Send + 'static,
+{
+    tokio::spawn(with_permission(f))
+}
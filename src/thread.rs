@@ -0,0 +1,120 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Spawning threads which start out with their mutex permission. Each thread
+//! has exactly one [`OuterMutexPermission`], and a freshly spawned thread
+//! can't have claimed it yet, so these wrappers claim it before any user code
+//! runs and pass it to the closure. Unlike calling
+//! [`OuterMutexPermission::get`] at the top of every closure, this can't
+//! panic.
+//!
+//! [`scope`] does the same for scoped threads, which can borrow mutices from
+//! the spawning thread's stack rather than needing them to be in an
+//! [`std::sync::Arc`].
+//!
+//! With the `tokio` feature, `spawn_blocking_with_permission` does the same
+//! for tokio's blocking thread pool.
+
+use std::{
+    io,
+    thread::{JoinHandle, ScopedJoinHandle},
+};
+
+use crate::OuterMutexPermission;
+
+/// Spawns a new thread, as [`std::thread::spawn`] does, passing the new
+/// thread's mutex permission to `f`.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce(OuterMutexPermission) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    std::thread::spawn(move || f(OuterMutexPermission::get()))
+}
+
+/// Thread factory equivalent to [`std::thread::Builder`], whose threads are
+/// passed their mutex permission just as for [`spawn`].
+#[derive(Debug)]
+pub struct Builder(std::thread::Builder);
+
+impl Builder {
+    /// Base configuration for spawning a thread, from which configuration
+    /// methods can be chained.
+    pub fn new() -> Self {
+        Self(std::thread::Builder::new())
+    }
+
+    /// Names the thread-to-be, as [`std::thread::Builder::name`] does.
+    pub fn name(self, name: String) -> Self {
+        Self(self.0.name(name))
+    }
+
+    /// Sets the size of the stack for the new thread, as
+    /// [`std::thread::Builder::stack_size`] does.
+    pub fn stack_size(self, size: usize) -> Self {
+        Self(self.0.stack_size(size))
+    }
+
+    /// Spawns a new thread with this configuration, passing its mutex
+    /// permission to `f`. Fails only if the operating system can't create
+    /// the thread, as for [`std::thread::Builder::spawn`].
+    pub fn spawn<F, T>(self, f: F) -> io::Result<JoinHandle<T>>
+    where
+        F: FnOnce(OuterMutexPermission) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.0.spawn(move || f(OuterMutexPermission::get()))
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Creates a scope for spawning scoped threads, as [`std::thread::scope`]
+/// does. Threads spawned within it using [`Scope::spawn`] are passed their
+/// mutex permission, and all of them are joined before this returns.
+pub fn scope<'env, F, T>(f: F) -> T
+where
+    F: for<'scope> FnOnce(&Scope<'scope, 'env>) -> T,
+{
+    std::thread::scope(|scope| f(&Scope(scope)))
+}
+
+/// A scope to spawn scoped threads in, obtained from [`scope`]. Equivalent
+/// to [`std::thread::Scope`].
+pub struct Scope<'scope, 'env: 'scope>(&'scope std::thread::Scope<'scope, 'env>);
+
+impl<'scope> Scope<'scope, '_> {
+    /// Spawns a new thread within this scope, as
+    /// [`std::thread::Scope::spawn`] does, passing the new thread's mutex
+    /// permission to `f`.
+    pub fn spawn<F, T>(&self, f: F) -> ScopedJoinHandle<'scope, T>
+    where
+        F: FnOnce(OuterMutexPermission) -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        self.0.spawn(move || f(OuterMutexPermission::get()))
+    }
+}
+
+/// Runs `f` on tokio's blocking thread pool, as
+/// [`tokio::task::spawn_blocking`] does, leasing it the permission of the
+/// pool thread it runs on. Pool threads are reused, so the permission can't
+/// simply be claimed once per thread; but it can't leave the closure, since
+/// it isn't `Send`, so it's back with the thread by the time `f` returns.
+#[cfg(feature = "tokio")]
+pub fn spawn_blocking_with_permission<F, R>(f: F) -> tokio::task::JoinHandle<R>
+where
+    F: FnOnce(OuterMutexPermission) -> R + Send + 'static,
+    R: Send + 'static,
+{
+    tokio::task::spawn_blocking(move || f(OuterMutexPermission::get()))
+}
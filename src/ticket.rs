@@ -0,0 +1,236 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Condvar, LockResult, Mutex, MutexGuard, PoisonError, TryLockError, TryLockResult,
+    },
+};
+
+use crate::{DeadlockProofMutex, MutexBackend, MutexPermission};
+
+/// The tickets handed out so far, and the one now being served.
+struct Tickets {
+    next: u64,
+    serving: u64,
+}
+
+/// A fair mutex: threads claim it strictly in the order in which they
+/// started waiting for it, so none can be starved by others repeatedly
+/// barging in ahead of it, as can happen with [`Mutex`]. Each thread takes
+/// a ticket, and waits until its number is served. This costs throughput,
+/// since the mutex can't go to whichever thread happens to be running, so
+/// use it only where fairness matters more.
+///
+/// Use it as the backend of a [`DeadlockProofMutex`], created with
+/// [`DeadlockProofMutex::new_fair`]. It's poisoned, just as a
+/// [`Mutex`] is, if a thread panics while holding it.
+pub struct TicketMutex<T> {
+    tickets: Mutex<Tickets>,
+    served: Condvar,
+    poisoned: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+// Safety: the tickets give exclusive access to the data, as for `Mutex`.
+unsafe impl<T: Send> Send for TicketMutex<T> {}
+unsafe impl<T: Send> Sync for TicketMutex<T> {}
+
+impl<T> TicketMutex<T> {
+    /// Create a new fair mutex containing `content`.
+    pub const fn new(content: T) -> Self {
+        Self {
+            tickets: Mutex::new(Tickets {
+                next: 0,
+                serving: 0,
+            }),
+            served: Condvar::new(),
+            poisoned: AtomicBool::new(false),
+            data: UnsafeCell::new(content),
+        }
+    }
+
+    fn tickets(&self) -> MutexGuard<'_, Tickets> {
+        // The tickets are always consistent, even if a thread panicked.
+        self.tickets.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Wraps the mutex, just claimed, in a guard, reporting whether it's
+    /// poisoned.
+    fn guard(&self) -> LockResult<TicketMutexGuard<'_, T>> {
+        let guard = TicketMutexGuard(self, PhantomData);
+        if self.poisoned.load(Ordering::Relaxed) {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+}
+
+impl<T: Default> Default for TicketMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> fmt::Debug for TicketMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TicketMutex").finish_non_exhaustive()
+    }
+}
+
+/// A guard for a [`TicketMutex`]. When dropped, the mutex goes to the
+/// thread which has been waiting longest.
+pub struct TicketMutexGuard<'a, T>(&'a TicketMutex<T>, PhantomData<&'a mut T>);
+
+impl<T> Deref for TicketMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: the mutex is held for as long as the guard exists.
+        unsafe { &*self.0.data.get() }
+    }
+}
+
+impl<T> DerefMut for TicketMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: the mutex is held for as long as the guard exists.
+        unsafe { &mut *self.0.data.get() }
+    }
+}
+
+impl<T> Drop for TicketMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.0.poisoned.store(true, Ordering::Relaxed);
+        }
+        self.0.tickets().serving += 1;
+        self.0.served.notify_all();
+    }
+}
+
+// Safety: the contents live within the mutex, not the guard.
+unsafe impl<T> MutexBackend<T> for TicketMutex<T> {
+    type Guard<'a>
+        = TicketMutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn lock(&self) -> LockResult<TicketMutexGuard<'_, T>> {
+        let mut tickets = self.tickets();
+        let ticket = tickets.next;
+        tickets.next += 1;
+        while tickets.serving != ticket {
+            tickets = self
+                .served
+                .wait(tickets)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        drop(tickets);
+        self.guard()
+    }
+
+    fn try_lock(&self) -> TryLockResult<TicketMutexGuard<'_, T>> {
+        let mut tickets = self.tickets();
+        // Only if nobody holds it or is waiting for it.
+        if tickets.serving != tickets.next {
+            return Err(TryLockError::WouldBlock);
+        }
+        tickets.next += 1;
+        drop(tickets);
+        Ok(self.guard()?)
+    }
+
+    fn get_mut(&mut self) -> LockResult<&mut T> {
+        let data = self.data.get_mut();
+        if *self.poisoned.get_mut() {
+            Err(PoisonError::new(data))
+        } else {
+            Ok(data)
+        }
+    }
+
+    fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.poisoned.into_inner();
+        let data = self.data.into_inner();
+        if poisoned {
+            Err(PoisonError::new(data))
+        } else {
+            Ok(data)
+        }
+    }
+
+    fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    fn clear_poison(&self) {
+        self.poisoned.store(false, Ordering::Relaxed);
+    }
+}
+
+impl<T, P: MutexPermission, I> DeadlockProofMutex<T, P, I, TicketMutex<T>> {
+    /// Create a new deadlock-proof mutex which is claimed by waiting threads
+    /// strictly in turn, using a [`TicketMutex`]. The `identifier` is a type
+    /// unique to this mutex, just as for [`DeadlockProofMutex::new`].
+    pub fn new_fair(content: T, identifier: I) -> Self {
+        Self::from_backend(TicketMutex::new(content), identifier)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::{DeadlockProofMutex, MutexBackend, OuterMutexPermission};
+
+    use super::TicketMutex;
+
+    struct Counter;
+
+    #[test]
+    fn waiters_are_served_in_turn() {
+        let mutex = TicketMutex::new(Vec::new());
+        let guard = mutex.lock().unwrap();
+        thread::scope(|s| {
+            for i in 0..4 {
+                let mutex = &mutex;
+                s.spawn(move || mutex.lock().unwrap().push(i));
+                // Only start the next thread once this one holds its ticket.
+                while mutex.tickets().next != i + 2 {
+                    thread::yield_now();
+                }
+            }
+            drop(guard);
+        });
+        assert_eq!(mutex.into_inner().unwrap(), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn excludes_other_threads() {
+        let counter: DeadlockProofMutex<u32, OuterMutexPermission, Counter, TicketMutex<u32>> =
+            DeadlockProofMutex::new_fair(0, Counter);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        let mut guard = counter.lock(OuterMutexPermission::get()).unwrap();
+                        *guard += 1;
+                        guard.unlock();
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.into_inner().unwrap(), 400);
+    }
+}
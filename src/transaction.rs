@@ -0,0 +1,258 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Strict two-phase locking: mutices are claimed one by one in a declared
+//! lock order, and then all released together when the transaction commits
+//! or rolls back.
+
+use std::{
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::LockResult,
+};
+
+use crate::{
+    map_lock_result, DeadlockProofMutex, HeldLock, LockAfter, MutexBackend, MutexPermission,
+};
+
+/// The mutices held by a [`Transaction`]: a single [`TransactionLock`] for
+/// the first, then a pair of those before and the one added after, so that
+/// the contents come out as nested pairs in the order claimed, as in
+/// `let ((a, b), c) = transaction.get_mut();`.
+pub trait TransactionLocks {
+    /// References to the contents of each mutex.
+    type Contents<'g>
+    where
+        Self: 'g;
+
+    /// Mutable references to the contents of each mutex.
+    type ContentsMut<'g>
+    where
+        Self: 'g;
+
+    #[doc(hidden)]
+    fn contents(&self) -> Self::Contents<'_>;
+
+    #[doc(hidden)]
+    fn contents_mut(&mut self) -> Self::ContentsMut<'_>;
+
+    #[doc(hidden)]
+    fn rollback(&mut self);
+}
+
+/// One mutex held by a [`Transaction`], along with its contents as they
+/// were when it was claimed.
+#[doc(hidden)]
+pub struct TransactionLock<'a, T, M: MutexBackend<T> + 'a>(M::Guard<'a>, T);
+
+impl<'a, T, M: MutexBackend<T> + 'a> TransactionLocks for TransactionLock<'a, T, M> {
+    type Contents<'g>
+        = &'g T
+    where
+        Self: 'g;
+
+    type ContentsMut<'g>
+        = &'g mut T
+    where
+        Self: 'g;
+
+    fn contents(&self) -> &T {
+        self.0.deref()
+    }
+
+    fn contents_mut(&mut self) -> &mut T {
+        self.0.deref_mut()
+    }
+
+    fn rollback(&mut self) {
+        std::mem::swap(self.0.deref_mut(), &mut self.1);
+    }
+}
+
+impl<L: TransactionLocks, R: TransactionLocks> TransactionLocks for (L, R) {
+    type Contents<'g>
+        = (L::Contents<'g>, R::Contents<'g>)
+    where
+        Self: 'g;
+
+    type ContentsMut<'g>
+        = (L::ContentsMut<'g>, R::ContentsMut<'g>)
+    where
+        Self: 'g;
+
+    fn contents(&self) -> Self::Contents<'_> {
+        (self.0.contents(), self.1.contents())
+    }
+
+    fn contents_mut(&mut self) -> Self::ContentsMut<'_> {
+        (self.0.contents_mut(), self.1.contents_mut())
+    }
+
+    fn rollback(&mut self) {
+        self.0.rollback();
+        self.1.rollback();
+    }
+}
+
+/// The result of [`DeadlockProofMutex::begin_transaction`] and
+/// [`Transaction::lock`]: the transaction, holding one more mutex. If that
+/// mutex is poisoned, the error still contains the transaction.
+pub type TransactionResult<'a, P, I, L> = LockResult<Transaction<'a, P, I, L>>;
+
+/// A strict two-phase locking transaction, in its acquire phase: mutices
+/// can only be added, using [`Transaction::lock`], and only in increasing
+/// order of a lock order declared with [`crate::declare_lock_order`], the
+/// last of them so far having identifier `I`. Since every thread adds
+/// mutices in the same canonical order, transactions can't deadlock
+/// against each other. A single permission `P` claims them all.
+///
+/// The contents of each mutex are copied as it's claimed, so that
+/// [`Transaction::rollback`] can put them all back. Once everything needed
+/// is held, [`Transaction::prepare`] moves the transaction to its release
+/// phase, in which no more mutices can be added, and which ends by
+/// releasing them all at once, either keeping the changes or rolling them
+/// back. Dropping the transaction in either phase keeps the changes, just
+/// as dropping a guard does, but it's strongly recommended to commit or
+/// roll back explicitly.
+pub struct Transaction<'a, P: MutexPermission, I, L: TransactionLocks>(
+    PreparedTransaction<'a, P, L>,
+    PhantomData<I>,
+);
+
+impl<T: Clone, P: MutexPermission, I, M: MutexBackend<T>> DeadlockProofMutex<T, P, I, M> {
+    /// Starts a [`Transaction`] by acquiring this mutex, blocking the
+    /// current thread until it is able to do so. Later mutices must come
+    /// after this one in its lock order.
+    pub fn begin_transaction(
+        &self,
+        permission: P,
+    ) -> TransactionResult<'_, P, I, TransactionLock<'_, T, M>> {
+        let (guard, held) = self.transaction_lock();
+        map_lock_result(guard, |guard| {
+            Transaction(
+                PreparedTransaction {
+                    locks: guard,
+                    held: vec![held],
+                    permission,
+                    _lifetime: PhantomData,
+                },
+                PhantomData,
+            )
+        })
+    }
+
+    /// Claims this mutex for a transaction, copying its contents.
+    fn transaction_lock(&self) -> (LockResult<TransactionLock<'_, T, M>>, HeldLock) {
+        let guard = map_lock_result(self.0.lock::<T, I, P::Domain, M>(&self.3), |guard| {
+            let original = guard.clone();
+            TransactionLock(guard, original)
+        });
+        (guard, self.0.held::<I, P::Domain, _>(&self.3))
+    }
+}
+
+impl<'a, P: MutexPermission, I, L: TransactionLocks> Transaction<'a, P, I, L> {
+    /// Adds `mutex` to the transaction, blocking the current thread until
+    /// it is able to claim it. Its identifier must be declared to come after
+    /// that of the mutex added last.
+    // The error holds the transaction, just as for every other way of
+    // locking.
+    #[allow(clippy::result_large_err)]
+    pub fn lock<T: Clone, J: LockAfter<I>, M: MutexBackend<T>>(
+        self,
+        mutex: &'a DeadlockProofMutex<T, P, J, M>,
+    ) -> TransactionResult<'a, P, J, (L, TransactionLock<'a, T, M>)> {
+        let PreparedTransaction {
+            locks,
+            mut held,
+            permission,
+            _lifetime,
+        } = self.0;
+        let (guard, hold) = mutex.transaction_lock();
+        held.push(hold);
+        map_lock_result(guard, |guard| {
+            Transaction(
+                PreparedTransaction {
+                    locks: (locks, guard),
+                    held,
+                    permission,
+                    _lifetime,
+                },
+                PhantomData,
+            )
+        })
+    }
+
+    /// Ends the acquire phase: no more mutices can be added.
+    pub fn prepare(self) -> PreparedTransaction<'a, P, L> {
+        self.0
+    }
+
+    /// The contents of every mutex, as nested pairs in the order claimed.
+    pub fn get(&self) -> L::Contents<'_> {
+        self.0.get()
+    }
+
+    /// The contents of every mutex, mutably, as nested pairs in the order
+    /// claimed.
+    pub fn get_mut(&mut self) -> L::ContentsMut<'_> {
+        self.0.get_mut()
+    }
+
+    /// Releases every mutex, keeping the changes made to their contents, as
+    /// [`PreparedTransaction::commit`] does. Returns the mutex permission
+    /// token such that you can use it again to claim a different mutex.
+    pub fn commit(self) -> P {
+        self.0.commit()
+    }
+
+    /// Puts back the contents of every mutex as they were when claimed, then
+    /// releases them all, as [`PreparedTransaction::rollback`] does.
+    pub fn rollback(self) -> P {
+        self.0.rollback()
+    }
+}
+
+/// A [`Transaction`] in its release phase, from [`Transaction::prepare`]:
+/// the mutices can still be used, but no more can be added, and all of
+/// them are released together by [`PreparedTransaction::commit`] or
+/// [`PreparedTransaction::rollback`].
+pub struct PreparedTransaction<'a, P: MutexPermission, L: TransactionLocks> {
+    locks: L,
+    /// After the guards, so that the mutices are released first.
+    held: Vec<HeldLock>,
+    permission: P,
+    _lifetime: PhantomData<&'a ()>,
+}
+
+impl<P: MutexPermission, L: TransactionLocks> PreparedTransaction<'_, P, L> {
+    /// The contents of every mutex, as nested pairs in the order claimed.
+    pub fn get(&self) -> L::Contents<'_> {
+        self.locks.contents()
+    }
+
+    /// The contents of every mutex, mutably, as nested pairs in the order
+    /// claimed.
+    pub fn get_mut(&mut self) -> L::ContentsMut<'_> {
+        self.locks.contents_mut()
+    }
+
+    /// Releases every mutex, keeping the changes made to their contents.
+    /// Returns the mutex permission token such that you can use it again to
+    /// claim a different mutex.
+    pub fn commit(self) -> P {
+        self.permission
+    }
+
+    /// Puts back the contents of every mutex as they were when claimed, then
+    /// releases them all. Returns the mutex permission token.
+    pub fn rollback(mut self) -> P {
+        self.locks.rollback();
+        self.permission
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    hint,
+    sync::{LockResult, Mutex, TryLockError, TryLockResult},
+};
+
+use crate::{DeadlockProofMutex, MutexBackend, MutexPermission};
+
+/// How a [`TunedMutex`] waits for its mutex when another thread holds it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AcquireStrategy {
+    /// Block straight away, just as the underlying mutex would by itself.
+    #[default]
+    Block,
+    /// Retry up to this many times, spinning briefly between attempts,
+    /// then block. This avoids the cost of parking and waking a thread
+    /// when the mutex is only ever held for a very short time.
+    SpinThenPark(u32),
+    /// Keep spinning until the mutex is free, never blocking. Only suitable
+    /// where the holder is certain to be running on another core and to
+    /// release the mutex almost at once.
+    Spin,
+}
+
+/// A backend for [`DeadlockProofMutex`] which wraps any other, choosing how
+/// to wait for it using an [`AcquireStrategy`], for tuning the performance
+/// of short critical sections. Create one using
+/// [`DeadlockProofMutex::with_strategy`], or [`TunedMutex::new`] along with
+/// [`DeadlockProofMutex::from_backend`] to tune some other backend.
+#[derive(Debug, Default)]
+pub struct TunedMutex<M> {
+    mutex: M,
+    strategy: AcquireStrategy,
+}
+
+impl<M> TunedMutex<M> {
+    /// Wraps `mutex`, waiting for it according to `strategy`.
+    pub const fn new(mutex: M, strategy: AcquireStrategy) -> Self {
+        Self { mutex, strategy }
+    }
+
+    /// The strategy used to wait for the mutex.
+    pub fn strategy(&self) -> AcquireStrategy {
+        self.strategy
+    }
+}
+
+// Safety: the guards are those of the wrapped mutex, which upholds the
+// requirements itself.
+unsafe impl<T, M: MutexBackend<T>> MutexBackend<T> for TunedMutex<M> {
+    type Guard<'a>
+        = M::Guard<'a>
+    where
+        Self: 'a;
+
+    fn lock(&self) -> LockResult<M::Guard<'_>> {
+        let spins = match self.strategy {
+            AcquireStrategy::Block => 0,
+            AcquireStrategy::SpinThenPark(spins) => spins.into(),
+            // Far longer than any mutex is held for.
+            AcquireStrategy::Spin => u64::MAX,
+        };
+        for _ in 0..spins {
+            match self.mutex.try_lock() {
+                Ok(guard) => return Ok(guard),
+                Err(TryLockError::Poisoned(e)) => return Err(e),
+                Err(TryLockError::WouldBlock) => hint::spin_loop(),
+            }
+        }
+        self.mutex.lock()
+    }
+
+    fn try_lock(&self) -> TryLockResult<M::Guard<'_>> {
+        self.mutex.try_lock()
+    }
+
+    fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.mutex.get_mut()
+    }
+
+    fn into_inner(self) -> LockResult<T> {
+        self.mutex.into_inner()
+    }
+
+    fn is_poisoned(&self) -> bool {
+        self.mutex.is_poisoned()
+    }
+
+    fn clear_poison(&self) {
+        self.mutex.clear_poison()
+    }
+}
+
+impl<T, P: MutexPermission, I> DeadlockProofMutex<T, P, I, TunedMutex<Mutex<T>>> {
+    /// Create a new deadlock-proof mutex which waits for its [`Mutex`]
+    /// according to `strategy`. The `identifier` is a type unique to this
+    /// mutex, just as for [`DeadlockProofMutex::new`].
+    pub fn with_strategy(content: T, identifier: I, strategy: AcquireStrategy) -> Self {
+        Self::from_backend(TunedMutex::new(Mutex::new(content), strategy), identifier)
+    }
+}
@@ -0,0 +1,60 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use core::ops::{Deref, DerefMut};
+
+use crate::{
+    map_lock_result, poison::LockResult, DeadlockProofMutex, HeldLock, Mutex, MutexBackend,
+    MutexPermission,
+};
+
+impl<T, P: MutexPermission, I, M: MutexBackend<T>> DeadlockProofMutex<T, P, I, M> {
+    /// Acquires this mutex without a permission, blocking the current thread
+    /// until it is able to do so. This is an escape hatch for converting a
+    /// large codebase to deadlock-proof mutices a module at a time: code not
+    /// yet converted can keep claiming the mutex as it always has.
+    ///
+    /// The `runtime-checks` feature still sees mutices claimed this way, and
+    /// is a good way to find mistakes while such code remains.
+    ///
+    /// # Safety
+    ///
+    /// This voids the proof that the program can't deadlock. The caller must
+    /// ensure by other means that claiming this mutex can't take part in a
+    /// deadlock: for example, that no other mutex is held at the time, or
+    /// that all threads claim the mutices involved in the same order. Doing
+    /// otherwise can't cause undefined behaviour, only deadlocks.
+    pub unsafe fn lock_unchecked(&self) -> LockResult<UncheckedMutexGuard<'_, T, M>> {
+        map_lock_result(self.0.lock::<T, I, P::Domain, M>(&self.3), |guard| {
+            UncheckedMutexGuard(guard, self.0.held::<I, P::Domain, _>(&self.3))
+        })
+    }
+}
+
+/// An ordinary guard for a [`DeadlockProofMutex`] claimed without a
+/// permission, using [`DeadlockProofMutex::lock_unchecked`]. The mutex is
+/// released when this is dropped.
+pub struct UncheckedMutexGuard<'a, T, M: MutexBackend<T> + 'a = Mutex<T>>(
+    M::Guard<'a>,
+    #[allow(dead_code)] // only needed for its drop behaviour
+    HeldLock,
+);
+
+impl<'a, T, M: MutexBackend<T> + 'a> Deref for UncheckedMutexGuard<'a, T, M> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.deref()
+    }
+}
+
+impl<'a, T, M: MutexBackend<T> + 'a> DerefMut for UncheckedMutexGuard<'a, T, M> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.deref_mut()
+    }
+}
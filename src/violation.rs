@@ -0,0 +1,232 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    backtrace::Backtrace,
+    fmt,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use crate::IdentifierName;
+
+/// A problem found by one of the crate's runtime checks - that is, a rule
+/// which the type system can't enforce by itself.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Violation {
+    /// What went wrong.
+    pub kind: ViolationKind,
+    /// The type names of the identifiers of the mutices involved. For
+    /// [`ViolationKind::DomainOrder`], these are instead the identifier of
+    /// the mutex being claimed, its permission domain, and the domain of a
+    /// mutex already held.
+    pub identifiers: Vec<&'static str>,
+    /// Where the guard involved was created, for
+    /// [`ViolationKind::GuardDropped`].
+    pub backtrace: Option<Arc<Backtrace>>,
+}
+
+/// The different sorts of [`Violation`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ViolationKind {
+    /// A guard was held while an async task yielded.
+    GuardHeldAcrossAwait,
+    /// A mutex was claimed while holding one from a permission domain of
+    /// equal or higher rank, or one which a [`crate::BridgePermission`]
+    /// declared to be entered after it.
+    DomainOrder,
+    /// The first guard of a [`crate::DeadlockProofReentrantLock`] was
+    /// released while the same thread still held further guards for it.
+    ReentrantLockOutlived,
+    /// Two mutices were claimed in one order by one thread and the opposite
+    /// order by another (or earlier), so the threads could deadlock. Only
+    /// checked for [`crate::DynamicCheckedMutex`], and for other mutices
+    /// with the `runtime-checks` feature.
+    LockOrderInversion,
+    /// A mutex was created with the same identifier as another which still
+    /// exists. Only checked with the `identifier-check` feature in debug
+    /// builds.
+    DuplicateIdentifier,
+    /// Two permission domains were bridged in both orders, so threads
+    /// using the two bridges could deadlock. The identifiers are the outer
+    /// and inner domains of the later bridge.
+    ConflictingHierarchyOrder,
+    /// A [`crate::ErasedPermission`] was created at a level no higher than
+    /// one already alive in the same thread. Only checked with the
+    /// `runtime-checks` feature.
+    ErasedLevelOrder,
+    /// A guard was dropped, other than while panicking, rather than being
+    /// unlocked to hand back the permission within it. Only checked with
+    /// the `leak-check` feature in debug builds, and only for the guards of
+    /// a [`crate::DeadlockProofMutex`] which own their permission: those
+    /// from [`crate::DeadlockProofMutex::lock`],
+    /// [`crate::DeadlockProofMutex::lock_for_nested`] and their `try_`,
+    /// timed and conditional variants. Guards which borrow their permission
+    /// lose nothing when dropped, and those of other locks, pools and
+    /// groups of mutices aren't checked.
+    GuardDropped,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ViolationKind::GuardHeldAcrossAwait => write!(
+                f,
+                "Deadlock-proof mutex guard(s) held across an await point"
+            )?,
+            ViolationKind::DomainOrder => write!(
+                f,
+                "Deadlock-proof mutex claimed while holding one from a permission domain ordered after its own"
+            )?,
+            ViolationKind::ReentrantLockOutlived => write!(
+                f,
+                "Deadlock-proof reentrant lock unlocked while re-locked guards remained"
+            )?,
+            ViolationKind::LockOrderInversion => write!(
+                f,
+                "Deadlock-proof mutex claimed while holding one normally claimed after it"
+            )?,
+            ViolationKind::DuplicateIdentifier => write!(
+                f,
+                "Deadlock-proof mutex created with the same identifier as another which still exists"
+            )?,
+            ViolationKind::ConflictingHierarchyOrder => write!(
+                f,
+                "Deadlock-proof lock hierarchies bridged in both orders"
+            )?,
+            ViolationKind::ErasedLevelOrder => write!(
+                f,
+                "Erased mutex permission created at a level not above one already in use"
+            )?,
+            ViolationKind::GuardDropped => write!(
+                f,
+                "Deadlock-proof mutex guard dropped rather than unlocked"
+            )?,
+        }
+        write!(f, ":")?;
+        for (i, identifier) in self.identifiers.iter().enumerate() {
+            let separator = if i == 0 { " " } else { ", " };
+            write!(f, "{separator}{}", IdentifierName(identifier))?;
+        }
+        if let Some(backtrace) = &self.backtrace {
+            write!(f, "\nGuard created at:\n{backtrace}")?;
+        }
+        Ok(())
+    }
+}
+
+/// What to do when a runtime check finds a [`Violation`]. This can be set
+/// for the whole program using [`set_violation_action`], and overridden for
+/// individual mutices, with the `violation-override` feature, or checks
+/// where they allow it.
+#[derive(Clone, Copy, Debug)]
+pub enum ViolationAction {
+    /// Panic with a description of the violation. This is the default, and
+    /// is most suitable for tests.
+    Panic,
+    /// Abort the whole process, for code where continuing after a broken
+    /// invariant is worse than stopping.
+    Abort,
+    /// Print a description of the violation to stderr and carry on.
+    Log,
+    /// Call the given function and carry on (unless it panics).
+    Callback(fn(&Violation)),
+}
+
+static DEFAULT_ACTION: RwLock<ViolationAction> = RwLock::new(ViolationAction::Panic);
+
+/// Set what happens when a runtime check finds a violation, unless the
+/// check has been told otherwise.
+pub fn set_violation_action(action: ViolationAction) {
+    *DEFAULT_ACTION
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = action;
+}
+
+/// The action set by [`set_violation_action`].
+pub fn violation_action() -> ViolationAction {
+    *DEFAULT_ACTION
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The action for violations involving one mutex, stored within it,
+/// overriding the global setting if given.
+pub(crate) struct ActionOverride(RwLock<Option<ViolationAction>>);
+
+impl ActionOverride {
+    pub(crate) const fn new() -> Self {
+        Self(RwLock::new(None))
+    }
+
+    pub(crate) fn set(&self, action: ViolationAction) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = Some(action);
+    }
+
+    pub(crate) fn get(&self) -> Option<ViolationAction> {
+        *self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Responds to a violation as configured: by `action` if given, otherwise by
+/// the global setting.
+pub(crate) fn report_violation(violation: Violation, action: Option<ViolationAction>) {
+    match action.unwrap_or_else(violation_action) {
+        ViolationAction::Panic => panic!("{violation}"),
+        ViolationAction::Abort => {
+            eprintln!("{violation}");
+            std::process::abort()
+        }
+        ViolationAction::Log => eprintln!("{violation}"),
+        ViolationAction::Callback(callback) => callback(&violation),
+    }
+}
+
+#[cfg(all(test, feature = "violation-override"))]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::{
+        declare_permission_domain, set_violation_action, DeadlockProofMutex, OuterMutexPermission,
+    };
+
+    use super::{Violation, ViolationAction, ViolationKind};
+
+    declare_permission_domain!(Settings, 2);
+    declare_permission_domain!(Logging, 1);
+
+    static REPORTED: AtomicUsize = AtomicUsize::new(0);
+
+    fn record(violation: &Violation) {
+        assert_eq!(violation.kind, ViolationKind::DomainOrder);
+        REPORTED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn mutex_action_overrides_the_global_one() {
+        struct Config;
+        struct Log;
+        set_violation_action(ViolationAction::Panic);
+        let config: DeadlockProofMutex<u32, OuterMutexPermission<Settings>, Config> =
+            DeadlockProofMutex::new(0, Config);
+        let log: DeadlockProofMutex<u32, OuterMutexPermission<Logging>, Log> =
+            DeadlockProofMutex::new(0, Log);
+        log.set_violation_action(ViolationAction::Callback(record));
+        let config_guard = config.lock(OuterMutexPermission::get_for_domain()).unwrap();
+        // Logging ranks below Settings, so this is out of order, but the
+        // mutex's own action records it rather than panicking.
+        let log_guard = log.lock(OuterMutexPermission::get_for_domain()).unwrap();
+        log_guard.unlock();
+        config_guard.unlock();
+        assert_eq!(
+            REPORTED.load(Ordering::SeqCst),
+            usize::from(!cfg!(deadlock_proof_mutex_unverified))
+        );
+    }
+}
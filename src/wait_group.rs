@@ -0,0 +1,73 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+
+use crate::{domain, DefaultDomain, OuterMutexPermission};
+
+/// Waits for a number of pieces of work, typically done by other threads,
+/// to finish, like Go's `sync.WaitGroup` or a countdown latch. Waiting for
+/// it can't be used to build a deadlock out of deadlock-proof mutices.
+///
+/// Each piece of work is registered with [`DeadlockProofWaitGroup::add`]
+/// and reported finished with [`DeadlockProofWaitGroup::done`].
+/// [`DeadlockProofWaitGroup::wait`] then blocks until every piece of work
+/// registered has finished. A thread which waits while holding a mutex that
+/// one of the workers needs would never wake, so, as for
+/// [`crate::DeadlockProofBarrier`], waiting needs to see this thread's
+/// [`OuterMutexPermission`], which proves that no mutex is held. Mutices
+/// from other permission domains are checked for at runtime.
+#[derive(Default)]
+pub struct DeadlockProofWaitGroup(Mutex<usize>, Condvar);
+
+impl DeadlockProofWaitGroup {
+    /// Create a new wait group with no work outstanding.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn outstanding(&self) -> MutexGuard<'_, usize> {
+        // The count is always consistent, even if a thread panicked.
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Registers `n` more pieces of work to be waited for.
+    pub fn add(&self, n: usize) {
+        *self.outstanding() += n;
+    }
+
+    /// Reports that one piece of work has finished, waking the waiting
+    /// threads if it was the last. Panics if no work is outstanding.
+    pub fn done(&self) {
+        let mut outstanding = self.outstanding();
+        *outstanding = outstanding
+            .checked_sub(1)
+            .expect("DeadlockProofWaitGroup::done called more times than work was added");
+        if *outstanding == 0 {
+            self.1.notify_all();
+        }
+    }
+
+    /// The number of pieces of work still outstanding. Other threads may
+    /// change this at any moment.
+    pub fn count(&self) -> usize {
+        *self.outstanding()
+    }
+
+    /// Blocks until all the work registered has finished. The permission is
+    /// only borrowed, since the thread holds no mutex while waiting and so
+    /// needs it back afterwards anyway.
+    pub fn wait(&self, _permission: &OuterMutexPermission) {
+        domain::check_lock::<DefaultDomain, Self>();
+        drop(
+            self.1
+                .wait_while(self.outstanding(), |outstanding| *outstanding > 0)
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+    }
+}
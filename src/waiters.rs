@@ -0,0 +1,156 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A global list of threads waiting for the contents of a mutex to change,
+//! keyed by the address of the mutex. Keeping this outside the mutices
+//! means they don't each need to carry a condvar around, and releasing a
+//! guard only costs an atomic load when nobody is waiting.
+
+use std::thread::{self, Thread};
+
+struct Waiter {
+    key: usize,
+    id: usize,
+    thread: Thread,
+}
+
+/// The list of waiters, shared between threads.
+#[cfg(not(all(target_family = "wasm", not(target_feature = "atomics"))))]
+mod list {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, PoisonError,
+    };
+
+    use super::Waiter;
+
+    static WAITERS: Mutex<Vec<Waiter>> = Mutex::new(Vec::new());
+    static WAITER_COUNT: AtomicUsize = AtomicUsize::new(0);
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+    pub(super) fn next_id() -> usize {
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub(super) fn is_empty() -> bool {
+        WAITER_COUNT.load(Ordering::SeqCst) == 0
+    }
+
+    pub(super) fn with<R>(f: impl FnOnce(&mut Vec<Waiter>) -> R) -> R {
+        let mut waiters = WAITERS.lock().unwrap_or_else(PoisonError::into_inner);
+        let before = waiters.len();
+        let result = f(&mut waiters);
+        if waiters.len() > before {
+            WAITER_COUNT.fetch_add(waiters.len() - before, Ordering::SeqCst);
+        } else if waiters.len() < before {
+            WAITER_COUNT.fetch_sub(before - waiters.len(), Ordering::SeqCst);
+        }
+        result
+    }
+}
+
+/// The list of waiters on WebAssembly without threads, where there's only
+/// ever one thread and so no need for atomics or locking.
+#[cfg(all(target_family = "wasm", not(target_feature = "atomics")))]
+mod list {
+    use std::cell::{Cell, RefCell};
+
+    use super::Waiter;
+
+    thread_local! {
+        static WAITERS: RefCell<Vec<Waiter>> = const { RefCell::new(Vec::new()) };
+        static NEXT_ID: Cell<usize> = const { Cell::new(0) };
+    }
+
+    pub(super) fn next_id() -> usize {
+        NEXT_ID.replace(NEXT_ID.get() + 1)
+    }
+
+    pub(super) fn is_empty() -> bool {
+        WAITERS.with_borrow(Vec::is_empty)
+    }
+
+    pub(super) fn with<R>(f: impl FnOnce(&mut Vec<Waiter>) -> R) -> R {
+        WAITERS.with_borrow_mut(f)
+    }
+}
+
+/// The key identifying `mutex` in the list of waiters: its address.
+pub(crate) fn key<M: ?Sized>(mutex: &M) -> usize {
+    (mutex as *const M).cast::<u8>() as usize
+}
+
+/// The current thread's entry in the list of waiters. Removed when dropped.
+pub(crate) struct Registration(usize);
+
+/// Records that the current thread is about to park waiting for a change to
+/// the mutex with the given [`key`]. This must be done while still holding
+/// that mutex, so that whoever next changes its contents will see the
+/// registration when they release it.
+pub(crate) fn register(key: usize) -> Registration {
+    let id = list::next_id();
+    list::with(|waiters| {
+        waiters.push(Waiter {
+            key,
+            id,
+            thread: thread::current(),
+        })
+    });
+    Registration(id)
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        list::with(|waiters| {
+            if let Some(pos) = waiters.iter().position(|waiter| waiter.id == self.0) {
+                waiters.swap_remove(pos);
+            }
+        });
+    }
+}
+
+/// Wakes any threads waiting for changes to the mutex with the given [`key`].
+/// Called after a guard for that mutex has been released.
+pub(crate) fn notify(key: usize) {
+    if list::is_empty() {
+        return;
+    }
+    list::with(|waiters| {
+        for waiter in waiters.iter().filter(|waiter| waiter.key == key) {
+            waiter.thread.unpark();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use super::{notify, register};
+
+    #[test]
+    fn notify_wakes_waiters_for_its_key() {
+        let changed = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| {
+                let _registration = register(1);
+                while !changed.load(Ordering::SeqCst) {
+                    thread::park();
+                }
+            });
+            thread::sleep(Duration::from_millis(20));
+            changed.store(true, Ordering::SeqCst);
+            notify(2);
+            notify(1);
+        });
+    }
+}
@@ -0,0 +1,15 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Rank zero belongs to the default domain, whose mutices aren't tracked
+// between domains, so another domain with that rank couldn't be ordered
+// against them.
+
+deadlock_proof_mutex::declare_permission_domain!(Logging, 0);
+
+fn main() {}
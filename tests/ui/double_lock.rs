@@ -0,0 +1,20 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Claiming the same mutex twice in one thread would deadlock immediately.
+
+use deadlock_proof_mutex::{DeadlockProofMutex, OuterMutexPermission};
+
+struct Only;
+
+fn main() {
+    let mutex: DeadlockProofMutex<u32, OuterMutexPermission, Only> =
+        DeadlockProofMutex::new(0, Only);
+    let (_guard, nested) = mutex.lock_for_nested(OuterMutexPermission::get()).unwrap();
+    let _ = mutex.lock(nested);
+}
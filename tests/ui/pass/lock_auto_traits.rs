@@ -0,0 +1,27 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Although permissions are neither Send nor Sync, the locks parameterized
+// by them must be both, so that they can be shared between threads.
+
+use deadlock_proof_mutex::{
+    DeadlockProofMutex, DeadlockProofRwLock, DeadlockProofSemaphore, NestedMutexPermission,
+    OuterMutexPermission, SequentialMutexPermission,
+};
+
+struct Id;
+
+fn assert_send_sync<T: Send + Sync>() {}
+
+fn main() {
+    assert_send_sync::<DeadlockProofMutex<u32, OuterMutexPermission, Id>>();
+    assert_send_sync::<DeadlockProofMutex<u32, NestedMutexPermission<OuterMutexPermission, Id>, Id>>();
+    assert_send_sync::<DeadlockProofMutex<u32, SequentialMutexPermission<OuterMutexPermission, Id>, Id>>();
+    assert_send_sync::<DeadlockProofRwLock<u32, OuterMutexPermission, Id>>();
+    assert_send_sync::<DeadlockProofSemaphore<OuterMutexPermission, Id>>();
+}
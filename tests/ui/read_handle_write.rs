@@ -0,0 +1,23 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A read handle's guard only gives shared access to the contents.
+
+use std::sync::Arc;
+
+use deadlock_proof_mutex::{DeadlockProofMutex, OuterMutexPermission};
+
+struct Id;
+
+fn main() {
+    let mutex = Arc::new(DeadlockProofMutex::new(0, Id));
+    let handle = mutex.read_handle();
+    let guard = handle.lock(OuterMutexPermission::get()).unwrap();
+    *guard += 1;
+    guard.unlock();
+}
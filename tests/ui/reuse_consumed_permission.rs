@@ -0,0 +1,25 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A permission is used up by claiming a mutex, so it can't claim a second
+// one while the first is held.
+
+use deadlock_proof_mutex::{DeadlockProofMutex, OuterMutexPermission};
+
+struct First;
+struct Second;
+
+fn main() {
+    let mutex1: DeadlockProofMutex<u32, OuterMutexPermission, First> =
+        DeadlockProofMutex::new(0, First);
+    let mutex2: DeadlockProofMutex<u32, OuterMutexPermission, Second> =
+        DeadlockProofMutex::new(0, Second);
+    let permission = OuterMutexPermission::get();
+    let _guard1 = mutex1.lock(permission);
+    let _guard2 = mutex2.lock(permission);
+}
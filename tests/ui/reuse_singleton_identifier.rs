@@ -0,0 +1,26 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// An identifier declared along with its static mutex belongs to that mutex
+// alone, so no other mutex can be created with it.
+
+use deadlock_proof_mutex::{
+    declare_static_deadlock_proof_mutex, DeadlockProofMutex, OuterMutexPermission,
+};
+
+mod counter {
+    use super::*;
+
+    declare_static_deadlock_proof_mutex!(pub static COUNTER: DeadlockProofMutex<u32, OuterMutexPermission, CounterId> = 0;);
+}
+
+fn main() {
+    let other: DeadlockProofMutex<u32, OuterMutexPermission, counter::CounterId> =
+        DeadlockProofMutex::new(1, counter::CounterId);
+    let _ = (&counter::COUNTER, other);
+}
@@ -0,0 +1,21 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A guard holds its thread's permission, so can't be sent to another thread
+// either, whatever the mutex backend.
+
+use deadlock_proof_mutex::{DeadlockProofMutex, OuterMutexPermission};
+
+struct Id;
+
+static MUTEX: DeadlockProofMutex<u32, OuterMutexPermission, Id> = DeadlockProofMutex::const_new(0, Id);
+
+fn main() {
+    let guard = MUTEX.lock(OuterMutexPermission::get()).unwrap();
+    std::thread::spawn(move || drop(guard));
+}
@@ -0,0 +1,16 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Permissions belong to their thread, so can't be sent to another.
+
+use deadlock_proof_mutex::OuterMutexPermission;
+
+fn main() {
+    let permission = OuterMutexPermission::get();
+    std::thread::spawn(move || drop(permission));
+}
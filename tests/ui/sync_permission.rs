@@ -0,0 +1,18 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Permissions can't be shared between threads either, or another thread
+// could borrow one to claim mutices with lock_borrowing.
+
+use deadlock_proof_mutex::OuterMutexPermission;
+
+fn assert_sync<T: Sync>() {}
+
+fn main() {
+    assert_sync::<OuterMutexPermission>();
+}
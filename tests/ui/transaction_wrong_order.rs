@@ -0,0 +1,24 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A transaction can only add mutices in their declared lock order.
+
+use deadlock_proof_mutex::{declare_lock_order, DeadlockProofMutex, OuterMutexPermission};
+
+declare_lock_order!(First < Second);
+
+fn main() {
+    let first: DeadlockProofMutex<u32, OuterMutexPermission, First> =
+        DeadlockProofMutex::new(0, First);
+    let second: DeadlockProofMutex<u32, OuterMutexPermission, Second> =
+        DeadlockProofMutex::new(0, Second);
+    let transaction = second
+        .begin_transaction(OuterMutexPermission::get())
+        .unwrap();
+    let _ = transaction.lock(&first);
+}
@@ -0,0 +1,30 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Waiting releases the parent and claims it again, which would claim it
+// after a child still held.
+
+use deadlock_proof_mutex::{
+    DeadlockProofCondvar, DeadlockProofMutex, NestedMutexPermission, OuterMutexPermission,
+};
+
+struct Parent;
+struct Child;
+
+fn main() {
+    let parent: DeadlockProofMutex<u32, OuterMutexPermission, Parent> =
+        DeadlockProofMutex::new(0, Parent);
+    let child: DeadlockProofMutex<u32, NestedMutexPermission<OuterMutexPermission, Parent>, Child> =
+        DeadlockProofMutex::new(0, Child);
+    let condvar = DeadlockProofCondvar::new();
+    let (guard, mut nested) = parent.lock_for_nested(OuterMutexPermission::get()).unwrap();
+    let child_guard = child.lock_borrowing(&mut nested).unwrap();
+    let guard = condvar.wait_nested(guard, &mut nested).unwrap();
+    drop(child_guard);
+    drop(guard);
+}
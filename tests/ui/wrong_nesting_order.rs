@@ -0,0 +1,24 @@
+// Copyright 2023 Google LLC
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Claiming two nested mutices the other way round from how they're
+// declared would let two threads deadlock.
+
+use deadlock_proof_mutex::{DeadlockProofMutex, NestedMutexPermission, OuterMutexPermission};
+
+struct Outer;
+struct Inner;
+
+fn main() {
+    let outer: DeadlockProofMutex<u32, OuterMutexPermission, Outer> =
+        DeadlockProofMutex::new(0, Outer);
+    let inner: DeadlockProofMutex<u32, NestedMutexPermission<OuterMutexPermission, Outer>, Inner> =
+        DeadlockProofMutex::new(0, Inner);
+    let (_inner_guard, inner_nested) = inner.lock_for_nested(OuterMutexPermission::get()).unwrap();
+    let _ = outer.lock(inner_nested);
+}